@@ -0,0 +1,1002 @@
+#[cfg(feature = "admin")]
+use crate::audit::{Auditor, Change};
+#[cfg(feature = "admin")]
+use crate::build_info::{BuildInfo, FEATURES};
+use crate::cache::SimpleCache;
+#[cfg(feature = "admin")]
+use crate::cache::{CacheStats, ExportedEntry, WrongType};
+#[cfg(feature = "chaos")]
+use crate::chaos::Faults;
+#[cfg(feature = "admin")]
+use crate::dashboard;
+#[cfg(feature = "admin")]
+use crate::digest::{self, DEFAULT_BUCKETS};
+#[cfg(feature = "admin")]
+use crate::encoding::{self, Encoding};
+#[cfg(feature = "admin")]
+use crate::eviction::Priority;
+#[cfg(feature = "admin")]
+use crate::key_groups::KeyGroups;
+#[cfg(feature = "admin")]
+use crate::keys::{CacheKey, InvalidKey};
+#[cfg(feature = "admin")]
+use crate::listener::BoundAddresses;
+#[cfg(feature = "admin")]
+use crate::openapi;
+#[cfg(feature = "metrics")]
+use crate::openmetrics;
+#[cfg(feature = "admin")]
+use crate::pins::Pin;
+use crate::pressure::MemoryPressure;
+#[cfg(feature = "admin")]
+use crate::purge::{self, Purges};
+#[cfg(feature = "metrics")]
+use crate::scrape::Scrapes;
+#[cfg(any(feature = "admin", feature = "metrics"))]
+use crate::settings::Settings;
+#[cfg(feature = "admin")]
+use crate::ttl::{self, Expiry};
+#[cfg(feature = "admin")]
+use crate::usage::UsageTracker;
+#[cfg(feature = "admin")]
+use actix_web::delete;
+#[cfg(any(feature = "admin", feature = "chaos"))]
+use actix_web::post;
+#[cfg(any(feature = "admin", feature = "metrics"))]
+use actix_web::HttpRequest;
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse},
+    error::ErrorUnauthorized,
+    get,
+    http::header,
+    web, Error, HttpResponse,
+};
+use futures::future::{ok, Either, Future};
+#[cfg(feature = "admin")]
+use futures::{stream, StreamExt};
+#[cfg(any(feature = "admin", feature = "metrics"))]
+use prometheus::Registry;
+#[cfg(feature = "metrics")]
+use prometheus::{Encoder, TextEncoder, TEXT_FORMAT};
+#[cfg(feature = "admin")]
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "admin")]
+use std::collections::HashMap;
+#[cfg(feature = "metrics")]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The largest body accepted by the import endpoint.
+#[cfg(feature = "admin")]
+const MAX_IMPORT_SIZE: usize = 256 * 1024 * 1024;
+
+/// The number of keys read for each chunk of a streamed export.
+#[cfg(feature = "admin")]
+const EXPORT_CHUNK_SIZE: usize = 256;
+
+/// The most keys listed by an eviction preview by default.
+#[cfg(feature = "admin")]
+const PREVIEW_MAX_KEYS: usize = 1000;
+
+/// Paths that are served without authentication, e.g. for load balancer health checks.
+const UNAUTHENTICATED_PATHS: &[&str] = &["/healthz"];
+
+#[cfg(feature = "admin")]
+#[derive(Serialize)]
+struct Stats<'a> {
+    #[serde(flatten)]
+    cache: CacheStats,
+    listen_addresses: &'a BoundAddresses,
+}
+
+/// The configuration a running instance is using.
+#[cfg(feature = "admin")]
+#[derive(Serialize)]
+struct Config<'a> {
+    /// The settings with secrets redacted.
+    #[serde(flatten)]
+    settings: Settings,
+    listen_addresses: &'a BoundAddresses,
+    /// The cargo features compiled in.
+    features: &'static [&'static str],
+}
+
+#[cfg(feature = "admin")]
+#[derive(Deserialize)]
+struct KeysQuery {
+    #[serde(default)]
+    prefix: String,
+    limit: Option<usize>,
+}
+
+#[cfg(feature = "admin")]
+#[derive(Deserialize)]
+struct PurgeQuery {
+    pattern: String,
+}
+
+#[cfg(feature = "admin")]
+#[derive(Deserialize)]
+struct RenameQuery {
+    from: String,
+    to: String,
+    /// Renames every key starting with `from` to start with `to` instead.
+    #[serde(default)]
+    prefix: bool,
+}
+
+#[cfg(feature = "admin")]
+#[derive(Deserialize)]
+struct CopyQuery {
+    from: String,
+    to: String,
+    /// The seconds the copy lives, `0` or `infinite` for never, the expiry of `from` by default.
+    ttl: Option<String>,
+}
+
+#[cfg(feature = "admin")]
+#[derive(Deserialize)]
+struct EvictionPreviewQuery {
+    /// The bytes to free.
+    bytes: usize,
+    /// The priority of the value room would be made for.
+    #[serde(default)]
+    priority: Priority,
+    max_keys: Option<usize>,
+}
+
+#[cfg(feature = "admin")]
+#[derive(Deserialize)]
+struct DigestQuery {
+    #[serde(default)]
+    prefix: String,
+    buckets: Option<usize>,
+    bucket: Option<usize>,
+    /// Exports only entries with at least this remaining ttl, or that never expire.
+    min_ttl_ms: Option<u64>,
+    /// Exports only values of at most this many bytes.
+    max_size: Option<usize>,
+}
+
+#[cfg(feature = "admin")]
+impl DigestQuery {
+    fn buckets(&self) -> usize {
+        self.buckets.unwrap_or(DEFAULT_BUCKETS).max(1)
+    }
+
+    /// Returns true if `key` is in the requested bucket, or no bucket was requested.
+    fn in_bucket(&self, key: &str) -> bool {
+        match self.bucket {
+            Some(bucket) => digest::bucket(key, self.buckets()) == bucket,
+            None => true,
+        }
+    }
+
+    /// Returns the entry of `key` if it passes the ttl and size filters of the export.
+    fn export(&self, cache: &SimpleCache<'static>, key: &str) -> Option<ExportedEntry> {
+        cache.exported_entry(key).filter(|entry| {
+            (entry.immortal || entry.ttl_ms >= self.min_ttl_ms.unwrap_or(0))
+                && self
+                    .max_size
+                    .is_none_or(|max_size| entry.value.len() <= max_size)
+        })
+    }
+}
+
+/// Renders the metrics in the OpenMetrics or the Prometheus text format.
+#[cfg(feature = "metrics")]
+fn render(
+    registry: &Registry,
+    config: &Settings,
+    openmetrics: bool,
+) -> prometheus::Result<web::Bytes> {
+    let mut families = registry.gather();
+    if config.metrics.timestamps {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        openmetrics::set_timestamps(&mut families, now.as_millis() as i64);
+    }
+    if openmetrics {
+        return Ok(openmetrics::encode(&families).into());
+    }
+    let mut buffer = vec![];
+    TextEncoder::new().encode(&families, &mut buffer)?;
+    Ok(buffer.into())
+}
+
+/// Serves the metrics in the OpenMetrics text format if the scraper accepts it, otherwise in the
+/// Prometheus text format.
+#[cfg(feature = "metrics")]
+#[get("/metrics")]
+async fn metrics(
+    req: HttpRequest,
+    registry: web::Data<Registry>,
+    config: web::Data<Settings>,
+    scrapes: web::Data<Scrapes>,
+) -> HttpResponse {
+    if config.statsd.enabled && config.statsd.replace_prometheus {
+        return HttpResponse::NotFound().body("Metrics are pushed to StatsD");
+    }
+    let openmetrics = openmetrics::accepted(&req);
+    let content_type = if openmetrics {
+        openmetrics::CONTENT_TYPE
+    } else {
+        TEXT_FORMAT
+    };
+    match scrapes.get_or_render(openmetrics, || render(&registry, &config, openmetrics)) {
+        Ok(output) => HttpResponse::Ok().content_type(content_type).body(output),
+        Err(err) => {
+            log::error!("Could not encode metrics. {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Responds with a Grafana dashboard for the metrics this build has recorded.
+#[cfg(feature = "admin")]
+#[get("/_admin/dashboards/grafana")]
+async fn grafana_dashboard(
+    registry: web::Data<Registry>,
+    config: web::Data<Settings>,
+) -> HttpResponse {
+    HttpResponse::Ok().json(dashboard::grafana(
+        &registry.gather(),
+        &config.metrics.const_labels,
+    ))
+}
+
+/// Responds with the OpenAPI document of the cache and metrics servers.
+#[cfg(feature = "admin")]
+#[get("/_admin/openapi.json")]
+async fn openapi_document() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(openapi::DOCUMENT)
+}
+
+/// Responds with Swagger UI for the OpenAPI document, or 404 unless `admin.swagger_ui` is set.
+#[cfg(feature = "admin")]
+#[get("/_admin/docs")]
+async fn swagger_ui(config: web::Data<Settings>) -> HttpResponse {
+    if !config.admin.swagger_ui {
+        return HttpResponse::NotFound().body("Swagger UI is not enabled");
+    }
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(openapi::swagger_ui("/_admin/openapi.json"))
+}
+
+#[get("/healthz")]
+async fn healthz(
+    cache: web::Data<SimpleCache<'static>>,
+    pressure: web::Data<MemoryPressure>,
+) -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": "ok",
+        "memory_pressure": pressure.check(cache.size()),
+    }))
+}
+
+#[cfg(feature = "admin")]
+#[get("/_admin/stats")]
+async fn stats(
+    req: HttpRequest,
+    cache: web::Data<SimpleCache<'static>>,
+    bound_addresses: web::Data<BoundAddresses>,
+) -> HttpResponse {
+    let stats = Stats {
+        cache: cache.stats(),
+        listen_addresses: &bound_addresses,
+    };
+    encoding::respond(&req, &stats)
+}
+
+#[cfg(feature = "admin")]
+#[get("/_admin/keys")]
+async fn list_keys(
+    query: web::Query<KeysQuery>,
+    cache: web::Data<SimpleCache<'static>>,
+) -> HttpResponse {
+    let limit = query.limit.unwrap_or(1000);
+    HttpResponse::Ok().json(cache.keys(&query.prefix, limit))
+}
+
+#[cfg(feature = "admin")]
+#[post("/_admin/flush")]
+async fn flush(cache: web::Data<SimpleCache<'static>>) -> HttpResponse {
+    let removed = cache.flush();
+    HttpResponse::Ok().json(serde_json::json!({ "removed": removed }))
+}
+
+/// A gauge corrected by `POST /_admin/metrics/recalculate`.
+#[cfg(feature = "admin")]
+#[derive(Debug, Serialize)]
+struct Correction {
+    before: i64,
+    after: i64,
+}
+
+/// Recomputes the items and size gauges from the entries, correcting any drift.
+#[cfg(feature = "admin")]
+#[post("/_admin/metrics/recalculate")]
+async fn recalculate_metrics(cache: web::Data<SimpleCache<'static>>) -> HttpResponse {
+    let accounting = cache.recalculate_metrics();
+    let correction = |(before, after): (i64, i64)| Correction { before, after };
+    HttpResponse::Ok().json(serde_json::json!({
+        "items": correction(accounting.items),
+        "size": correction(accounting.size),
+    }))
+}
+
+/// Returns the digest of the keys with `prefix`, or the etag of every key in `bucket`.
+#[cfg(feature = "admin")]
+#[get("/_admin/digest")]
+async fn cache_digest(
+    query: web::Query<DigestQuery>,
+    cache: web::Data<SimpleCache<'static>>,
+) -> HttpResponse {
+    let etags = cache.etags(&query.prefix);
+    match query.bucket {
+        None => HttpResponse::Ok().json(digest::digest(&etags, query.buckets())),
+        Some(_) => {
+            let etags: HashMap<_, _> = etags
+                .into_iter()
+                .filter(|(key, _)| query.in_bucket(key))
+                .map(|(key, etag)| (key, digest::to_hex(etag)))
+                .collect();
+            HttpResponse::Ok().json(etags)
+        }
+    }
+}
+
+/// Returns the entries with `prefix` in `bucket` that pass the ttl and size filters as newline
+/// delimited JSON, as a sequence of MessagePack maps or as a CBOR array.
+///
+/// Only the matching keys are listed up front, the entries are read and written in chunks as the
+/// response is sent so large caches are not copied into memory. Entries written or removed
+/// meanwhile may or may not be exported.
+#[cfg(feature = "admin")]
+#[get("/_admin/export")]
+async fn export(
+    req: HttpRequest,
+    query: web::Query<DigestQuery>,
+    cache: web::Data<SimpleCache<'static>>,
+) -> HttpResponse {
+    let query = query.into_inner();
+    let keys = cache.matching_keys(|key| key.starts_with(&query.prefix) && query.in_bucket(key));
+    let encoding = Encoding::accepted(&req);
+    let entries = stream::iter(keys)
+        .chunks(EXPORT_CHUNK_SIZE)
+        .map(move |keys| {
+            keys.iter()
+                .filter_map(|key| query.export(&cache, key))
+                .collect::<Vec<_>>()
+        });
+    HttpResponse::Ok()
+        .content_type(encoding.stream_content_type())
+        .streaming(encoding.encode_stream(entries))
+}
+
+/// Adds the entries produced by export to the cache, as newline delimited JSON, as a sequence or
+/// an array of MessagePack maps or as a CBOR array.
+#[cfg(feature = "admin")]
+async fn import(
+    req: HttpRequest,
+    body: web::Bytes,
+    cache: web::Data<SimpleCache<'static>>,
+) -> HttpResponse {
+    let entries: Result<Vec<ExportedEntry>, String> =
+        Encoding::of_request(&req).decode_stream(&body);
+    match entries {
+        Ok(entries) => {
+            let imported = cache.import(entries);
+            encoding::respond(&req, &serde_json::json!({ "imported": imported }))
+        }
+        Err(err) => HttpResponse::BadRequest().body(err),
+    }
+}
+
+#[cfg(feature = "admin")]
+#[get("/_admin/meta/{key:.+}")]
+async fn meta(key: CacheKey, cache: web::Data<SimpleCache<'static>>) -> HttpResponse {
+    match cache.meta(&key) {
+        Some(meta) => HttpResponse::Ok().json(meta),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+/// Returns the keys renamed by `query` and their new names, or the response rejecting the rename:
+/// 400 for invalid keys and 422 for new keys that are denied.
+#[cfg(feature = "admin")]
+fn renames(
+    query: &RenameQuery,
+    cache: &SimpleCache<'static>,
+) -> Result<Vec<(String, String)>, HttpResponse> {
+    let RenameQuery { from, to, prefix } = query;
+    let invalid = |err: InvalidKey| HttpResponse::BadRequest().body(err.to_string());
+    let keys = if *prefix {
+        if from.starts_with(to.as_str()) || to.starts_with(from.as_str()) {
+            return Err(HttpResponse::BadRequest().body("The from and to prefixes can not overlap"));
+        }
+        cache.matching_keys(|key| key.starts_with(from.as_str()))
+    } else {
+        vec![cache.key(from).map_err(invalid)?]
+    };
+    keys.into_iter()
+        .map(|key| {
+            let renamed = if *prefix {
+                format!("{}{}", to, &key[from.len()..])
+            } else {
+                to.clone()
+            };
+            let renamed = cache.key(&renamed).map_err(invalid)?;
+            cache
+                .check_denied(&renamed)
+                .map_err(|denied| HttpResponse::UnprocessableEntity().body(denied.to_string()))?;
+            Ok((key, renamed))
+        })
+        .collect()
+}
+
+/// Moves the value of the key `from` to the key `to`, or with `prefix=true` every key starting
+/// with `from` to start with `to`, keeping their expiry. Nothing is moved if a new key is invalid.
+#[cfg(feature = "admin")]
+#[post("/_admin/rename")]
+async fn rename(
+    query: web::Query<RenameQuery>,
+    cache: web::Data<SimpleCache<'static>>,
+) -> HttpResponse {
+    let renames = match renames(&query, &cache) {
+        Ok(renames) => renames,
+        Err(response) => return response,
+    };
+    let renamed = web::block(move || {
+        let renamed = renames
+            .into_iter()
+            .filter(|(from, to)| cache.rename(from, to))
+            .count();
+        Ok::<_, ()>(renamed)
+    })
+    .await;
+    let renamed = match renamed {
+        Ok(renamed) => renamed,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    log::info!(
+        "Renamed {} keys from {} to {}",
+        renamed,
+        query.from,
+        query.to
+    );
+    if renamed == 0 && !query.prefix {
+        return HttpResponse::NotFound().finish();
+    }
+    HttpResponse::Ok().json(serde_json::json!({ "renamed": renamed }))
+}
+
+/// Writes the value of the key `from` to the key `to` as well, expiring after `ttl` seconds or
+/// with `from`.
+#[cfg(feature = "admin")]
+#[post("/_admin/copy")]
+async fn copy(
+    query: web::Query<CopyQuery>,
+    cache: web::Data<SimpleCache<'static>>,
+) -> HttpResponse {
+    let CopyQuery { from, to, ttl } = query.into_inner();
+    let expiry = match ttl.as_deref().map(ttl::ttl_seconds).transpose() {
+        Ok(expiry) => expiry.unwrap_or(Expiry::Default),
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+    let (from, to) = match (cache.key(&from), cache.key(&to)) {
+        (Ok(from), Ok(to)) => (from, to),
+        (Err(err), _) | (_, Err(err)) => return HttpResponse::BadRequest().body(err.to_string()),
+    };
+    if let Err(denied) = cache.check_denied(&to) {
+        return HttpResponse::UnprocessableEntity().body(denied.to_string());
+    }
+    let copied = web::block(move || Ok::<_, ()>(cache.copy(&from, &to, expiry))).await;
+    match copied {
+        Ok(Some(Ok(true))) => HttpResponse::Created().finish(),
+        Ok(Some(Ok(false))) => HttpResponse::Ok().finish(),
+        Ok(Some(Err(WrongType))) => {
+            HttpResponse::Conflict().body("Only plain values can be copied")
+        }
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+/// Lists the keys the eviction policy would evict to free `bytes` bytes, without evicting them.
+#[cfg(feature = "admin")]
+#[get("/_admin/eviction/preview")]
+async fn eviction_preview(
+    query: web::Query<EvictionPreviewQuery>,
+    cache: web::Data<SimpleCache<'static>>,
+) -> HttpResponse {
+    if !cache.evicts() {
+        return HttpResponse::NotFound()
+            .body("Nothing is evicted with cache.eviction_policy: none");
+    }
+    let EvictionPreviewQuery {
+        bytes,
+        priority,
+        max_keys,
+    } = query.into_inner();
+    let max_keys = max_keys.unwrap_or(PREVIEW_MAX_KEYS);
+    let preview =
+        web::block(move || Ok::<_, ()>(cache.eviction_preview(bytes, priority, max_keys))).await;
+    match preview {
+        Ok(preview) => HttpResponse::Ok().json(preview),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+/// Returns `pin` with its key normalized, or why it can not be pinned.
+#[cfg(feature = "admin")]
+fn normalized(pin: Pin, cache: &SimpleCache<'static>) -> Result<Pin, String> {
+    if !pin.prefix {
+        let key = cache.key(&pin.key).map_err(|err| err.to_string())?;
+        return Ok(Pin { key, prefix: false });
+    }
+    if pin.key.is_empty() {
+        return Err("An empty prefix would pin every key".into());
+    }
+    Ok(pin)
+}
+
+#[cfg(feature = "admin")]
+#[get("/_admin/pins")]
+async fn pins(cache: web::Data<SimpleCache<'static>>) -> HttpResponse {
+    HttpResponse::Ok().json(cache.pins())
+}
+
+/// Exempts the key `key`, or with `prefix=true` every key starting with `key`, from eviction.
+#[cfg(feature = "admin")]
+#[post("/_admin/pins")]
+async fn pin_keys(query: web::Query<Pin>, cache: web::Data<SimpleCache<'static>>) -> HttpResponse {
+    let pin = match normalized(query.into_inner(), &cache) {
+        Ok(pin) => pin,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+    let pinned = cache.clone();
+    match web::block(move || pinned.pin(&pin)).await {
+        Ok(true) => HttpResponse::Created().finish(),
+        Ok(false) => HttpResponse::Ok().finish(),
+        Err(err) => {
+            log::error!("Could not save the pins. {}", err);
+            cache.internal_error("pins");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Makes the keys pinned by `key` and `prefix` evictable again.
+#[cfg(feature = "admin")]
+#[delete("/_admin/pins")]
+async fn unpin(query: web::Query<Pin>, cache: web::Data<SimpleCache<'static>>) -> HttpResponse {
+    let pin = match normalized(query.into_inner(), &cache) {
+        Ok(pin) => pin,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+    let pinned = cache.clone();
+    match web::block(move || pinned.unpin(&pin)).await {
+        Ok(true) => HttpResponse::Ok().finish(),
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(err) => {
+            log::error!("Could not save the pins. {}", err);
+            cache.internal_error("pins");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Lists the keys matching `pattern` and returns a token that confirms deleting them.
+#[cfg(feature = "admin")]
+#[post("/_admin/purge")]
+async fn purge_dry_run(
+    query: web::Query<PurgeQuery>,
+    cache: web::Data<SimpleCache<'static>>,
+    purges: web::Data<Purges>,
+) -> HttpResponse {
+    let PurgeQuery { pattern } = query.into_inner();
+    let keys = cache.matching_keys(|key| purge::matches(&pattern, key));
+    HttpResponse::Ok().json(purges.dry_run(pattern, keys))
+}
+
+/// Deletes the keys listed by the dry run with `token`, recording each deletion in the audit trail.
+#[cfg(feature = "admin")]
+#[post("/_admin/purge/{token}/confirm")]
+async fn purge_confirm(
+    req: HttpRequest,
+    token: web::Path<String>,
+    cache: web::Data<SimpleCache<'static>>,
+    purges: web::Data<Purges>,
+    auditor: Option<web::Data<Auditor>>,
+) -> HttpResponse {
+    let (pattern, keys) = match purges.confirm(&token) {
+        Some(purge) => purge,
+        None => return HttpResponse::NotFound().body("Unknown or expired purge token"),
+    };
+    let purged: Vec<String> = keys.into_iter().filter(|key| cache.remove(key)).collect();
+    log::info!("Purged {} keys matching: {}", purged.len(), pattern);
+    if let Some(auditor) = auditor {
+        let changes: Vec<Change> = purged
+            .iter()
+            .map(|key| Change {
+                operation: "delete",
+                key: key.clone(),
+                size: None,
+            })
+            .collect();
+        auditor.write_changes(&req, &changes);
+    }
+    HttpResponse::Ok().json(serde_json::json!({ "pattern": pattern, "purged": purged }))
+}
+
+/// Returns the usage of each API key, or 404 when usage is not tracked.
+#[cfg(feature = "admin")]
+#[get("/_admin/usage")]
+async fn usage(tracker: Option<web::Data<UsageTracker>>) -> HttpResponse {
+    match tracker {
+        Some(tracker) => HttpResponse::Ok().json(tracker.report()),
+        None => HttpResponse::NotFound().body("Usage is not tracked"),
+    }
+}
+
+#[cfg(feature = "admin")]
+#[get("/_admin/groups")]
+async fn groups(key_groups: Option<web::Data<KeyGroups>>) -> HttpResponse {
+    match key_groups {
+        Some(key_groups) => HttpResponse::Ok().json(key_groups.report()),
+        None => HttpResponse::NotFound().body("Keys are not grouped"),
+    }
+}
+
+/// Responds with the version, commit, build date and features of the running build and the limits
+/// in effect.
+#[cfg(feature = "admin")]
+#[get("/_admin/version")]
+async fn version(settings: web::Data<Settings>) -> HttpResponse {
+    HttpResponse::Ok().json(BuildInfo::new(&settings))
+}
+
+#[cfg(feature = "admin")]
+#[get("/_admin/config")]
+async fn effective_config(
+    settings: web::Data<Settings>,
+    bound_addresses: web::Data<BoundAddresses>,
+) -> HttpResponse {
+    HttpResponse::Ok().json(Config {
+        settings: settings.redacted(),
+        listen_addresses: &bound_addresses,
+        features: FEATURES,
+    })
+}
+
+/// Responds with the faults injected into the cache.
+#[cfg(feature = "chaos")]
+#[get("/_admin/chaos")]
+async fn faults(cache: web::Data<SimpleCache<'static>>) -> HttpResponse {
+    HttpResponse::Ok().json(cache.chaos().faults())
+}
+
+/// Replaces the faults injected into the cache, an empty object clears them.
+#[cfg(feature = "chaos")]
+#[post("/_admin/chaos")]
+async fn inject_faults(
+    cache: web::Data<SimpleCache<'static>>,
+    injected: web::Json<Faults>,
+) -> HttpResponse {
+    cache.chaos().set(injected.into_inner());
+    HttpResponse::Ok().json(cache.chaos().faults())
+}
+
+/// Registers the admin endpoints, of the features compiled in.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(healthz);
+    #[cfg(feature = "metrics")]
+    cfg.service(metrics);
+    #[cfg(feature = "admin")]
+    cfg.service(stats)
+        .service(list_keys)
+        .service(flush)
+        .service(recalculate_metrics)
+        .service(cache_digest)
+        .service(export)
+        .service(
+            web::resource("/_admin/import")
+                .app_data(web::PayloadConfig::new(MAX_IMPORT_SIZE))
+                .route(web::post().to(import)),
+        )
+        .service(meta)
+        .service(rename)
+        .service(copy)
+        .service(eviction_preview)
+        .service(pins)
+        .service(pin_keys)
+        .service(unpin)
+        .service(purge_dry_run)
+        .service(purge_confirm)
+        .service(usage)
+        .service(groups)
+        .service(grafana_dashboard)
+        .service(openapi_document)
+        .service(swagger_ui)
+        .service(version)
+        .service(effective_config);
+    #[cfg(feature = "chaos")]
+    cfg.service(faults).service(inject_faults);
+}
+
+/// Returns whether `given` is `expected`, in a time that only depends on their lengths so the token
+/// can not be guessed byte by byte from response times.
+fn tokens_match(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |difference, (given, expected)| difference | (given ^ expected))
+            == 0
+}
+
+/// Rejects requests without the bearer token when one is configured.
+/// # Arguments
+/// * `auth_token` - The expected bearer token, all requests are allowed when `None`.
+/// * `req` - The incoming request.
+/// * `srv` - The service to call when the request is authorized.
+pub fn authorize<S, B>(
+    auth_token: &Option<String>,
+    req: ServiceRequest,
+    srv: &mut S,
+) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    let authorized = match auth_token {
+        None => true,
+        Some(_) if UNAUTHENTICATED_PATHS.contains(&req.path()) => true,
+        Some(token) => req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|bearer| tokens_match(bearer, token)),
+    };
+    if authorized {
+        Either::Left(srv.call(req))
+    } else {
+        log::warn!("Rejected unauthorized admin request for {}", req.path());
+        Either::Right(ok(req.error_response(ErrorUnauthorized("unauthorized"))))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[cfg(feature = "admin")]
+    use crate::cache::CacheMetrics;
+    #[cfg(feature = "admin")]
+    use crate::settings::EvictionPolicy;
+    #[cfg(feature = "admin")]
+    use actix_web::{
+        dev::{BodySize, MessageBody},
+        http::StatusCode,
+        test, App,
+    };
+    #[cfg(feature = "admin")]
+    use std::time::Duration;
+
+    #[cfg(feature = "admin")]
+    #[actix_rt::test]
+    async fn large_exports_are_streamed_and_filtered() {
+        let cache = SimpleCache::new(Duration::from_secs(60), CacheMetrics::default());
+        cache.put_with_ttl("users/expiring", "1", Duration::from_secs(1));
+        for index in 0..100_000 {
+            let size = if index % 2 == 0 { 10 } else { 200 };
+            cache.put(format!("users/{}", index), "1".repeat(size));
+        }
+        cache.put("pages/1", "1");
+        let mut app =
+            test::init_service(App::new().app_data(web::Data::new(cache)).service(export)).await;
+
+        let req = test::TestRequest::get()
+            .uri("/_admin/export?prefix=users/&min_ttl_ms=30000&max_size=100")
+            .to_request();
+        let response = test::call_service(&mut app, req).await;
+
+        assert_eq!(response.response().body().size(), BodySize::Stream);
+        let body = test::read_body(response).await;
+        let entries: Vec<ExportedEntry> = body
+            .split(|&byte| byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 50_000);
+        assert!(entries.iter().all(|entry| entry.value.len() == 10));
+    }
+
+    #[cfg(feature = "admin")]
+    #[actix_rt::test]
+    async fn binary_exports_are_streamed_and_imported() {
+        let encodings = [
+            ("application/msgpack", Encoding::MessagePack),
+            ("application/cbor", Encoding::Cbor),
+        ];
+        for (media_type, encoding) in &encodings {
+            let source = SimpleCache::new(Duration::from_secs(60), CacheMetrics::default());
+            for index in 0..1000 {
+                source.put(format!("users/{}", index), "1");
+            }
+            let target = web::Data::new(SimpleCache::new(
+                Duration::from_secs(60),
+                CacheMetrics::default(),
+            ));
+            let mut app =
+                test::init_service(App::new().app_data(web::Data::new(source)).service(export))
+                    .await;
+
+            let req = test::TestRequest::get()
+                .uri("/_admin/export")
+                .header("Accept", *media_type)
+                .to_request();
+            let response = test::call_service(&mut app, req).await;
+            assert_eq!(response.response().body().size(), BodySize::Stream);
+            let body = test::read_body(response).await;
+            let entries: Vec<ExportedEntry> = encoding.decode_stream(&body).unwrap();
+            let imported = import(
+                test::TestRequest::post()
+                    .header("Content-Type", *media_type)
+                    .header("Accept", "application/json")
+                    .to_http_request(),
+                body,
+                target.clone(),
+            )
+            .await;
+
+            assert_eq!(entries.len(), 1000, "{}", media_type);
+            assert_eq!(imported.status(), StatusCode::OK);
+            assert_eq!(target.stats().items, 1000, "{}", media_type);
+        }
+    }
+
+    #[cfg(feature = "admin")]
+    #[actix_rt::test]
+    async fn keys_are_renamed_by_prefix() {
+        let cache = SimpleCache::new(Duration::from_secs(60), CacheMetrics::default());
+        cache.put("users/1", "1");
+        cache.put("users/2", "2");
+        cache.put("pages/1", "3");
+        let cache = web::Data::new(cache);
+        let mut app = test::init_service(App::new().app_data(cache.clone()).service(rename)).await;
+
+        let req = test::TestRequest::post()
+            .uri("/_admin/rename?from=users/&to=accounts/&prefix=true")
+            .to_request();
+        let body: serde_json::Value = test::read_response_json(&mut app, req).await;
+        let req = test::TestRequest::post()
+            .uri("/_admin/rename?from=users/1&to=pages/1")
+            .to_request();
+        let response = test::call_service(&mut app, req).await;
+
+        let mut keys = cache.matching_keys(|_| true);
+        keys.sort();
+        assert_eq!(body["renamed"], 2);
+        assert_eq!(response.status(), 404);
+        assert_eq!(keys, vec!["accounts/1", "accounts/2", "pages/1"]);
+    }
+
+    #[cfg(feature = "admin")]
+    #[actix_rt::test]
+    async fn copies_get_their_own_ttl() {
+        let cache = web::Data::new(SimpleCache::new(
+            Duration::from_secs(60),
+            CacheMetrics::default(),
+        ));
+        cache.put("staging/flags", "1");
+        let mut app = test::init_service(App::new().app_data(cache.clone()).service(copy)).await;
+
+        let req = test::TestRequest::post()
+            .uri("/_admin/copy?from=staging/flags&to=flags&ttl=0")
+            .to_request();
+        let response = test::call_service(&mut app, req).await;
+
+        assert_eq!(response.status(), 201);
+        assert!(cache.meta("flags").unwrap().immortal);
+        assert!(!cache.meta("staging/flags").unwrap().immortal);
+    }
+
+    #[cfg(feature = "admin")]
+    #[actix_rt::test]
+    async fn keys_are_pinned_by_prefix() {
+        let cache = web::Data::new(SimpleCache::new(
+            Duration::from_secs(60),
+            CacheMetrics::default(),
+        ));
+        let mut app = test::init_service(
+            App::new()
+                .app_data(cache.clone())
+                .service(pins)
+                .service(pin_keys)
+                .service(unpin),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/_admin/pins?key=config/&prefix=true")
+            .to_request();
+        let pinned = test::call_service(&mut app, req).await;
+        let req = test::TestRequest::delete()
+            .uri("/_admin/pins?key=config/")
+            .to_request();
+        let not_pinned = test::call_service(&mut app, req).await;
+        let req = test::TestRequest::get().uri("/_admin/pins").to_request();
+        let body: serde_json::Value = test::read_response_json(&mut app, req).await;
+
+        assert_eq!(pinned.status(), 201);
+        assert_eq!(not_pinned.status(), 404);
+        assert_eq!(body["prefixes"], serde_json::json!(["config/"]));
+    }
+
+    #[cfg(feature = "admin")]
+    #[actix_rt::test]
+    async fn eviction_previews_leave_the_cache_unchanged() {
+        let cache = SimpleCache::new(Duration::from_secs(60), CacheMetrics::default())
+            .with_eviction_policy(EvictionPolicy::Slru);
+        cache.put("bulk", "value");
+        cache.put_with_priority("config", "value", Expiry::Default, Priority::High);
+        let cache = web::Data::new(cache);
+        let mut app =
+            test::init_service(App::new().app_data(cache.clone()).service(eviction_preview)).await;
+
+        let req = test::TestRequest::get()
+            .uri("/_admin/eviction/preview?bytes=100")
+            .to_request();
+        let body: serde_json::Value = test::read_response_json(&mut app, req).await;
+
+        assert_eq!(
+            body["keys"],
+            serde_json::json!([{"key": "bulk", "size": 5}])
+        );
+        assert_eq!(body["sufficient"], false);
+        assert!(cache.contains_key("bulk"));
+    }
+
+    #[cfg(feature = "admin")]
+    #[actix_rt::test]
+    async fn config_is_shown_with_secrets_redacted() {
+        let mut settings = Settings::new().unwrap();
+        settings.admin.auth_token = Some("admin-secret".into());
+        settings.redis.url = Some("redis://:redis-secret@127.0.0.1:6379".into());
+        let addresses = BoundAddresses {
+            cache_server: vec!["127.0.0.1:8080".parse().unwrap()],
+            metrics_server: vec![],
+        };
+        let mut app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(settings))
+                .app_data(web::Data::new(addresses))
+                .service(effective_config),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/_admin/config").to_request();
+        let body: serde_json::Value = test::read_response_json(&mut app, req).await;
+
+        assert_eq!(body["admin"]["auth_token"], "<redacted>");
+        assert_eq!(body["redis"]["url"], "redis://<redacted>@127.0.0.1:6379");
+        assert_eq!(
+            body["listen_addresses"]["cache_server"][0],
+            "127.0.0.1:8080"
+        );
+        assert!(body["features"].is_array());
+        assert!(body["cache"]["key_live_duration"].is_u64());
+    }
+
+    #[test]
+    fn tokens_must_match_in_full() {
+        assert!(tokens_match("admin-secret", "admin-secret"));
+        assert!(!tokens_match("admin-secreT", "admin-secret"));
+        assert!(!tokens_match("admin", "admin-secret"));
+        assert!(!tokens_match("", "admin-secret"));
+    }
+}
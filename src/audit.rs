@@ -0,0 +1,501 @@
+//! Records an audit trail of the requests that change the cache, i.e. writes, updates, flushes and
+//! imports, as one JSON object per line in a file or as JSON POSTed to an HTTP endpoint.
+//!
+//! Each record has the time, operation, key, request body size, response status, client address,
+//! API key from `audit.identity_header` and request id. The request id is taken from the
+//! `X-Request-Id` header, or generated and sent back in it. The file is rotated once it reaches
+//! `audit.max_size` bytes, keeping `audit.max_files` rotated files named `<path>.1`, `<path>.2`...
+//!
+//! Requests changing several keys, i.e. pipelines, transactions, batch puts, scripts and purges,
+//! are recorded once more for each key they put or deleted. Records are appended to the file by a
+//! thread of its own and sent to the HTTP sink in the background, so requests never wait for them.
+use crate::cache::{CacheMetrics, MetricOpts, SimpleCache};
+use crate::settings::{self, AuditSink};
+use actix_web::{
+    client::Client,
+    dev::{Service, ServiceRequest, ServiceResponse},
+    http::{header, HeaderName, HeaderValue, Method},
+    rt, web, Error, HttpMessage, HttpRequest,
+};
+use futures::future::{Future, FutureExt};
+use prometheus::{IntCounter, Registry};
+use serde::Serialize;
+use std::{
+    collections::hash_map::RandomState,
+    fs::{self, File, OpenOptions},
+    hash::{BuildHasher, Hasher},
+    io::{self, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Sender},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// The header carrying the id of a request.
+const REQUEST_ID: &str = "x-request-id";
+
+/// How long the HTTP sink may take to accept a record.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+thread_local! {
+    /// The client sending records to the HTTP sink, one per worker thread as clients can not be
+    /// shared between threads.
+    static CLIENT: Client = Client::builder().timeout(HTTP_TIMEOUT).finish();
+}
+
+/// Container for the audit metrics.
+#[derive(Clone)]
+pub struct AuditMetrics {
+    /// A count of the records written.
+    pub records: IntCounter,
+    /// A count of the records that could not be written.
+    pub errors: IntCounter,
+}
+
+impl AuditMetrics {
+    /// Creates a new AuditMetrics named using `opts`.
+    pub fn with_opts(opts: &MetricOpts) -> Self {
+        Self {
+            records: IntCounter::with_opts(opts.opts(
+                "audit_records_total",
+                "A count of the audit records written",
+            ))
+            .unwrap(),
+            errors: IntCounter::with_opts(opts.opts(
+                "audit_errors_total",
+                "A count of the audit records that could not be written",
+            ))
+            .unwrap(),
+        }
+    }
+
+    /// Registers the audit metrics with a registry.
+    pub fn register(&self, registry: &Registry) {
+        registry.register(Box::new(self.records.clone())).unwrap();
+        registry.register(Box::new(self.errors.clone())).unwrap();
+    }
+}
+
+/// The id of a request, available from the request extensions when auditing is enabled.
+pub struct RequestId(pub String);
+
+/// A change made to the cache.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct AuditRecord {
+    /// Milliseconds since the unix epoch.
+    pub timestamp_ms: u64,
+    pub operation: String,
+    pub key: Option<String>,
+    /// The size of the request body, if it was sent with a `Content-Length`.
+    pub size: Option<u64>,
+    pub status: u16,
+    pub client: Option<String>,
+    pub api_key: Option<String>,
+    pub request_id: String,
+}
+
+/// A key put or deleted by a request that changes several keys.
+#[derive(Debug, PartialEq)]
+pub struct Change {
+    /// `put` or `delete`.
+    pub operation: &'static str,
+    pub key: String,
+    /// The size of the value put.
+    pub size: Option<u64>,
+}
+
+/// The file records are appended to and its size.
+struct AuditFile {
+    file: File,
+    size: u64,
+}
+
+impl AuditFile {
+    fn open(path: &str) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { file, size })
+    }
+}
+
+/// The audit file and its rotation, owned by the thread appending records to it.
+struct FileSink {
+    settings: settings::Audit,
+    file: Option<AuditFile>,
+}
+
+impl FileSink {
+    /// Returns the path of the `n`th rotated file.
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        PathBuf::from(format!("{}.{}", self.settings.path, n))
+    }
+
+    /// Renames the audit file to `<path>.1`, shifting older files up and dropping the oldest.
+    fn rotate(&self) -> io::Result<AuditFile> {
+        let max_files = self.settings.max_files;
+        if max_files == 0 {
+            fs::remove_file(&self.settings.path)?;
+        } else {
+            for n in (1..max_files).rev() {
+                let from = self.rotated_path(n);
+                if from.exists() {
+                    fs::rename(from, self.rotated_path(n + 1))?;
+                }
+            }
+            fs::rename(&self.settings.path, self.rotated_path(1))?;
+        }
+        AuditFile::open(&self.settings.path)
+    }
+
+    /// Appends a line to the audit file, rotating it first if the line would not fit.
+    fn append(&mut self, line: &[u8]) -> io::Result<()> {
+        let full = self.file.as_ref().is_none_or(|file| {
+            file.size > 0 && file.size + line.len() as u64 > self.settings.max_size
+        });
+        if full {
+            self.file = Some(self.rotate()?);
+        }
+        let file = self.file.as_mut().unwrap();
+        file.file.write_all(line)?;
+        file.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Appends the lines received until every sender is dropped.
+    fn run(
+        mut self,
+        lines: mpsc::Receiver<Vec<u8>>,
+        metrics: AuditMetrics,
+        cache_metrics: CacheMetrics,
+    ) {
+        for line in lines {
+            match self.append(&line) {
+                Ok(()) => metrics.records.inc(),
+                Err(err) => {
+                    let record = String::from_utf8_lossy(&line);
+                    log::error!(
+                        "Could not write audit record: {}. {}",
+                        record.trim_end(),
+                        err
+                    );
+                    metrics.errors.inc();
+                    cache_metrics.internal_error("audit_file");
+                }
+            }
+        }
+    }
+}
+
+/// Sends lines to the thread appending them to the audit file, which appends the lines left and
+/// stops when this is dropped.
+struct FileWriter {
+    lines: Option<Sender<Vec<u8>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for FileWriter {
+    fn drop(&mut self) {
+        self.lines.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Writes audit records to the configured sink.
+pub struct Auditor {
+    settings: settings::Audit,
+    writer: Option<FileWriter>,
+    request_ids: RandomState,
+    requests: AtomicU64,
+    metrics: AuditMetrics,
+    /// Counts the records that could not be written as internal errors.
+    cache_metrics: CacheMetrics,
+}
+
+impl Auditor {
+    /// Returns a new `Auditor`, opening the audit file and starting the thread appending to it for
+    /// the file sink.
+    /// # Arguments
+    /// * `settings` - The sink and rotation of the audit trail.
+    /// * `metrics` - A container for the audit metrics.
+    /// * `cache_metrics` - The metrics records that could not be written are counted by.
+    pub fn new(
+        settings: settings::Audit,
+        metrics: AuditMetrics,
+        cache_metrics: CacheMetrics,
+    ) -> io::Result<Self> {
+        let writer = match settings.sink {
+            AuditSink::File => {
+                let sink = FileSink {
+                    file: Some(AuditFile::open(&settings.path)?),
+                    settings: settings.clone(),
+                };
+                let (lines, received) = mpsc::channel();
+                let (metrics, cache_metrics) = (metrics.clone(), cache_metrics.clone());
+                let thread = thread::Builder::new()
+                    .name("audit".to_string())
+                    .spawn(move || sink.run(received, metrics, cache_metrics))?;
+                Some(FileWriter {
+                    lines: Some(lines),
+                    thread: Some(thread),
+                })
+            }
+            AuditSink::Http => None,
+        };
+        Ok(Self {
+            settings,
+            writer,
+            request_ids: RandomState::new(),
+            requests: AtomicU64::new(0),
+            metrics,
+            cache_metrics,
+        })
+    }
+
+    /// Returns a new request id, unique within the process.
+    fn request_id(&self) -> String {
+        let mut hasher = self.request_ids.build_hasher();
+        hasher.write_u64(self.requests.fetch_add(1, Ordering::Relaxed));
+        format!("{:016x}", hasher.finish())
+    }
+
+    /// Writes `record` to the sink in the background, logging records that could not be written.
+    pub fn write(&self, record: &AuditRecord) {
+        // Serializing a struct of strings and numbers can not fail.
+        let mut line = serde_json::to_vec(record).unwrap();
+        match &self.writer {
+            Some(writer) => {
+                line.push(b'\n');
+                let sent = writer
+                    .lines
+                    .as_ref()
+                    .is_some_and(|lines| lines.send(line).is_ok());
+                if !sent {
+                    log::error!("Could not write audit record: {:?}", record);
+                    self.metrics.errors.inc();
+                    self.cache_metrics.internal_error("audit_queue");
+                }
+            }
+            None => {
+                let url = self.settings.url.clone().unwrap_or_default();
+                let (metrics, cache_metrics) = (self.metrics.clone(), self.cache_metrics.clone());
+                let request = CLIENT.with(|client| {
+                    client
+                        .post(url)
+                        .content_type("application/json")
+                        .send_body(line)
+                });
+                rt::spawn(async move {
+                    let response = request.await;
+                    match response {
+                        Ok(response) if response.status().is_success() => metrics.records.inc(),
+                        Ok(response) => {
+                            log::error!("The audit sink responded with: {}", response.status());
+                            metrics.errors.inc();
+                            cache_metrics.internal_error("audit_http");
+                        }
+                        Err(err) => {
+                            log::error!("Could not send audit record. {}", err);
+                            metrics.errors.inc();
+                            cache_metrics.internal_error("audit_http");
+                        }
+                    }
+                });
+            }
+        }
+    }
+
+    /// Writes a record of each change made by `req`.
+    pub fn write_changes(&self, req: &HttpRequest, changes: &[Change]) {
+        for change in changes {
+            let mut record = self.record(req, 200);
+            record.operation = change.operation.to_string();
+            record.key = Some(change.key.clone());
+            record.size = change.size;
+            self.write(&record);
+        }
+    }
+
+    /// Returns the record of the response to `req`, which handlers can adjust to record the
+    /// individual changes made by a request.
+    pub fn record(&self, req: &HttpRequest, status: u16) -> AuditRecord {
+        let header_value = |name: &str| {
+            req.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let key = req.match_info().get("key").map(|key| {
+            match req.app_data::<web::Data<SimpleCache<'static>>>() {
+                Some(cache) => cache.key(key).unwrap_or_else(|_| key.to_string()),
+                None => key.to_string(),
+            }
+        });
+        AuditRecord {
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis() as u64),
+            operation: operation(req.method(), req.match_pattern().as_deref()),
+            key,
+            size: header_value(header::CONTENT_LENGTH.as_str()).and_then(|size| size.parse().ok()),
+            status,
+            client: req.peer_addr().map(|addr| addr.ip().to_string()),
+            api_key: header_value(&self.settings.identity_header),
+            request_id: match req.extensions().get::<RequestId>() {
+                Some(request_id) => request_id.0.clone(),
+                None => self.request_id(),
+            },
+        }
+    }
+}
+
+/// Returns the name of the operation of a request for the route `pattern`, e.g. `put` for
+/// `POST /{key}` and `list_push` for `POST /{key}/list/push`.
+pub fn operation(method: &Method, pattern: Option<&str>) -> String {
+    let pattern = match pattern {
+        Some(pattern) => pattern,
+        None => return method.as_str().to_lowercase(),
+    };
+    if pattern.starts_with("/_") {
+        // Admin and pipeline routes are named by their static segments.
+        let segments: Vec<&str> = pattern
+            .trim_start_matches("/_admin")
+            .split('/')
+            .filter(|segment| !segment.is_empty() && !segment.starts_with('{'))
+            .map(|segment| segment.trim_start_matches('_'))
+            .collect();
+        return segments.join("_");
+    }
+    match pattern.rsplit_once('}') {
+        Some((_, "")) if method == Method::POST => "put".to_string(),
+        Some((_, route)) if !route.is_empty() => route.trim_matches('/').replace('/', "_"),
+        _ => method.as_str().to_lowercase(),
+    }
+}
+
+/// Records the requests that may change the cache once they have been handled, if auditing is
+/// enabled. Every request is given a request id, sent back in the `X-Request-Id` header.
+/// # Arguments
+/// * `req` - The incoming request.
+/// * `srv` - The service handling the request.
+pub fn audit<S, B>(
+    req: ServiceRequest,
+    srv: &mut S,
+) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    let auditor = req.app_data::<web::Data<Auditor>>().cloned();
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+        .or_else(|| auditor.as_ref().map(|auditor| auditor.request_id()));
+    if let Some(request_id) = &request_id {
+        req.extensions_mut().insert(RequestId(request_id.clone()));
+    }
+    let mutating = !matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    srv.call(req).map(move |response| {
+        let mut response = response?;
+        if let (Some(auditor), Some(request_id)) = (auditor, request_id) {
+            if mutating {
+                let record = auditor.record(response.request(), response.status().as_u16());
+                auditor.write(&record);
+            }
+            if let Ok(value) = HeaderValue::from_str(&request_id) {
+                response
+                    .headers_mut()
+                    .insert(HeaderName::from_static(REQUEST_ID), value);
+            }
+        }
+        Ok(response)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+
+    fn auditor(name: &str) -> Auditor {
+        let path = env::temp_dir().join(format!("simple-mem-cache-audit-{}", name));
+        for n in 0..3 {
+            let _ = fs::remove_file(format!("{}.{}", path.display(), n));
+        }
+        let _ = fs::remove_file(&path);
+        let settings = settings::Audit {
+            enabled: true,
+            path: path.display().to_string(),
+            max_size: 300,
+            max_files: 1,
+            ..Default::default()
+        };
+        Auditor::new(
+            settings,
+            AuditMetrics::with_opts(&MetricOpts::default()),
+            CacheMetrics::default(),
+        )
+        .unwrap()
+    }
+
+    fn record() -> AuditRecord {
+        AuditRecord {
+            timestamp_ms: 0,
+            operation: "put".into(),
+            key: Some("a".into()),
+            size: Some(5),
+            status: 200,
+            client: Some("127.0.0.1".into()),
+            api_key: None,
+            request_id: "1".into(),
+        }
+    }
+
+    #[test]
+    fn operations_are_named_by_route() {
+        assert_eq!(operation(&Method::POST, Some("/{key:[^_].*}")), "put");
+        assert_eq!(operation(&Method::PATCH, Some("/{key:[^_].*}")), "patch");
+        assert_eq!(operation(&Method::DELETE, Some("/{key:[^_].*}")), "delete");
+        assert_eq!(
+            operation(&Method::POST, Some("/{key:[^_].*}/list/push")),
+            "list_push"
+        );
+        assert_eq!(operation(&Method::POST, Some("/_admin/flush")), "flush");
+        assert_eq!(operation(&Method::POST, Some("/_pipeline")), "pipeline");
+        assert_eq!(
+            operation(&Method::POST, Some("/_admin/purge/{token}/confirm")),
+            "purge_confirm"
+        );
+        assert_eq!(operation(&Method::POST, None), "post");
+    }
+
+    #[test]
+    fn the_audit_file_is_rotated() {
+        let sut = auditor("rotation");
+        let (path, metrics) = (sut.settings.path.clone(), sut.metrics.clone());
+        for _ in 0..3 {
+            sut.write(&record());
+        }
+        // Waits for the records to be appended.
+        drop(sut);
+
+        let current = fs::read_to_string(&path).unwrap();
+        let rotated = fs::read_to_string(format!("{}.1", path)).unwrap();
+        assert_eq!(current.lines().count(), 1);
+        assert_eq!(rotated.lines().count(), 2);
+        assert!(!PathBuf::from(format!("{}.2", path)).exists());
+        assert_eq!(metrics.records.get(), 3);
+    }
+
+    #[test]
+    fn request_ids_are_unique() {
+        let sut = auditor("request-ids");
+
+        assert_ne!(sut.request_id(), sut.request_id());
+    }
+}
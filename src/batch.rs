@@ -0,0 +1,299 @@
+//! Optimistic concurrency for several keys in two calls, a cheaper alternative to transactions:
+//! `POST /_batch/get` returns the values of keys with their versions, and `POST /_batch/put`
+//! writes each entry only if the version of its key is still the one that was read.
+//!
+//! Entries of a batch put succeed or conflict independently, with one result per entry in the
+//! same order. An entry without a version is only written if its key is absent, and entries go
+//! through the checks of `POST /{key}` and are stored with the expiry of their namespace. Versions
+//! are checked with `SimpleCache::peek`, so checking one is not a read. Batches may be sent and
+//! received as JSON, MessagePack or CBOR.
+use crate::audit::{Auditor, Change};
+use crate::cache::SimpleCache;
+use crate::encoding::{self, Encoding};
+use crate::eviction::Priority;
+use crate::limits::KeyLimiter;
+use crate::pressure::MemoryPressure;
+use crate::settings;
+use crate::ttl::TtlPolicy;
+use crate::txn::version;
+use crate::value::Value;
+use crate::write_checks::WriteChecks;
+use actix_web::{post, web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Deserialize)]
+pub struct GetRequest {
+    keys: Vec<String>,
+}
+
+/// A value read by a batch get, `value` and `version` are None if there is no value.
+#[derive(Debug, PartialEq, Serialize)]
+struct Entry {
+    key: String,
+    value: Option<String>,
+    version: Option<String>,
+}
+
+/// A value to write if the version of its key has not changed.
+#[derive(Debug, Deserialize)]
+struct ConditionalPut {
+    key: String,
+    value: String,
+    /// The version that was read, or None if the key must be absent.
+    version: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PutRequest {
+    entries: Vec<ConditionalPut>,
+}
+
+/// The result of one entry of a batch put, `status` follows the status codes of the single key
+/// endpoints and is 409 if the version of the key has changed.
+#[derive(Debug, PartialEq, Serialize)]
+struct PutResult {
+    key: String,
+    status: u16,
+    /// The new version of the key if the entry was written.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl PutResult {
+    fn error(key: String, status: u16, error: String) -> Self {
+        Self {
+            key,
+            status,
+            version: None,
+            error: Some(error),
+        }
+    }
+}
+
+/// Returns the value and version of `key`.
+fn read(key: String, cache: &SimpleCache<'static>) -> Entry {
+    let read = cache.get(key.clone(), &|value| {
+        (
+            String::from_utf8_lossy(&value.to_bytes()).into_owned(),
+            version(value),
+        )
+    });
+    let (value, version) = match read {
+        Some((value, version)) => (Some(value), Some(version)),
+        None => (None, None),
+    };
+    Entry {
+        key,
+        value,
+        version,
+    }
+}
+
+/// Writes `put` if the version of its key has not changed.
+fn put(put: ConditionalPut, cache: &SimpleCache<'static>, checks: &WriteChecks) -> PutResult {
+    let key = match cache.key(&put.key) {
+        Ok(key) => key,
+        Err(err) => return PutResult::error(put.key, 400, err.to_string()),
+    };
+    let value = Value::from(put.value);
+    let checked = checks
+        .check_key(cache, &key)
+        .and_then(|_| checks.check_value(cache, &key, &value));
+    if let Err(rejected) = checked {
+        return PutResult::error(key, rejected.status().as_u16(), rejected.to_string());
+    }
+    let _guard = cache.lock_keys(std::slice::from_ref(&key));
+    let current = cache.peek(&key, &version);
+    if current != put.version {
+        return PutResult::error(key, 409, "The version of the key has changed".into());
+    }
+    if let Err(rejected) = checks.check_new_key(cache, &key) {
+        return PutResult::error(key, rejected.status().as_u16(), rejected.to_string());
+    }
+    let version = version(&value);
+    let expiry = TtlPolicy::new(&checks.settings).for_key(&key);
+    let stored = checks.store(cache, key.clone(), value, expiry, Priority::Normal, false);
+    if let Err(rejected) = stored {
+        return PutResult::error(key, rejected.status().as_u16(), rejected.to_string());
+    }
+    PutResult {
+        key,
+        status: 200,
+        version: Some(version),
+        error: None,
+    }
+}
+
+/// Responds with the value and version of every key, in the order of the request.
+#[post("/_batch/get")]
+async fn batch_get(
+    req: HttpRequest,
+    body: web::Bytes,
+    cache: web::Data<SimpleCache<'static>>,
+) -> HttpResponse {
+    let request: GetRequest = match Encoding::of_request(&req).decode(&body) {
+        Ok(request) => request,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+    let mut entries = Vec::new();
+    for key in request.keys {
+        match cache.key(&key) {
+            Ok(key) => entries.push(read(key, &cache)),
+            Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+        }
+    }
+    encoding::respond(&req, &serde_json::json!({ "entries": entries }))
+}
+
+/// Writes every entry whose key still has the version that was read, and responds with the result
+/// of each entry in the order of the request.
+#[post("/_batch/put")]
+async fn batch_put(
+    req: HttpRequest,
+    body: web::Bytes,
+    cache: web::Data<SimpleCache<'static>>,
+    pressure: web::Data<MemoryPressure>,
+    limiter: web::Data<KeyLimiter>,
+    settings: web::Data<settings::Cache>,
+    auditor: Option<web::Data<Auditor>>,
+) -> HttpResponse {
+    let checks = WriteChecks::new(&req, pressure, limiter, settings);
+    if let Err(rejected) = checks.admit(&cache) {
+        return rejected.response();
+    }
+    let request: PutRequest = match Encoding::of_request(&req).decode(&body) {
+        Ok(request) => request,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+    // Entries may wait for the keys of transactions and scripts, so they are written on the
+    // blocking thread pool.
+    let sizes: Vec<u64> = request
+        .entries
+        .iter()
+        .map(|entry| entry.value.len() as u64)
+        .collect();
+    let results = web::block(move || {
+        let results: Vec<PutResult> = request
+            .entries
+            .into_iter()
+            .map(|entry| put(entry, &cache, &checks))
+            .collect();
+        Ok::<_, ()>(results)
+    })
+    .await;
+    match results {
+        Ok(results) => {
+            if let Some(auditor) = auditor {
+                let changes: Vec<Change> = results
+                    .iter()
+                    .zip(sizes)
+                    .filter(|(result, _)| result.status == 200)
+                    .map(|(result, size)| Change {
+                        operation: "put",
+                        key: result.key.clone(),
+                        size: Some(size),
+                    })
+                    .collect();
+                auditor.write_changes(&req, &changes);
+            }
+            encoding::respond(&req, &serde_json::json!({ "results": results }))
+        }
+        Err(err) => {
+            log::error!("Could not apply batch put. {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::{CacheMetrics, MetricOpts};
+    use crate::settings::Settings;
+    use std::time::Duration;
+
+    fn checks() -> WriteChecks {
+        checks_with(Settings::new().unwrap().cache)
+    }
+
+    fn checks_with(settings: settings::Cache) -> WriteChecks {
+        WriteChecks {
+            pressure: web::Data::new(MemoryPressure::new(
+                Default::default(),
+                &MetricOpts::default(),
+            )),
+            limiter: web::Data::new(KeyLimiter::new(Default::default(), &MetricOpts::default())),
+            settings: web::Data::new(settings),
+            client: None,
+            usage: None,
+        }
+    }
+
+    fn conditional_put(key: &str, value: &str, version: Option<String>) -> ConditionalPut {
+        ConditionalPut {
+            key: key.into(),
+            value: value.into(),
+            version,
+        }
+    }
+
+    #[test]
+    fn entries_are_written_only_if_their_version_is_unchanged() {
+        let cache = SimpleCache::new(Duration::from_secs(60), CacheMetrics::default());
+        cache.put("a", "1");
+        cache.put("b", "1");
+        let a = read("a".into(), &cache);
+        let b = read("b".into(), &cache);
+        cache.put("b", "2");
+
+        let results: Vec<PutResult> = vec![
+            conditional_put("a", "3", a.version),
+            conditional_put("b", "3", b.version),
+            conditional_put("c", "3", None),
+        ]
+        .into_iter()
+        .map(|entry| put(entry, &cache, &checks()))
+        .collect();
+
+        let statuses: Vec<u16> = results.iter().map(|result| result.status).collect();
+        assert_eq!(statuses, vec![200, 409, 200]);
+        assert_eq!(results[0].version, Some(version(&Value::from("3"))));
+        assert_eq!(read("b".into(), &cache).value, Some("2".into()));
+        assert_eq!(read("c".into(), &cache).value, Some("3".into()));
+    }
+
+    #[test]
+    fn missing_keys_have_no_version() {
+        let cache = SimpleCache::new(Duration::from_secs(60), CacheMetrics::default());
+
+        assert_eq!(
+            read("a".into(), &cache),
+            Entry {
+                key: "a".into(),
+                value: None,
+                version: None,
+            }
+        );
+        let entry = conditional_put("a", "1", Some("0".into()));
+        let result = put(entry, &cache, &checks());
+        assert_eq!(result.status, 409);
+    }
+
+    #[test]
+    fn entries_expire_like_writes() {
+        let cache = SimpleCache::new(Duration::from_secs(60), CacheMetrics::default());
+        let mut settings = Settings::new().unwrap().cache;
+        settings.immortal_prefixes = vec!["config/".into()];
+
+        let result = put(
+            conditional_put("config/a", "1", None),
+            &cache,
+            &checks_with(settings),
+        );
+
+        assert_eq!(result.status, 200);
+        assert!(cache.entries(|_| true)[0].immortal);
+    }
+}
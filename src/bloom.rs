@@ -0,0 +1,141 @@
+//! A Bloom filter, which answers whether an item has probably been added without storing the
+//! items themselves.
+use serde::Serialize;
+use std::hash::Hasher;
+use twox_hash::XxHash64;
+
+/// A summary of a Bloom filter, returned instead of its bits.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct BloomInfo {
+    pub capacity: u64,
+    pub error_rate: f64,
+    pub bits: u64,
+    pub hashes: u32,
+    /// The number of items added that were not already reported as members.
+    pub items: u64,
+}
+
+/// A Bloom filter sized for a number of items and a false positive rate.
+#[derive(Debug)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    hashes: u32,
+    capacity: u64,
+    error_rate: f64,
+    items: u64,
+}
+
+impl BloomFilter {
+    /// Returns an empty filter, or None if the parameters are out of range.
+    /// # Arguments
+    /// * `capacity` - The number of items the filter is sized for.
+    /// * `error_rate` - The false positive rate once `capacity` items have been added.
+    pub fn new(capacity: u64, error_rate: f64) -> Option<Self> {
+        if capacity == 0 || !(error_rate > 0.0 && error_rate < 1.0) {
+            return None;
+        }
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = (-(capacity as f64) * error_rate.ln() / (ln2 * ln2)).ceil();
+        if num_bits > u32::MAX as f64 {
+            return None;
+        }
+        let num_bits = (num_bits as u64).max(64);
+        let hashes = ((num_bits as f64 / capacity as f64) * ln2).round().max(1.0) as u32;
+        Some(Self {
+            bits: vec![0; num_bits.div_ceil(64) as usize],
+            num_bits,
+            hashes,
+            capacity,
+            error_rate,
+            items: 0,
+        })
+    }
+
+    /// Returns the positions of the bits for `item`, using double hashing.
+    fn positions<'b>(&'b self, item: &[u8]) -> impl Iterator<Item = u64> + 'b {
+        let hash = |seed| {
+            let mut hasher = XxHash64::with_seed(seed);
+            hasher.write(item);
+            hasher.finish()
+        };
+        let (first, second) = (hash(0), hash(1));
+        (0..self.hashes as u64)
+            .map(move |i| first.wrapping_add(i.wrapping_mul(second)) % self.num_bits)
+    }
+
+    /// Adds `item` and returns true if it was not already reported as a member.
+    pub fn insert(&mut self, item: &[u8]) -> bool {
+        let positions: Vec<u64> = self.positions(item).collect();
+        let mut added = false;
+        for position in positions {
+            let (word, bit) = ((position / 64) as usize, 1 << (position % 64));
+            added |= self.bits[word] & bit == 0;
+            self.bits[word] |= bit;
+        }
+        if added {
+            self.items += 1;
+        }
+        added
+    }
+
+    /// Returns true if `item` has probably been added, and false if it has not.
+    pub fn contains(&self, item: &[u8]) -> bool {
+        self.positions(item)
+            .all(|position| self.bits[(position / 64) as usize] & (1 << (position % 64)) != 0)
+    }
+
+    /// Returns the size in bytes of the bits.
+    pub fn len(&self) -> usize {
+        self.bits.len() * 8
+    }
+
+    /// Returns a summary of the filter.
+    pub fn info(&self) -> BloomInfo {
+        BloomInfo {
+            capacity: self.capacity,
+            error_rate: self.error_rate,
+            bits: self.num_bits,
+            hashes: self.hashes,
+            items: self.items,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn added_items_are_members() {
+        let mut sut = BloomFilter::new(100, 0.01).unwrap();
+
+        assert!(sut.insert(b"a"));
+        assert!(!sut.insert(b"a"));
+
+        assert!(sut.contains(b"a"));
+        assert!(!sut.contains(b"b"));
+        assert_eq!(sut.info().items, 1);
+    }
+
+    #[test]
+    fn false_positives_are_near_the_error_rate() {
+        let mut sut = BloomFilter::new(1000, 0.01).unwrap();
+        for i in 0..1000 {
+            sut.insert(format!("item-{}", i).as_bytes());
+        }
+
+        let false_positives = (0..10000)
+            .filter(|i| sut.contains(format!("other-{}", i).as_bytes()))
+            .count();
+
+        assert!(false_positives < 200, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn parameters_are_checked() {
+        assert!(BloomFilter::new(0, 0.01).is_none());
+        assert!(BloomFilter::new(100, 1.0).is_none());
+        assert_eq!(BloomFilter::new(1000, 0.01).unwrap().info().hashes, 7);
+    }
+}
@@ -0,0 +1,143 @@
+//! Identifies the running build, logged at startup and served at `/_admin/version`, with the limits
+//! in effect under the loaded configuration.
+use crate::settings::Settings;
+use crate::value::DEFAULT_MAX_VALUE_SIZE;
+use serde::Serialize;
+use std::fmt;
+
+/// The version of the cache server.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The build of the cache server, set with the `SMC_BUILD` environment variable when compiling.
+pub const BUILD: &str = match option_env!("SMC_BUILD") {
+    Some(build) => build,
+    None => "unknown",
+};
+
+/// The git commit built, set by the build script unless given with `SMC_GIT_COMMIT`.
+pub const COMMIT: &str = match option_env!("SMC_GIT_COMMIT") {
+    Some(commit) => commit,
+    None => "unknown",
+};
+
+/// The date of the build, set by the build script unless given with `SMC_BUILD_DATE`.
+pub const BUILD_DATE: &str = match option_env!("SMC_BUILD_DATE") {
+    Some(date) => date,
+    None => "unknown",
+};
+
+/// The cargo features compiled in.
+pub const FEATURES: &[&str] = &[
+    #[cfg(feature = "admin")]
+    "admin",
+    #[cfg(feature = "metrics")]
+    "metrics",
+    #[cfg(feature = "persistence")]
+    "persistence",
+    #[cfg(feature = "scripting")]
+    "scripting",
+    #[cfg(feature = "chaos")]
+    "chaos",
+];
+
+/// The default of `max_connections` in actix-web.
+const DEFAULT_MAX_CONNECTIONS: usize = 25_000;
+
+/// The limits in effect, with the defaults applied to unset settings. `None` is unlimited.
+#[derive(Debug, Serialize)]
+pub struct Limits {
+    key_live_duration: u64,
+    max_value_size: usize,
+    max_key_length: Option<usize>,
+    max_keys: Option<u32>,
+    max_keys_per_client: Option<u32>,
+    memory_high_water_mark: Option<u64>,
+    max_connections: usize,
+    max_connection_rate: Option<usize>,
+}
+
+impl Limits {
+    fn new(settings: &Settings) -> Self {
+        Self {
+            key_live_duration: settings.cache.key_live_duration,
+            max_value_size: settings
+                .cache
+                .max_value_size
+                .unwrap_or(DEFAULT_MAX_VALUE_SIZE),
+            max_key_length: settings.cache.keys.max_length,
+            max_keys: settings.key_limits.global,
+            max_keys_per_client: settings.key_limits.per_client,
+            memory_high_water_mark: settings.memory_pressure.high_water_mark,
+            max_connections: settings
+                .cache_server
+                .max_connections
+                .unwrap_or(DEFAULT_MAX_CONNECTIONS),
+            max_connection_rate: settings.cache_server.max_connection_rate,
+        }
+    }
+}
+
+/// The build and limits of the running cache server.
+#[derive(Debug, Serialize)]
+pub struct BuildInfo {
+    version: &'static str,
+    build: &'static str,
+    commit: &'static str,
+    build_date: &'static str,
+    features: &'static [&'static str],
+    limits: Limits,
+}
+
+impl BuildInfo {
+    /// Returns the `BuildInfo` of this build run with `settings`.
+    pub fn new(settings: &Settings) -> Self {
+        Self {
+            version: VERSION,
+            build: BUILD,
+            commit: COMMIT,
+            build_date: BUILD_DATE,
+            features: FEATURES,
+            limits: Limits::new(settings),
+        }
+    }
+}
+
+/// Writes an unlimited limit as `unlimited`.
+fn limit<T: fmt::Display>(limit: &Option<T>) -> String {
+    match limit {
+        Some(limit) => limit.to_string(),
+        None => "unlimited".to_string(),
+    }
+}
+
+impl fmt::Display for BuildInfo {
+    /// Writes the startup banner.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let features = if self.features.is_empty() {
+            "none".to_string()
+        } else {
+            self.features.join(",")
+        };
+        let limits = &self.limits;
+        write!(
+            f,
+            "simple-mem-cache {} (build {}, commit {}, built {}, features {}), \
+             key_live_duration {}s, max_value_size {}, max_key_length {}, max_keys {}, \
+             max_keys_per_client {}, memory_high_water_mark {}, max_connections {}, \
+             max_connection_rate {}",
+            self.version,
+            self.build,
+            self.commit,
+            self.build_date,
+            features,
+            limits.key_live_duration,
+            limits.max_value_size,
+            limit(&limits.max_key_length),
+            limit(&limits.max_keys),
+            limit(&limits.max_keys_per_client),
+            limit(&limits.memory_high_water_mark),
+            limits.max_connections,
+            limit(&limits.max_connection_rate),
+        )
+    }
+}
@@ -1,14 +1,83 @@
-use actix_rt::time::{delay_for, Delay};
+use crate::bloom::BloomFilter;
+use crate::build_info::{BUILD, VERSION};
+#[cfg(feature = "chaos")]
+use crate::chaos::Chaos;
+use crate::checksum::Checksum;
+use crate::chunks::{ChunkStore, StoredChunks};
+use crate::clock::{Clock, SystemClock};
+use crate::counter::WindowedCounter;
+use crate::deny::{Denied, DenyList};
+use crate::digest;
+#[cfg(feature = "persistence")]
+use crate::disk::DiskTier;
+use crate::eviction::{Evictor, Priority};
+use crate::keys::{self, InvalidKey};
+use crate::locks::{KeyGuard, KeyLocks};
+use crate::pins::Pins;
+#[cfg(feature = "admin")]
+use crate::pins::{Pin, PinSet};
+use crate::plugin::{CachePlugin, LogPlugin, MetricsPlugin};
+use crate::schema::{SchemaErrors, Schemas};
+use crate::settings::{self, AdaptiveTtl, ChecksumAlgorithm, EvictionPolicy};
+use crate::slab::{SlabAllocation, SlabAllocator};
+use crate::ttl::Expiry;
+use crate::usage::{ApiKeyUsage, StoredBytes};
+use crate::value::{Value, DEFAULT_CHUNK_SIZE};
+use crate::write_gate::{Write, WriteGate};
+use actix_web::web;
 use chashmap::CHashMap;
-use crossbeam_channel::{unbounded, Receiver, Sender};
-use prometheus::{IntCounterVec, IntGauge, Opts, Registry};
+use prometheus::{
+    Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "admin")]
+use std::io;
 use std::{
     borrow::Cow,
+    cell::{Cell, RefCell},
+    cmp::{self, Reverse},
+    collections::{BTreeSet, BinaryHeap, HashMap, VecDeque},
     ops::Deref,
-    sync::Arc,
-    time::{Duration, Instant},
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+/// The buckets in seconds of the remaining ttl and lifetime histograms, from a second to a day.
+const TTL_BUCKETS: &[f64] = &[
+    1.0, 5.0, 15.0, 30.0, 60.0, 300.0, 900.0, 1800.0, 3600.0, 7200.0, 21600.0, 86400.0,
+];
+
+/// How far away the expiry of values that never expire is, about a hundred years, so comparisons
+/// with it need no special case.
+const FOREVER: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+
+/// The upper bounds in bytes of the size buckets when none are configured.
+pub const DEFAULT_SIZE_BUCKETS: &[usize] = &[1024, 10 * 1024, 100 * 1024];
+
+/// The naming applied to every metric in `CacheMetrics`.
+#[derive(Clone, Debug, Default)]
+pub struct MetricOpts {
+    /// The Prometheus namespace, may be empty.
+    pub namespace: String,
+    /// The Prometheus subsystem, may be empty.
+    pub subsystem: String,
+    /// Labels added to every metric.
+    pub const_labels: HashMap<String, String>,
+}
+
+impl MetricOpts {
+    /// Returns `Opts` for the named metric with the namespace, subsystem and constant labels set.
+    pub fn opts(&self, name: &str, help: &str) -> Opts {
+        Opts::new(name, help)
+            .namespace(self.namespace.clone())
+            .subsystem(self.subsystem.clone())
+            .const_labels(self.const_labels.clone())
+    }
+}
+
 /// Container for the cache metrics.
 #[derive(Clone)]
 pub struct CacheMetrics {
@@ -18,49 +87,473 @@ pub struct CacheMetrics {
     pub items: IntGauge,
     /// The size in byts of values (not keys or expiry info) stored in the cache.
     pub size: IntGauge,
+    /// A count of internal errors, labelled by the kind of failure.
+    pub internal_errors: IntCounterVec,
+    /// A count of writes, labelled by whether they created or updated a key.
+    pub writes: IntCounterVec,
+    /// A count of the times the cleaner was restarted after panicking.
+    pub cleaner_restarts: IntCounter,
+    /// A count of keys evicted to make room for new keys.
+    pub evictions: IntCounter,
+    /// A count of new keys that were not admitted by the eviction policy.
+    pub rejected_admissions: IntCounter,
+    /// The number of keys in each segment of the segmented LRU eviction policy.
+    pub segment_items: IntGaugeVec,
+    /// The size in bytes of values in each segment of the segmented LRU eviction policy.
+    pub segment_size: IntGaugeVec,
+    /// Always 1, labelled by the version and build of the cache server.
+    pub info: IntGaugeVec,
+    /// The remaining ttl of values when they are read.
+    pub remaining_ttl: Histogram,
+    /// The time values were cached for when they expire.
+    pub lifetimes: Histogram,
+    /// The number of items in each size bucket, labelled by its upper bound in bytes.
+    pub items_by_size: IntGaugeVec,
+    /// The upper bounds of the size buckets in ascending order, larger values are counted as
+    /// `+Inf`.
+    size_buckets: Vec<usize>,
+}
+
+impl Default for CacheMetrics {
+    /// Creates a new CacheMetrics without a namespace, subsystem or constant labels.
+    fn default() -> Self {
+        Self::with_opts(&MetricOpts::default())
+    }
 }
 
 impl CacheMetrics {
-    /// Creates a new CacheMetrics.
-    pub fn new() -> Self {
+    /// Creates a new CacheMetrics named using `opts`.
+    pub fn with_opts(opts: &MetricOpts) -> Self {
+        let info = IntGaugeVec::new(
+            opts.opts(
+                "cache_info",
+                "The version and build of the cache server, always 1",
+            ),
+            &["version", "build"],
+        )
+        .unwrap();
+        info.with_label_values(&[VERSION, BUILD]).set(1);
+        let remaining_ttl = opts.opts(
+            "cache_hit_remaining_ttl_seconds",
+            "The remaining time to live of values when they are read",
+        );
+        let lifetimes = opts.opts(
+            "cache_entry_lifetime_seconds",
+            "The time values were cached for when they expire",
+        );
         Self {
             queries: IntCounterVec::new(
-                Opts::new("cache_query", "A count of cache hits and misses"),
+                opts.opts("cache_query", "A count of cache hits and misses"),
                 &["hit_or_miss"],
             )
             .unwrap(),
-            items: IntGauge::new("cache_items", "The number of item in the cache").unwrap(),
-            size: IntGauge::new(
+            items: IntGauge::with_opts(opts.opts("cache_items", "The number of item in the cache"))
+                .unwrap(),
+            size: IntGauge::with_opts(opts.opts(
                 "cache_size",
                 "The total size in bytes of all values in the cache",
+            ))
+            .unwrap(),
+            internal_errors: IntCounterVec::new(
+                opts.opts(
+                    "cache_internal_errors_total",
+                    "A count of internal errors by kind",
+                ),
+                &["kind"],
+            )
+            .unwrap(),
+            writes: IntCounterVec::new(
+                opts.opts(
+                    "cache_writes_total",
+                    "A count of writes by whether they created or updated a key",
+                ),
+                &["outcome"],
+            )
+            .unwrap(),
+            cleaner_restarts: IntCounter::with_opts(opts.opts(
+                "cleaner_restarts_total",
+                "A count of the times the cache cleaner was restarted after panicking",
+            ))
+            .unwrap(),
+            evictions: IntCounter::with_opts(opts.opts(
+                "cache_evictions_total",
+                "A count of keys evicted to make room for new keys",
+            ))
+            .unwrap(),
+            rejected_admissions: IntCounter::with_opts(opts.opts(
+                "cache_rejected_admissions_total",
+                "A count of new keys not admitted by the eviction policy",
+            ))
+            .unwrap(),
+            segment_items: IntGaugeVec::new(
+                opts.opts(
+                    "cache_segment_items",
+                    "The number of items in each eviction segment",
+                ),
+                &["segment"],
+            )
+            .unwrap(),
+            segment_size: IntGaugeVec::new(
+                opts.opts(
+                    "cache_segment_size",
+                    "The total size in bytes of values in each eviction segment",
+                ),
+                &["segment"],
             )
             .unwrap(),
+            info,
+            remaining_ttl: Histogram::with_opts(
+                HistogramOpts::from(remaining_ttl).buckets(TTL_BUCKETS.to_vec()),
+            )
+            .unwrap(),
+            lifetimes: Histogram::with_opts(
+                HistogramOpts::from(lifetimes).buckets(TTL_BUCKETS.to_vec()),
+            )
+            .unwrap(),
+            items_by_size: IntGaugeVec::new(
+                opts.opts(
+                    "cache_items_by_size",
+                    "The number of items whose size is at most max_size and above the next smaller bucket",
+                ),
+                &["max_size"],
+            )
+            .unwrap(),
+            size_buckets: DEFAULT_SIZE_BUCKETS.to_vec(),
         }
     }
 
+    /// Sets the upper bounds in bytes of the size buckets items are counted in.
+    pub fn with_size_buckets(mut self, buckets: &[usize]) -> Self {
+        self.size_buckets = buckets.to_vec();
+        self.size_buckets.sort_unstable();
+        self.size_buckets.dedup();
+        self
+    }
+
     /// Registery the metrics contained in CacheMetrics with a registry.
     pub fn register(&self, resgistry: &Registry) {
         resgistry.register(Box::new(self.queries.clone())).unwrap();
         resgistry.register(Box::new(self.items.clone())).unwrap();
         resgistry.register(Box::new(self.size.clone())).unwrap();
+        resgistry
+            .register(Box::new(self.internal_errors.clone()))
+            .unwrap();
+        resgistry.register(Box::new(self.writes.clone())).unwrap();
+        resgistry
+            .register(Box::new(self.cleaner_restarts.clone()))
+            .unwrap();
+        resgistry
+            .register(Box::new(self.evictions.clone()))
+            .unwrap();
+        resgistry
+            .register(Box::new(self.rejected_admissions.clone()))
+            .unwrap();
+        resgistry
+            .register(Box::new(self.segment_items.clone()))
+            .unwrap();
+        resgistry
+            .register(Box::new(self.segment_size.clone()))
+            .unwrap();
+        resgistry.register(Box::new(self.info.clone())).unwrap();
+        resgistry
+            .register(Box::new(self.remaining_ttl.clone()))
+            .unwrap();
+        resgistry
+            .register(Box::new(self.lifetimes.clone()))
+            .unwrap();
+        resgistry
+            .register(Box::new(self.items_by_size.clone()))
+            .unwrap();
         log::info!("Registered cache metrics");
     }
+
+    /// Returns the size bucket of a value of `size` bytes.
+    fn size_bucket(&self, size: usize) -> IntGauge {
+        let bucket = match self.size_buckets.iter().find(|max_size| size <= **max_size) {
+            Some(max_size) => max_size.to_string(),
+            None => "+Inf".to_string(),
+        };
+        self.items_by_size.with_label_values(&[&bucket])
+    }
+
+    /// Counts a write that created a new key or updated an existing one.
+    pub fn write(&self, created: bool) {
+        let outcome = if created { "created" } else { "updated" };
+        self.writes.with_label_values(&[outcome]).inc();
+    }
+
+    /// Records that a value of `size` bytes was stored.
+    pub fn value_added(&self, size: usize) {
+        self.size.add(size as i64);
+        self.size_bucket(size).inc();
+    }
+
+    /// Records that a value of `size` bytes was removed.
+    pub fn value_removed(&self, size: usize) {
+        self.size.sub(size as i64);
+        self.size_bucket(size).dec();
+    }
+
+    /// Records that a value of `old` bytes was changed to `new` bytes.
+    pub fn value_resized(&self, old: usize, new: usize) {
+        self.value_removed(old);
+        self.value_added(new);
+    }
+
+    /// Records an internal error of the given kind, e.g. `disk_tier`.
+    pub fn internal_error(&self, kind: &str) {
+        self.internal_errors.with_label_values(&[kind]).inc();
+    }
+}
+
+/// The accounting of the cache, checked by the invariant checker.
+#[derive(Debug, PartialEq)]
+pub struct Accounting {
+    /// The `cache_items` gauge and the number of entries.
+    pub items: (i64, i64),
+    /// The `cache_size` gauge and the sum of the sizes of the values.
+    pub size: (i64, i64),
+    /// The number of entries still stored after their grace period.
+    pub stale: usize,
+}
+
+/// The entries making room for a value would evict, see `SimpleCache::eviction_preview`.
+#[cfg(feature = "admin")]
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct EvictionPreview {
+    /// The bytes the evicted values would free.
+    pub freed: usize,
+    /// The number of keys that would be evicted.
+    pub count: usize,
+    /// The first keys that would be evicted, in eviction order.
+    pub keys: Vec<Victim>,
+    /// Evicting frees at least the bytes asked for. If not, the write still evicts what it can
+    /// and is only rejected when there is nothing to evict.
+    pub sufficient: bool,
+}
+
+/// A key that would be evicted and the size of its value.
+#[cfg(feature = "admin")]
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Victim {
+    pub key: String,
+    pub size: usize,
+}
+
+/// A point in time summary of the cache.
+#[cfg(feature = "admin")]
+#[derive(Debug, Serialize)]
+pub struct CacheStats {
+    /// The number of items in the cache.
+    pub items: usize,
+    /// The total size in bytes of all values in the cache.
+    pub size: i64,
+    /// The number of seconds a key exists within the cache.
+    pub key_live_duration: u64,
+    /// The number of cache hits.
+    pub hits: i64,
+    /// The number of cache misses.
+    pub misses: i64,
+    /// The number of items that never expire.
+    pub immortal_items: usize,
+    /// The number of chunks the values larger than `cache.chunk_size` are stored in.
+    pub chunks: usize,
+}
+
+/// An entry with its remaining time to live, in the form it is exported and imported.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct ExportedEntry {
+    pub key: String,
+    pub value: String,
+    pub ttl_ms: u64,
+    /// The value was stored in JSON mode.
+    #[serde(default)]
+    pub json: bool,
+    /// The value never expires, its `ttl_ms` is 0.
+    #[serde(default)]
+    pub immortal: bool,
+    /// The unix time in milliseconds the value expires at by the wall clock of the exporting
+    /// process, so the time between an export and its import does not extend the value.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at_ms: Option<u64>,
+    /// The eviction priority of the value.
+    #[serde(default, skip_serializing_if = "Priority::is_normal")]
+    pub priority: Priority,
+}
+
+impl ExportedEntry {
+    /// Returns the ttl left at `now`, or None if the entry has expired. Entries with an
+    /// `expires_at_ms` expire at that time, older exports `ttl_ms` after they are imported.
+    pub fn remaining_ttl(&self, now: SystemTime) -> Option<Duration> {
+        let ttl = match self.expires_at_ms {
+            Some(expires_at_ms) => {
+                let now = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+                Duration::from_millis(expires_at_ms).checked_sub(now)?
+            }
+            None => Duration::from_millis(self.ttl_ms),
+        };
+        Some(ttl).filter(|ttl| *ttl > Duration::from_millis(0))
+    }
+}
+
+/// Metadata about an entry.
+#[derive(Debug, Serialize)]
+pub struct EntryMeta {
+    /// The size of the value in bytes.
+    pub size: usize,
+    /// The remaining time to live in milliseconds.
+    pub ttl_ms: u64,
+    /// The time since the value was written in milliseconds.
+    pub age_ms: u64,
+    pub etag: String,
+    pub checksum: String,
+    /// The number of reads since the value was written or its expiry was last extended.
+    pub hits: u32,
+    /// The value never expires.
+    pub immortal: bool,
+}
+
+/// Returned when a key holds a different kind of data than an operation expects.
+#[derive(Debug, PartialEq)]
+pub struct WrongType;
+
+/// The data stored for a key, either a value, a collection of strings, a counter or a Bloom filter.
+enum Data {
+    /// A value, which is shared rather than copied when it is read.
+    Value(Value),
+    /// A value larger than a chunk, stored in the chunk store.
+    Chunks(StoredChunks),
+    List(VecDeque<String>),
+    Set(BTreeSet<String>),
+    Counter(WindowedCounter),
+    Bloom(BloomFilter),
+}
+
+impl Data {
+    /// Returns the size in bytes of the value, the items in a collection, a counter's buckets or a
+    /// Bloom filter's bits.
+    fn len(&self) -> usize {
+        match self {
+            Data::Value(value) => value.len(),
+            Data::Chunks(chunks) => chunks.len(),
+            Data::List(items) => items.iter().map(String::len).sum(),
+            Data::Set(members) => members.iter().map(String::len).sum(),
+            Data::Counter(counter) => counter.len(),
+            Data::Bloom(filter) => filter.len(),
+        }
+    }
+
+    /// Returns the value, a collection as a JSON array, the total of a counter at `now` or a summary
+    /// of a Bloom filter.
+    fn to_value(&self, now: Instant) -> Value {
+        fn to_json<T: Serialize>(items: &T) -> Value {
+            Value::from(serde_json::to_string(items).unwrap()).into_json(true)
+        }
+        match self {
+            Data::Value(value) => value.clone(),
+            Data::Chunks(chunks) => chunks.to_value(),
+            Data::List(items) => to_json(items),
+            Data::Set(members) => to_json(members),
+            Data::Counter(counter) => Value::from(counter.total(now).to_string()).into_json(true),
+            Data::Bloom(filter) => to_json(&filter.info()),
+        }
+    }
 }
 
 struct CacheValue {
-    value: String,
+    data: Data,
     expiry: Instant,
+    /// When the value was written, which limits how far adaptive ttl can extend the expiry.
+    written: Instant,
+    /// The number of reads since the value was written or its expiry was last extended.
+    hits: AtomicU32,
+    /// The milliseconds after it was written that the value was last read or changed.
+    accessed_ms: AtomicU64,
+    /// The hash of the value.
+    etag: u64,
+    /// The checksum of the value, which is the etag when using xxHash.
+    checksum: Checksum,
+    /// The space of the value in a slab, if it was copied into one.
+    slab: Option<SlabAllocation>,
+    /// The bytes of the value attributed to the API key that wrote it.
+    owner: Option<StoredBytes>,
+    /// The value never expires and is not in the expiry queue, its expiry is `FOREVER` away.
+    immortal: bool,
+    /// Entries of a lower priority are evicted first.
+    priority: Priority,
+}
+
+impl CacheValue {
+    /// Returns the value as exported under `key` at `now`, expiring at `deadline`.
+    fn export(&self, key: &str, now: Instant, deadline: Instant) -> ExportedEntry {
+        let value = self.data.to_value(now);
+        let expires_at = SystemTime::now() + (deadline - now);
+        ExportedEntry {
+            key: key.to_string(),
+            value: String::from_utf8_lossy(&value.to_bytes()).into_owned(),
+            ttl_ms: if self.immortal {
+                0
+            } else {
+                (deadline - now).as_millis() as u64
+            },
+            json: value.is_json(),
+            immortal: self.immortal,
+            expires_at_ms: expires_at
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .filter(|_| !self.immortal)
+                .map(|expires_at| expires_at.as_millis() as u64),
+            priority: self.priority,
+        }
+    }
 }
 
+/// A key queued to be removed once its deadline has passed.
+#[derive(PartialEq, Eq)]
 struct KeyExpiry<'a>(Cow<'a, str>, Instant);
 
+impl Ord for KeyExpiry<'_> {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        (self.1, &self.0).cmp(&(other.1, &other.0))
+    }
+}
+
+impl PartialOrd for KeyExpiry<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 /// A cache based around CHashMap.
 pub struct SimpleCache<'a> {
     key_live_duration: Duration,
     backing_store: CHashMap<Cow<'a, str>, CacheValue>,
-    sender: Sender<KeyExpiry<'a>>,
-    receiver: Receiver<KeyExpiry<'a>>,
+    /// The keys to remove, earliest deadline first.
+    expiries: Mutex<BinaryHeap<Reverse<KeyExpiry<'a>>>>,
     metrics: CacheMetrics,
+    checksum_algorithm: ChecksumAlgorithm,
+    verify_checksums: bool,
+    #[cfg(feature = "persistence")]
+    disk_tier: Option<DiskTier>,
+    adaptive_ttl: Option<AdaptiveTtl>,
+    /// How long values may go unread before they expire.
+    time_to_idle: Option<Duration>,
+    evictor: Option<Evictor>,
+    /// The keys exempt from eviction.
+    pins: Pins,
+    slab_allocator: Option<SlabAllocator>,
+    chunks: Arc<ChunkStore>,
+    key_rules: settings::Keys,
+    deny_list: Option<DenyList>,
+    schemas: Option<Schemas>,
+    plugins: Vec<Box<dyn CachePlugin>>,
+    key_locks: KeyLocks,
+    /// The gate the writes made in the background, read-through and promotion from disk, pass
+    /// through, closed while the cache is handed off.
+    write_gate: Option<web::Data<WriteGate>>,
+    clock: Arc<dyn Clock>,
+    #[cfg(feature = "chaos")]
+    chaos: Chaos,
 }
 
 impl<'a> SimpleCache<'a> {
@@ -69,224 +562,2108 @@ impl<'a> SimpleCache<'a> {
     /// * `key_live_duration` - The `Duration` a key exists within the cache.
     /// * `metrics` - A container for the metrics used by the cache.
     pub fn new(key_live_duration: Duration, metrics: CacheMetrics) -> Self {
-        let (sender, receiver) = unbounded();
+        let metrics_plugin = MetricsPlugin::new(&metrics);
         Self {
             key_live_duration,
-            sender,
-            receiver,
+            expiries: Mutex::new(BinaryHeap::new()),
             backing_store: CHashMap::default(),
             metrics,
+            checksum_algorithm: ChecksumAlgorithm::default(),
+            verify_checksums: false,
+            #[cfg(feature = "persistence")]
+            disk_tier: None,
+            adaptive_ttl: None,
+            time_to_idle: None,
+            evictor: None,
+            pins: Pins::default(),
+            slab_allocator: None,
+            chunks: ChunkStore::new(DEFAULT_CHUNK_SIZE),
+            key_rules: settings::Keys::default(),
+            deny_list: None,
+            schemas: None,
+            plugins: Vec::new(),
+            key_locks: KeyLocks::default(),
+            write_gate: None,
+            clock: Arc::new(SystemClock),
+            #[cfg(feature = "chaos")]
+            chaos: Chaos::default(),
         }
+        .with_plugin(metrics_plugin)
+        .with_plugin(LogPlugin)
     }
 
-    /// Removes a key from the cache if the `CacheValue::expiry` is older than the supplied expiry.
-    /// # Arguments
-    /// * `key` - The key to remove.
-    /// * `expiry` - The `Instant` to test against.
-    fn remove_key_if_older_than(&self, key: Cow<'a, str>, expiry: Instant) {
-        self.backing_store
-            .alter(key.clone(), |maybe_value| match maybe_value {
-                Some(value) if value.expiry > expiry => Some(value),
-                Some(value) => {
-                    log::debug!("Removed expired key from cache: {}", key);
-                    self.metrics.items.set(self.len() as i64);
-                    self.metrics.size.sub(value.value.len() as i64);
-                    None
-                }
-                None => None,
-            });
+    /// Sets the clock the cache reads the time from, e.g. a `VirtualClock` when replaying a trace.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        #[cfg(feature = "persistence")]
+        {
+            self.disk_tier = self
+                .disk_tier
+                .take()
+                .map(|disk_tier| disk_tier.with_clock(clock.clone()));
+        }
+        self.clock = clock;
+        self
     }
 
-    /// Processes expired keys until it receives a key that is not expired or there are no keys
-    /// available.
-    /// # Arguments
-    /// * `delay` - A function that generates a delay.
-    async fn clean(&self, delay: fn(Duration) -> Delay) {
-        for KeyExpiry(key, expiry) in self.receiver.try_iter() {
-            let now = Instant::now();
-            if expiry > now {
-                delay(expiry - now).await;
-            }
-            self.remove_key_if_older_than(key, expiry);
-        }
+    /// Sets a disk tier that evicted values are written to and read back from on a miss, which
+    /// shares the clock of the cache.
+    #[cfg(feature = "persistence")]
+    pub fn with_disk_tier(mut self, disk_tier: DiskTier) -> Self {
+        self.disk_tier = Some(disk_tier.with_clock(self.clock.clone()));
+        self
     }
 
-    /// Runs the clean method until there are no keys available and then delays for the
-    /// key_live_duration.
-    /// # Arguments
-    /// * `simple_cache` - The cache to clean. It is type that Derefs to Arc<SimpleCache> to be
-    /// compatible with actix_web.
-    pub async fn cleaner<C: Deref<Target = Arc<Self>>>(simple_cache: C) {
-        log::info!("Starting cache cleaner");
-        loop {
-            simple_cache.clean(delay_for).await;
-            delay_for(simple_cache.key_live_duration).await;
-        }
+    /// Returns the disk tier, if there is one.
+    #[cfg(feature = "persistence")]
+    pub fn disk_tier(&self) -> Option<&DiskTier> {
+        self.disk_tier.as_ref()
     }
 
-    fn len(&self) -> usize {
-        self.backing_store.len()
+    /// Returns true if evicted values are written to a disk tier.
+    #[cfg(all(feature = "admin", feature = "persistence"))]
+    pub fn has_disk_tier(&self) -> bool {
+        self.disk_tier.is_some()
     }
 
-    /// Returns the value mapped using `as_value` or None.
-    /// # Arguments
-    /// * `key` - The cache key.
-    /// * `as_value` - A mapping function.
-    pub fn get<K, V>(&self, key: K, as_value: &dyn Fn(&String) -> V) -> Option<V>
-    where
-        K: Into<Cow<'a, str>>,
-    {
-        let key: Cow<'a, str> = key.into();
-        match self.backing_store.get(&key) {
-            Some(v) => {
-                log::debug!("Cache hit for key: {}", key);
-                self.metrics.queries.with_label_values(&["hit"]).inc();
-                Some(as_value(&v.value))
-            }
-            None => {
-                log::debug!("Cache miss for key: {}", key);
-                self.metrics.queries.with_label_values(&["miss"]).inc();
-                None
-            }
-        }
+    #[cfg(all(feature = "admin", not(feature = "persistence")))]
+    pub fn has_disk_tier(&self) -> bool {
+        false
     }
 
-    /// Adds a value to the cache and sets it's expiry to `now()` +  `key_live_duration`
-    /// # Arguments
-    /// * `key` - The cache key.
-    /// * `value` - The value to be stored in the cache.
-    pub fn put<K>(&self, key: K, value: String)
-    where
-        K: Into<Cow<'a, str>>,
-    {
-        let key: Cow<'a, str> = key.into();
-        let expiry = Instant::now() + self.key_live_duration;
-        let value_size = value.len();
-        if let Some(old_value) = self
-            .backing_store
-            .insert(key.clone(), CacheValue { value, expiry })
-        {
-            self.metrics.size.sub(old_value.value.len() as i64);
-        }
-        log::debug!("Added key: {} with expiry: {:?} to cache", key, expiry);
-        self.metrics.items.set(self.len() as i64);
-        self.metrics.size.add(value_size as i64);
-        if let Err(err) = self.sender.send(KeyExpiry(key, expiry)) {
-            log::error!("Could not add key to expiry queue. {}", err);
-        };
+    /// Renews the expiry of values that are read often instead of removing them, see `renew`.
+    pub fn with_adaptive_ttl(mut self, adaptive_ttl: AdaptiveTtl) -> Self {
+        self.adaptive_ttl = Some(adaptive_ttl);
+        self
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use actix_web::web;
-    use futures::join;
-    use std::thread;
+    /// Expires values that are not read or changed for `time_to_idle`, even before their ttl has
+    /// elapsed.
+    pub fn with_time_to_idle(mut self, time_to_idle: Duration) -> Self {
+        self.time_to_idle = Some(time_to_idle);
+        self
+    }
 
-    #[test]
-    fn cach_hit_returns_value() {
-        let (sut, _) = new_cache();
+    /// Sets how room is made for new keys with `make_room`.
+    pub fn with_eviction_policy(mut self, policy: EvictionPolicy) -> Self {
+        self.evictor = Evictor::new(
+            policy,
+            self.metrics.segment_items.clone(),
+            self.metrics.segment_size.clone(),
+        );
+        self
+    }
 
-        sut.put("", "".to_string());
-        let result = sut.get("", &|v| v.clone());
+    /// Sets the keys exempt from eviction, e.g. the pins saved before a restart.
+    #[cfg(feature = "persistence")]
+    pub fn with_pins(mut self, pins: Pins) -> Self {
+        self.pins = pins;
+        self
+    }
 
-        assert_eq!(result, Some("".to_string()));
+    /// Returns the pinned keys and prefixes.
+    #[cfg(feature = "admin")]
+    pub fn pins(&self) -> PinSet {
+        self.pins.list()
     }
 
-    #[test]
-    fn cache_miss_returns_none() {
-        let (sut, _) = new_cache();
+    /// Exempts the keys matching `pin` from eviction, returning false if it was already pinned.
+    #[cfg(feature = "admin")]
+    pub fn pin(&self, pin: &Pin) -> io::Result<bool> {
+        let pinned = self.pins.add(pin)?;
+        if pinned {
+            log::info!("Pinned {}", pin.key);
+        }
+        Ok(pinned)
+    }
 
-        let result = sut.get("bar", &|v| v.clone());
+    /// Makes the keys matching `pin` evictable again unless another pin matches them, returning
+    /// false if it was not pinned.
+    #[cfg(feature = "admin")]
+    pub fn unpin(&self, pin: &Pin) -> io::Result<bool> {
+        if !self.pins.remove(pin)? {
+            return Ok(false);
+        }
+        log::info!("Unpinned {}", pin.key);
+        // Pinned keys are dropped from the order of the evictor once they are passed over.
+        if let Some(evictor) = &self.evictor {
+            for key in self.matching_keys(|key| pin.matches(key)) {
+                if self.pins.contains(&key) {
+                    continue;
+                }
+                let entry = self
+                    .backing_store
+                    .get(key.as_str())
+                    .map(|value| (value.data.len(), value.priority));
+                let (size, priority) = match entry {
+                    Some(entry) => entry,
+                    None => continue,
+                };
+                evictor.prioritize(&key, size, self.len(), priority, |key| {
+                    self.priority_of(key)
+                });
+            }
+        }
+        Ok(true)
+    }
 
-        assert_eq!(result, None);
+    /// Copies small values into shared slabs when they are written.
+    pub fn with_slab_allocator(mut self, slab_allocator: SlabAllocator) -> Self {
+        self.slab_allocator = Some(slab_allocator);
+        self
     }
 
-    #[actix_rt::test]
-    async fn expired_items_are_removed_from_the_cache() {
-        let (sut, _) = new_cache();
+    /// Stores values larger than `chunk_size` bytes as chunks under derived keys.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunks = ChunkStore::new(chunk_size);
+        self
+    }
 
-        sut.put("", "".to_string());
-        thread::sleep(Duration::from_millis(5));
+    /// Passes the writes the cache makes on its own, values read through from the Redis tier and
+    /// promoted from the disk tier, through `write_gate`, so none land while it is closed.
+    pub fn with_write_gate(mut self, write_gate: web::Data<WriteGate>) -> Self {
+        self.write_gate = Some(write_gate);
+        self
+    }
 
-        sut.clean(delay_for).await;
-        let result = sut.get("", &|v| v.clone());
+    /// Returns the background write let through the gate, or `None` if the gate is closed.
+    fn enter_gate(&self) -> Option<Option<Write>> {
+        match &self.write_gate {
+            Some(write_gate) => WriteGate::enter(write_gate).map(Some),
+            None => Some(None),
+        }
+    }
 
-        assert_eq!(result, None);
+    /// Sets how keys received by the cache server are normalized and validated, see `key`.
+    pub fn with_key_rules(mut self, key_rules: settings::Keys) -> Self {
+        self.key_rules = key_rules;
+        self
     }
 
-    #[actix_rt::test]
-    async fn items_that_are_updated_with_new_value_do_not_expire_on_previous_expiry() {
-        let (sut, _) = new_cache();
+    /// Returns how keys are normalized and validated.
+    pub fn key_rules(&self) -> &settings::Keys {
+        &self.key_rules
+    }
 
-        sut.put("", "old_value".to_string());
-        thread::sleep(Duration::from_millis(5));
-        sut.put("", "new_value".to_string());
-        let (_, result) = join!(sut.clean(delay_for), async { sut.get("", &|v| v.clone()) });
+    /// Returns `key` normalized by the key rules, or why it is not a valid key.
+    pub fn key(&self, key: &str) -> Result<String, InvalidKey> {
+        keys::normalize(&self.key_rules, key)
+    }
 
-        assert_eq!(result, Some("new_value".to_string()));
+    /// Sets the keys that the cache server rejects writes to, see `check_denied`.
+    pub fn with_deny_list(mut self, deny_list: DenyList) -> Self {
+        self.deny_list = Some(deny_list);
+        self
     }
 
-    #[test]
-    fn unexpired_values_are_not_removed() {
-        let (sut, _) = new_cache();
+    /// Returns the deny rule `key` matches, or Ok if it may be written.
+    pub fn check_denied(&self, key: &str) -> Result<(), Denied> {
+        match &self.deny_list {
+            Some(deny_list) => deny_list.check(key),
+            None => Ok(()),
+        }
+    }
 
-        sut.put("", "old_value".to_string());
+    /// Sets the JSON Schemas that the cache server validates values against, see `validate`.
+    pub fn with_schemas(mut self, schemas: Schemas) -> Self {
+        self.schemas = Some(schemas);
+        self
+    }
 
-        sut.remove_key_if_older_than("".into(), Instant::now());
-        let result = sut.get("", &|v| v.clone());
+    /// Returns Ok if `value` conforms to the schema of the namespace of `key`, if it has one.
+    pub fn validate(&self, key: &str, value: &Value) -> Result<(), SchemaErrors> {
+        match &self.schemas {
+            Some(schemas) => schemas.validate(key, value),
+            None => Ok(()),
+        }
+    }
 
-        assert_eq!(result, Some("old_value".to_string()));
+    /// Adds a plugin that is called as entries are written, read, evicted and expire.
+    pub fn with_plugin<P: CachePlugin + 'static>(mut self, plugin: P) -> Self {
+        self.plugins.push(Box::new(plugin));
+        self
     }
 
-    #[test]
-    fn expired_values_are_removed() {
-        let (sut, _) = new_cache();
+    /// Waits until no other script or transaction uses any of `keys` and locks them until the
+    /// guard is dropped.
+    pub fn lock_keys(&self, keys: &[String]) -> KeyGuard<'_> {
+        self.key_locks.lock(keys)
+    }
 
-        sut.put("", "old_value".to_string());
+    /// Calls `hook` on every plugin.
+    fn notify(&self, hook: impl Fn(&dyn CachePlugin)) {
+        for plugin in &self.plugins {
+            hook(plugin.as_ref());
+        }
+    }
 
-        sut.remove_key_if_older_than("".into(), Instant::now() + Duration::from_millis(5));
-        let result = sut.get("", &|v| v.clone());
+    /// Returns true if entries can be evicted to make room for new keys.
+    pub fn evicts(&self) -> bool {
+        self.evictor.is_some()
+    }
 
-        assert_eq!(result, None);
+    /// Evicts entries to make room for a new value and returns true, or returns false if the
+    /// eviction policy does not admit `key` or nothing of the same or a lower priority can be
+    /// evicted. Evicted values are written to the disk tier, if there is one.
+    /// # Arguments
+    /// * `key` - The key about to be written.
+    /// * `size` - The size in bytes of the value about to be written.
+    /// * `priority` - The priority of the value about to be written.
+    pub fn make_room(&self, key: &str, size: usize, priority: Priority) -> bool {
+        let evictor = match &self.evictor {
+            Some(evictor) => evictor,
+            None => return false,
+        };
+        let mut freed = 0;
+        while freed < size.max(1) {
+            let victim = evictor.victim(key, priority, |key| self.priority_of(key));
+            let victim = match victim {
+                Some(victim) => victim,
+                None if freed == 0 => {
+                    log::debug!("Not admitting key: {}", key);
+                    self.metrics.rejected_admissions.inc();
+                    return false;
+                }
+                None => break,
+            };
+            if let Some(value) = self.backing_store.remove(victim.as_str()) {
+                freed += value.data.len().max(1);
+                self.metrics.items.set(self.len() as i64);
+                self.metrics.value_removed(value.data.len());
+                self.notify(|plugin| plugin.on_evict(&victim, value.data.len()));
+                self.spill_to_disk(&victim, &value);
+            }
+        }
+        true
     }
 
-    #[test]
-    fn key_that_does_not_exist_does_not_add_anything_to_cache() {
-        let (sut, _) = new_cache();
+    /// Sets the checksum stored with each value and whether it is verified on every read.
+    /// # Arguments
+    /// * `algorithm` - The checksum algorithm.
+    /// * `verify` - Verify checksums when values are read.
+    pub fn with_checksums(mut self, algorithm: ChecksumAlgorithm, verify: bool) -> Self {
+        self.checksum_algorithm = algorithm;
+        self.verify_checksums = verify;
+        self
+    }
 
-        sut.remove_key_if_older_than("".into(), Instant::now() + Duration::from_millis(5));
-        let result = sut.get("", &|v| v.clone());
+    /// Removes a key from the cache if the `CacheValue::expiry` is older than the supplied expiry.
+    /// # Arguments
+    /// * `key` - The key to remove.
+    /// * `expiry` - The `Instant` to test against.
+    fn remove_key_if_older_than(&self, key: Cow<'a, str>, expiry: Instant) {
+        let mut removed = None;
+        self.backing_store
+            .alter(key.clone(), |maybe_value| match maybe_value {
+                Some(value) if self.deadline(&value) > expiry => {
+                    // Counters extend their expiry, and reads the idle deadline, without adding it
+                    // to the queue again. Values written since were queued with their own deadline.
+                    let counter = matches!(value.data, Data::Counter(_));
+                    let read = self.time_to_idle.is_some() && self.first_deadline(&value) <= expiry;
+                    if counter || read {
+                        self.queue_expiry(key.clone(), self.deadline(&value));
+                    }
+                    Some(value)
+                }
+                Some(mut value) => {
+                    // Values that were idle are removed even if they were read often before.
+                    if value.expiry <= expiry && self.renew(&mut value) {
+                        log::debug!("Renewed frequently read key: {} in cache", key);
+                        self.queue_expiry(key.clone(), self.deadline(&value));
+                        return Some(value);
+                    }
+                    self.metrics.items.set(self.len() as i64);
+                    self.metrics.value_removed(value.data.len());
+                    self.metrics.lifetimes.observe(
+                        self.deadline(&value)
+                            .saturating_duration_since(value.written)
+                            .as_secs_f64(),
+                    );
+                    removed = Some(value.data.len());
+                    None
+                }
+                None => None,
+            });
+        if let Some(size) = removed {
+            self.forget(&key);
+            self.notify(|plugin| plugin.on_expire(&key, size));
+        }
+    }
 
-        assert_eq!(result, None);
+    /// Returns the entries `make_room` would evict to free `size` bytes for a value of `priority`,
+    /// without evicting them. Whether TinyLFU would admit the new key is not considered.
+    /// # Arguments
+    /// * `size` - The bytes to free.
+    /// * `priority` - The priority of the value room would be made for.
+    /// * `max_keys` - The most keys listed, the rest are only counted.
+    #[cfg(feature = "admin")]
+    pub fn eviction_preview(
+        &self,
+        size: usize,
+        priority: Priority,
+        max_keys: usize,
+    ) -> EvictionPreview {
+        let mut preview = EvictionPreview::default();
+        if let Some(evictor) = &self.evictor {
+            evictor.preview(
+                priority,
+                |key| self.priority_of(key),
+                |key| {
+                    let value_size = self
+                        .backing_store
+                        .get(key)
+                        .map_or(0, |value| value.data.len());
+                    preview.freed += value_size.max(1);
+                    preview.count += 1;
+                    if preview.keys.len() < max_keys {
+                        preview.keys.push(Victim {
+                            key: key.to_string(),
+                            size: value_size,
+                        });
+                    }
+                    preview.freed < size.max(1)
+                },
+            );
+        }
+        preview.sufficient = preview.freed >= size.max(1);
+        preview
     }
 
-    #[test]
-    fn metrics_query_hit_is_incremented() {
-        let (sut, metrics) = new_cache();
+    /// Returns the eviction priority of the value of `key` in memory, or None if it is not in
+    /// memory or is pinned.
+    fn priority_of(&self, key: &str) -> Option<Priority> {
+        if self.pins.contains(key) {
+            return None;
+        }
+        self.backing_store.get(key).map(|value| value.priority)
+    }
 
-        sut.put("", "".to_string());
-        let _ = sut.get("", &|v| v.clone());
+    /// Tells the evictor that `key` was removed, outside of any lock on the backing store.
+    fn forget(&self, key: &str) {
+        if let Some(evictor) = &self.evictor {
+            evictor.forget(key);
+        }
+    }
 
-        assert_eq!(
-            metrics
-                .queries
-                .get_metric_with_label_values(&["hit"])
-                .unwrap()
-                .get(),
-            1
-        );
+    /// Returns the faults injected into the cache.
+    #[cfg(feature = "chaos")]
+    pub fn chaos(&self) -> &Chaos {
+        &self.chaos
     }
 
-    #[test]
-    fn metrics_query_miss_is_incremented() {
-        let (sut, metrics) = new_cache();
+    /// Returns true if an injected fault fails a read or write of the store, after any injected
+    /// delay.
+    #[cfg(feature = "chaos")]
+    fn store_fails(&self) -> bool {
+        self.chaos.store_fails()
+    }
 
-        let _ = sut.get("", &|v| v.clone());
+    #[cfg(not(feature = "chaos"))]
+    fn store_fails(&self) -> bool {
+        false
+    }
 
-        assert_eq!(
-            metrics
+    #[cfg(feature = "chaos")]
+    fn drops_expiry(&self) -> bool {
+        self.chaos.drops_expiry()
+    }
+
+    #[cfg(not(feature = "chaos"))]
+    fn drops_expiry(&self) -> bool {
+        false
+    }
+
+    /// Returns how long an injected fault delays the next pass of the cleaner.
+    #[cfg(feature = "chaos")]
+    fn cleaner_delay(&self) -> Option<Duration> {
+        self.chaos.cleaner_delay()
+    }
+
+    #[cfg(not(feature = "chaos"))]
+    fn cleaner_delay(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Returns when `value` expires, the earlier of its expiry and `time_to_idle` after it was last
+    /// read or changed.
+    fn deadline(&self, value: &CacheValue) -> Instant {
+        match self.time_to_idle {
+            Some(time_to_idle) if !value.immortal => {
+                let accessed = Duration::from_millis(value.accessed_ms.load(Ordering::Relaxed));
+                value.expiry.min(value.written + accessed + time_to_idle)
+            }
+            _ => value.expiry,
+        }
+    }
+
+    /// Returns the deadline `value` was queued with when it was written.
+    fn first_deadline(&self, value: &CacheValue) -> Instant {
+        match self.time_to_idle {
+            Some(time_to_idle) if !value.immortal => value.expiry.min(value.written + time_to_idle),
+            _ => value.expiry,
+        }
+    }
+
+    /// Records that `value` was read or changed, postponing its idle deadline.
+    fn touch(&self, value: &CacheValue) {
+        if self.time_to_idle.is_some() {
+            let accessed = self.clock.now().saturating_duration_since(value.written);
+            value
+                .accessed_ms
+                .store(accessed.as_millis() as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Adds a key to the expiry queue.
+    fn queue_expiry(&self, key: Cow<'a, str>, expiry: Instant) {
+        if self.drops_expiry() {
+            log::debug!("Dropped the expiry of key: {}", key);
+            return;
+        }
+        self.expiries
+            .lock()
+            .unwrap()
+            .push(Reverse(KeyExpiry(key, expiry)));
+    }
+
+    /// Takes the key with the earliest deadline off the expiry queue, if that deadline is by `now`.
+    fn next_expiry(&self, now: Instant) -> Option<KeyExpiry<'a>> {
+        let mut expiries = self.expiries.lock().unwrap();
+        match expiries.peek() {
+            Some(Reverse(KeyExpiry(_, expiry))) if *expiry <= now => {
+                expiries.pop().map(|Reverse(expiry)| expiry)
+            }
+            _ => None,
+        }
+    }
+
+    /// Extends the expiry of an expired value by another `key_live_duration`, up to
+    /// `adaptive_ttl.max_ttl` after it was written, if it was read at least `adaptive_ttl.min_hits`
+    /// times since it was written or last renewed. Returns true if the value was renewed.
+    fn renew(&self, value: &mut CacheValue) -> bool {
+        let adaptive_ttl = match &self.adaptive_ttl {
+            Some(adaptive_ttl) => adaptive_ttl,
+            None => return false,
+        };
+        let now = self.clock.now();
+        let max_expiry = value.written + Duration::from_secs(adaptive_ttl.max_ttl);
+        if value.hits.load(Ordering::Relaxed) < adaptive_ttl.min_hits || max_expiry <= now {
+            return false;
+        }
+        value.expiry = (now + self.key_live_duration).min(max_expiry);
+        value.hits.store(0, Ordering::Relaxed);
+        true
+    }
+
+    /// Removes queued keys in the order of their deadlines, waiting on the clock for each deadline
+    /// that has not passed yet, until the queue is empty or the next deadline is more than
+    /// `key_live_duration` away. A key queued while waiting with an earlier deadline is removed
+    /// after the wait, reads do not see its value in the meantime.
+    async fn clean(&self) {
+        loop {
+            let next = match self.expiries.lock().unwrap().peek() {
+                Some(Reverse(KeyExpiry(_, expiry))) => *expiry,
+                None => return,
+            };
+            let now = self.clock.now();
+            if next > now + self.key_live_duration {
+                return;
+            }
+            if next > now {
+                self.clock.delay(next - now).await;
+            }
+            self.remove_expired();
+        }
+    }
+
+    /// Removes the keys that have expired by now without waiting for later expiries, which stay
+    /// queued. Used instead of the cleaner when the clock is only moved by a replay.
+    pub fn remove_expired(&self) {
+        let now = self.clock.now();
+        while let Some(KeyExpiry(key, expiry)) = self.next_expiry(now) {
+            self.remove_key_if_older_than(key, expiry);
+        }
+    }
+
+    /// Runs the clean method until there are no keys available and then delays for the
+    /// key_live_duration.
+    /// # Arguments
+    /// * `simple_cache` - The cache to clean. It is type that Derefs to Arc<SimpleCache> to be
+    ///   compatible with actix_web.
+    pub async fn cleaner<C: Deref<Target = Arc<Self>>>(simple_cache: C) {
+        log::info!("Starting cache cleaner");
+        loop {
+            if let Some(delay) = simple_cache.cleaner_delay() {
+                simple_cache.clock.delay(delay).await;
+            }
+            simple_cache.clean().await;
+            simple_cache
+                .clock
+                .delay(simple_cache.key_live_duration)
+                .await;
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.backing_store.len()
+    }
+
+    /// Calls `f` with every entry in the cache, each bucket is locked while `f` is called.
+    fn for_each<F>(&self, f: F)
+    where
+        F: FnMut(&Cow<'a, str>, &CacheValue),
+    {
+        let f = RefCell::new(f);
+        self.backing_store.retain(|key, value| {
+            (f.borrow_mut())(key, value);
+            true
+        });
+    }
+
+    /// Returns the total size in bytes of all values in the cache.
+    pub fn size(&self) -> i64 {
+        self.metrics.size.get()
+    }
+
+    /// Returns a summary of the cache.
+    #[cfg(feature = "admin")]
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            items: self.len(),
+            size: self.size(),
+            key_live_duration: self.key_live_duration.as_secs(),
+            hits: self.metrics.queries.with_label_values(&["hit"]).get(),
+            misses: self.metrics.queries.with_label_values(&["miss"]).get(),
+            immortal_items: self.count_immortal(),
+            chunks: self.chunks.len(),
+        }
+    }
+
+    /// Returns the items and size gauges next to the values recomputed from the entries, and the
+    /// number of entries still stored more than `grace` after they expired.
+    pub fn accounting(&self, grace: Duration) -> Accounting {
+        let mut accounting = Accounting {
+            items: (self.metrics.items.get(), 0),
+            size: (self.size(), 0),
+            stale: 0,
+        };
+        let now = self.clock.now();
+        self.for_each(|_, value| {
+            accounting.items.1 += 1;
+            accounting.size.1 += value.data.len() as i64;
+            if !value.immortal && self.deadline(value) + grace < now {
+                accounting.stale += 1;
+            }
+        });
+        accounting
+    }
+
+    /// Sets the items and size gauges to the values recomputed from the entries, correcting any
+    /// drift, and returns the accounting found. Writes racing the recalculation may leave a small
+    /// drift, which a later recalculation corrects.
+    #[cfg(feature = "admin")]
+    pub fn recalculate_metrics(&self) -> Accounting {
+        let accounting = self.accounting(self.key_live_duration);
+        let (items, size) = (accounting.items.1, accounting.size.1);
+        self.metrics.items.set(items);
+        self.metrics.size.set(size);
+        log::info!(
+            "Recalculated the cache metrics, items corrected by {} to {} and size by {} to {}",
+            items - accounting.items.0,
+            items,
+            size - accounting.size.0,
+            size
+        );
+        accounting
+    }
+
+    /// Returns the number of values in memory that never expire.
+    #[cfg(feature = "admin")]
+    fn count_immortal(&self) -> usize {
+        let mut count = 0;
+        self.for_each(|_, value| {
+            if value.immortal {
+                count += 1;
+            }
+        });
+        count
+    }
+
+    /// Returns up to `limit` keys that start with `prefix`, in no particular order.
+    /// # Arguments
+    /// * `prefix` - The prefix keys must start with.
+    /// * `limit` - The maximum number of keys to return.
+    #[cfg(feature = "admin")]
+    pub fn keys(&self, prefix: &str, limit: usize) -> Vec<String> {
+        let mut keys = Vec::new();
+        self.for_each(|key, _| {
+            if keys.len() < limit && key.starts_with(prefix) {
+                keys.push(key.to_string());
+            }
+        });
+        keys
+    }
+
+    /// Returns the keys in memory or in the disk tier that satisfy `matches`.
+    #[cfg(feature = "admin")]
+    pub fn matching_keys<F: Fn(&str) -> bool>(&self, matches: F) -> Vec<String> {
+        let mut keys = Vec::new();
+        self.for_each(|key, _| {
+            if matches(key) {
+                keys.push(key.to_string());
+            }
+        });
+        if self.has_disk_tier() {
+            keys.extend(self.disk_keys().into_iter().filter(|key| matches(key)));
+            keys.sort();
+            keys.dedup();
+        }
+        keys
+    }
+
+    /// Removes `key` from memory and the disk tier, returning true if there was a value.
+    pub fn remove(&self, key: &str) -> bool {
+        let removed = match self.backing_store.remove(key) {
+            Some(value) => {
+                self.metrics.items.set(self.len() as i64);
+                self.metrics.value_removed(value.data.len());
+                self.forget(key);
+                true
+            }
+            None => false,
+        };
+        self.remove_from_disk(key) || removed
+    }
+
+    /// Moves the value of `from` to `to`, keeping its expiry and replacing any value of `to`.
+    /// Returns false if there is no value of `from`. Scripts and transactions see the move
+    /// atomically.
+    #[cfg(feature = "admin")]
+    pub fn rename(&self, from: &str, to: &str) -> bool {
+        let _guard = self.lock_keys(&[from.to_string(), to.to_string()]);
+        self.promote(from);
+        if from == to {
+            return self.contains_key(from);
+        }
+        let value = match self.backing_store.remove(from) {
+            Some(value) => value,
+            None => return false,
+        };
+        self.forget(from);
+        self.remove(to);
+        let to: Cow<'a, str> = Cow::Owned(to.to_string());
+        let (size, deadline, immortal) = (value.data.len(), self.deadline(&value), value.immortal);
+        let priority = value.priority;
+        self.backing_store.insert(to.clone(), value);
+        self.metrics.items.set(self.len() as i64);
+        if let Some(evictor) = &self.evictor {
+            evictor.record_write(&to, size, self.len(), priority, |key| self.priority_of(key));
+        }
+        log::debug!("Renamed key: {} to {}", from, to);
+        if !immortal {
+            self.queue_expiry(to, deadline);
+        }
+        true
+    }
+
+    /// Writes the value of `from` to `to` as well, replacing any value of `to`. Returns None if
+    /// there is no value of `from`, `WrongType` if it is not a plain value, and otherwise true if
+    /// `to` was created. The copy has the priority of `from`. Scripts and transactions see the
+    /// copy atomically.
+    /// # Arguments
+    /// * `from` - The key copied.
+    /// * `to` - The key written.
+    /// * `expiry` - When the copy expires, `Expiry::Default` keeps the expiry of `from`.
+    #[cfg(feature = "admin")]
+    pub fn copy(&self, from: &str, to: &str, expiry: Expiry) -> Option<Result<bool, WrongType>> {
+        let _guard = self.lock_keys(&[from.to_string(), to.to_string()]);
+        self.promote(from);
+        let now = self.clock.now();
+        let (value, ttl, priority) = {
+            let source = self
+                .backing_store
+                .get(from)
+                .filter(|value| self.deadline(value) > now)?;
+            let value = match &source.data {
+                Data::Value(_) | Data::Chunks(_) => source.data.to_value(now),
+                _ => return Some(Err(WrongType)),
+            };
+            let ttl = match expiry {
+                Expiry::Default if source.immortal => None,
+                Expiry::Default => Some(source.expiry.saturating_duration_since(now)),
+                Expiry::After(ttl) => Some(ttl),
+                Expiry::Never => None,
+            };
+            (value, ttl, source.priority)
+        };
+        log::debug!("Copying key: {} to {}", from, to);
+        let to = Cow::Owned(to.to_string());
+        Some(Ok(self.store(to, value, ttl, priority, false) == Some(true)))
+    }
+
+    /// Removes every key from the cache and returns the number of keys removed.
+    #[cfg(feature = "admin")]
+    pub fn flush(&self) -> usize {
+        let removed = self.backing_store.clear();
+        if let Some(evictor) = &self.evictor {
+            evictor.clear();
+        }
+        let count = removed.len() + self.clear_disk();
+        for (_, value) in removed {
+            self.metrics.value_removed(value.data.len());
+        }
+        log::info!("Flushed {} keys from cache", count);
+        self.metrics.items.set(self.len() as i64);
+        count
+    }
+
+    /// Returns the value mapped using `as_value` or None.
+    /// # Arguments
+    /// * `key` - The cache key.
+    /// * `as_value` - A mapping function.
+    pub fn get<K, V>(&self, key: K, as_value: &dyn Fn(&Value) -> V) -> Option<V>
+    where
+        K: Into<Cow<'a, str>>,
+    {
+        let key: Cow<'a, str> = key.into();
+        if self.store_fails() {
+            self.notify(|plugin| plugin.after_get(&key, false));
+            return None;
+        }
+        if let Some(evictor) = &self.evictor {
+            evictor.record_read(&key);
+        }
+        let now = self.clock.now();
+        let corrupted = match self.backing_store.get(&key) {
+            // Not removed by the cleaner yet.
+            Some(v) if self.deadline(&v) <= now => false,
+            Some(v) if !self.verify_checksums || v.checksum.verify(&v.data.to_value(now)) => {
+                v.hits.fetch_add(1, Ordering::Relaxed);
+                self.touch(&v);
+                if !v.immortal {
+                    self.metrics
+                        .remaining_ttl
+                        .observe(v.expiry.saturating_duration_since(now).as_secs_f64());
+                }
+                let (result, size) = match &v.data {
+                    Data::Value(value) => (as_value(value), value.len()),
+                    data => {
+                        let value = data.to_value(now);
+                        (as_value(&value), value.len())
+                    }
+                };
+                drop(v);
+                if let Some(evictor) = &self.evictor {
+                    evictor.record_hit(&key);
+                }
+                self.notify(|plugin| plugin.after_get(&key, true));
+                self.notify(|plugin| plugin.after_read(&key, size));
+                return Some(result);
+            }
+            Some(_) => true,
+            None => false,
+        };
+        if corrupted {
+            self.remove_corrupted(&key);
+        } else if let Some(_write) = self.enter_gate() {
+            if let Some((value, ttl)) = self.take_from_disk(&key) {
+                log::debug!("Promoting key: {} from disk", key);
+                self.metrics.remaining_ttl.observe(ttl.as_secs_f64());
+                let result = as_value(&value);
+                self.notify(|plugin| plugin.after_get(&key, true));
+                self.notify(|plugin| plugin.after_read(&key, value.len()));
+                // Promote the value back into memory.
+                self.put_with_ttl(key, value, ttl);
+                return Some(result);
+            }
+        } else if let Some(value) = self.peek_on_disk(&key) {
+            // Left on disk while writes are paused, as it could land in memory after the entries
+            // have been handed off.
+            self.notify(|plugin| plugin.after_get(&key, true));
+            self.notify(|plugin| plugin.after_read(&key, value.len()));
+            return Some(as_value(&value));
+        }
+        self.notify(|plugin| plugin.after_get(&key, false));
+        None
+    }
+
+    /// Returns the value mapped using `as_value` or None, without the side effects of `get`: no hit
+    /// or miss is counted, the idle deadline is not postponed, the eviction policy and plugins are
+    /// not told and a value on disk is not promoted.
+    /// # Arguments
+    /// * `key` - The cache key.
+    /// * `as_value` - A mapping function.
+    pub fn peek<V>(&self, key: &str, as_value: &dyn Fn(&Value) -> V) -> Option<V> {
+        let now = self.clock.now();
+        if let Some(v) = self.backing_store.get(key) {
+            if self.deadline(&v) <= now {
+                return None;
+            }
+            return Some(match &v.data {
+                Data::Value(value) => as_value(value),
+                data => as_value(&data.to_value(now)),
+            });
+        }
+        self.peek_on_disk(key).map(|value| as_value(&value))
+    }
+
+    /// Returns the keys in the disk tier.
+    #[cfg(all(feature = "admin", feature = "persistence"))]
+    fn disk_keys(&self) -> Vec<String> {
+        self.disk_tier
+            .as_ref()
+            .map_or_else(Vec::new, |disk_tier| disk_tier.keys())
+    }
+
+    #[cfg(all(feature = "admin", not(feature = "persistence")))]
+    fn disk_keys(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Returns true if `key` is in the disk tier.
+    #[cfg(feature = "persistence")]
+    fn on_disk(&self, key: &str) -> bool {
+        self.disk_tier
+            .as_ref()
+            .is_some_and(|disk_tier| disk_tier.contains(key))
+    }
+
+    #[cfg(not(feature = "persistence"))]
+    fn on_disk(&self, _: &str) -> bool {
+        false
+    }
+
+    /// Removes `key` from the disk tier, returning true if it was there and had not expired.
+    #[cfg(feature = "persistence")]
+    fn remove_from_disk(&self, key: &str) -> bool {
+        let disk_tier = match &self.disk_tier {
+            Some(disk_tier) => disk_tier,
+            None => return false,
+        };
+        disk_tier.remove(key).unwrap_or_else(|err| {
+            log::error!("Could not remove key: {} from the disk tier. {}", key, err);
+            self.metrics.internal_error("disk_tier");
+            true
+        })
+    }
+
+    #[cfg(not(feature = "persistence"))]
+    fn remove_from_disk(&self, _: &str) -> bool {
+        false
+    }
+
+    /// Returns the value of `key` in the disk tier without removing it.
+    #[cfg(feature = "persistence")]
+    fn peek_on_disk(&self, key: &str) -> Option<Value> {
+        match self.disk_tier.as_ref()?.peek(key) {
+            Ok(value) => value,
+            Err(err) => {
+                log::error!("Could not read key: {} from the disk tier. {}", key, err);
+                self.metrics.internal_error("disk_tier");
+                None
+            }
+        }
+    }
+
+    #[cfg(not(feature = "persistence"))]
+    fn peek_on_disk(&self, _: &str) -> Option<Value> {
+        None
+    }
+
+    /// Removes a value from the disk tier and returns it with its remaining ttl.
+    #[cfg(feature = "persistence")]
+    fn take_from_disk(&self, key: &str) -> Option<(Value, Duration)> {
+        let disk_tier = self.disk_tier.as_ref()?;
+        match disk_tier.take(key) {
+            Ok(Some((value, expiry))) => {
+                Some((value, expiry.saturating_duration_since(self.clock.now())))
+            }
+            Ok(None) => None,
+            Err(err) => {
+                log::error!("Could not read key: {} from the disk tier. {}", key, err);
+                self.metrics.internal_error("disk_tier");
+                None
+            }
+        }
+    }
+
+    #[cfg(not(feature = "persistence"))]
+    fn take_from_disk(&self, _: &str) -> Option<(Value, Duration)> {
+        None
+    }
+
+    /// Writes an evicted value to the disk tier, if there is one, so it is read back from there
+    /// rather than lost. Collections only live in memory and are dropped.
+    #[cfg(feature = "persistence")]
+    fn spill_to_disk(&self, key: &str, value: &CacheValue) {
+        let disk_tier = match (&self.disk_tier, &value.data) {
+            (Some(disk_tier), Data::Value(_) | Data::Chunks(_)) => disk_tier,
+            _ => return,
+        };
+        let data = value.data.to_value(self.clock.now());
+        if let Err(err) = disk_tier.put(key, &data, self.deadline(value)) {
+            log::error!("Could not write key: {} to the disk tier. {}", key, err);
+            self.metrics.internal_error("disk_tier");
+        }
+    }
+
+    #[cfg(not(feature = "persistence"))]
+    fn spill_to_disk(&self, _: &str, _: &CacheValue) {}
+
+    /// Removes every value from the disk tier and returns the number removed.
+    #[cfg(all(feature = "admin", feature = "persistence"))]
+    fn clear_disk(&self) -> usize {
+        self.disk_tier.as_ref().map_or(0, DiskTier::clear)
+    }
+
+    #[cfg(all(feature = "admin", not(feature = "persistence")))]
+    fn clear_disk(&self) -> usize {
+        0
+    }
+
+    /// Writes a value to the disk tier instead of memory and returns false when there is no disk
+    /// tier or the write failed.
+    /// # Arguments
+    /// * `key` - The cache key.
+    /// * `value` - The value to be stored on disk.
+    #[cfg(feature = "persistence")]
+    pub fn put_on_disk<V: Into<Value>>(&self, key: &str, value: V) -> bool {
+        let disk_tier = match &self.disk_tier {
+            Some(disk_tier) => disk_tier,
+            None => return false,
+        };
+        let created = !self.contains_key(key);
+        let expiry = self.clock.now() + self.key_live_duration;
+        if let Err(err) = disk_tier.put(key, &value.into(), expiry) {
+            log::error!("Could not write key: {} to the disk tier. {}", key, err);
+            self.metrics.internal_error("disk_tier");
+            return false;
+        }
+        self.metrics.write(created);
+        // Remove the older value in memory so it is not returned instead.
+        if let Some(old_value) = self.backing_store.remove(key) {
+            self.metrics.items.set(self.len() as i64);
+            self.metrics.value_removed(old_value.data.len());
+            self.forget(key);
+        }
+        true
+    }
+
+    /// Removes a value that no longer matches its checksum.
+    fn remove_corrupted(&self, key: &str) {
+        log::error!(
+            "Checksum mismatch for key: {}, removing it from the cache",
+            key
+        );
+        self.metrics.internal_error("checksum_mismatch");
+        if let Some(value) = self.backing_store.remove(key) {
+            self.metrics.items.set(self.len() as i64);
+            self.metrics.value_removed(value.data.len());
+            self.forget(key);
+        }
+    }
+
+    /// Returns true if `key` is in memory or the disk tier and has not expired.
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.backing_store
+            .get(key)
+            .is_some_and(|value| self.deadline(&value) > self.clock.now())
+            || self.on_disk(key)
+    }
+
+    /// Attributes the value of `key` in memory to the API key of `usage`.
+    pub fn set_owner(&self, key: &str, usage: &Arc<ApiKeyUsage>) {
+        if let Some(mut entry) = self.backing_store.get_mut(key) {
+            entry.owner = Some(StoredBytes::new(usage.clone(), entry.data.len()));
+        }
+    }
+
+    /// Returns the size of the value of `key` if it is attributed to the API key of `usage`.
+    pub fn owned_size(&self, key: &str, usage: &Arc<ApiKeyUsage>) -> usize {
+        self.backing_store
+            .get(key)
+            .filter(|entry| {
+                entry
+                    .owner
+                    .as_ref()
+                    .is_some_and(|owner| Arc::ptr_eq(owner.usage(), usage))
+            })
+            .map_or(0, |entry| entry.data.len())
+    }
+
+    /// Returns metadata about the entry for `key`.
+    pub fn meta(&self, key: &str) -> Option<EntryMeta> {
+        let now = self.clock.now();
+        self.backing_store
+            .get(key)
+            .filter(|value| self.deadline(value) > now)
+            .map(|value| EntryMeta {
+                size: value.data.len(),
+                ttl_ms: (self.deadline(&value) - now).as_millis() as u64,
+                age_ms: (now - value.written).as_millis() as u64,
+                etag: digest::to_hex(value.etag),
+                checksum: value.checksum.to_string(),
+                hits: value.hits.load(Ordering::Relaxed),
+                immortal: value.immortal,
+            })
+    }
+
+    /// Adds a value to the cache and sets it's expiry to `now()` +  `key_live_duration`. Returns
+    /// true if the key was created and false if its value was replaced.
+    /// # Arguments
+    /// * `key` - The cache key.
+    /// * `value` - The value to be stored in the cache.
+    pub fn put<K, V>(&self, key: K, value: V) -> bool
+    where
+        K: Into<Cow<'a, str>>,
+        V: Into<Value>,
+    {
+        self.put_with_ttl(key, value, self.key_live_duration)
+    }
+
+    /// Adds a value to the cache and sets it's expiry to `now()` + `ttl`. Returns true if the key was
+    /// created and false if its value was replaced or the write was dropped.
+    /// # Arguments
+    /// * `key` - The cache key.
+    /// * `value` - The value to be stored in the cache.
+    /// * `ttl` - The `Duration` the key exists within the cache.
+    pub fn put_with_ttl<K, V>(&self, key: K, value: V, ttl: Duration) -> bool
+    where
+        K: Into<Cow<'a, str>>,
+        V: Into<Value>,
+    {
+        self.store(key.into(), value.into(), Some(ttl), Priority::Normal, false) == Some(true)
+    }
+
+    /// Adds a value to the cache that never expires, it is only removed when it is replaced,
+    /// removed or evicted. Returns true if the key was created and false if its value was replaced
+    /// or the write was dropped.
+    /// # Arguments
+    /// * `key` - The cache key.
+    /// * `value` - The value to be stored in the cache.
+    pub fn put_forever<K, V>(&self, key: K, value: V) -> bool
+    where
+        K: Into<Cow<'a, str>>,
+        V: Into<Value>,
+    {
+        self.store(key.into(), value.into(), None, Priority::Normal, false) == Some(true)
+    }
+
+    /// Adds a value to the cache with an eviction priority. Returns true if the key was created and
+    /// false if its value was replaced or the write was dropped.
+    /// # Arguments
+    /// * `key` - The cache key.
+    /// * `value` - The value to be stored in the cache.
+    /// * `expiry` - When the value expires.
+    /// * `priority` - The eviction priority of the value.
+    pub fn put_with_priority<K, V>(
+        &self,
+        key: K,
+        value: V,
+        expiry: Expiry,
+        priority: Priority,
+    ) -> bool
+    where
+        K: Into<Cow<'a, str>>,
+        V: Into<Value>,
+    {
+        let ttl = self.ttl_of(expiry);
+        self.store(key.into(), value.into(), ttl, priority, false) == Some(true)
+    }
+
+    /// Adds a value to the cache unless there is a value for `key` in memory or on disk. The check
+    /// and the write are made holding the lock of the key's entry, so of concurrent creates of a key
+    /// at most one succeeds. Returns None if the key exists, otherwise whether it was created, false
+    /// if the write was dropped.
+    /// # Arguments
+    /// * `key` - The cache key.
+    /// * `value` - The value to be stored in the cache.
+    /// * `expiry` - When the value expires.
+    /// * `priority` - The eviction priority of the value.
+    pub fn put_if_absent<K, V>(
+        &self,
+        key: K,
+        value: V,
+        expiry: Expiry,
+        priority: Priority,
+    ) -> Option<bool>
+    where
+        K: Into<Cow<'a, str>>,
+        V: Into<Value>,
+    {
+        let ttl = self.ttl_of(expiry);
+        self.store(key.into(), value.into(), ttl, priority, true)
+    }
+
+    /// Returns the ttl of a value written with `expiry`, or None if it never expires.
+    fn ttl_of(&self, expiry: Expiry) -> Option<Duration> {
+        match expiry {
+            Expiry::Default => Some(self.key_live_duration),
+            Expiry::After(ttl) => Some(ttl),
+            Expiry::Never => None,
+        }
+    }
+
+    /// Adds a value to the cache that expires after `ttl`, or never if there is none, with the
+    /// eviction `priority`. Returns whether the key was created, or None if `create_only` and the
+    /// key exists.
+    fn store(
+        &self,
+        key: Cow<'a, str>,
+        value: Value,
+        ttl: Option<Duration>,
+        priority: Priority,
+        create_only: bool,
+    ) -> Option<bool> {
+        if self.store_fails() {
+            log::debug!("Dropped the write of key: {}", key);
+            return Some(false);
+        }
+        if create_only && self.contains_key(&key) {
+            return None;
+        }
+        self.notify(|plugin| plugin.before_put(&key, &value));
+        let now = self.clock.now();
+        let expiry = now + ttl.map_or(FOREVER, |ttl| ttl.min(FOREVER));
+        let value_size = value.len();
+        let mut created = true;
+        let mut cache_value = self.cache_value(&key, Data::Value(value), expiry);
+        cache_value.immortal = ttl.is_none();
+        cache_value.priority = priority;
+        let deadline = self.first_deadline(&cache_value);
+        let old_value = if create_only {
+            // Checked again under the entry's lock, as the key may have been written since.
+            let mut exists = false;
+            let mut expired = None;
+            self.backing_store.alter(key.clone(), |old_value| {
+                exists = old_value
+                    .as_ref()
+                    .is_some_and(|old_value| self.deadline(old_value) > now)
+                    || self.on_disk(&key);
+                if exists {
+                    old_value
+                } else {
+                    expired = old_value;
+                    Some(cache_value)
+                }
+            });
+            if exists {
+                return None;
+            }
+            if let Some(expired) = expired {
+                self.metrics.value_removed(expired.data.len());
+            }
+            None
+        } else {
+            self.backing_store.insert(key.clone(), cache_value)
+        };
+        if let Some(old_value) = old_value {
+            self.metrics.value_removed(old_value.data.len());
+            // Replacing a value not removed by the cleaner yet creates the key.
+            if self.deadline(&old_value) > now {
+                created = false;
+            }
+        }
+        if self.remove_from_disk(&key) {
+            created = false;
+        }
+        log::debug!("Added key: {} with expiry: {:?} to cache", key, expiry);
+        self.metrics.items.set(self.len() as i64);
+        self.metrics.value_added(value_size);
+        self.metrics.write(created);
+        if let Some(evictor) = &self.evictor {
+            evictor.record_write(&key, value_size, self.len(), priority, |key| {
+                self.priority_of(key)
+            });
+        }
+        if ttl.is_some() {
+            self.queue_expiry(key, deadline);
+        }
+        Some(created)
+    }
+
+    /// Replaces the value of `key` with the result of `f`, holding the entry's lock so concurrent
+    /// updates are not lost. The expiry is kept and values on disk are promoted first, collections
+    /// are passed to `f` as a JSON array. Returns None if there is no such key, otherwise the new
+    /// value or the error from `f`.
+    /// # Arguments
+    /// * `key` - The cache key.
+    /// * `f` - Returns the new value given the current value.
+    pub fn update<F, E>(&self, key: &str, f: F) -> Option<Result<Value, E>>
+    where
+        F: FnOnce(&Value) -> Result<Value, E>,
+    {
+        self.promote(key);
+        let mut entry = self.backing_store.get_mut(key)?;
+        let now = self.clock.now();
+        if self.deadline(&entry) <= now {
+            return None;
+        }
+        self.touch(&entry);
+        let value = match f(&entry.data.to_value(now)) {
+            Ok(value) => value,
+            Err(err) => return Some(Err(err)),
+        };
+        self.replace_value(key, &mut entry, &value);
+        let priority = entry.priority;
+        drop(entry);
+        log::debug!("Updated key: {} in cache", key);
+        self.record_resize(key, value.len(), priority);
+        Some(Ok(value))
+    }
+
+    /// Caches `value` read for `key` from the Redis tier, unless the key was written since
+    /// `read_since` or writes are paused. A live entry keeps its expiry, priority and owner,
+    /// otherwise the value is added with the default expiry. Returns true if the value was cached.
+    /// # Arguments
+    /// * `key` - The cache key.
+    /// * `value` - The value read.
+    /// * `read_since` - When the read started, by the clock of the cache.
+    pub fn refresh(&self, key: &str, value: Value, read_since: Instant) -> bool {
+        let _write = match self.enter_gate() {
+            Some(write) => write,
+            None => {
+                log::debug!("Not refreshing key: {} while writes are paused", key);
+                return false;
+            }
+        };
+        if let Some(mut entry) = self.backing_store.get_mut(key) {
+            if self.deadline(&entry) > self.clock.now() {
+                if entry.written > read_since {
+                    log::debug!("Not refreshing key: {} written since it was read", key);
+                    return false;
+                }
+                self.replace_value(key, &mut entry, &value);
+                let priority = entry.priority;
+                drop(entry);
+                log::debug!("Refreshed key: {} in cache", key);
+                self.record_resize(key, value.len(), priority);
+                return true;
+            }
+        }
+        // Only created, as the key may have been written since it was checked above.
+        let ttl = Some(self.key_live_duration);
+        self.store(
+            Cow::Owned(key.to_string()),
+            value,
+            ttl,
+            Priority::Normal,
+            true,
+        )
+        .is_some()
+    }
+
+    /// Replaces the value of the locked `entry` for `key`, keeping its expiry, priority and owner.
+    fn replace_value(&self, key: &str, entry: &mut CacheValue, value: &Value) {
+        self.notify(|plugin| plugin.before_put(key, value));
+        self.metrics.value_resized(entry.data.len(), value.len());
+        let (data, slab) = self.allocate(key, Data::Value(value.clone()));
+        entry.data = data;
+        entry.slab = slab;
+        entry.owner = entry
+            .owner
+            .take()
+            .map(|owner| StoredBytes::new(owner.usage().clone(), value.len()));
+        self.update_digests(entry);
+    }
+
+    /// Tells the evictor the new size of the value of `key`, outside of any lock on the backing
+    /// store.
+    fn record_resize(&self, key: &str, size: usize, priority: Priority) {
+        if let Some(evictor) = &self.evictor {
+            evictor.record_write(key, size, self.len(), priority, |key| self.priority_of(key));
+        }
+    }
+
+    /// Records an internal error of the given kind outside of the cache, e.g. `redis_write`.
+    pub fn internal_error(&self, kind: &str) {
+        self.metrics.internal_error(kind);
+    }
+
+    /// Returns the current time by the clock of the cache.
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// Returns a `CacheValue` with the etag and checksum of `data`.
+    fn cache_value(&self, key: &str, data: Data, expiry: Instant) -> CacheValue {
+        let (data, slab) = self.allocate(key, data);
+        let mut cache_value = CacheValue {
+            data,
+            expiry,
+            written: self.clock.now(),
+            hits: AtomicU32::new(0),
+            accessed_ms: AtomicU64::new(0),
+            etag: 0,
+            checksum: Checksum::XxHash64(0),
+            slab,
+            owner: None,
+            immortal: false,
+            priority: Priority::Normal,
+        };
+        self.update_digests(&mut cache_value);
+        cache_value
+    }
+
+    /// Stores a value larger than a chunk in the chunk store under keys derived from `key`, or
+    /// copies it into a slab if there is a slab allocator and the value is small enough.
+    fn allocate(&self, key: &str, data: Data) -> (Data, Option<SlabAllocation>) {
+        match (data, &self.slab_allocator) {
+            (Data::Value(value), _) if self.chunks.splits(&value) => {
+                (Data::Chunks(self.chunks.store(key, &value)), None)
+            }
+            (Data::Value(value), Some(slab_allocator)) => {
+                let (value, slab) = slab_allocator.allocate(value);
+                (Data::Value(value), slab)
+            }
+            (data, _) => (data, None),
+        }
+    }
+
+    /// Updates the etag and checksum after the data of `cache_value` has changed.
+    fn update_digests(&self, cache_value: &mut CacheValue) {
+        let value = cache_value.data.to_value(self.clock.now());
+        cache_value.etag = digest::hash_chunks(value.chunks());
+        cache_value.checksum = match self.checksum_algorithm {
+            ChecksumAlgorithm::XxHash64 => Checksum::XxHash64(cache_value.etag),
+            algorithm => Checksum::new(algorithm, &value),
+        };
+    }
+
+    /// Moves the value for `key` from the disk tier into memory, if it is only on disk.
+    fn promote(&self, key: &str) {
+        if !self.backing_store.contains_key(key) {
+            if let Some((value, ttl)) = self.take_from_disk(key) {
+                self.put_with_ttl(key.to_string(), value, ttl);
+            }
+        }
+    }
+
+    /// Changes the collection or counter stored for `key` with `f`, which is first created with
+    /// `empty` when there is no such key. The entry is locked while `f` is called.
+    /// # Arguments
+    /// * `key` - The cache key.
+    /// * `ttl` - The `Duration` a created key exists within the cache.
+    /// * `empty` - Returns an empty collection or counter.
+    /// * `f` - Changes the entry, or returns `WrongType` for other kinds of data.
+    fn alter_data<R, E, F>(&self, key: &str, ttl: Duration, empty: E, f: F) -> Result<R, WrongType>
+    where
+        E: FnOnce() -> Data,
+        F: FnOnce(&mut CacheValue) -> Result<R, WrongType>,
+    {
+        self.promote(key);
+        let key: Cow<'a, str> = Cow::Owned(key.to_string());
+        let mut result = Err(WrongType);
+        let mut created = None;
+        let mut sizes = (None, 0);
+        let mut expired = None;
+        let now = self.clock.now();
+        self.backing_store.alter(key.clone(), |cache_value| {
+            // An entry not removed by the cleaner yet is replaced as if there were no such key.
+            let cache_value = match cache_value {
+                Some(cache_value) if self.deadline(&cache_value) <= now => {
+                    expired = Some(cache_value.data.len());
+                    None
+                }
+                cache_value => cache_value,
+            };
+            let mut cache_value = cache_value.unwrap_or_else(|| {
+                let cache_value = self.cache_value(&key, empty(), now + ttl.min(FOREVER));
+                created = Some(self.first_deadline(&cache_value));
+                cache_value
+            });
+            self.touch(&cache_value);
+            let old_size = match created {
+                Some(_) => None,
+                None => Some(cache_value.data.len()),
+            };
+            result = f(&mut cache_value);
+            if result.is_ok() {
+                self.update_digests(&mut cache_value);
+            }
+            sizes = (old_size, cache_value.data.len());
+            Some(cache_value)
+        });
+        if let Some(expired) = expired {
+            self.metrics.value_removed(expired);
+        }
+        match sizes {
+            (Some(old_size), new_size) => self.metrics.value_resized(old_size, new_size),
+            (None, new_size) => self.metrics.value_added(new_size),
+        }
+        if let Some(deadline) = created {
+            log::debug!("Added key: {} with expiry: {:?} to cache", key, deadline);
+            self.metrics.items.set(self.len() as i64);
+            self.queue_expiry(key, deadline);
+        }
+        result
+    }
+
+    /// Changes the data stored for `key` with `f`, if there is such a key.
+    fn alter_existing<R, F>(&self, key: &str, f: F) -> Option<Result<R, WrongType>>
+    where
+        F: FnOnce(&mut Data) -> Result<R, WrongType>,
+    {
+        let mut cache_value = self.backing_store.get_mut(key)?;
+        if self.deadline(&cache_value) <= self.clock.now() {
+            return None;
+        }
+        self.touch(&cache_value);
+        let old_size = cache_value.data.len();
+        let result = f(&mut cache_value.data);
+        if result.is_ok() {
+            self.metrics.value_resized(old_size, cache_value.data.len());
+            self.update_digests(&mut cache_value);
+        }
+        Some(result)
+    }
+
+    /// Reads the collection stored for `key` with `f`, counting a hit or miss.
+    fn read_data<R, F>(&self, key: &str, f: F) -> Option<Result<R, WrongType>>
+    where
+        F: FnOnce(&Data) -> Result<R, WrongType>,
+    {
+        let now = self.clock.now();
+        let result = self
+            .backing_store
+            .get(key)
+            .filter(|cache_value| self.deadline(cache_value) > now)
+            .map(|cache_value| {
+                cache_value.hits.fetch_add(1, Ordering::Relaxed);
+                self.touch(&cache_value);
+                f(&cache_value.data)
+            });
+        self.notify(|plugin| plugin.after_get(key, result.is_some()));
+        result
+    }
+
+    /// Appends `item` to the list stored for `key`, creating the list if needed, and returns the
+    /// new length of the list.
+    pub fn push(&self, key: &str, item: String) -> Result<usize, WrongType> {
+        self.alter_data(
+            key,
+            self.key_live_duration,
+            || Data::List(VecDeque::new()),
+            |cache_value| match &mut cache_value.data {
+                Data::List(items) => {
+                    items.push_back(item);
+                    Ok(items.len())
+                }
+                _ => Err(WrongType),
+            },
+        )
+    }
+
+    /// Returns the items of the list stored for `key` from `start` to `stop` inclusive, negative
+    /// indexes count back from the end of the list.
+    /// # Arguments
+    /// * `key` - The cache key.
+    /// * `start` - The index of the first item.
+    /// * `stop` - The index of the last item, -1 is the last item in the list.
+    pub fn range(
+        &self,
+        key: &str,
+        start: i64,
+        stop: i64,
+    ) -> Option<Result<Vec<String>, WrongType>> {
+        self.read_data(key, |data| match data {
+            Data::List(items) => {
+                let len = items.len() as i64;
+                let index = |index: i64| if index < 0 { len + index } else { index };
+                let start = index(start).max(0).min(len);
+                let stop = (index(stop) + 1).max(start).min(len);
+                Ok(items
+                    .range(start as usize..stop as usize)
+                    .cloned()
+                    .collect())
+            }
+            _ => Err(WrongType),
+        })
+    }
+
+    /// Adds `member` to the set stored for `key`, creating the set if needed, and returns true if
+    /// it was not already a member.
+    pub fn add(&self, key: &str, member: String) -> Result<bool, WrongType> {
+        self.alter_data(
+            key,
+            self.key_live_duration,
+            || Data::Set(BTreeSet::new()),
+            |cache_value| match &mut cache_value.data {
+                Data::Set(members) => Ok(members.insert(member)),
+                _ => Err(WrongType),
+            },
+        )
+    }
+
+    /// Returns true if `member` is in the set stored for `key`.
+    pub fn is_member(&self, key: &str, member: &str) -> Option<Result<bool, WrongType>> {
+        self.read_data(key, |data| match data {
+            Data::Set(members) => Ok(members.contains(member)),
+            _ => Err(WrongType),
+        })
+    }
+
+    /// Increments the counter stored for `key` and returns its total over the last `window`. The
+    /// counter is created if needed, or replaced if it has a different window, and expires one
+    /// window after its last increment.
+    /// # Arguments
+    /// * `key` - The cache key.
+    /// * `window` - The duration increments are counted for.
+    /// * `by` - The amount to add.
+    pub fn count(&self, key: &str, window: Duration, by: u64) -> Result<u64, WrongType> {
+        self.alter_data(
+            key,
+            window,
+            || Data::Counter(WindowedCounter::new(window)),
+            |cache_value| match &mut cache_value.data {
+                Data::Counter(counter) => {
+                    if counter.window() != window {
+                        *counter = WindowedCounter::new(window);
+                    }
+                    let now = self.clock.now();
+                    cache_value.expiry = cache_value.expiry.max(now + window.min(FOREVER));
+                    Ok(counter.increment(now, by))
+                }
+                _ => Err(WrongType),
+            },
+        )
+    }
+
+    /// Stores `filter` for `key` and returns true, or returns false if there is already a Bloom
+    /// filter for `key`.
+    pub fn create_bloom(&self, key: &str, filter: BloomFilter) -> Result<bool, WrongType> {
+        let created = Cell::new(false);
+        self.alter_data(
+            key,
+            self.key_live_duration,
+            || {
+                created.set(true);
+                Data::Bloom(filter)
+            },
+            |cache_value| match cache_value.data {
+                Data::Bloom(_) => Ok(created.get()),
+                _ => Err(WrongType),
+            },
+        )
+    }
+
+    /// Adds `item` to the Bloom filter stored for `key` and returns true if it was not already
+    /// reported as a member.
+    pub fn bloom_add(&self, key: &str, item: &[u8]) -> Option<Result<bool, WrongType>> {
+        self.alter_existing(key, |data| match data {
+            Data::Bloom(filter) => Ok(filter.insert(item)),
+            _ => Err(WrongType),
+        })
+    }
+
+    /// Returns true if `item` has probably been added to the Bloom filter stored for `key`.
+    pub fn bloom_contains(&self, key: &str, item: &[u8]) -> Option<Result<bool, WrongType>> {
+        self.read_data(key, |data| match data {
+            Data::Bloom(filter) => Ok(filter.contains(item)),
+            _ => Err(WrongType),
+        })
+    }
+
+    /// Returns every unexpired entry whose key matches `filter`, ordered by remaining ttl so they
+    /// can be added to another cache with `import`. Collections are exported as JSON arrays.
+    /// # Arguments
+    /// * `filter` - Returns true for the keys to include.
+    pub fn entries<F>(&self, filter: F) -> Vec<ExportedEntry>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let now = self.clock.now();
+        let mut entries = Vec::new();
+        self.for_each(|key, value| {
+            let deadline = self.deadline(value);
+            if deadline > now && filter(key) {
+                entries.push(value.export(key, now, deadline));
+            }
+        });
+        entries.sort_by_key(|entry| entry.ttl_ms);
+        entries
+    }
+
+    /// Returns the entry of `key` in memory as exported, or None if there is no such key or it
+    /// has expired.
+    #[cfg(feature = "admin")]
+    pub fn exported_entry(&self, key: &str) -> Option<ExportedEntry> {
+        let now = self.clock.now();
+        self.backing_store
+            .get(key)
+            .filter(|value| self.deadline(value) > now)
+            .map(|value| value.export(key, now, self.deadline(&value)))
+    }
+
+    /// Adds exported entries to the cache keeping their remaining ttl, and returns how many were
+    /// added. Entries that expired since they were exported are skipped.
+    /// # Arguments
+    /// * `entries` - The entries to add.
+    pub fn import(&self, entries: Vec<ExportedEntry>) -> usize {
+        let now = SystemTime::now();
+        let entries: Vec<(Option<Duration>, ExportedEntry)> = entries
+            .into_iter()
+            .filter_map(|entry| {
+                if entry.immortal {
+                    return Some((None, entry));
+                }
+                Some((Some(entry.remaining_ttl(now)?), entry))
+            })
+            .collect();
+        let count = entries.len();
+        for (ttl, entry) in entries {
+            let value = Value::from(entry.value).into_json(entry.json);
+            self.store(Cow::Owned(entry.key), value, ttl, entry.priority, false);
+        }
+        count
+    }
+
+    /// Returns the key and etag of every entry whose key starts with `prefix`.
+    #[cfg(feature = "admin")]
+    pub fn etags(&self, prefix: &str) -> Vec<(String, u64)> {
+        let mut etags = Vec::new();
+        self.for_each(|key, value| {
+            if key.starts_with(prefix) {
+                etags.push((key.to_string(), value.etag));
+            }
+        });
+        etags
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::VirtualClock;
+    #[cfg(feature = "persistence")]
+    use crate::disk::DiskMetrics;
+    #[cfg(feature = "admin")]
+    use crate::slab::SlabMetrics;
+    #[cfg(feature = "admin")]
+    use crate::usage::{UsageMetrics, UsageTracker};
+    use actix_web::web;
+    use std::sync::Mutex;
+
+    #[test]
+    fn cach_hit_returns_value() {
+        let (sut, _) = new_cache();
+
+        sut.put("", "".to_string());
+        let result = sut.get("", &|v| v.clone());
+
+        assert_eq!(result, Some(Value::from("")));
+    }
+
+    #[test]
+    fn cache_miss_returns_none() {
+        let (sut, _) = new_cache();
+
+        let result = sut.get("bar", &|v| v.clone());
+
+        assert_eq!(result, None);
+    }
+
+    #[actix_rt::test]
+    async fn expired_items_are_removed_from_the_cache() {
+        let (sut, clock) = new_virtual_cache();
+
+        sut.put("", "".to_string());
+        clock.advance(Duration::from_millis(5));
+
+        sut.clean().await;
+        let result = sut.get("", &|v| v.clone());
+
+        assert_eq!(result, None);
+    }
+
+    #[actix_rt::test]
+    async fn distant_expiries_do_not_hold_up_the_cleaner() {
+        let (sut, clock) = new_virtual_cache();
+        let start = clock.now();
+
+        sut.put_with_ttl("distant", "1", Duration::from_secs(3600));
+        sut.put("near", "2");
+        sut.clean().await;
+
+        assert!(!sut.contains_key("near"));
+        assert!(sut.contains_key("distant"));
+        assert!(clock.now() - start < Duration::from_secs(1));
+        assert!(matches!(queued(&sut), Some(KeyExpiry(key, _)) if key == "distant"));
+    }
+
+    #[test]
+    fn keys_expire_in_the_order_of_their_deadlines() {
+        let (sut, clock) = new_virtual_cache();
+
+        sut.put_with_ttl("late", "1", Duration::from_millis(10));
+        sut.put_with_ttl("early", "2", Duration::from_millis(1));
+        clock.advance(Duration::from_millis(2));
+        sut.remove_expired();
+
+        assert!(!sut.backing_store.contains_key("early"));
+        assert!(sut.backing_store.contains_key("late"));
+    }
+
+    #[test]
+    fn expired_values_are_not_read_before_they_are_removed() {
+        let (sut, clock) = new_virtual_cache();
+        sut.put("a", "1");
+        clock.advance(Duration::from_millis(5));
+
+        assert_eq!(sut.get("a", &|v| v.clone()), None);
+        assert_eq!(sut.peek("a", &|v| v.clone()), None);
+        assert!(!sut.contains_key("a"));
+        assert_eq!(
+            sut.put_if_absent("a", "2", Expiry::Default, Priority::Normal),
+            Some(true)
+        );
+        assert_eq!(sut.get("a", &|v| v.clone()), Some(Value::from("2")));
+    }
+
+    #[test]
+    fn ttls_are_capped_at_forever() {
+        let (sut, _) = new_virtual_cache();
+
+        sut.put_with_ttl("a", "1", Duration::from_secs(u64::MAX));
+
+        assert!(sut.contains_key("a"));
+    }
+
+    #[test]
+    fn items_that_are_updated_with_new_value_do_not_expire_on_previous_expiry() {
+        let (sut, clock) = new_virtual_cache();
+
+        sut.put("", "old_value".to_string());
+        clock.advance(Duration::from_millis(5));
+        sut.put("", "new_value".to_string());
+        sut.remove_expired();
+        let result = sut.get("", &|v| v.clone());
+
+        assert_eq!(result, Some(Value::from("new_value")));
+    }
+
+    #[test]
+    fn values_expire_when_idle_before_their_ttl() {
+        let clock = Arc::new(VirtualClock::default());
+        let sut = SimpleCache::new(Duration::from_millis(100), CacheMetrics::default())
+            .with_clock(clock.clone())
+            .with_time_to_idle(Duration::from_millis(10));
+        sut.put("read", "1");
+        sut.put("idle", "1");
+
+        clock.advance(Duration::from_millis(8));
+        sut.get("read", &|_| ());
+        clock.advance(Duration::from_millis(8));
+        sut.remove_expired();
+
+        assert!(sut.contains_key("read"));
+        assert!(!sut.contains_key("idle"));
+        clock.advance(Duration::from_millis(10));
+        sut.remove_expired();
+        assert!(!sut.contains_key("read"));
+    }
+
+    #[test]
+    fn idle_collections_are_not_revived() {
+        let clock = Arc::new(VirtualClock::default());
+        let sut = SimpleCache::new(Duration::from_millis(100), CacheMetrics::default())
+            .with_clock(clock.clone())
+            .with_time_to_idle(Duration::from_millis(10));
+        sut.push("list", "a".into()).unwrap();
+        sut.add("set", "a".into()).unwrap();
+        sut.put("value", "1");
+
+        clock.advance(Duration::from_millis(20));
+
+        assert!(sut.range("list", 0, -1).is_none());
+        assert!(sut.is_member("set", "a").is_none());
+        assert!(sut
+            .update("value", |_| Ok::<_, ()>(Value::from("2")))
+            .is_none());
+        assert_eq!(sut.push("list", "b".into()), Ok(1));
+        assert_eq!(sut.add("set", "a".into()), Ok(true));
+        assert_eq!(sut.range("list", 0, -1), Some(Ok(vec!["b".to_string()])));
+    }
+
+    #[cfg(feature = "admin")]
+    #[test]
+    fn idle_values_are_not_exported() {
+        let clock = Arc::new(VirtualClock::default());
+        let sut = SimpleCache::new(Duration::from_millis(100), CacheMetrics::default())
+            .with_clock(clock.clone())
+            .with_time_to_idle(Duration::from_millis(10));
+        sut.put("read", "1");
+        sut.put("idle", "1");
+
+        clock.advance(Duration::from_millis(8));
+        sut.get("read", &|_| ());
+        clock.advance(Duration::from_millis(8));
+
+        assert!(sut.meta("idle").is_none());
+        assert!(sut.exported_entry("idle").is_none());
+        assert_eq!(sut.meta("read").unwrap().ttl_ms, 2);
+        let exported = sut.entries(|_| true);
+        assert_eq!(exported.len(), 1);
+        assert_eq!(exported[0].ttl_ms, 2);
+    }
+
+    #[cfg(feature = "admin")]
+    #[test]
+    fn immortal_values_are_not_expired() {
+        let clock = Arc::new(VirtualClock::default());
+        let sut = SimpleCache::new(Duration::from_millis(100), CacheMetrics::default())
+            .with_clock(clock.clone())
+            .with_time_to_idle(Duration::from_millis(10));
+        sut.put("a", "1");
+        sut.put_forever("a", "2");
+        sut.put_forever("b", "3");
+
+        clock.advance(Duration::from_secs(3600));
+        sut.remove_expired();
+
+        assert!(sut.contains_key("a"));
+        assert!(queued(&sut).is_none());
+        assert_eq!(sut.stats().immortal_items, 2);
+        let exported = sut.entries(|_| true);
+        assert!(exported
+            .iter()
+            .all(|entry| entry.immortal && entry.ttl_ms == 0));
+    }
+
+    #[test]
+    fn accounting_counts_entries_left_after_they_expired() {
+        let clock = Arc::new(VirtualClock::default());
+        let sut = SimpleCache::new(Duration::from_secs(60), CacheMetrics::default())
+            .with_clock(clock.clone());
+        sut.put("a", "1");
+        sut.put_with_ttl("b", "22", Duration::from_secs(1));
+        sut.put_forever("c", "333");
+
+        clock.advance(Duration::from_secs(30));
+
+        let accounting = sut.accounting(Duration::from_secs(10));
+        assert_eq!(accounting.items, (3, 3));
+        assert_eq!(accounting.size, (6, 6));
+        assert_eq!(accounting.stale, 1);
+    }
+
+    #[cfg(feature = "admin")]
+    #[test]
+    fn recalculating_the_metrics_corrects_drift() {
+        let metrics = CacheMetrics::default();
+        let sut = SimpleCache::new(Duration::from_secs(60), metrics.clone());
+        sut.put("a", "1");
+        sut.put("b", "22");
+        metrics.items.set(5);
+        metrics.size.add(10);
+
+        let accounting = sut.recalculate_metrics();
+
+        assert_eq!(accounting.items, (5, 2));
+        assert_eq!(accounting.size, (13, 3));
+        assert_eq!((metrics.items.get(), metrics.size.get()), (2, 3));
+    }
+
+    #[cfg(feature = "admin")]
+    #[test]
+    fn renamed_values_keep_their_expiry() {
+        let (sut, clock) = new_virtual_cache();
+        sut.put_with_ttl("a", "1", Duration::from_secs(10));
+        sut.put("b", "22");
+        sut.put_forever("c", "3");
+
+        assert!(sut.rename("a", "b"));
+        assert!(sut.rename("c", "d"));
+        assert!(!sut.rename("a", "e"));
+
+        assert!(!sut.contains_key("a"));
+        assert_eq!(sut.stats().items, 2);
+        assert_eq!(sut.size(), 2);
+        clock.advance(Duration::from_secs(11));
+        sut.remove_expired();
+        assert!(!sut.contains_key("b"));
+        assert!(sut.contains_key("d"));
+    }
+
+    #[cfg(feature = "admin")]
+    #[test]
+    fn copies_expire_independently() {
+        let (sut, clock) = new_virtual_cache();
+        sut.put_with_ttl("a", "1", Duration::from_secs(10));
+        sut.put_forever("b", "2");
+        sut.push("c", "x".to_string()).unwrap();
+
+        assert_eq!(sut.copy("a", "d", Expiry::Default), Some(Ok(true)));
+        assert_eq!(
+            sut.copy("a", "e", Expiry::After(Duration::from_secs(60))),
+            Some(Ok(true))
+        );
+        assert_eq!(sut.copy("b", "f", Expiry::Default), Some(Ok(true)));
+        assert_eq!(sut.copy("c", "g", Expiry::Default), Some(Err(WrongType)));
+        assert_eq!(sut.copy("x", "g", Expiry::Default), None);
+
+        clock.advance(Duration::from_secs(11));
+        sut.remove_expired();
+        assert!(!sut.contains_key("a"));
+        assert!(!sut.contains_key("d"));
+        assert!(sut.contains_key("e"));
+        assert!(sut.meta("f").unwrap().immortal);
+    }
+
+    #[test]
+    fn puts_report_whether_the_key_was_created() {
+        let (sut, _) = new_virtual_cache();
+
+        let created = sut.put("a", "1".to_string());
+        let updated = sut.put("a", "2".to_string());
+
+        assert!(created);
+        assert!(!updated);
+        let count = |outcome: &str| sut.metrics.writes.with_label_values(&[outcome]).get();
+        assert_eq!(count("created"), 1);
+        assert_eq!(count("updated"), 1);
+    }
+
+    #[cfg(feature = "admin")]
+    #[test]
+    fn values_larger_than_a_chunk_are_stored_as_chunks() {
+        let sut =
+            SimpleCache::new(Duration::from_secs(60), CacheMetrics::default()).with_chunk_size(4);
+
+        sut.put("a", "0123456789");
+
+        assert_eq!(sut.stats().chunks, 3);
+        assert_eq!(sut.meta("a").unwrap().size, 10);
+        assert_eq!(
+            sut.get("a", &|v| (v.to_bytes(), v.chunks().len())),
+            Some(("0123456789".into(), 3))
+        );
+        sut.put("a", "0123");
+        assert_eq!(sut.stats().chunks, 0);
+        sut.put("b", "0123456789");
+        assert!(sut.remove("b"));
+        assert_eq!(sut.stats().chunks, 0);
+    }
+
+    #[test]
+    fn puts_over_expired_values_create_the_key() {
+        let (sut, clock) = new_virtual_cache();
+        sut.put("a", "1".to_string());
+
+        clock.advance(Duration::from_millis(5));
+        let created = sut.put("a", "2".to_string());
+
+        assert!(created);
+        let count = |outcome: &str| sut.metrics.writes.with_label_values(&[outcome]).get();
+        assert_eq!(count("created"), 2);
+        assert_eq!(count("updated"), 0);
+    }
+
+    #[test]
+    fn puts_if_absent_only_create_keys() {
+        let (sut, metrics) = new_cache();
+
+        let created = sut.put_if_absent("a", "1".to_string(), Expiry::Never, Priority::Normal);
+        let existing = sut.put_if_absent("a", "2".to_string(), Expiry::Default, Priority::Normal);
+
+        assert_eq!(created, Some(true));
+        assert_eq!(existing, None);
+        assert_eq!(sut.get("a", &|v| v.clone()), Some(Value::from("1")));
+        assert_eq!(metrics.items.get(), 1);
+    }
+
+    #[test]
+    fn items_are_counted_by_size_bucket() {
+        let sut = SimpleCache::new(
+            Duration::from_secs(60),
+            CacheMetrics::default().with_size_buckets(&[4, 2]),
+        );
+        let count = |bucket: &str| sut.metrics.items_by_size.with_label_values(&[bucket]).get();
+
+        sut.put("a", "1".to_string());
+        sut.put("b", "123".to_string());
+        sut.put("c", "12345".to_string());
+        sut.put("a", "1234".to_string());
+        sut.remove("c");
+
+        assert_eq!((count("2"), count("4"), count("+Inf")), (0, 2, 0));
+    }
+
+    #[test]
+    fn remaining_ttls_and_lifetimes_are_observed() {
+        let (sut, clock) = new_virtual_cache();
+
+        sut.put("a", "value".to_string());
+        clock.advance(Duration::from_millis(1));
+        sut.get("a", &|v| v.clone());
+        clock.advance(Duration::from_millis(4));
+        sut.remove_expired();
+
+        let metrics = &sut.metrics;
+        assert_eq!(metrics.remaining_ttl.get_sample_count(), 1);
+        assert!((metrics.remaining_ttl.get_sample_sum() - 0.003).abs() < 1e-9);
+        assert_eq!(metrics.lifetimes.get_sample_count(), 1);
+        assert!((metrics.lifetimes.get_sample_sum() - 0.004).abs() < 1e-9);
+    }
+
+    #[test]
+    fn unexpired_values_are_not_removed() {
+        let (sut, _) = new_cache();
+
+        sut.put("", "old_value".to_string());
+
+        sut.remove_key_if_older_than("".into(), sut.clock.now());
+        let result = sut.get("", &|v| v.clone());
+
+        assert_eq!(result, Some(Value::from("old_value")));
+    }
+
+    #[test]
+    fn expired_values_are_removed() {
+        let (sut, _) = new_cache();
+
+        sut.put("", "old_value".to_string());
+
+        sut.remove_key_if_older_than("".into(), sut.clock.now() + Duration::from_millis(5));
+        let result = sut.get("", &|v| v.clone());
+
+        assert_eq!(result, None);
+    }
+
+    #[test]
+    fn key_that_does_not_exist_does_not_add_anything_to_cache() {
+        let (sut, _) = new_cache();
+
+        sut.remove_key_if_older_than("".into(), sut.clock.now() + Duration::from_millis(5));
+        let result = sut.get("", &|v| v.clone());
+
+        assert_eq!(result, None);
+    }
+
+    #[test]
+    fn metrics_query_hit_is_incremented() {
+        let (sut, metrics) = new_cache();
+
+        sut.put("", "".to_string());
+        let _ = sut.get("", &|v| v.clone());
+
+        assert_eq!(
+            metrics
+                .queries
+                .get_metric_with_label_values(&["hit"])
+                .unwrap()
+                .get(),
+            1
+        );
+    }
+
+    #[test]
+    fn metrics_query_miss_is_incremented() {
+        let (sut, metrics) = new_cache();
+
+        let _ = sut.get("", &|v| v.clone());
+
+        assert_eq!(
+            metrics
                 .queries
                 .get_metric_with_label_values(&["miss"])
                 .unwrap()
@@ -295,6 +2672,20 @@ mod test {
         );
     }
 
+    #[cfg(feature = "admin")]
+    #[test]
+    fn peeking_is_not_counted_as_a_query() {
+        let (sut, _) = new_cache();
+
+        sut.put("a", "1".to_string());
+        let peeked = sut.peek("a", &|v| v.clone());
+        let missing = sut.peek("b", &|v| v.clone());
+
+        assert_eq!(peeked, Some(Value::from("1")));
+        assert_eq!(missing, None);
+        assert_eq!(sut.stats().hits + sut.stats().misses, 0);
+    }
+
     #[test]
     fn metrics_cache_put_increments_items() {
         let (sut, metrics) = new_cache();
@@ -328,9 +2719,687 @@ mod test {
         assert_eq!(metrics.size.get(), expected);
     }
 
+    #[cfg(feature = "admin")]
+    #[test]
+    fn keys_returns_keys_with_prefix() {
+        let (sut, _) = new_cache();
+
+        sut.put("a/1", "".to_string());
+        sut.put("a/2", "".to_string());
+        sut.put("b/1", "".to_string());
+        let mut result = sut.keys("a/", 10);
+        result.sort();
+
+        assert_eq!(result, vec!["a/1".to_string(), "a/2".to_string()]);
+    }
+
+    #[cfg(feature = "admin")]
+    #[test]
+    fn keys_are_limited() {
+        let (sut, _) = new_cache();
+
+        sut.put("a", "".to_string());
+        sut.put("b", "".to_string());
+
+        assert_eq!(sut.keys("", 1).len(), 1);
+    }
+
+    #[cfg(feature = "admin")]
+    #[test]
+    fn flush_removes_all_keys_and_resets_metrics() {
+        let (sut, metrics) = new_cache();
+
+        sut.put("a", "AAA".to_string());
+        sut.put("b", "BB".to_string());
+        let removed = sut.flush();
+
+        assert_eq!(removed, 2);
+        assert_eq!(sut.get("a", &|v| v.clone()), None);
+        assert_eq!(metrics.items.get(), 0);
+        assert_eq!(metrics.size.get(), 0);
+    }
+
+    #[cfg(feature = "admin")]
+    #[test]
+    fn stats_reports_items_size_and_queries() {
+        let (sut, _) = new_cache();
+
+        sut.put("a", "AAA".to_string());
+        let _ = sut.get("a", &|v| v.clone());
+        let _ = sut.get("b", &|v| v.clone());
+        let stats = sut.stats();
+
+        assert_eq!(stats.items, 1);
+        assert_eq!(stats.size, 3);
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 1);
+    }
+
+    #[test]
+    fn entries_are_ordered_by_remaining_ttl() {
+        let metrics = CacheMetrics::default();
+        let sut = SimpleCache::new(Duration::from_secs(60), metrics);
+
+        sut.put_with_ttl("b", "B".to_string(), Duration::from_secs(20));
+        sut.put_with_ttl("a", "A".to_string(), Duration::from_secs(10));
+        let result: Vec<_> = sut
+            .entries(|_| true)
+            .into_iter()
+            .map(|entry| entry.key)
+            .collect();
+
+        assert_eq!(result, vec!["a".to_string(), "b".to_string()]);
+    }
+
+    #[test]
+    fn imported_entries_keep_their_ttl() {
+        let (sut, _) = new_cache();
+        let entry = ExportedEntry {
+            key: "a".to_string(),
+            value: "A".to_string(),
+            ttl_ms: 60_000,
+            json: false,
+            immortal: false,
+            expires_at_ms: None,
+            priority: Priority::Normal,
+        };
+
+        sut.import(vec![entry]);
+        let result = sut.entries(|_| true);
+
+        assert_eq!(result[0].value, "A");
+        assert!(result[0].ttl_ms > 50_000);
+    }
+
+    #[test]
+    fn entries_imported_after_a_restart_expire_by_the_wall_clock() {
+        let (sut, _) = new_cache();
+        sut.put_with_ttl("a", "A", Duration::from_secs(60));
+        let exported = sut.entries(|_| true).remove(0);
+        let exported_at = SystemTime::now();
+
+        let after_sleep =
+            |seconds| exported.remaining_ttl(exported_at + Duration::from_secs(seconds));
+
+        assert!(after_sleep(30).unwrap() <= Duration::from_secs(30));
+        assert_eq!(after_sleep(90), None);
+        let (restarted, _) = new_cache();
+        let expired = ExportedEntry {
+            expires_at_ms: Some(1_000),
+            ..exported
+        };
+        assert_eq!(restarted.import(vec![expired]), 0);
+        assert!(!restarted.contains_key("a"));
+    }
+
+    #[cfg(feature = "admin")]
+    #[test]
+    fn etags_change_with_the_value() {
+        let (sut, _) = new_cache();
+
+        sut.put("a", "1".to_string());
+        let first = sut.etags("");
+        sut.put("a", "2".to_string());
+        let second = sut.etags("");
+
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn updates_replace_the_value_and_keep_the_expiry() {
+        let (sut, metrics) = new_cache();
+        sut.put("a", "one");
+        let expiry = sut.backing_store.get("a").unwrap().expiry;
+
+        let updated = sut.update("a", |_| Ok::<_, ()>(Value::from("three")));
+
+        assert_eq!(updated, Some(Ok(Value::from("three"))));
+        assert_eq!(sut.get("a", &|v| v.clone()), Some(Value::from("three")));
+        assert_eq!(sut.backing_store.get("a").unwrap().expiry, expiry);
+        assert_eq!(metrics.size.get(), 5);
+        assert_eq!(sut.update("b", |_| Ok::<_, ()>(Value::from("b"))), None);
+        assert_eq!(sut.update("a", |_| Err("failed")), Some(Err("failed")));
+    }
+
+    #[test]
+    fn lists_are_pushed_and_ranged() {
+        let (sut, metrics) = new_cache();
+
+        assert_eq!(sut.push("a", "one".into()), Ok(1));
+        assert_eq!(sut.push("a", "two".into()), Ok(2));
+        assert_eq!(sut.push("a", "three".into()), Ok(3));
+
+        assert_eq!(
+            sut.range("a", 0, -1),
+            Some(Ok(vec!["one".into(), "two".into(), "three".into()]))
+        );
+        assert_eq!(
+            sut.range("a", -2, 10),
+            Some(Ok(vec!["two".into(), "three".into()]))
+        );
+        assert_eq!(sut.range("a", 2, 1), Some(Ok(vec![])));
+        assert_eq!(sut.range("b", 0, -1), None);
+        assert_eq!(
+            sut.get("a", &|v| v.clone()),
+            Some(Value::from(r#"["one","two","three"]"#).into_json(true))
+        );
+        assert_eq!(metrics.items.get(), 1);
+        assert_eq!(metrics.size.get(), 11);
+    }
+
+    #[test]
+    fn sets_have_unique_members() {
+        let (sut, _) = new_cache();
+
+        assert_eq!(sut.add("a", "one".into()), Ok(true));
+        assert_eq!(sut.add("a", "one".into()), Ok(false));
+
+        assert_eq!(sut.is_member("a", "one"), Some(Ok(true)));
+        assert_eq!(sut.is_member("a", "two"), Some(Ok(false)));
+        assert_eq!(sut.is_member("b", "one"), None);
+    }
+
+    #[test]
+    fn collection_operations_check_the_kind_of_data() {
+        let (sut, _) = new_cache();
+        sut.put("value", "one");
+        sut.push("list", "one".into()).unwrap();
+
+        assert_eq!(sut.push("value", "two".into()), Err(WrongType));
+        assert_eq!(sut.add("list", "two".into()), Err(WrongType));
+        assert_eq!(sut.is_member("list", "one"), Some(Err(WrongType)));
+        assert_eq!(sut.range("value", 0, -1), Some(Err(WrongType)));
+    }
+
+    #[test]
+    fn counters_total_their_window() {
+        let (sut, _) = new_cache();
+        let window = Duration::from_secs(60);
+
+        assert_eq!(sut.count("a", window, 1), Ok(1));
+        assert_eq!(sut.count("a", window, 2), Ok(3));
+        assert_eq!(
+            sut.get("a", &|v| v.clone()),
+            Some(Value::from("3").into_json(true))
+        );
+        assert_eq!(sut.count("a", Duration::from_secs(1), 1), Ok(1));
+        sut.put("b", "value");
+        assert_eq!(sut.count("b", window, 1), Err(WrongType));
+    }
+
+    #[test]
+    fn extended_counters_are_queued_again() {
+        let (sut, _) = new_cache();
+        sut.count("a", Duration::from_secs(1), 1).unwrap();
+        let KeyExpiry(key, expiry) = queued(&sut).unwrap();
+        let extended = sut.clock.now() + Duration::from_secs(60);
+        sut.backing_store.get_mut("a").unwrap().expiry = extended;
+
+        sut.remove_key_if_older_than(key, expiry);
+
+        assert!(sut.backing_store.contains_key("a"));
+        assert!(matches!(queued(&sut), Some(KeyExpiry(_, expiry)) if expiry == extended));
+    }
+
+    #[test]
+    fn bloom_filters_are_created_once() {
+        let (sut, metrics) = new_cache();
+
+        assert_eq!(sut.bloom_add("a", b"one"), None);
+        assert_eq!(
+            sut.create_bloom("a", BloomFilter::new(100, 0.01).unwrap()),
+            Ok(true)
+        );
+        assert_eq!(
+            sut.create_bloom("a", BloomFilter::new(100, 0.01).unwrap()),
+            Ok(false)
+        );
+        assert_eq!(sut.bloom_add("a", b"one"), Some(Ok(true)));
+
+        assert_eq!(sut.bloom_contains("a", b"one"), Some(Ok(true)));
+        assert_eq!(sut.bloom_contains("a", b"two"), Some(Ok(false)));
+        assert_eq!(metrics.size.get(), 120);
+    }
+
+    #[test]
+    fn frequently_read_values_are_renewed() {
+        let clock = Arc::new(VirtualClock::default());
+        let sut = SimpleCache::new(Duration::from_millis(1), CacheMetrics::default())
+            .with_clock(clock.clone())
+            .with_adaptive_ttl(AdaptiveTtl {
+                enabled: true,
+                min_hits: 2,
+                max_ttl: 60,
+            });
+        sut.put("hot", "value");
+        sut.put("cold", "value");
+        let KeyExpiry(first, first_expiry) = queued(&sut).unwrap();
+        let KeyExpiry(second, second_expiry) = queued(&sut).unwrap();
+        sut.get("hot", &|_| ());
+        sut.get("hot", &|_| ());
+        sut.get("cold", &|_| ());
+        clock.advance(Duration::from_millis(5));
+
+        sut.remove_key_if_older_than(first, first_expiry);
+        sut.remove_key_if_older_than(second, second_expiry);
+
+        assert!(sut.backing_store.contains_key("hot"));
+        assert!(!sut.backing_store.contains_key("cold"));
+        assert_eq!(sut.meta("hot").unwrap().hits, 0);
+        assert!(queued(&sut).is_some());
+    }
+
+    #[test]
+    fn tinylfu_evicts_for_frequently_read_keys() {
+        let metrics = CacheMetrics::default();
+        let sut = SimpleCache::new(Duration::from_secs(60), metrics.clone())
+            .with_eviction_policy(EvictionPolicy::TinyLfu);
+        sut.put("old", "value");
+        sut.put("hot", "value");
+        sut.get("hot", &|_| ());
+
+        assert!(!sut.make_room("once", 5, Priority::Normal));
+        sut.get("new", &|_| ());
+        sut.get("new", &|_| ());
+        assert!(sut.make_room("new", 5, Priority::Normal));
+
+        assert!(!sut.backing_store.contains_key("old"));
+        assert!(sut.backing_store.contains_key("hot"));
+        assert_eq!(metrics.evictions.get(), 1);
+        assert_eq!(metrics.rejected_admissions.get(), 1);
+    }
+
+    #[cfg(feature = "admin")]
+    #[test]
+    fn slab_space_is_released_when_values_are_replaced_or_removed() {
+        let slab_metrics = SlabMetrics::with_opts(&MetricOpts::default());
+        let sut = SimpleCache::new(Duration::from_secs(60), CacheMetrics::default())
+            .with_slab_allocator(SlabAllocator::new(64, 8, slab_metrics.clone()));
+        sut.put("small", "value");
+        sut.put("large", "a larger value");
+        assert_eq!(slab_metrics.used.get(), 5);
+
+        sut.update("small", |_| Ok::<_, ()>(Value::from("abc")));
+        assert_eq!(slab_metrics.used.get(), 3);
+        assert_eq!(sut.get("small", &|v| v.clone()), Some(Value::from("abc")));
+
+        sut.flush();
+        assert_eq!(slab_metrics.used.get(), 0);
+    }
+
+    #[cfg(feature = "admin")]
+    #[test]
+    fn stored_bytes_follow_the_values_of_an_api_key() {
+        let settings = settings::Usage {
+            aliases: vec![("secret".to_string(), "team".to_string())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        let tracker = UsageTracker::new(settings, UsageMetrics::with_opts(&MetricOpts::default()));
+        let usage = tracker.usage("secret");
+        let sut = SimpleCache::new(Duration::from_secs(60), CacheMetrics::default());
+        sut.put("a", "value");
+        sut.set_owner("a", &usage);
+        assert_eq!(sut.owned_size("a", &usage), 5);
+        assert_eq!(sut.owned_size("a", &tracker.usage("unknown")), 0);
+
+        sut.update("a", |_| Ok::<_, ()>(Value::from("abc")));
+        assert_eq!(tracker.report()["team"].stored_bytes, 3);
+
+        sut.flush();
+        assert_eq!(tracker.report()["team"].stored_bytes, 0);
+    }
+
+    #[cfg(feature = "admin")]
+    #[test]
+    fn matching_keys_are_removed() {
+        let sut = SimpleCache::new(Duration::from_secs(60), CacheMetrics::default());
+        sut.put("users/1", "a");
+        sut.put("users/2", "b");
+        sut.put("groups/1", "c");
+
+        let mut keys = sut.matching_keys(|key| key.starts_with("users/"));
+        keys.sort();
+        assert_eq!(keys, vec!["users/1", "users/2"]);
+        assert!(sut.remove("users/1"));
+        assert!(!sut.remove("users/1"));
+        assert_eq!(sut.len(), 2);
+        assert_eq!(sut.size(), 2);
+    }
+
+    struct RecordingPlugin(Arc<Mutex<Vec<String>>>);
+
+    impl CachePlugin for RecordingPlugin {
+        fn before_put(&self, key: &str, _value: &Value) {
+            self.0.lock().unwrap().push(format!("put {}", key));
+        }
+
+        fn after_get(&self, key: &str, hit: bool) {
+            self.0.lock().unwrap().push(format!("get {} {}", key, hit));
+        }
+
+        fn on_expire(&self, key: &str, size: usize) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("expire {} {}", key, size));
+        }
+    }
+
+    #[actix_rt::test]
+    async fn plugins_are_called_in_the_life_of_an_entry() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sut = SimpleCache::new(Duration::from_millis(4), CacheMetrics::default())
+            .with_clock(Arc::new(VirtualClock::default()))
+            .with_plugin(RecordingPlugin(events.clone()));
+
+        sut.put("a", "value");
+        sut.get("a", &|_| ());
+        sut.get("b", &|_| ());
+        sut.clean().await;
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec!["put a", "get a true", "get b false", "expire a 5"]
+        );
+    }
+
+    #[test]
+    fn refreshes_keep_the_entry_and_never_replace_newer_writes() {
+        let (sut, clock) = new_virtual_cache();
+        let read_since = sut.now();
+        clock.advance(Duration::from_millis(1));
+        sut.put_with_priority("a", "local", Expiry::Never, Priority::High);
+
+        assert!(!sut.refresh("a", Value::from("stale"), read_since));
+        assert!(sut.refresh("a", Value::from("remote"), sut.now()));
+        assert!(sut.refresh("b", Value::from("remote"), read_since));
+
+        let a = sut.entries(|key| key == "a").remove(0);
+        assert!(a.immortal);
+        assert_eq!(a.priority, Priority::High);
+        assert_eq!(a.value, "remote");
+        assert_eq!(sut.peek("b", &|value| value.clone()), Some("remote".into()));
+    }
+
+    #[test]
+    fn updates_are_seen_by_plugins() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sut = SimpleCache::new(Duration::from_secs(60), CacheMetrics::default())
+            .with_plugin(RecordingPlugin(events.clone()));
+        sut.put("a", "1");
+
+        sut.update("a", |_| Ok::<_, ()>(Value::from("2")));
+
+        assert_eq!(*events.lock().unwrap(), vec!["put a", "put a"]);
+    }
+
+    #[test]
+    fn slru_evicts_keys_read_once_before_keys_read_again() {
+        let metrics = CacheMetrics::default();
+        let sut = SimpleCache::new(Duration::from_secs(60), metrics.clone())
+            .with_eviction_policy(EvictionPolicy::Slru);
+        sut.put("hot", "value");
+        sut.put("scan", "value");
+        sut.get("hot", &|_| ());
+
+        assert!(sut.make_room("new", 5, Priority::Normal));
+
+        assert!(sut.backing_store.contains_key("hot"));
+        assert!(!sut.backing_store.contains_key("scan"));
+        let segment_items =
+            |segment: &str| metrics.segment_items.with_label_values(&[segment]).get();
+        assert_eq!(
+            (segment_items("protected"), segment_items("probation")),
+            (1, 0)
+        );
+    }
+
+    #[test]
+    fn low_priority_values_are_evicted_first() {
+        let sut = SimpleCache::new(Duration::from_secs(60), CacheMetrics::default())
+            .with_eviction_policy(EvictionPolicy::Slru);
+        sut.put_with_priority("bulk", "value", Expiry::Default, Priority::Low);
+        sut.put_with_priority("config", "value", Expiry::Default, Priority::High);
+        sut.put("page", "value");
+        sut.get("bulk", &|_| ());
+
+        assert!(sut.make_room("new", 5, Priority::Normal));
+        assert!(!sut.make_room("more", 5, Priority::Low));
+
+        assert!(!sut.backing_store.contains_key("bulk"));
+        assert!(sut.backing_store.contains_key("config"));
+        assert!(sut.backing_store.contains_key("page"));
+        let exported = sut.entries(|_| true);
+        let config = exported.iter().find(|entry| entry.key == "config").unwrap();
+        assert_eq!(config.priority, Priority::High);
+    }
+
+    #[cfg(feature = "admin")]
+    #[test]
+    fn pinned_values_are_not_evicted() {
+        let sut = SimpleCache::new(Duration::from_secs(60), CacheMetrics::default())
+            .with_eviction_policy(EvictionPolicy::Slru);
+        let pin = Pin {
+            key: "config/".into(),
+            prefix: true,
+        };
+        sut.put("config/flags", "value");
+        sut.put("users/1", "value");
+        sut.pin(&pin).unwrap();
+
+        assert!(sut.make_room("new", 5, Priority::Normal));
+        assert!(!sut.make_room("new", 5, Priority::Normal));
+        assert!(sut.backing_store.contains_key("config/flags"));
+        assert!(!sut.backing_store.contains_key("users/1"));
+
+        sut.unpin(&pin).unwrap();
+        assert!(sut.make_room("new", 5, Priority::Normal));
+        assert!(!sut.backing_store.contains_key("config/flags"));
+    }
+
+    #[cfg(feature = "admin")]
+    #[test]
+    fn eviction_previews_list_the_keys_make_room_would_evict() {
+        let sut = SimpleCache::new(Duration::from_secs(60), CacheMetrics::default())
+            .with_eviction_policy(EvictionPolicy::Slru);
+        sut.put("a", "value");
+        sut.put("b", "value");
+        sut.put("c", "value");
+
+        let preview = sut.eviction_preview(6, Priority::Normal, 1);
+
+        assert_eq!(preview.count, 2);
+        assert_eq!(preview.freed, 10);
+        assert_eq!(
+            preview.keys,
+            vec![Victim {
+                key: "a".into(),
+                size: 5
+            }]
+        );
+        assert!(preview.sufficient);
+        assert!(!sut.eviction_preview(20, Priority::Normal, 10).sufficient);
+        assert_eq!(sut.len(), 3);
+    }
+
+    #[test]
+    fn corrupted_values_are_removed_when_verified() {
+        let metrics = CacheMetrics::default();
+        let sut = SimpleCache::new(Duration::from_secs(60), metrics.clone())
+            .with_checksums(ChecksumAlgorithm::Sha256, true);
+
+        sut.put("a", "1".to_string());
+        sut.backing_store.get_mut("a").unwrap().data = Data::Value(Value::from("2"));
+        let result = sut.get("a", &|v| v.clone());
+
+        assert_eq!(result, None);
+        assert_eq!(sut.meta("a").map(|meta| meta.size), None);
+        assert_eq!(
+            metrics
+                .internal_errors
+                .get_metric_with_label_values(&["checksum_mismatch"])
+                .unwrap()
+                .get(),
+            1
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "persistence")]
+    fn values_on_disk_are_promoted_when_read() {
+        let dir =
+            std::env::temp_dir().join(format!("simple-mem-cache-promote-{}", std::process::id()));
+        let disk_tier =
+            DiskTier::open(&dir, DiskMetrics::with_opts(&MetricOpts::default())).unwrap();
+        let metrics = CacheMetrics::default();
+        let sut =
+            SimpleCache::new(Duration::from_secs(60), metrics.clone()).with_disk_tier(disk_tier);
+
+        assert!(sut.put_on_disk("a", "1".to_string()));
+        assert_eq!(metrics.items.get(), 0);
+        let result = sut.get("a", &|v| v.clone());
+
+        assert_eq!(result, Some(Value::from("1")));
+        assert_eq!(metrics.items.get(), 1);
+        assert!(!sut.disk_tier().unwrap().contains("a"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "persistence")]
+    fn nothing_is_read_through_or_promoted_while_writes_are_paused() {
+        let dir =
+            std::env::temp_dir().join(format!("simple-mem-cache-paused-{}", std::process::id()));
+        let disk_tier =
+            DiskTier::open(&dir, DiskMetrics::with_opts(&MetricOpts::default())).unwrap();
+        let gate = web::Data::new(WriteGate::default());
+        let metrics = CacheMetrics::default();
+        let sut = SimpleCache::new(Duration::from_secs(60), metrics.clone())
+            .with_disk_tier(disk_tier)
+            .with_write_gate(gate.clone());
+        assert!(sut.put_on_disk("a", "1".to_string()));
+
+        gate.close();
+        let read = sut.get("a", &|v| v.clone());
+        let refreshed = sut.refresh("b", Value::from("remote"), sut.now());
+
+        assert_eq!(read, Some(Value::from("1")));
+        assert!(!refreshed);
+        assert_eq!(metrics.items.get(), 0);
+        assert!(sut.disk_tier().unwrap().contains("a"));
+        gate.open();
+        assert!(sut.refresh("b", Value::from("remote"), sut.now()));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "persistence")]
+    fn puts_over_expired_values_on_disk_create_the_key() {
+        let dir =
+            std::env::temp_dir().join(format!("simple-mem-cache-expired-{}", std::process::id()));
+        let disk_tier =
+            DiskTier::open(&dir, DiskMetrics::with_opts(&MetricOpts::default())).unwrap();
+        let clock = Arc::new(VirtualClock::default());
+        let sut = SimpleCache::new(Duration::from_millis(4), CacheMetrics::default())
+            .with_clock(clock.clone())
+            .with_disk_tier(disk_tier);
+        assert!(sut.put_on_disk("a", "1".to_string()));
+
+        clock.advance(Duration::from_millis(5));
+
+        assert!(!sut.contains_key("a"));
+        assert!(sut.put("a", "2".to_string()));
+        assert!(!sut.disk_tier().unwrap().contains("a"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(all(feature = "admin", feature = "persistence"))]
+    fn evicted_values_spill_to_disk_until_flushed() {
+        let dir =
+            std::env::temp_dir().join(format!("simple-mem-cache-spill-{}", std::process::id()));
+        let disk_tier =
+            DiskTier::open(&dir, DiskMetrics::with_opts(&MetricOpts::default())).unwrap();
+        let sut = SimpleCache::new(Duration::from_secs(60), CacheMetrics::default())
+            .with_eviction_policy(EvictionPolicy::Slru)
+            .with_disk_tier(disk_tier);
+        sut.put("a", "value");
+
+        assert!(sut.make_room("b", 5, Priority::Normal));
+        assert!(sut.disk_tier().unwrap().contains("a"));
+        assert_eq!(sut.peek("a", &|v| v.clone()), Some(Value::from("value")));
+
+        assert_eq!(sut.flush(), 1);
+        assert!(!sut.contains_key("a"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn meta_includes_the_checksum() {
+        let sut = SimpleCache::new(Duration::from_secs(60), CacheMetrics::default())
+            .with_checksums(ChecksumAlgorithm::Sha256, false);
+
+        sut.put("a", "abc".to_string());
+        let meta = sut.meta("a").unwrap();
+
+        assert_eq!(meta.size, 3);
+        assert!(meta.checksum.starts_with("sha256:ba7816bf"));
+    }
+
+    #[test]
+    fn metrics_internal_error_is_incremented_by_kind() {
+        let metrics = CacheMetrics::default();
+
+        metrics.internal_error("disk_tier");
+
+        assert_eq!(
+            metrics
+                .internal_errors
+                .get_metric_with_label_values(&["disk_tier"])
+                .unwrap()
+                .get(),
+            1
+        );
+    }
+
+    #[test]
+    fn metrics_are_named_using_metric_opts() {
+        use prometheus::core::Collector;
+        let opts = MetricOpts {
+            namespace: "ns".to_string(),
+            subsystem: "sub".to_string(),
+            const_labels: vec![("cluster".to_string(), "a".to_string())]
+                .into_iter()
+                .collect(),
+        };
+
+        let metrics = CacheMetrics::with_opts(&opts);
+        let desc = metrics.items.desc()[0];
+
+        assert_eq!(desc.fq_name, "ns_sub_cache_items");
+        assert_eq!(desc.const_label_pairs[0].get_value(), "a");
+    }
+
     fn new_cache() -> (web::Data<SimpleCache<'static>>, CacheMetrics) {
-        let metrics = CacheMetrics::new();
+        let metrics = CacheMetrics::default();
         let cache = web::Data::new(SimpleCache::new(Duration::from_millis(4), metrics.clone()));
         (cache, metrics)
     }
+
+    fn new_virtual_cache() -> (web::Data<SimpleCache<'static>>, Arc<VirtualClock>) {
+        let clock = Arc::new(VirtualClock::default());
+        let cache = SimpleCache::new(Duration::from_millis(4), CacheMetrics::default())
+            .with_clock(clock.clone());
+        (web::Data::new(cache), clock)
+    }
+
+    fn queued<'a>(sut: &SimpleCache<'a>) -> Option<KeyExpiry<'a>> {
+        sut.expiries
+            .lock()
+            .unwrap()
+            .pop()
+            .map(|Reverse(expiry)| expiry)
+    }
 }
@@ -0,0 +1,150 @@
+//! Adds `Cache-Control`, `Age` and `Expires` headers to values read with `GET /{key}`, so HTTP
+//! caches in front of the cache server keep a value no longer than the cache itself does.
+//!
+//! The policy is chosen by the longest key prefix in `cache.cache_control.namespaces`, falling back
+//! to `cache.cache_control.default`.
+use crate::cache::EntryMeta;
+use crate::settings::{self, CacheControlPolicy};
+use actix_web::{
+    http::{
+        header::{self, HttpDate},
+        HeaderValue,
+    },
+    HttpResponse,
+};
+use std::time::{Duration, SystemTime};
+
+/// The lifetime given to HTTP caches for values that never expire, the longest conventionally
+/// allowed.
+const IMMORTAL_TTL_MS: u64 = 365 * 24 * 60 * 60 * 1000;
+
+/// Returns the policy for `key`, from the namespace with the longest prefix of `key`.
+fn policy(settings: &settings::CacheControl, key: &str) -> CacheControlPolicy {
+    settings
+        .namespaces
+        .iter()
+        .filter(|(prefix, _)| key.starts_with(prefix.as_str()))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map_or(settings.default, |(_, policy)| *policy)
+}
+
+/// Sets the caching headers of a successful response for `key`.
+/// # Arguments
+/// * `settings` - The caching policies.
+/// * `key` - The key that was read.
+/// * `meta` - The metadata of the entry, which is None if it has already expired.
+/// * `response` - The response to add headers to.
+pub fn apply(
+    settings: &settings::CacheControl,
+    key: &str,
+    meta: Option<&EntryMeta>,
+    response: &mut HttpResponse,
+) {
+    if !response.status().is_success() {
+        return;
+    }
+    let visibility = match policy(settings, key) {
+        CacheControlPolicy::None => return,
+        CacheControlPolicy::NoStore => {
+            response
+                .headers_mut()
+                .insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+            return;
+        }
+        CacheControlPolicy::Public => "public",
+        CacheControlPolicy::Private => "private",
+    };
+    let meta = match meta {
+        Some(meta) => meta,
+        None => return,
+    };
+    let ttl_ms = if meta.immortal {
+        IMMORTAL_TTL_MS
+    } else {
+        meta.ttl_ms
+    };
+    let max_age = ttl_ms / 1000;
+    let expires = HttpDate::from(SystemTime::now() + Duration::from_millis(ttl_ms));
+    let headers = response.headers_mut();
+    for (name, value) in [
+        (
+            header::CACHE_CONTROL,
+            format!("{}, max-age={}", visibility, max_age),
+        ),
+        (header::AGE, (meta.age_ms / 1000).to_string()),
+        (header::EXPIRES, expires.to_string()),
+    ] {
+        // Formatted numbers and dates are always valid header values.
+        headers.insert(name, HeaderValue::from_str(&value).unwrap());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn meta() -> EntryMeta {
+        EntryMeta {
+            size: 1,
+            ttl_ms: 59_500,
+            age_ms: 1_500,
+            etag: String::new(),
+            checksum: String::new(),
+            hits: 0,
+            immortal: false,
+        }
+    }
+
+    fn settings() -> settings::CacheControl {
+        let mut namespaces = HashMap::new();
+        namespaces.insert("users/".to_string(), CacheControlPolicy::Private);
+        namespaces.insert("users/secret/".to_string(), CacheControlPolicy::NoStore);
+        settings::CacheControl {
+            default: CacheControlPolicy::Public,
+            namespaces,
+        }
+    }
+
+    fn header(response: &HttpResponse, name: header::HeaderName) -> Option<&str> {
+        response
+            .headers()
+            .get(name)
+            .map(|value| value.to_str().unwrap())
+    }
+
+    #[test]
+    fn headers_follow_the_remaining_ttl() {
+        let mut response = HttpResponse::Ok().finish();
+
+        apply(&settings(), "a", Some(&meta()), &mut response);
+
+        assert_eq!(
+            header(&response, header::CACHE_CONTROL),
+            Some("public, max-age=59")
+        );
+        assert_eq!(header(&response, header::AGE), Some("1"));
+        assert!(header(&response, header::EXPIRES).is_some());
+    }
+
+    #[test]
+    fn the_longest_namespace_is_used() {
+        let settings = settings();
+
+        assert_eq!(policy(&settings, "users/1"), CacheControlPolicy::Private);
+        assert_eq!(
+            policy(&settings, "users/secret/1"),
+            CacheControlPolicy::NoStore
+        );
+        assert_eq!(policy(&settings, "other"), CacheControlPolicy::Public);
+    }
+
+    #[test]
+    fn errors_are_not_cached() {
+        let mut response = HttpResponse::NotFound().finish();
+
+        apply(&settings(), "a", Some(&meta()), &mut response);
+
+        assert_eq!(header(&response, header::CACHE_CONTROL), None);
+    }
+}
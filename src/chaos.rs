@@ -0,0 +1,103 @@
+//! Injects faults into the cache on command, so we can verify the server degrades gracefully when
+//! its store is slow or failing, expiries are lost or the cleaner falls behind. Only built with
+//! the `chaos` feature, the faults are read and set with `GET` and `POST /_admin/chaos`.
+//!
+//! Failed reads miss and failed writes are dropped. Faults are spread evenly over the operations
+//! they apply to, and delays block the thread the operation runs on, as a slow store would.
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    sync::RwLock,
+    thread,
+    time::Duration,
+};
+
+/// The faults injected, none by default.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Faults {
+    /// Milliseconds every read and write of the store is delayed by.
+    pub store_delay_ms: u64,
+    /// The percentage of reads and writes of the store that fail.
+    pub store_failure_percentage: f64,
+    /// The percentage of expiries dropped, leaving their keys in the cache until they are written
+    /// again.
+    pub dropped_expiry_percentage: f64,
+    /// Milliseconds the cleaner waits before each pass over the expiry queue.
+    pub cleaner_delay_ms: u64,
+}
+
+/// Spreads a percentage of operations evenly.
+#[derive(Default)]
+struct Spread(AtomicU64);
+
+impl Spread {
+    /// Returns true if the next operation is in the percentage.
+    fn next(&self, percentage: f64) -> bool {
+        let share = percentage.clamp(0.0, 100.0) / 100.0;
+        let seen = self.0.fetch_add(1, Ordering::Relaxed) as f64;
+        ((seen + 1.0) * share).floor() > (seen * share).floor()
+    }
+}
+
+/// The faults injected into a cache.
+#[derive(Default)]
+pub struct Chaos {
+    faults: RwLock<Faults>,
+    store_failures: Spread,
+    dropped_expiries: Spread,
+}
+
+impl Chaos {
+    /// Returns the faults injected.
+    pub fn faults(&self) -> Faults {
+        self.faults.read().unwrap().clone()
+    }
+
+    /// Replaces the faults injected.
+    pub fn set(&self, faults: Faults) {
+        log::warn!("Injecting faults: {:?}", faults);
+        *self.faults.write().unwrap() = faults;
+    }
+
+    /// Delays a read or write of the store, returning true if it should fail.
+    pub fn store_fails(&self) -> bool {
+        let faults = self.faults();
+        if faults.store_delay_ms > 0 {
+            thread::sleep(Duration::from_millis(faults.store_delay_ms));
+        }
+        self.store_failures.next(faults.store_failure_percentage)
+    }
+
+    /// Returns true if an expiry should be dropped.
+    pub fn drops_expiry(&self) -> bool {
+        let percentage = self.faults.read().unwrap().dropped_expiry_percentage;
+        self.dropped_expiries.next(percentage)
+    }
+
+    /// Returns how long the cleaner waits before its next pass, if it is slowed.
+    pub fn cleaner_delay(&self) -> Option<Duration> {
+        let delay_ms = self.faults.read().unwrap().cleaner_delay_ms;
+        Some(Duration::from_millis(delay_ms)).filter(|delay| *delay > Duration::default())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn faults_are_spread_evenly() {
+        let sut = Chaos::default();
+        sut.set(Faults {
+            store_failure_percentage: 50.0,
+            ..Default::default()
+        });
+
+        let failures: Vec<bool> = (0..4).map(|_| sut.store_fails()).collect();
+
+        assert_eq!(failures, vec![false, true, false, true]);
+        assert!(!sut.drops_expiry());
+        assert_eq!(sut.cleaner_delay(), None);
+    }
+}
@@ -0,0 +1,80 @@
+//! Checksums stored with each value to detect corruption.
+use crate::digest;
+use crate::settings::ChecksumAlgorithm;
+use crate::value::Value;
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+/// The checksum of a value.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Checksum {
+    XxHash64(u64),
+    Sha256([u8; 32]),
+}
+
+impl Checksum {
+    /// Returns the checksum of `value`.
+    /// # Arguments
+    /// * `algorithm` - The algorithm to use.
+    /// * `value` - The value to checksum.
+    pub fn new(algorithm: ChecksumAlgorithm, value: &Value) -> Self {
+        match algorithm {
+            ChecksumAlgorithm::XxHash64 => Checksum::XxHash64(digest::hash_chunks(value.chunks())),
+            ChecksumAlgorithm::Sha256 => {
+                let mut hasher = Sha256::new();
+                for chunk in value.chunks() {
+                    hasher.update(chunk);
+                }
+                Checksum::Sha256(hasher.finalize().into())
+            }
+        }
+    }
+
+    /// Returns true when `value` still has this checksum.
+    pub fn verify(&self, value: &Value) -> bool {
+        let algorithm = match self {
+            Checksum::XxHash64(_) => ChecksumAlgorithm::XxHash64,
+            Checksum::Sha256(_) => ChecksumAlgorithm::Sha256,
+        };
+        *self == Self::new(algorithm, value)
+    }
+}
+
+impl fmt::Display for Checksum {
+    /// Formats the checksum as `<algorithm>:<hex>`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Checksum::XxHash64(hash) => write!(f, "xxh64:{}", digest::to_hex(*hash)),
+            Checksum::Sha256(hash) => {
+                write!(f, "sha256:")?;
+                hash.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn checksums_are_formatted_with_the_algorithm() {
+        let value = Value::from("abc");
+
+        assert_eq!(
+            Checksum::new(ChecksumAlgorithm::Sha256, &value).to_string(),
+            "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert!(Checksum::new(ChecksumAlgorithm::XxHash64, &value)
+            .to_string()
+            .starts_with("xxh64:"));
+    }
+
+    #[test]
+    fn changed_values_fail_verification() {
+        let checksum = Checksum::new(ChecksumAlgorithm::Sha256, &Value::from("abc"));
+
+        assert!(checksum.verify(&Value::from("abc")));
+        assert!(!checksum.verify(&Value::from("abd")));
+    }
+}
@@ -0,0 +1,132 @@
+//! Values larger than `cache.chunk_size` are split into chunks of that size, stored under keys
+//! derived from the key of the value, e.g. `a#7.0`, `a#7.1` and so on, so one large value is spread
+//! over many buckets of the chunk store instead of monopolizing a single entry. The chunks are
+//! reassembled when the value is read, without copying them, and streamed out one by one.
+//!
+//! Every stored value gets a new id in its derived keys, so the chunks of a value being replaced
+//! are never overwritten while it is read. Values are held by the cache with `StoredChunks`, which
+//! removes the chunks once the value is dropped.
+use crate::value::Value;
+use actix_web::web::Bytes;
+use chashmap::CHashMap;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+/// The chunks of the large values in the cache, under their derived keys.
+pub struct ChunkStore {
+    chunk_size: usize,
+    chunks: CHashMap<String, Bytes>,
+    next_id: AtomicU64,
+}
+
+impl ChunkStore {
+    /// Returns an empty store splitting values into chunks of `chunk_size` bytes.
+    pub fn new(chunk_size: usize) -> Arc<Self> {
+        Arc::new(Self {
+            chunk_size: chunk_size.max(1),
+            chunks: CHashMap::new(),
+            next_id: AtomicU64::new(0),
+        })
+    }
+
+    /// Returns true if `value` is larger than a chunk, so it is stored in the chunk store.
+    pub fn splits(&self, value: &Value) -> bool {
+        value.len() > self.chunk_size
+    }
+
+    /// Stores `value` as chunks under keys derived from `key`, without copying it.
+    /// # Arguments
+    /// * `key` - The key of the value.
+    /// * `value` - The value to store.
+    pub fn store(self: &Arc<Self>, key: &str, value: &Value) -> StoredChunks {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut keys = Vec::new();
+        for chunk in value.chunks() {
+            let mut start = 0;
+            while start < chunk.len() {
+                let end = chunk.len().min(start + self.chunk_size);
+                let derived = format!("{}#{}.{}", key, id, keys.len());
+                self.chunks.insert(derived.clone(), chunk.slice(start..end));
+                keys.push(derived);
+                start = end;
+            }
+        }
+        StoredChunks {
+            store: self.clone(),
+            keys,
+            len: value.len(),
+            json: value.is_json(),
+        }
+    }
+
+    /// Returns the number of chunks stored.
+    #[cfg(feature = "admin")]
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+}
+
+/// A value stored in the chunk store, whose chunks are removed when dropped.
+pub struct StoredChunks {
+    store: Arc<ChunkStore>,
+    /// The derived keys of the chunks, in order.
+    keys: Vec<String>,
+    len: usize,
+    json: bool,
+}
+
+impl StoredChunks {
+    /// Returns the length of the value in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns the value reassembled from its chunks, which are shared rather than copied.
+    pub fn to_value(&self) -> Value {
+        self.keys
+            .iter()
+            .filter_map(|key| self.store.chunks.get(key).map(|chunk| chunk.clone()))
+            .collect::<Value>()
+            .into_json(self.json)
+    }
+}
+
+impl Drop for StoredChunks {
+    fn drop(&mut self) {
+        for key in &self.keys {
+            self.store.chunks.remove(key);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "admin"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn values_are_reassembled_from_their_chunks() {
+        let sut = ChunkStore::new(4);
+        let value = vec![Bytes::from("0123456"), Bytes::from("789")]
+            .into_iter()
+            .collect::<Value>()
+            .into_json(true);
+
+        let stored = sut.store("a", &value);
+        let replaced = sut.store("a", &Value::from("abcdefghi"));
+
+        assert!(sut.splits(&value));
+        assert!(!sut.splits(&Value::from("0123")));
+        assert_eq!(sut.len(), 6);
+        assert_eq!(stored.to_value().to_bytes(), "0123456789");
+        assert_eq!(stored.to_value().chunks().len(), 3);
+        assert!(stored.to_value().is_json());
+        assert_eq!(stored.len(), 10);
+        assert_eq!(replaced.to_value().to_bytes(), "abcdefghi");
+        drop(stored);
+        assert_eq!(sut.len(), 3);
+        drop(replaced);
+        assert_eq!(sut.len(), 0);
+    }
+}
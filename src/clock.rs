@@ -0,0 +1,70 @@
+//! The time the cache reads when values are written, read and expire, and waits on until they
+//! expire, so tests and replays can run the cache on virtual time instead of sleeping.
+use actix_rt::time::delay_for;
+use futures::future::{self, BoxFuture, FutureExt};
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// A source of the current time.
+pub trait Clock: Send + Sync {
+    /// Returns the current time.
+    fn now(&self) -> Instant;
+
+    /// Returns a future that completes once `duration` has passed.
+    fn delay(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// The time of the system.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn delay(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        delay_for(duration).boxed()
+    }
+}
+
+/// A clock that only moves when it is set or waited on, starting from when it was created.
+pub struct VirtualClock {
+    start: Instant,
+    elapsed: Mutex<Duration>,
+}
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            elapsed: Mutex::new(Duration::default()),
+        }
+    }
+}
+
+impl VirtualClock {
+    /// Moves the clock to `elapsed` after its start, it never moves back.
+    pub fn set(&self, elapsed: Duration) {
+        let mut current = self.elapsed.lock().unwrap();
+        *current = elapsed.max(*current);
+    }
+
+    /// Moves the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock().unwrap()
+    }
+
+    /// Completes at once, moving the clock forward by `duration` as if it had passed.
+    fn delay(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        self.advance(duration);
+        future::ready(()).boxed()
+    }
+}
@@ -0,0 +1,342 @@
+//! Lists and sets of strings stored under a key, e.g. `POST /{key}/list/push` with an item as the
+//! body or `GET /{key}/set/contains?member=a`, and windowed counters, e.g.
+//! `POST /{key}/count?window=60s`, and Bloom filters, e.g. `POST /{key}/bloom?capacity=1000`.
+//! These only live in memory, `GET /{key}` returns collections as a JSON array, counters as their
+//! total and Bloom filters as a summary. A collection is attributed to the API key that last
+//! wrote it and its writes count towards the quota of that key.
+use crate::bloom::BloomFilter;
+use crate::cache::{SimpleCache, WrongType};
+use crate::keys::CacheKey;
+use crate::limits::KeyLimiter;
+use crate::pressure::MemoryPressure;
+use crate::settings;
+use crate::usage::{ApiKeyUsage, UsageTracker};
+use crate::value::{Value, DEFAULT_CHUNK_SIZE, DEFAULT_MAX_VALUE_SIZE};
+use crate::write_checks::Rejected;
+use actix_web::{get, post, web, Error, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use serde_json::json;
+use std::{sync::Arc, time::Duration};
+
+#[derive(Deserialize)]
+struct RangeQuery {
+    #[serde(default)]
+    start: i64,
+    #[serde(default = "RangeQuery::default_stop")]
+    stop: i64,
+}
+
+impl RangeQuery {
+    fn default_stop() -> i64 {
+        -1
+    }
+}
+
+#[derive(Deserialize)]
+struct MemberQuery {
+    member: String,
+}
+
+#[derive(Deserialize)]
+struct CountQuery {
+    window: String,
+    #[serde(default = "CountQuery::default_by")]
+    by: u64,
+}
+
+impl CountQuery {
+    fn default_by() -> u64 {
+        1
+    }
+}
+
+/// Parses a window such as `500ms`, `60s`, `5m` or `1h`, a number without a unit is in seconds.
+fn parse_window(window: &str) -> Option<Duration> {
+    let split = window
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(window.len());
+    let (amount, unit) = window.split_at(split);
+    let amount: u64 = amount.parse().ok()?;
+    let window = match unit {
+        "ms" => Duration::from_millis(amount),
+        "" | "s" => Duration::from_secs(amount),
+        "m" => Duration::from_secs(amount * 60),
+        "h" => Duration::from_secs(amount * 60 * 60),
+        _ => return None,
+    };
+    Some(window).filter(|window| *window > Duration::from_secs(0))
+}
+
+#[derive(Deserialize)]
+struct BloomQuery {
+    capacity: u64,
+    #[serde(default = "BloomQuery::default_error_rate")]
+    error_rate: f64,
+}
+
+impl BloomQuery {
+    fn default_error_rate() -> f64 {
+        0.01
+    }
+}
+
+#[derive(Deserialize)]
+struct ItemQuery {
+    item: String,
+}
+
+fn wrong_type() -> HttpResponse {
+    HttpResponse::Conflict().body("The key holds a different kind of value")
+}
+
+/// Returns a 507 if storing `size` more bytes takes the API key of the request over its quota.
+fn over_quota(usage: &Option<Arc<ApiKeyUsage>>, size: usize) -> Option<HttpResponse> {
+    usage
+        .as_ref()
+        .filter(|usage| !usage.within_quota(size))
+        .map(|_| Rejected::OverQuota.response())
+}
+
+/// Attributes the collection under `key` to the API key of the request, with its current size.
+fn own(cache: &SimpleCache<'_>, key: &str, usage: &Option<Arc<ApiKeyUsage>>) {
+    if let Some(usage) = usage {
+        cache.set_owner(key, usage);
+    }
+}
+
+/// Reads an item from the request body, or responds with 503 when under memory pressure.
+async fn read_item<'a>(
+    payload: web::Payload,
+    cache: &SimpleCache<'a>,
+    pressure: &MemoryPressure,
+    settings: &settings::Cache,
+) -> Result<Result<String, HttpResponse>, Error> {
+    if pressure.check(cache.size()) {
+        return Ok(Err(
+            HttpResponse::ServiceUnavailable().body("Rejecting writes under memory pressure")
+        ));
+    }
+    let item = Value::read(
+        payload,
+        settings.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE),
+        settings.max_value_size.unwrap_or(DEFAULT_MAX_VALUE_SIZE),
+    )
+    .await?;
+    Ok(Ok(String::from_utf8_lossy(&item.to_bytes()).into_owned()))
+}
+
+#[post("/{key:[^_].*}/list/push")]
+async fn list_push<'a>(
+    req: HttpRequest,
+    key: CacheKey,
+    payload: web::Payload,
+    cache: web::Data<SimpleCache<'a>>,
+    pressure: web::Data<MemoryPressure>,
+    limiter: web::Data<KeyLimiter>,
+    settings: web::Data<settings::Cache>,
+) -> Result<HttpResponse, Error> {
+    if let Err(denied) = cache.check_denied(&key) {
+        return Ok(HttpResponse::UnprocessableEntity().body(denied.to_string()));
+    }
+    if let Some(response) = limiter.check(&req, !cache.contains_key(&key)) {
+        return Ok(response);
+    }
+    let item = match read_item(payload, &cache, &pressure, &settings).await? {
+        Ok(item) => item,
+        Err(response) => return Ok(response),
+    };
+    let usage = UsageTracker::for_request(&req);
+    if let Some(response) = over_quota(&usage, item.len()) {
+        return Ok(response);
+    }
+    Ok(match cache.push(&key, item) {
+        Ok(len) => {
+            own(&cache, &key, &usage);
+            HttpResponse::Ok().json(json!({ "len": len }))
+        }
+        Err(WrongType) => wrong_type(),
+    })
+}
+
+#[get("/{key:[^_].*}/list/range")]
+async fn list_range<'a>(
+    key: CacheKey,
+    query: web::Query<RangeQuery>,
+    cache: web::Data<SimpleCache<'a>>,
+) -> HttpResponse {
+    match cache.range(&key, query.start, query.stop) {
+        Some(Ok(items)) => HttpResponse::Ok().json(items),
+        Some(Err(WrongType)) => wrong_type(),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[post("/{key:[^_].*}/set/add")]
+async fn set_add<'a>(
+    req: HttpRequest,
+    key: CacheKey,
+    payload: web::Payload,
+    cache: web::Data<SimpleCache<'a>>,
+    pressure: web::Data<MemoryPressure>,
+    limiter: web::Data<KeyLimiter>,
+    settings: web::Data<settings::Cache>,
+) -> Result<HttpResponse, Error> {
+    if let Err(denied) = cache.check_denied(&key) {
+        return Ok(HttpResponse::UnprocessableEntity().body(denied.to_string()));
+    }
+    if let Some(response) = limiter.check(&req, !cache.contains_key(&key)) {
+        return Ok(response);
+    }
+    let member = match read_item(payload, &cache, &pressure, &settings).await? {
+        Ok(member) => member,
+        Err(response) => return Ok(response),
+    };
+    let usage = UsageTracker::for_request(&req);
+    if let Some(response) = over_quota(&usage, member.len()) {
+        return Ok(response);
+    }
+    Ok(match cache.add(&key, member) {
+        Ok(added) => {
+            own(&cache, &key, &usage);
+            HttpResponse::Ok().json(json!({ "added": added }))
+        }
+        Err(WrongType) => wrong_type(),
+    })
+}
+
+#[get("/{key:[^_].*}/set/contains")]
+async fn set_contains<'a>(
+    key: CacheKey,
+    query: web::Query<MemberQuery>,
+    cache: web::Data<SimpleCache<'a>>,
+) -> HttpResponse {
+    match cache.is_member(&key, &query.member) {
+        Some(Ok(member)) => HttpResponse::Ok().json(json!({ "member": member })),
+        Some(Err(WrongType)) => wrong_type(),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[post("/{key:[^_].*}/count")]
+async fn count<'a>(
+    req: HttpRequest,
+    key: CacheKey,
+    query: web::Query<CountQuery>,
+    cache: web::Data<SimpleCache<'a>>,
+    pressure: web::Data<MemoryPressure>,
+    limiter: web::Data<KeyLimiter>,
+) -> HttpResponse {
+    let window = match parse_window(&query.window) {
+        Some(window) => window,
+        None => {
+            return HttpResponse::BadRequest().body(format!("Invalid window: {}", query.window))
+        }
+    };
+    if pressure.check(cache.size()) {
+        return HttpResponse::ServiceUnavailable().body("Rejecting writes under memory pressure");
+    }
+    if let Err(denied) = cache.check_denied(&key) {
+        return HttpResponse::UnprocessableEntity().body(denied.to_string());
+    }
+    if let Some(response) = limiter.check(&req, !cache.contains_key(&key)) {
+        return response;
+    }
+    // A count adds at most one bucket, so it is only rejected once the API key is over its quota.
+    let usage = UsageTracker::for_request(&req);
+    if let Some(response) = over_quota(&usage, 0) {
+        return response;
+    }
+    match cache.count(&key, window, query.by) {
+        Ok(total) => {
+            own(&cache, &key, &usage);
+            HttpResponse::Ok().json(json!({ "count": total }))
+        }
+        Err(WrongType) => wrong_type(),
+    }
+}
+
+#[post("/{key:[^_].*}/bloom")]
+async fn bloom_create<'a>(
+    req: HttpRequest,
+    key: CacheKey,
+    query: web::Query<BloomQuery>,
+    cache: web::Data<SimpleCache<'a>>,
+    pressure: web::Data<MemoryPressure>,
+    limiter: web::Data<KeyLimiter>,
+) -> HttpResponse {
+    let filter = match BloomFilter::new(query.capacity, query.error_rate) {
+        Some(filter) => filter,
+        None => {
+            return HttpResponse::BadRequest()
+                .body("capacity must be positive and error_rate between 0 and 1")
+        }
+    };
+    if pressure.check(cache.size()) {
+        return HttpResponse::ServiceUnavailable().body("Rejecting writes under memory pressure");
+    }
+    if let Err(denied) = cache.check_denied(&key) {
+        return HttpResponse::UnprocessableEntity().body(denied.to_string());
+    }
+    if let Some(response) = limiter.check(&req, !cache.contains_key(&key)) {
+        return response;
+    }
+    let usage = UsageTracker::for_request(&req);
+    if let Some(response) = over_quota(&usage, filter.len()) {
+        return response;
+    }
+    match cache.create_bloom(&key, filter) {
+        Ok(true) => {
+            own(&cache, &key, &usage);
+            HttpResponse::Created().finish()
+        }
+        Ok(false) => HttpResponse::Conflict().body("The Bloom filter already exists"),
+        Err(WrongType) => wrong_type(),
+    }
+}
+
+#[post("/{key:[^_].*}/bloom/add")]
+async fn bloom_add<'a>(
+    key: CacheKey,
+    payload: web::Payload,
+    cache: web::Data<SimpleCache<'a>>,
+    pressure: web::Data<MemoryPressure>,
+    settings: web::Data<settings::Cache>,
+) -> Result<HttpResponse, Error> {
+    let item = match read_item(payload, &cache, &pressure, &settings).await? {
+        Ok(item) => item,
+        Err(response) => return Ok(response),
+    };
+    Ok(match cache.bloom_add(&key, item.as_bytes()) {
+        Some(Ok(added)) => HttpResponse::Ok().json(json!({ "added": added })),
+        Some(Err(WrongType)) => wrong_type(),
+        None => HttpResponse::NotFound().finish(),
+    })
+}
+
+#[get("/{key:[^_].*}/bloom/contains")]
+async fn bloom_contains<'a>(
+    key: CacheKey,
+    query: web::Query<ItemQuery>,
+    cache: web::Data<SimpleCache<'a>>,
+) -> HttpResponse {
+    match cache.bloom_contains(&key, query.item.as_bytes()) {
+        Some(Ok(member)) => HttpResponse::Ok().json(json!({ "member": member })),
+        Some(Err(WrongType)) => wrong_type(),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn windows_are_parsed() {
+        assert_eq!(parse_window("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_window("60s"), Some(Duration::from_secs(60)));
+        assert_eq!(parse_window("60"), Some(Duration::from_secs(60)));
+        assert_eq!(parse_window("5m"), Some(Duration::from_secs(300)));
+        assert_eq!(parse_window("0s"), None);
+        assert_eq!(parse_window("1d"), None);
+    }
+}
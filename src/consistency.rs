@@ -0,0 +1,114 @@
+//! Chooses whether `GET /{key}` may return the local copy of a value or must read Redis, the copy
+//! shared by every cache server, with the `Consistency: strong|eventual` request header.
+//!
+//! Without the header the level is chosen by the longest key prefix in
+//! `cache.consistency.namespaces`, falling back to `cache.consistency.default`. Strong reads
+//! refresh the local copy, and are the same as eventual reads unless there is a Redis tier that
+//! every write goes through to, as otherwise the local copy may be the only one.
+use crate::settings::{self, ConsistencyLevel};
+use actix_web::http::HeaderMap;
+use std::fmt;
+
+/// The request header choosing the consistency of a read.
+pub const CONSISTENCY: &str = "consistency";
+
+/// Returned when the `Consistency` header is neither `strong` nor `eventual`.
+#[derive(Debug, PartialEq)]
+pub struct InvalidLevel(String);
+
+impl fmt::Display for InvalidLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invalid Consistency: {}, expected strong or eventual",
+            self.0
+        )
+    }
+}
+
+/// Returns the level for `key`, from the namespace with the longest prefix of `key`.
+fn namespace_level(settings: &settings::Consistency, key: &str) -> ConsistencyLevel {
+    settings
+        .namespaces
+        .iter()
+        .filter(|(prefix, _)| key.starts_with(prefix.as_str()))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map_or(settings.default, |(_, level)| *level)
+}
+
+/// Returns the consistency of a read of `key`, from the `Consistency` header or the settings.
+/// # Arguments
+/// * `settings` - The default levels.
+/// * `key` - The key that is read.
+/// * `headers` - The headers of the request.
+pub fn level(
+    settings: &settings::Consistency,
+    key: &str,
+    headers: &HeaderMap,
+) -> Result<ConsistencyLevel, InvalidLevel> {
+    let header = match headers.get(CONSISTENCY) {
+        Some(header) => header,
+        None => return Ok(namespace_level(settings, key)),
+    };
+    match header.to_str().map(str::trim) {
+        Ok(level) if level.eq_ignore_ascii_case("strong") => Ok(ConsistencyLevel::Strong),
+        Ok(level) if level.eq_ignore_ascii_case("eventual") => Ok(ConsistencyLevel::Eventual),
+        _ => Err(InvalidLevel(
+            String::from_utf8_lossy(header.as_bytes()).into_owned(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::http::{HeaderName, HeaderValue};
+    use std::collections::HashMap;
+
+    fn settings() -> settings::Consistency {
+        let mut namespaces = HashMap::new();
+        namespaces.insert("accounts/".to_string(), ConsistencyLevel::Strong);
+        settings::Consistency {
+            default: ConsistencyLevel::Eventual,
+            namespaces,
+        }
+    }
+
+    fn headers(level: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static(CONSISTENCY),
+            HeaderValue::from_static(level),
+        );
+        headers
+    }
+
+    #[test]
+    fn namespaces_choose_the_level_without_a_header() {
+        let settings = settings();
+
+        assert_eq!(
+            level(&settings, "accounts/1", &HeaderMap::new()),
+            Ok(ConsistencyLevel::Strong)
+        );
+        assert_eq!(
+            level(&settings, "other", &HeaderMap::new()),
+            Ok(ConsistencyLevel::Eventual)
+        );
+    }
+
+    #[test]
+    fn the_header_overrides_the_namespace() {
+        let settings = settings();
+
+        assert_eq!(
+            level(&settings, "accounts/1", &headers("eventual")),
+            Ok(ConsistencyLevel::Eventual)
+        );
+        assert_eq!(
+            level(&settings, "other", &headers("Strong")),
+            Ok(ConsistencyLevel::Strong)
+        );
+        assert!(level(&settings, "other", &headers("linearizable")).is_err());
+    }
+}
@@ -0,0 +1,167 @@
+//! Lets single-page applications call the cache server from the browser: preflight requests from
+//! the origins in `cors.allowed_origins` are answered with the allowed methods and headers, and the
+//! responses to their requests carry the headers letting scripts read them.
+//!
+//! Every other `OPTIONS` request is answered with the methods of the data API in `Allow`, whether
+//! CORS is enabled or not.
+use crate::settings;
+use actix_web::{
+    dev::{Body, Service, ServiceRequest, ServiceResponse},
+    http::{header, HeaderMap, HeaderValue, Method},
+    web, Error, HttpResponse,
+};
+use futures::future::{ok, Either, Future, FutureExt};
+use std::io;
+
+/// The methods of the data API.
+const ALLOW: &str = "GET, POST, PUT, PATCH, DELETE, OPTIONS";
+
+/// The allowed origins and the headers sent to them.
+pub struct Cors {
+    /// Whether `*` is among the allowed origins.
+    any_origin: bool,
+    origins: Vec<String>,
+    methods: HeaderValue,
+    headers: HeaderValue,
+    exposed_headers: HeaderValue,
+    max_age: HeaderValue,
+    allow_credentials: bool,
+}
+
+/// Returns the values of a header listing `values`.
+fn list(name: &str, values: &[String]) -> io::Result<HeaderValue> {
+    HeaderValue::from_str(&values.join(", ")).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid CORS {}: {:?}", name, values),
+        )
+    })
+}
+
+impl Cors {
+    /// Returns a `Cors` for the origins in `settings`, or `None` if no origin is allowed.
+    pub fn new(settings: settings::Cors) -> io::Result<Option<Self>> {
+        if settings.allowed_origins.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            any_origin: settings.allowed_origins.iter().any(|origin| origin == "*"),
+            methods: list("allowed_methods", &settings.allowed_methods)?,
+            headers: list("allowed_headers", &settings.allowed_headers)?,
+            exposed_headers: list("exposed_headers", &settings.exposed_headers)?,
+            max_age: HeaderValue::from(settings.max_age),
+            allow_credentials: settings.allow_credentials,
+            origins: settings.allowed_origins,
+        }))
+    }
+
+    /// Returns true if requests from `origin` are allowed.
+    fn allows(&self, origin: &HeaderValue) -> bool {
+        self.any_origin
+            || origin.to_str().is_ok_and(|origin| {
+                self.origins.iter().any(|allowed| allowed == origin)
+            })
+    }
+
+    /// Adds the headers letting scripts from `origin` read a response.
+    fn allow(&self, origin: &HeaderValue, headers: &mut HeaderMap) {
+        // Browsers do not send credentials to `*`, so the origin is echoed when they are allowed.
+        if self.any_origin && !self.allow_credentials {
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_ORIGIN,
+                HeaderValue::from_static("*"),
+            );
+        } else {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+            headers.append(header::VARY, HeaderValue::from_static("Origin"));
+        }
+        if self.allow_credentials {
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+        headers.insert(
+            header::ACCESS_CONTROL_EXPOSE_HEADERS,
+            self.exposed_headers.clone(),
+        );
+    }
+
+    /// Returns the answer to a preflight request from `origin`.
+    fn preflight(&self, origin: &HeaderValue) -> HttpResponse {
+        let mut response = HttpResponse::NoContent().finish();
+        let headers = response.headers_mut();
+        self.allow(origin, headers);
+        headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, self.methods.clone());
+        headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, self.headers.clone());
+        headers.insert(header::ACCESS_CONTROL_MAX_AGE, self.max_age.clone());
+        response
+    }
+}
+
+/// Answers `OPTIONS` requests and adds the CORS headers to the responses to allowed origins.
+/// # Arguments
+/// * `req` - The incoming request.
+/// * `srv` - The service handling every other request.
+pub fn handle<S>(
+    req: ServiceRequest,
+    srv: &mut S,
+) -> impl Future<Output = Result<ServiceResponse<Body>, Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<Body>, Error = Error>,
+{
+    let cors = req.app_data::<web::Data<Cors>>().cloned();
+    let origin = req
+        .headers()
+        .get(header::ORIGIN)
+        .filter(|origin| cors.as_ref().is_some_and(|cors| cors.allows(origin)))
+        .cloned();
+    if req.method() == Method::OPTIONS {
+        let preflight = req
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+        let response = match (cors, origin) {
+            (Some(cors), Some(origin)) if preflight => cors.preflight(&origin),
+            _ => HttpResponse::NoContent()
+                .header(header::ALLOW, ALLOW)
+                .finish(),
+        };
+        return Either::Left(ok(req.into_response(response)));
+    }
+    Either::Right(srv.call(req).map(move |response| {
+        response.map(|mut response| {
+            if let (Some(cors), Some(origin)) = (cors, origin) {
+                cors.allow(&origin, response.headers_mut());
+            }
+            response
+        })
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn origins_are_echoed_when_credentials_are_allowed() {
+        let settings = settings::Cors {
+            allowed_origins: vec!["*".into()],
+            allow_credentials: true,
+            ..Default::default()
+        };
+        let sut = Cors::new(settings).unwrap().unwrap();
+        let origin = HeaderValue::from_static("https://app.example.com");
+
+        let response = sut.preflight(&origin);
+
+        let value = |name| response.headers().get(name).unwrap();
+        assert_eq!(value(header::ACCESS_CONTROL_ALLOW_ORIGIN), &origin);
+        assert_eq!(value(header::VARY), "Origin");
+        assert_eq!(
+            value(header::ACCESS_CONTROL_ALLOW_METHODS),
+            "GET, POST, PUT, PATCH, DELETE"
+        );
+        assert_eq!(value(header::ACCESS_CONTROL_MAX_AGE), "3600");
+        assert!(Cors::new(settings::Cors::default()).unwrap().is_none());
+    }
+}
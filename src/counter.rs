@@ -0,0 +1,106 @@
+//! A counter over a rolling time window, e.g. for rate limiting.
+//!
+//! Increments are added to buckets a tenth of the window wide, and buckets that have left the
+//! window are dropped, so the total is exact to within one bucket.
+use std::{
+    collections::VecDeque,
+    mem,
+    time::{Duration, Instant},
+};
+
+/// The number of buckets a window is split into.
+const BUCKETS: u32 = 10;
+
+/// A counter that only counts increments made within the last `window`.
+#[derive(Debug)]
+pub struct WindowedCounter {
+    window: Duration,
+    /// The start of each bucket and the count within it, oldest first.
+    buckets: VecDeque<(Instant, u64)>,
+}
+
+impl WindowedCounter {
+    /// Returns a new counter with a total of 0.
+    /// # Arguments
+    /// * `window` - The duration increments are counted for.
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            buckets: VecDeque::new(),
+        }
+    }
+
+    /// Returns the duration increments are counted for.
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Adds `by` to the counter and returns the new total.
+    /// # Arguments
+    /// * `now` - The time of the increment.
+    /// * `by` - The amount to add.
+    pub fn increment(&mut self, now: Instant, by: u64) -> u64 {
+        self.prune(now);
+        let width = (self.window / BUCKETS).max(Duration::from_millis(1));
+        match self.buckets.back_mut() {
+            Some((start, count)) if now < *start + width => *count += by,
+            _ => self.buckets.push_back((now, by)),
+        }
+        self.total(now)
+    }
+
+    /// Returns the total of the increments made within the window before `now`.
+    pub fn total(&self, now: Instant) -> u64 {
+        self.buckets
+            .iter()
+            .filter(|(start, _)| *start + self.window > now)
+            .map(|(_, count)| count)
+            .sum()
+    }
+
+    /// Returns the size in bytes of the buckets.
+    pub fn len(&self) -> usize {
+        self.buckets.len() * mem::size_of::<(Instant, u64)>()
+    }
+
+    /// Drops the buckets that have left the window.
+    fn prune(&mut self, now: Instant) {
+        while let Some((start, _)) = self.buckets.front() {
+            if *start + self.window > now {
+                break;
+            }
+            self.buckets.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn increments_are_bucketed() {
+        let mut sut = WindowedCounter::new(Duration::from_secs(10));
+        let now = Instant::now();
+
+        sut.increment(now, 1);
+        sut.increment(now + Duration::from_millis(500), 2);
+        let total = sut.increment(now + Duration::from_secs(5), 3);
+
+        assert_eq!(total, 6);
+        assert_eq!(sut.buckets.len(), 2);
+    }
+
+    #[test]
+    fn increments_leave_the_window() {
+        let mut sut = WindowedCounter::new(Duration::from_secs(10));
+        let now = Instant::now();
+
+        sut.increment(now, 1);
+        sut.increment(now + Duration::from_secs(5), 2);
+
+        assert_eq!(sut.total(now + Duration::from_secs(12)), 2);
+        assert_eq!(sut.increment(now + Duration::from_secs(16), 4), 4);
+        assert_eq!(sut.buckets.len(), 1);
+    }
+}
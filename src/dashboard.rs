@@ -0,0 +1,186 @@
+//! Generates a Grafana dashboard for the metrics in the registry, served by
+//! `/_admin/dashboards/grafana`, so dashboards use the names and labels the running build exposes
+//! instead of drifting from them.
+//!
+//! Each metric family gets a panel: the rate of counters, the value of gauges, the 50th and 99th
+//! percentiles of histograms and the mean of summaries, each summed by the labels of the family
+//! other than the constant labels. Only families that have been recorded are in the registry.
+use prometheus::proto::{MetricFamily, MetricType};
+use serde_json::{json, Value};
+use std::collections::{BTreeSet, HashMap};
+
+/// The uid of the generated dashboard, so importing it again replaces the earlier version.
+const UID: &str = "simple-mem-cache";
+
+/// The width and height of a panel in grid units, two panels fit in a row.
+const PANEL_SIZE: (usize, usize) = (12, 8);
+
+/// Returns the labels of `family` other than `const_labels`, in order.
+fn labels(family: &MetricFamily, const_labels: &HashMap<String, String>) -> Vec<String> {
+    let labels: BTreeSet<&str> = family
+        .get_metric()
+        .iter()
+        .flat_map(|metric| metric.get_label())
+        .map(|label| label.get_name())
+        .filter(|name| !const_labels.contains_key(*name))
+        .collect();
+    labels.into_iter().map(str::to_string).collect()
+}
+
+/// Returns `expr` summed by `labels`, or summed entirely without labels.
+fn sum_by(expr: &str, labels: &[String]) -> String {
+    if labels.is_empty() {
+        format!("sum({})", expr)
+    } else {
+        format!("sum by ({}) ({})", labels.join(", "), expr)
+    }
+}
+
+/// Returns the legend of a series with `labels`, e.g. `{{hit_or_miss}}`.
+fn legend(labels: &[String], suffix: &str) -> String {
+    let mut legend: Vec<String> = labels
+        .iter()
+        .map(|label| format!("{{{{{}}}}}", label))
+        .collect();
+    if !suffix.is_empty() {
+        legend.push(suffix.to_string());
+    }
+    legend.join(" ")
+}
+
+/// Returns the queries and unit of the panel for `family`.
+fn targets(family: &MetricFamily, labels: &[String]) -> (Vec<Value>, &'static str) {
+    let name = family.get_name();
+    let target = |expr: String, legend: String| json!({ "expr": expr, "legendFormat": legend });
+    match family.get_field_type() {
+        MetricType::COUNTER => (
+            vec![target(
+                sum_by(&format!("rate({}[$__rate_interval])", name), labels),
+                legend(labels, ""),
+            )],
+            "ops",
+        ),
+        MetricType::GAUGE | MetricType::UNTYPED => (
+            vec![target(sum_by(name, labels), legend(labels, ""))],
+            "short",
+        ),
+        MetricType::HISTOGRAM => {
+            let mut by = labels.to_vec();
+            by.push("le".into());
+            let buckets = sum_by(&format!("rate({}_bucket[$__rate_interval])", name), &by);
+            let quantile = |q: &str| {
+                target(
+                    format!("histogram_quantile({}, {})", q, buckets),
+                    legend(labels, &format!("p{}", &q[2..])),
+                )
+            };
+            (vec![quantile("0.50"), quantile("0.99")], "s")
+        }
+        MetricType::SUMMARY => (
+            vec![target(
+                format!(
+                    "{} / {}",
+                    sum_by(&format!("rate({}_sum[$__rate_interval])", name), labels),
+                    sum_by(&format!("rate({}_count[$__rate_interval])", name), labels)
+                ),
+                legend(labels, "mean"),
+            )],
+            "short",
+        ),
+    }
+}
+
+/// Returns a Grafana dashboard with a panel for each of `families`.
+/// # Arguments
+/// * `families` - The metric families in the registry.
+/// * `const_labels` - The labels added to every metric, which panels do not split series by.
+pub fn grafana(families: &[MetricFamily], const_labels: &HashMap<String, String>) -> Value {
+    let (width, height) = PANEL_SIZE;
+    let panels: Vec<Value> = families
+        .iter()
+        .enumerate()
+        .map(|(index, family)| {
+            let labels = labels(family, const_labels);
+            let (targets, unit) = targets(family, &labels);
+            json!({
+                "id": index + 1,
+                "type": "timeseries",
+                "title": family.get_name(),
+                "description": family.get_help(),
+                "datasource": "${DS_PROMETHEUS}",
+                "gridPos": {
+                    "x": (index % 2) * width,
+                    "y": (index / 2) * height,
+                    "w": width,
+                    "h": height,
+                },
+                "fieldConfig": { "defaults": { "unit": unit }, "overrides": [] },
+                "targets": targets,
+            })
+        })
+        .collect();
+    json!({
+        "__inputs": [{
+            "name": "DS_PROMETHEUS",
+            "label": "Prometheus",
+            "type": "datasource",
+            "pluginId": "prometheus",
+            "pluginName": "Prometheus",
+        }],
+        "uid": UID,
+        "title": "simple-mem-cache",
+        "tags": ["simple-mem-cache"],
+        "timezone": "browser",
+        "schemaVersion": 27,
+        "time": { "from": "now-1h", "to": "now" },
+        "refresh": "30s",
+        "panels": panels,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use prometheus::{Histogram, HistogramOpts, IntCounterVec, Opts, Registry};
+
+    fn dashboard() -> Value {
+        let registry = Registry::new();
+        let mut const_labels = HashMap::new();
+        const_labels.insert("instance".to_string(), "a".to_string());
+        let queries = IntCounterVec::new(
+            Opts::new("cache_query", "Hits and misses").const_labels(const_labels.clone()),
+            &["hit_or_miss"],
+        )
+        .unwrap();
+        queries.with_label_values(&["hit"]).inc();
+        registry.register(Box::new(queries)).unwrap();
+        let wait = Histogram::with_opts(HistogramOpts::new("wait_seconds", "Wait")).unwrap();
+        wait.observe(0.1);
+        registry.register(Box::new(wait)).unwrap();
+        grafana(&registry.gather(), &const_labels)
+    }
+
+    #[test]
+    fn counters_are_graphed_by_their_labels() {
+        let panel = &dashboard()["panels"][0];
+
+        assert_eq!(panel["title"], "cache_query");
+        assert_eq!(
+            panel["targets"][0]["expr"],
+            "sum by (hit_or_miss) (rate(cache_query[$__rate_interval]))"
+        );
+        assert_eq!(panel["targets"][0]["legendFormat"], "{{hit_or_miss}}");
+    }
+
+    #[test]
+    fn histograms_are_graphed_by_percentile() {
+        let panel = &dashboard()["panels"][1];
+
+        assert_eq!(
+            panel["targets"][1]["expr"],
+            "histogram_quantile(0.99, sum by (le) (rate(wait_seconds_bucket[$__rate_interval])))"
+        );
+        assert_eq!(panel["targets"][1]["legendFormat"], "p99");
+        assert_eq!(panel["gridPos"]["x"], 12);
+    }
+}
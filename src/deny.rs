@@ -0,0 +1,117 @@
+//! Rejects writes to keys matching configured prefixes or regular expressions, a guardrail against
+//! accidentally caching secrets under keys like `users/1/password`.
+//!
+//! Rules are checked against the normalized key. Writes to denied keys are answered with 422 and
+//! counted by rule.
+use crate::cache::MetricOpts;
+use crate::settings;
+use prometheus::{IntCounterVec, Registry};
+use regex::Regex;
+use std::fmt;
+
+/// Returned when a key matches a deny rule.
+#[derive(Debug, PartialEq)]
+pub struct Denied(String);
+
+impl fmt::Display for Denied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Writes to keys matching {} are denied", self.0)
+    }
+}
+
+/// The prefixes and patterns of keys that may not be written.
+pub struct DenyList {
+    prefixes: Vec<String>,
+    patterns: Vec<Regex>,
+    /// A count of denied writes, labelled by the rule the key matched.
+    denied: IntCounterVec,
+}
+
+impl DenyList {
+    /// Returns a new `DenyList`, or the error of a pattern that is not a valid regular expression.
+    /// # Arguments
+    /// * `settings` - The denied key prefixes and patterns.
+    /// * `opts` - The naming of the denial metric.
+    pub fn new(settings: settings::DenyKeys, opts: &MetricOpts) -> Result<Self, regex::Error> {
+        let patterns = settings
+            .patterns
+            .iter()
+            .map(|pattern| Regex::new(pattern))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            prefixes: settings.prefixes,
+            patterns,
+            denied: IntCounterVec::new(
+                opts.opts(
+                    "keys_denied_total",
+                    "A count of writes rejected by the key deny rules",
+                ),
+                &["rule"],
+            )
+            .unwrap(),
+        })
+    }
+
+    /// Registers the denial metric with a registry.
+    pub fn register(&self, registry: &Registry) {
+        registry.register(Box::new(self.denied.clone())).unwrap();
+    }
+
+    /// Returns the rule `key` matches, counting the denial, or Ok if it may be written.
+    pub fn check(&self, key: &str) -> Result<(), Denied> {
+        let rule = self
+            .prefixes
+            .iter()
+            .find(|prefix| key.starts_with(prefix.as_str()))
+            .map(|prefix| format!("{}*", prefix))
+            .or_else(|| {
+                self.patterns
+                    .iter()
+                    .find(|pattern| pattern.is_match(key))
+                    .map(|pattern| pattern.as_str().to_string())
+            });
+        match rule {
+            Some(rule) => {
+                self.denied.with_label_values(&[&rule]).inc();
+                Err(Denied(rule))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn deny_list() -> DenyList {
+        let settings = settings::DenyKeys {
+            prefixes: vec!["secrets/".into()],
+            patterns: vec!["(?i)password".into()],
+        };
+        DenyList::new(settings, &MetricOpts::default()).unwrap()
+    }
+
+    #[test]
+    fn matching_keys_are_denied_and_counted() {
+        let sut = deny_list();
+
+        assert_eq!(sut.check("users/1/name"), Ok(()));
+        assert_eq!(sut.check("secrets/1"), Err(Denied("secrets/*".into())));
+        assert_eq!(
+            sut.check("users/1/Password"),
+            Err(Denied("(?i)password".into()))
+        );
+        assert_eq!(sut.denied.with_label_values(&["(?i)password"]).get(), 1);
+    }
+
+    #[test]
+    fn invalid_patterns_are_rejected() {
+        let settings = settings::DenyKeys {
+            prefixes: vec![],
+            patterns: vec!["(".into()],
+        };
+
+        assert!(DenyList::new(settings, &MetricOpts::default()).is_err());
+    }
+}
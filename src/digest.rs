@@ -0,0 +1,113 @@
+//! A Merkle-style digest of the cache contents used to cheaply compare two caches.
+//!
+//! Keys are spread over a fixed number of buckets. Each bucket digest combines the hashes of the
+//! keys and etags in it without depending on their order, and the root digest is the hash of the
+//! bucket digests. When two roots differ, only the keys in the buckets that differ need to be
+//! compared and exported.
+#[cfg(feature = "admin")]
+use serde::Serialize;
+use std::hash::Hasher;
+use twox_hash::XxHash64;
+
+/// The number of buckets used when none is requested.
+#[cfg(feature = "admin")]
+pub const DEFAULT_BUCKETS: usize = 64;
+
+/// The digest of a set of keys and etags.
+#[cfg(feature = "admin")]
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Digest {
+    pub root: String,
+    pub buckets: Vec<String>,
+}
+
+/// Returns the xxHash64 of `bytes`, which is stable across processes and platforms.
+pub fn hash(bytes: &[u8]) -> u64 {
+    hash_chunks(&[bytes])
+}
+
+/// Returns the xxHash64 of the concatenated `chunks`, the same as `hash` of the joined bytes.
+pub fn hash_chunks<B: AsRef<[u8]>>(chunks: &[B]) -> u64 {
+    let mut hasher = XxHash64::with_seed(0);
+    for chunk in chunks {
+        hasher.write(chunk.as_ref());
+    }
+    hasher.finish()
+}
+
+/// Formats a hash as fixed width hex.
+pub fn to_hex(hash: u64) -> String {
+    format!("{:016x}", hash)
+}
+
+/// Returns the bucket `key` belongs to.
+#[cfg(feature = "admin")]
+pub fn bucket(key: &str, buckets: usize) -> usize {
+    (hash(key.as_bytes()) % buckets as u64) as usize
+}
+
+#[cfg(feature = "admin")]
+fn entry_hash(key: &str, etag: u64) -> u64 {
+    let mut hasher = XxHash64::with_seed(0);
+    hasher.write(key.as_bytes());
+    hasher.write(&etag.to_le_bytes());
+    hasher.finish()
+}
+
+/// Returns the digest of `etags`.
+/// # Arguments
+/// * `etags` - The keys and etags to digest.
+/// * `buckets` - The number of buckets to spread the keys over.
+#[cfg(feature = "admin")]
+pub fn digest(etags: &[(String, u64)], buckets: usize) -> Digest {
+    let mut bucket_hashes = vec![0u64; buckets];
+    for (key, etag) in etags {
+        let bucket_hash = &mut bucket_hashes[bucket(key, buckets)];
+        *bucket_hash = bucket_hash.wrapping_add(entry_hash(key, *etag));
+    }
+    let mut root = XxHash64::with_seed(0);
+    for bucket_hash in &bucket_hashes {
+        root.write(&bucket_hash.to_le_bytes());
+    }
+    Digest {
+        root: to_hex(root.finish()),
+        buckets: bucket_hashes.into_iter().map(to_hex).collect(),
+    }
+}
+
+#[cfg(all(test, feature = "admin"))]
+mod test {
+    use super::*;
+
+    fn etags(entries: &[(&str, &str)]) -> Vec<(String, u64)> {
+        entries
+            .iter()
+            .map(|(key, value)| (key.to_string(), hash(value.as_bytes())))
+            .collect()
+    }
+
+    #[test]
+    fn chunks_hash_like_the_joined_bytes() {
+        assert_eq!(hash_chunks(&["ab", "c"]), hash(b"abc"));
+    }
+
+    #[test]
+    fn digest_does_not_depend_on_order() {
+        let first = digest(&etags(&[("a", "1"), ("b", "2")]), 4);
+        let second = digest(&etags(&[("b", "2"), ("a", "1")]), 4);
+
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn a_changed_value_only_changes_its_bucket() {
+        let first = digest(&etags(&[("a", "1"), ("b", "2")]), 4);
+        let second = digest(&etags(&[("a", "1"), ("b", "3")]), 4);
+        let changed: Vec<_> = (0..4)
+            .filter(|&i| first.buckets[i] != second.buckets[i])
+            .collect();
+
+        assert_ne!(first.root, second.root);
+        assert_eq!(changed, vec![bucket("b", 4)]);
+    }
+}
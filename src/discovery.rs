@@ -0,0 +1,198 @@
+//! Registers the cache server with a Consul agent on startup, with an HTTP check of `/healthz` on
+//! the metrics server, so clients can discover the cache nodes, and deregisters it on shutdown.
+//!
+//! A node that stops without deregistering, e.g. after a crash, is removed by Consul once its check
+//! has failed for `discovery.deregister_after` seconds. After a hot restart the registration is
+//! left in place for the new process, which registers the same instance again.
+use crate::listener::BoundAddresses;
+use crate::settings;
+use actix_web::client::{Client, ClientRequest};
+use serde::Serialize;
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+/// How long the agent may take to answer.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The header carrying the ACL token.
+const TOKEN_HEADER: &str = "X-Consul-Token";
+
+/// The health check of a service, as defined by the Consul agent API.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct Check {
+    #[serde(rename = "HTTP")]
+    http: String,
+    interval: String,
+    timeout: String,
+    deregister_critical_service_after: String,
+}
+
+/// A service instance, as defined by the Consul agent API.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct Service {
+    #[serde(rename = "ID")]
+    id: String,
+    name: String,
+    /// Empty for the address of the agent's node.
+    address: String,
+    port: u16,
+    tags: Vec<String>,
+    check: Check,
+}
+
+/// The registration of this instance with a Consul agent.
+pub struct Registration {
+    agent: String,
+    token: Option<String>,
+    service: Service,
+}
+
+impl Registration {
+    /// Returns the registration of the servers listening on `addresses`, or `None` when discovery
+    /// is disabled.
+    /// # Arguments
+    /// * `settings` - The agent and how the instance is registered.
+    /// * `addresses` - The addresses the servers are listening on.
+    pub fn new(settings: settings::Discovery, addresses: &BoundAddresses) -> Option<Self> {
+        let agent = settings.consul_url?;
+        let cache = addresses.cache_server.first()?;
+        let metrics = addresses.metrics_server.first()?;
+        // The agent's node address is advertised for servers listening on every interface, and the
+        // agent, which runs on the same node, checks them on the loopback address.
+        let address = match &settings.address {
+            Some(address) => address.clone(),
+            None if cache.ip().is_unspecified() => String::new(),
+            None => cache.ip().to_string(),
+        };
+        let check_address = match &settings.address {
+            Some(address) => format!("{}:{}", address, metrics.port()),
+            None if metrics.ip().is_unspecified() => {
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), metrics.port()).to_string()
+            }
+            None => metrics.to_string(),
+        };
+        let service_name = &settings.service_name;
+        let id = settings.service_id.unwrap_or_else(|| {
+            let host = if address.is_empty() {
+                cache.ip().to_string()
+            } else {
+                address.clone()
+            };
+            format!("{}-{}-{}", service_name, host, cache.port())
+        });
+        Some(Self {
+            agent: agent.trim_end_matches('/').to_string(),
+            token: settings.token,
+            service: Service {
+                id,
+                name: settings.service_name,
+                address,
+                port: cache.port(),
+                tags: settings.tags,
+                check: Check {
+                    http: format!("http://{}/healthz", check_address),
+                    interval: format!("{}s", settings.check_interval),
+                    timeout: format!("{}s", HTTP_TIMEOUT.as_secs()),
+                    deregister_critical_service_after: format!("{}s", settings.deregister_after),
+                },
+            },
+        })
+    }
+
+    /// Returns a request to the agent API at `path`.
+    fn request(&self, path: &str) -> ClientRequest {
+        let request = Client::builder()
+            .timeout(HTTP_TIMEOUT)
+            .finish()
+            .put(format!("{}{}", self.agent, path));
+        match &self.token {
+            Some(token) => request.header(TOKEN_HEADER, token.as_str()),
+            None => request,
+        }
+    }
+
+    /// Registers the instance, logging whether it succeeded as the servers run either way.
+    pub async fn register(&self) {
+        let response = self
+            .request("/v1/agent/service/register")
+            .send_json(&self.service)
+            .await;
+        match response {
+            Ok(response) if response.status().is_success() => {
+                log::info!("Registered {} with Consul", self.service.id)
+            }
+            Ok(response) => log::error!(
+                "Consul responded to the registration of {} with: {}",
+                self.service.id,
+                response.status()
+            ),
+            Err(err) => log::error!(
+                "Could not register {} with Consul. {}",
+                self.service.id,
+                err
+            ),
+        }
+    }
+
+    /// Deregisters the instance.
+    pub async fn deregister(&self) {
+        let path = format!("/v1/agent/service/deregister/{}", self.service.id);
+        match self.request(&path).send().await {
+            Ok(response) if response.status().is_success() => {
+                log::info!("Deregistered {} from Consul", self.service.id)
+            }
+            Ok(response) => log::error!(
+                "Consul responded to the deregistration of {} with: {}",
+                self.service.id,
+                response.status()
+            ),
+            Err(err) => log::error!(
+                "Could not deregister {} from Consul. {}",
+                self.service.id,
+                err
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn servers_on_every_interface_are_registered_at_the_node_address() {
+        let settings = settings::Discovery {
+            consul_url: Some("http://127.0.0.1:8500/".into()),
+            ..Default::default()
+        };
+        let addresses = BoundAddresses {
+            cache_server: vec!["0.0.0.0:8080".parse().unwrap()],
+            metrics_server: vec!["0.0.0.0:8081".parse().unwrap()],
+        };
+
+        let sut = Registration::new(settings, &addresses).unwrap();
+
+        assert_eq!(sut.agent, "http://127.0.0.1:8500");
+        assert_eq!(
+            serde_json::to_value(&sut.service).unwrap(),
+            serde_json::json!({
+                "ID": "simple-mem-cache-0.0.0.0-8080",
+                "Name": "simple-mem-cache",
+                "Address": "",
+                "Port": 8080,
+                "Tags": [],
+                "Check": {
+                    "HTTP": "http://127.0.0.1:8081/healthz",
+                    "Interval": "10s",
+                    "Timeout": "5s",
+                    "DeregisterCriticalServiceAfter": "60s",
+                },
+            })
+        );
+        assert!(Registration::new(settings::Discovery::default(), &addresses).is_none());
+    }
+}
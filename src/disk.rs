@@ -0,0 +1,359 @@
+//! A second cache tier that stores one file per key in a directory.
+//!
+//! Values evicted from memory to make room for new writes are written here and moved back into
+//! memory the next time they are read. Expiries are compared against the clock of the cache. The directory is emptied when the tier is opened, so it only extends the memory of a
+//! running process and is not a persistent store.
+use crate::cache::MetricOpts;
+use crate::clock::{Clock, SystemClock};
+use crate::digest;
+use crate::value::Value;
+use actix_rt::time::delay_for;
+use actix_web::web::Bytes;
+use chashmap::CHashMap;
+use prometheus::{IntCounter, IntGauge, Registry};
+use std::{
+    cell::RefCell,
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// The extension of files that are still being written.
+const TMP_EXTENSION: &str = "tmp";
+
+#[derive(Clone, Copy)]
+struct DiskEntry {
+    expiry: Instant,
+    size: usize,
+    json: bool,
+}
+
+/// Container for the disk tier metrics.
+#[derive(Clone)]
+pub struct DiskMetrics {
+    /// The number of items in the disk tier.
+    pub items: IntGauge,
+    /// The size in bytes of values in the disk tier.
+    pub size: IntGauge,
+    /// A count of the times the sweeper was restarted after panicking.
+    pub sweeper_restarts: IntCounter,
+}
+
+impl DiskMetrics {
+    /// Creates a new DiskMetrics named using `opts`.
+    pub fn with_opts(opts: &MetricOpts) -> Self {
+        Self {
+            items: IntGauge::with_opts(
+                opts.opts("cache_disk_items", "The number of items in the disk tier"),
+            )
+            .unwrap(),
+            size: IntGauge::with_opts(opts.opts(
+                "cache_disk_size",
+                "The total size in bytes of all values in the disk tier",
+            ))
+            .unwrap(),
+            sweeper_restarts: IntCounter::with_opts(opts.opts(
+                "disk_sweeper_restarts_total",
+                "A count of the times the disk tier sweeper was restarted after panicking",
+            ))
+            .unwrap(),
+        }
+    }
+
+    /// Registers the disk tier metrics with a registry.
+    pub fn register(&self, registry: &Registry) {
+        registry.register(Box::new(self.items.clone())).unwrap();
+        registry.register(Box::new(self.size.clone())).unwrap();
+        registry
+            .register(Box::new(self.sweeper_restarts.clone()))
+            .unwrap();
+    }
+}
+
+/// A directory of values with an in memory index of their keys and expiries.
+pub struct DiskTier {
+    dir: PathBuf,
+    index: CHashMap<String, DiskEntry>,
+    /// Makes the names of files being written unique.
+    writes: AtomicU64,
+    metrics: DiskMetrics,
+    /// The time expiries are compared with.
+    clock: Arc<dyn Clock>,
+}
+
+impl DiskTier {
+    /// Opens the disk tier in `dir`, creating it if needed and removing any previous contents.
+    /// # Arguments
+    /// * `dir` - The directory to store values in.
+    /// * `metrics` - A container for the metrics used by the disk tier.
+    pub fn open<P: AsRef<Path>>(dir: P, metrics: DiskMetrics) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.is_file() {
+                fs::remove_file(path)?;
+            }
+        }
+        log::info!("Opened disk tier in {}", dir.display());
+        Ok(Self {
+            dir,
+            index: CHashMap::new(),
+            writes: AtomicU64::new(0),
+            metrics,
+            clock: Arc::new(SystemClock),
+        })
+    }
+
+    /// Sets the clock expiries are compared with, the clock of the cache values come from.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(digest::to_hex(digest::hash(key.as_bytes())))
+    }
+
+    fn update_metrics(&self, added: i64) {
+        self.metrics.items.set(self.index.len() as i64);
+        self.metrics.size.add(added);
+    }
+
+    /// Writes a value to disk, replacing any previous value for the key.
+    /// # Arguments
+    /// * `key` - The cache key.
+    /// * `value` - The value to store.
+    /// * `expiry` - When the value expires.
+    pub fn put(&self, key: &str, value: &Value, expiry: Instant) -> io::Result<()> {
+        let path = self.path(key);
+        let write = self.writes.fetch_add(1, Ordering::Relaxed);
+        let tmp_path = path.with_extension(format!("{}.{}", write, TMP_EXTENSION));
+        let result = (|| {
+            let mut file = io::BufWriter::new(fs::File::create(&tmp_path)?);
+            // The key is stored so a different key with the same hash is never returned.
+            serde_json::to_writer(&mut file, key)?;
+            file.write_all(b"\n")?;
+            for chunk in value.chunks() {
+                file.write_all(chunk)?;
+            }
+            file.flush()?;
+            fs::rename(&tmp_path, &path)
+        })();
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+        result?;
+        let size = value.len();
+        let entry = DiskEntry {
+            expiry,
+            size,
+            json: value.is_json(),
+        };
+        let old = self.index.insert(key.to_string(), entry);
+        self.update_metrics(size as i64 - old.map_or(0, |old| old.size as i64));
+        log::debug!("Added key: {} to disk tier", key);
+        Ok(())
+    }
+
+    /// Removes the value for `key` from disk and returns it with its expiry if it has not expired.
+    pub fn take(&self, key: &str) -> io::Result<Option<(Value, Instant)>> {
+        let entry = match self.index.remove(key) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        self.update_metrics(-(entry.size as i64));
+        let path = self.path(key);
+        if entry.expiry <= self.clock.now() {
+            remove_file(&path)?;
+            return Ok(None);
+        }
+        let value = match self.read(key, &entry)? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        remove_file(&path)?;
+        Ok(Some((value, entry.expiry)))
+    }
+
+    /// Returns the value for `key` on disk without removing it, if it has not expired.
+    pub fn peek(&self, key: &str) -> io::Result<Option<Value>> {
+        let entry = match self.index.get(key) {
+            Some(entry) if entry.expiry > self.clock.now() => *entry,
+            _ => return Ok(None),
+        };
+        self.read(key, &entry)
+    }
+
+    /// Reads the value of `key` stored as `entry`, or returns None if its file belongs to another
+    /// key.
+    fn read(&self, key: &str, entry: &DiskEntry) -> io::Result<Option<Value>> {
+        let mut file = match fs::File::open(self.path(key)) {
+            Ok(file) => BufReader::new(file),
+            // Another key with the same hash replaced and then removed the file.
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let mut stored_key = Vec::new();
+        file.read_until(b'\n', &mut stored_key)?;
+        if serde_json::from_slice::<String>(&stored_key)
+            .ok()
+            .as_deref()
+            != Some(key)
+        {
+            // The file belongs to another key with the same hash.
+            return Ok(None);
+        }
+        let mut value = Vec::with_capacity(entry.size);
+        file.read_to_end(&mut value)?;
+        Ok(Some(Value::from(Bytes::from(value)).into_json(entry.json)))
+    }
+
+    /// Returns true if there is a value for `key` on disk that has not expired.
+    pub fn contains(&self, key: &str) -> bool {
+        self.index
+            .get(key)
+            .is_some_and(|entry| entry.expiry > self.clock.now())
+    }
+
+    /// Returns the keys of the values on disk, in no particular order.
+    #[cfg(feature = "admin")]
+    pub fn keys(&self) -> Vec<String> {
+        let keys = RefCell::new(Vec::new());
+        self.index.retain(|key, _| {
+            keys.borrow_mut().push(key.clone());
+            true
+        });
+        keys.into_inner()
+    }
+
+    /// Removes the value for `key` from disk and returns true if it had not expired.
+    pub fn remove(&self, key: &str) -> io::Result<bool> {
+        match self.index.remove(key) {
+            Some(entry) => {
+                self.update_metrics(-(entry.size as i64));
+                remove_file(&self.path(key))?;
+                Ok(entry.expiry > self.clock.now())
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Removes every value from disk and returns the number removed.
+    #[cfg(feature = "admin")]
+    pub fn clear(&self) -> usize {
+        let removed = self.index.clear();
+        let count = removed.len();
+        for (key, entry) in removed {
+            self.update_metrics(-(entry.size as i64));
+            let path = self.path(&key);
+            if let Err(err) = remove_file(&path) {
+                log::error!("Could not remove {}. {}", path.display(), err);
+            }
+        }
+        count
+    }
+
+    /// Removes every expired value from disk.
+    pub fn sweep(&self) {
+        let now = self.clock.now();
+        let expired = RefCell::new(Vec::new());
+        self.index.retain(|key, entry| {
+            if entry.expiry > now {
+                return true;
+            }
+            expired.borrow_mut().push((self.path(key), entry.size));
+            false
+        });
+        let expired = expired.into_inner();
+        for (path, size) in &expired {
+            self.update_metrics(-(*size as i64));
+            if let Err(err) = remove_file(path) {
+                log::error!("Could not remove {}. {}", path.display(), err);
+            }
+        }
+        if !expired.is_empty() {
+            log::debug!("Removed {} expired keys from disk tier", expired.len());
+        }
+    }
+
+    /// Sweeps expired values from disk every `interval`.
+    pub async fn sweeper(&self, interval: Duration) {
+        log::info!("Starting disk tier sweeper");
+        loop {
+            delay_for(interval).await;
+            self.sweep();
+        }
+    }
+}
+
+/// Removes a file that may already have been removed.
+fn remove_file(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[cfg(feature = "admin")]
+    use crate::clock::VirtualClock;
+    use std::{env, process};
+
+    fn disk_tier(name: &str) -> DiskTier {
+        let dir = env::temp_dir().join(format!("simple-mem-cache-{}-{}", name, process::id()));
+        DiskTier::open(dir, DiskMetrics::with_opts(&MetricOpts::default())).unwrap()
+    }
+
+    #[test]
+    fn values_are_taken_from_disk() {
+        let sut = disk_tier("take");
+        let expiry = Instant::now() + Duration::from_secs(60);
+
+        sut.put("a", &Value::from("value"), expiry).unwrap();
+        let peeked = sut.peek("a").unwrap();
+        let taken = sut.take("a").unwrap();
+
+        assert_eq!(peeked, Some(Value::from("value")));
+        assert_eq!(taken, Some((Value::from("value"), expiry)));
+        assert_eq!(sut.take("a").unwrap(), None);
+        assert_eq!(sut.metrics.size.get(), 0);
+        fs::remove_dir_all(&sut.dir).unwrap();
+    }
+
+    #[test]
+    fn expired_values_are_swept() {
+        let sut = disk_tier("sweep");
+
+        sut.put("a", &Value::from("value"), Instant::now()).unwrap();
+        sut.sweep();
+
+        assert!(!sut.contains("a"));
+        assert_eq!(fs::read_dir(&sut.dir).unwrap().count(), 0);
+        fs::remove_dir_all(&sut.dir).unwrap();
+    }
+
+    #[cfg(feature = "admin")]
+    #[test]
+    fn values_expire_by_the_clock_of_the_cache() {
+        let clock = Arc::new(VirtualClock::default());
+        let sut = disk_tier("clock").with_clock(clock.clone());
+        let expiry = clock.now() + Duration::from_secs(60);
+
+        sut.put("a", &Value::from("value"), expiry).unwrap();
+        sut.put("b", &Value::from("value"), expiry).unwrap();
+        clock.advance(Duration::from_secs(60));
+
+        assert_eq!(sut.peek("a").unwrap(), None);
+        assert_eq!(sut.clear(), 2);
+        assert_eq!(fs::read_dir(&sut.dir).unwrap().count(), 0);
+        fs::remove_dir_all(&sut.dir).unwrap();
+    }
+}
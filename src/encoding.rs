@@ -0,0 +1,231 @@
+//! Negotiates the encoding of the bulk and admin APIs, so clients that want a compact binary
+//! encoding can use MessagePack or CBOR instead of JSON.
+//!
+//! Request bodies are decoded according to their `Content-Type`, and responses are encoded in the
+//! first supported media type listed in `Accept`, or else in the encoding of the request. Both
+//! default to JSON.
+//!
+//! Long lists are streamed without knowing their length: as newline delimited JSON, as a sequence
+//! of MessagePack values or as an indefinite length CBOR array.
+#[cfg(feature = "admin")]
+use actix_web::web::Bytes;
+use actix_web::{http::header, HttpMessage, HttpRequest, HttpResponse};
+#[cfg(feature = "admin")]
+use futures::{stream, Stream, StreamExt};
+use serde::{de::DeserializeOwned, Serialize};
+#[cfg(feature = "admin")]
+use std::io::{self, Cursor};
+
+/// Opens a CBOR array of indefinite length.
+#[cfg(feature = "admin")]
+const CBOR_ARRAY_START: u8 = 0x9f;
+/// Closes a CBOR array of indefinite length.
+#[cfg(feature = "admin")]
+const CBOR_BREAK: u8 = 0xff;
+
+/// An encoding of request and response bodies.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl Encoding {
+    /// Returns the encoding of a media type, if it is supported.
+    fn of_media_type(media_type: &str) -> Option<Self> {
+        match media_type {
+            "application/json" => Some(Self::Json),
+            "application/msgpack" | "application/x-msgpack" => Some(Self::MessagePack),
+            "application/cbor" => Some(Self::Cbor),
+            _ => None,
+        }
+    }
+
+    /// Returns the encoding of the body of `req`.
+    pub fn of_request(req: &HttpRequest) -> Self {
+        Self::of_media_type(req.content_type()).unwrap_or(Self::Json)
+    }
+
+    /// Returns the encoding `req` accepts for the response.
+    pub fn accepted(req: &HttpRequest) -> Self {
+        req.headers()
+            .get_all(header::ACCEPT)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|media_type| {
+                let mut params = media_type.split(';').map(str::trim);
+                let encoding = Self::of_media_type(params.next()?)?;
+                Some(encoding).filter(|_| params.all(|param| param.replace(' ', "") != "q=0"))
+            })
+            .next()
+            .unwrap_or_else(|| Self::of_request(req))
+    }
+
+    /// Returns the content type of bodies in this encoding.
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::MessagePack => "application/msgpack",
+            Self::Cbor => "application/cbor",
+        }
+    }
+
+    /// Encodes `value`, structs as maps so every encoding has the same field names.
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, String> {
+        match self {
+            Self::Json => serde_json::to_vec(value).map_err(|err| err.to_string()),
+            Self::MessagePack => rmp_serde::to_vec_named(value).map_err(|err| err.to_string()),
+            Self::Cbor => serde_cbor::to_vec(value).map_err(|err| err.to_string()),
+        }
+    }
+
+    /// Decodes `body`.
+    pub fn decode<T: DeserializeOwned>(self, body: &[u8]) -> Result<T, String> {
+        match self {
+            Self::Json => serde_json::from_slice(body).map_err(|err| err.to_string()),
+            Self::MessagePack => rmp_serde::from_slice(body).map_err(|err| err.to_string()),
+            Self::Cbor => serde_cbor::from_slice(body).map_err(|err| err.to_string()),
+        }
+    }
+
+    /// Returns the content type of streamed lists in this encoding.
+    #[cfg(feature = "admin")]
+    pub fn stream_content_type(self) -> &'static str {
+        match self {
+            Self::Json => "application/x-ndjson",
+            encoding => encoding.content_type(),
+        }
+    }
+
+    /// Encodes the items of `chunks` as a streamed list, one chunk of the body for each chunk of
+    /// items, so the list is never held in memory.
+    #[cfg(feature = "admin")]
+    pub fn encode_stream<T, S>(self, chunks: S) -> impl Stream<Item = io::Result<Bytes>>
+    where
+        T: Serialize,
+        S: Stream<Item = Vec<T>>,
+    {
+        let delimiter = |byte| match self {
+            Self::Cbor => Some(Ok(Bytes::from(vec![byte]))),
+            _ => None,
+        };
+        let items = chunks.map(move |items| {
+            let mut body = Vec::new();
+            for item in &items {
+                match self {
+                    Self::Json => {
+                        serde_json::to_writer(&mut body, item)?;
+                        body.push(b'\n');
+                    }
+                    Self::MessagePack => {
+                        rmp_serde::encode::write_named(&mut body, item).map_err(io::Error::other)?
+                    }
+                    Self::Cbor => {
+                        serde_cbor::to_writer(&mut body, item).map_err(io::Error::other)?
+                    }
+                }
+            }
+            Ok(Bytes::from(body))
+        });
+        stream::iter(delimiter(CBOR_ARRAY_START))
+            .chain(items)
+            .chain(stream::iter(delimiter(CBOR_BREAK)))
+    }
+
+    /// Decodes a list in `body` as streamed by `encode_stream`, or as a MessagePack array.
+    #[cfg(feature = "admin")]
+    pub fn decode_stream<T: DeserializeOwned>(self, body: &[u8]) -> Result<Vec<T>, String> {
+        match self {
+            Self::Json => body
+                .split(|&byte| byte == b'\n')
+                .filter(|line| !line.is_empty())
+                .map(|line| serde_json::from_slice(line).map_err(|err| err.to_string()))
+                .collect(),
+            // Arrays are fixarrays, array 16 or array 32, a sequence starts with any other value.
+            Self::MessagePack if !matches!(body.first(), Some(0x90..=0x9f | 0xdc | 0xdd)) => {
+                let mut cursor = Cursor::new(body);
+                let mut items = Vec::new();
+                while (cursor.position() as usize) < body.len() {
+                    items.push(rmp_serde::from_read(&mut cursor).map_err(|err| err.to_string())?);
+                }
+                Ok(items)
+            }
+            encoding => encoding.decode(body),
+        }
+    }
+}
+
+/// Responds 200 with `value` in the encoding `req` accepts.
+pub fn respond<T: Serialize>(req: &HttpRequest, value: &T) -> HttpResponse {
+    let encoding = Encoding::accepted(req);
+    match encoding.encode(value) {
+        Ok(body) => HttpResponse::Ok()
+            .content_type(encoding.content_type())
+            .body(body),
+        Err(err) => {
+            log::error!("Could not encode the response. {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use std::collections::HashMap;
+
+    #[test]
+    fn responses_are_encoded_as_accepted_or_as_the_request() {
+        let accepted = |accept: Option<&str>, content_type: &str| {
+            let mut req = TestRequest::default().header(header::CONTENT_TYPE, content_type);
+            if let Some(accept) = accept {
+                req = req.header(header::ACCEPT, accept);
+            }
+            Encoding::accepted(&req.to_http_request())
+        };
+
+        assert_eq!(accepted(None, "application/json"), Encoding::Json);
+        assert_eq!(accepted(None, "application/cbor"), Encoding::Cbor);
+        assert_eq!(
+            accepted(Some("application/msgpack"), "application/cbor"),
+            Encoding::MessagePack
+        );
+        assert_eq!(
+            accepted(Some("application/cbor;q=0, text/html"), "text/plain"),
+            Encoding::Json
+        );
+    }
+
+    #[test]
+    fn values_round_trip_in_every_encoding() {
+        let mut value = HashMap::new();
+        value.insert("keys".to_string(), vec!["a".to_string(), "b".to_string()]);
+
+        for encoding in &[Encoding::Json, Encoding::MessagePack, Encoding::Cbor] {
+            let body = encoding.encode(&value).unwrap();
+            let decoded: HashMap<String, Vec<String>> = encoding.decode(&body).unwrap();
+            assert_eq!(decoded, value, "{:?}", encoding);
+        }
+    }
+
+    #[cfg(feature = "admin")]
+    #[actix_rt::test]
+    async fn streamed_lists_round_trip_in_every_encoding() {
+        let chunks = vec![vec![1, 2], vec![], vec![3]];
+
+        for encoding in &[Encoding::Json, Encoding::MessagePack, Encoding::Cbor] {
+            let body: Vec<u8> = encoding
+                .encode_stream(stream::iter(chunks.clone()))
+                .map(|chunk| chunk.unwrap().to_vec())
+                .concat()
+                .await;
+            let decoded: Vec<u32> = encoding.decode_stream(&body).unwrap();
+            assert_eq!(decoded, vec![1, 2, 3], "{:?}", encoding);
+        }
+        let array = Encoding::MessagePack.encode(&vec![1, 2, 3]).unwrap();
+        let decoded: Vec<u32> = Encoding::MessagePack.decode_stream(&array).unwrap();
+        assert_eq!(decoded, vec![1, 2, 3]);
+    }
+}
@@ -0,0 +1,730 @@
+//! Eviction of entries to make room for new keys under memory pressure.
+//!
+//! With the TinyLFU admission policy keys are evicted oldest first, but a new key only evicts the
+//! oldest key when it has been accessed more often recently, as estimated by a frequency sketch,
+//! so keys that are seen once do not displace proven hot keys.
+//!
+//! With the segmented LRU policy new keys start in a probation segment and move to a protected
+//! segment when they are read again. Keys are evicted least recently used first from probation,
+//! so a scan of keys that are read once only displaces other keys on probation.
+//!
+//! Values written with a `Cache-Priority: low`, `normal` or `high` header are kept in separate
+//! orders by their priority, and every low priority key is evicted before any normal priority key
+//! regardless of recency, and those before any high priority key. A write never evicts keys of a
+//! higher priority than its own, so bulk low value data cannot displace critical configuration.
+use crate::digest;
+use crate::settings::EvictionPolicy;
+use actix_web::HttpRequest;
+use prometheus::IntGaugeVec;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    sync::Mutex,
+};
+
+/// The number of counters in each row of the sketch.
+const SKETCH_WIDTH: usize = 1 << 16;
+/// Multipliers that give each row of the sketch a different hash.
+const SEEDS: [u64; 4] = [
+    0xc3a5_c85c_97cb_3127,
+    0xb492_b66f_be98_f273,
+    0x9ae1_6a3b_2f90_404f,
+    0xcbf2_9ce4_8422_2325,
+];
+/// Counters saturate at this count, as in a sketch of 4 bit counters.
+const MAX_COUNT: u8 = 15;
+/// The order of keys is compacted once it is this much longer than the number of keys.
+const COMPACT_RATIO: usize = 2;
+/// The protected segment holds at most this percentage of the size of both segments.
+const PROTECTED_PERCENT: usize = 80;
+
+/// The header setting the priority of a written value.
+pub const PRIORITY_HEADER: &str = "cache-priority";
+
+/// The eviction priority of an entry, lower priorities are evicted first.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl Priority {
+    /// Every priority, lowest first.
+    const ALL: [Priority; 3] = [Priority::Low, Priority::Normal, Priority::High];
+
+    /// Returns true for the priority of values written without a `Cache-Priority` header.
+    pub fn is_normal(&self) -> bool {
+        *self == Priority::Normal
+    }
+
+    /// Returns the priority requested by the `Cache-Priority` header of `req`, or why it is
+    /// invalid.
+    pub fn requested(req: &HttpRequest) -> Result<Self, String> {
+        let value = match req.headers().get(PRIORITY_HEADER) {
+            Some(value) => value.to_str().unwrap_or_default(),
+            None => return Ok(Priority::Normal),
+        };
+        match value.trim().to_ascii_lowercase().as_str() {
+            "low" => Ok(Priority::Low),
+            "normal" => Ok(Priority::Normal),
+            "high" => Ok(Priority::High),
+            _ => Err(format!(
+                "Invalid {}, expected low, normal or high",
+                PRIORITY_HEADER
+            )),
+        }
+    }
+}
+
+/// A count-min sketch of how often keys were accessed recently. Counters are halved after every
+/// `10 * width` increments so old accesses are forgotten.
+pub struct FrequencySketch {
+    counters: Vec<u8>,
+    width: usize,
+    additions: usize,
+}
+
+impl FrequencySketch {
+    /// Returns an empty sketch with `width` counters in each row.
+    pub fn new(width: usize) -> Self {
+        Self {
+            counters: vec![0; SEEDS.len() * width],
+            width,
+            additions: 0,
+        }
+    }
+
+    fn indexes(&self, key: &str) -> impl Iterator<Item = usize> {
+        let hash = digest::hash(key.as_bytes());
+        let width = self.width;
+        SEEDS.iter().enumerate().map(move |(row, seed)| {
+            row * width + (hash.wrapping_mul(*seed).rotate_right(32) as usize % width)
+        })
+    }
+
+    /// Records an access to `key`.
+    pub fn increment(&mut self, key: &str) {
+        let mut added = false;
+        for index in self.indexes(key).collect::<Vec<_>>() {
+            if self.counters[index] < MAX_COUNT {
+                self.counters[index] += 1;
+                added = true;
+            }
+        }
+        if added {
+            self.additions += 1;
+            if self.additions >= 10 * self.width {
+                self.age();
+            }
+        }
+    }
+
+    /// Returns the estimated number of recent accesses to `key`.
+    pub fn estimate(&self, key: &str) -> u8 {
+        self.indexes(key)
+            .map(|index| self.counters[index])
+            .min()
+            .unwrap_or(0)
+    }
+
+    fn age(&mut self) {
+        for counter in &mut self.counters {
+            *counter /= 2;
+        }
+        self.additions /= 2;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Segment {
+    Probation,
+    Protected,
+}
+
+impl Segment {
+    fn label(self) -> &'static str {
+        match self {
+            Segment::Probation => "probation",
+            Segment::Protected => "protected",
+        }
+    }
+}
+
+struct SegmentEntry {
+    /// The order of the last access, lower is less recent.
+    access: u64,
+    segment: Segment,
+    size: usize,
+}
+
+/// The keys in the probation and protected segments, each in least recently used order.
+#[derive(Default)]
+struct Segments {
+    entries: HashMap<String, SegmentEntry>,
+    probation: BTreeMap<u64, String>,
+    protected: BTreeMap<u64, String>,
+    accesses: u64,
+    /// The number of keys and their size in bytes in probation and protected.
+    items: [usize; 2],
+    sizes: [usize; 2],
+}
+
+impl Segments {
+    fn segment(&mut self, segment: Segment) -> &mut BTreeMap<u64, String> {
+        match segment {
+            Segment::Probation => &mut self.probation,
+            Segment::Protected => &mut self.protected,
+        }
+    }
+
+    fn insert(&mut self, key: String, segment: Segment, size: usize) {
+        self.accesses += 1;
+        let access = self.accesses;
+        self.segment(segment).insert(access, key.clone());
+        self.items[segment as usize] += 1;
+        self.sizes[segment as usize] += size;
+        self.entries.insert(
+            key,
+            SegmentEntry {
+                access,
+                segment,
+                size,
+            },
+        );
+    }
+
+    fn remove(&mut self, key: &str) -> Option<SegmentEntry> {
+        let entry = self.entries.remove(key)?;
+        self.segment(entry.segment).remove(&entry.access);
+        self.items[entry.segment as usize] -= 1;
+        self.sizes[entry.segment as usize] -= entry.size;
+        Some(entry)
+    }
+
+    /// Records a write, which keeps the segment of a key that was already written.
+    fn write(&mut self, key: &str, size: usize) {
+        let segment = self
+            .remove(key)
+            .map_or(Segment::Probation, |entry| entry.segment);
+        self.insert(key.to_string(), segment, size);
+        self.rebalance();
+    }
+
+    /// Records a read of a key in the cache, which moves it to the protected segment.
+    fn hit(&mut self, key: &str) {
+        if let Some(entry) = self.remove(key) {
+            self.insert(key.to_string(), Segment::Protected, entry.size);
+            self.rebalance();
+        }
+    }
+
+    /// Moves the least recently used protected keys back to probation while protected is full.
+    fn rebalance(&mut self) {
+        let total = self.sizes[0] + self.sizes[1];
+        while self.sizes[Segment::Protected as usize] * 100 > total * PROTECTED_PERCENT {
+            let access = match self.protected.keys().next() {
+                Some(access) => *access,
+                None => break,
+            };
+            let key = self.protected[&access].clone();
+            if let Some(entry) = self.remove(&key) {
+                self.insert(key, Segment::Probation, entry.size);
+            }
+        }
+    }
+
+    /// Removes and returns the least recently used key, from probation if it has any keys.
+    fn pop(&mut self) -> Option<String> {
+        let key = self
+            .probation
+            .values()
+            .next()
+            .or_else(|| self.protected.values().next())?
+            .clone();
+        self.remove(&key);
+        Some(key)
+    }
+}
+
+/// The order keys are evicted in.
+enum Order {
+    /// Keys in the order they were written, oldest first. Keys that were written again or removed
+    /// may appear more than once and are skipped when they are no longer in the cache or were
+    /// written again with another priority.
+    Fifo(VecDeque<String>),
+    Segmented(Segments),
+}
+
+/// Chooses the entries to evict.
+pub struct Evictor {
+    sketch: Option<Mutex<FrequencySketch>>,
+    /// The order of the keys of each priority, lowest first.
+    orders: Mutex<Vec<Order>>,
+    /// The number of keys in each segment.
+    segment_items: IntGaugeVec,
+    /// The size in bytes of the values in each segment.
+    segment_size: IntGaugeVec,
+}
+
+impl Evictor {
+    /// Returns an evictor for `policy`, or None if entries are never evicted.
+    /// # Arguments
+    /// * `policy` - The eviction policy.
+    /// * `segment_items` - Reports the number of keys in each segment.
+    /// * `segment_size` - Reports the size of each segment.
+    pub fn new(
+        policy: EvictionPolicy,
+        segment_items: IntGaugeVec,
+        segment_size: IntGaugeVec,
+    ) -> Option<Self> {
+        let sketch = match policy {
+            EvictionPolicy::None => return None,
+            EvictionPolicy::TinyLfu => Some(Mutex::new(FrequencySketch::new(SKETCH_WIDTH))),
+            EvictionPolicy::Slru => None,
+        };
+        let orders = Priority::ALL
+            .iter()
+            .map(|_| match policy {
+                EvictionPolicy::Slru => Order::Segmented(Segments::default()),
+                _ => Order::Fifo(VecDeque::new()),
+            })
+            .collect();
+        Some(Self {
+            sketch,
+            orders: Mutex::new(orders),
+            segment_items,
+            segment_size,
+        })
+    }
+
+    /// Reports the keys in the segments of every priority.
+    fn update_metrics(&self, orders: &[Order]) {
+        let segments: Vec<&Segments> = orders
+            .iter()
+            .filter_map(|order| match order {
+                Order::Segmented(segments) => Some(segments),
+                Order::Fifo(_) => None,
+            })
+            .collect();
+        if segments.is_empty() {
+            return;
+        }
+        for segment in [Segment::Probation, Segment::Protected].iter() {
+            let index = *segment as usize;
+            let items: usize = segments.iter().map(|segments| segments.items[index]).sum();
+            let size: usize = segments.iter().map(|segments| segments.sizes[index]).sum();
+            self.segment_items
+                .with_label_values(&[segment.label()])
+                .set(items as i64);
+            self.segment_size
+                .with_label_values(&[segment.label()])
+                .set(size as i64);
+        }
+    }
+
+    /// Records a read of `key`, whether or not it was in the cache.
+    pub fn record_read(&self, key: &str) {
+        if let Some(sketch) = &self.sketch {
+            sketch.lock().unwrap().increment(key);
+        }
+    }
+
+    /// Records a read of `key` that found it in the cache.
+    pub fn record_hit(&self, key: &str) {
+        let mut orders = self.orders.lock().unwrap();
+        for order in orders.iter_mut() {
+            if let Order::Segmented(segments) = order {
+                segments.hit(key);
+            }
+        }
+        self.update_metrics(&orders);
+    }
+
+    /// Records a write of `key`.
+    /// # Arguments
+    /// * `key` - The key written.
+    /// * `size` - The size in bytes of the value written.
+    /// * `len` - The number of keys in the cache.
+    /// * `priority` - The priority of the value written.
+    /// * `priority_of` - Returns the priority of a key in the cache, or None if it is not.
+    pub fn record_write<F>(
+        &self,
+        key: &str,
+        size: usize,
+        len: usize,
+        priority: Priority,
+        priority_of: F,
+    ) where
+        F: Fn(&str) -> Option<Priority>,
+    {
+        self.record_read(key);
+        self.prioritize(key, size, len, priority, priority_of);
+    }
+
+    /// Records that the value of `key` was given `priority`, moving it to the most recently
+    /// written end of the order of that priority.
+    /// # Arguments
+    /// * `key` - The key of the value.
+    /// * `size` - The size in bytes of the value.
+    /// * `len` - The number of keys in the cache.
+    /// * `priority` - The priority of the value.
+    /// * `priority_of` - Returns the priority of a key in the cache, or None if it is not.
+    pub fn prioritize<F>(
+        &self,
+        key: &str,
+        size: usize,
+        len: usize,
+        priority: Priority,
+        priority_of: F,
+    ) where
+        F: Fn(&str) -> Option<Priority>,
+    {
+        let mut orders = self.orders.lock().unwrap();
+        for (order, class) in orders.iter_mut().zip(Priority::ALL.iter().copied()) {
+            match order {
+                Order::Fifo(order) if class == priority => {
+                    order.push_back(key.to_string());
+                    if order.len() > COMPACT_RATIO * len.max(1024) {
+                        // Keep the last time each key in the cache was written.
+                        let mut seen = HashSet::new();
+                        let mut compacted: VecDeque<String> = order
+                            .drain(..)
+                            .rev()
+                            .filter(|key| {
+                                priority_of(key) == Some(class) && seen.insert(key.clone())
+                            })
+                            .collect();
+                        compacted.make_contiguous().reverse();
+                        *order = compacted;
+                    }
+                }
+                // Keys written with another priority are skipped when they are evicted.
+                Order::Fifo(_) => {}
+                Order::Segmented(segments) if class == priority => segments.write(key, size),
+                Order::Segmented(segments) => {
+                    segments.remove(key);
+                }
+            }
+        }
+        self.update_metrics(&orders);
+    }
+
+    /// Records that `key` was removed from the cache.
+    pub fn forget(&self, key: &str) {
+        let mut orders = self.orders.lock().unwrap();
+        for order in orders.iter_mut() {
+            if let Order::Segmented(segments) = order {
+                segments.remove(key);
+            }
+        }
+        self.update_metrics(&orders);
+    }
+
+    /// Records that every key was removed from the cache.
+    #[cfg(feature = "admin")]
+    pub fn clear(&self) {
+        let mut orders = self.orders.lock().unwrap();
+        for order in orders.iter_mut() {
+            match order {
+                Order::Fifo(order) => order.clear(),
+                Order::Segmented(segments) => *segments = Segments::default(),
+            }
+        }
+        self.update_metrics(&orders);
+    }
+
+    /// Returns the next key to evict to make room for `candidate`, or None if there is no key to
+    /// evict or `candidate` should not be admitted. Keys of a lower priority than `candidate` are
+    /// evicted first, and keys of a higher priority are never evicted for it.
+    /// # Arguments
+    /// * `candidate` - The key being written.
+    /// * `priority` - The priority of the value being written.
+    /// * `priority_of` - Returns the priority of a key in the cache, or None if it is not.
+    pub fn victim<F>(&self, candidate: &str, priority: Priority, priority_of: F) -> Option<String>
+    where
+        F: Fn(&str) -> Option<Priority>,
+    {
+        let mut orders = self.orders.lock().unwrap();
+        let victim = self.pop_victim(&mut orders, candidate, priority, priority_of);
+        self.update_metrics(&orders);
+        victim
+    }
+
+    /// Calls `visit` with the keys that making room for a value of `priority` would evict, in the
+    /// order they would be evicted, until it returns false. Nothing is evicted, and the admission
+    /// of the new key by TinyLFU is not considered. The orders are copied before any key is
+    /// visited, so writes are not held up by the preview.
+    /// # Arguments
+    /// * `priority` - The priority of the value room would be made for.
+    /// * `priority_of` - Returns the priority of a key in the cache, or None if it is not.
+    /// * `visit` - Called with each key, returns false to stop.
+    #[cfg(feature = "admin")]
+    pub fn preview<F, V>(&self, priority: Priority, priority_of: F, mut visit: V)
+    where
+        F: Fn(&str) -> Option<Priority>,
+        V: FnMut(&str) -> bool,
+    {
+        let candidates: Vec<(String, Priority)> = {
+            let orders = self.orders.lock().unwrap();
+            orders
+                .iter()
+                .zip(Priority::ALL.iter().copied())
+                .take_while(|(_, class)| *class <= priority)
+                .flat_map(|(order, class)| {
+                    let keys: Box<dyn Iterator<Item = &String>> = match order {
+                        Order::Fifo(order) => Box::new(order.iter()),
+                        Order::Segmented(segments) => Box::new(
+                            segments
+                                .probation
+                                .values()
+                                .chain(segments.protected.values()),
+                        ),
+                    };
+                    keys.map(move |key| (key.clone(), class))
+                })
+                .collect()
+        };
+        // A key written more than once is evicted at the first time it appears in a FIFO order.
+        let mut seen = HashSet::new();
+        for (key, class) in &candidates {
+            if priority_of(key) != Some(*class) || !seen.insert(key.as_str()) {
+                continue;
+            }
+            if !visit(key) {
+                return;
+            }
+        }
+    }
+
+    fn pop_victim<F>(
+        &self,
+        orders: &mut [Order],
+        candidate: &str,
+        priority: Priority,
+        priority_of: F,
+    ) -> Option<String>
+    where
+        F: Fn(&str) -> Option<Priority>,
+    {
+        for (order, class) in orders.iter_mut().zip(Priority::ALL.iter().copied()) {
+            if class > priority {
+                break;
+            }
+            loop {
+                let key = match order {
+                    Order::Fifo(order) => order.pop_front(),
+                    Order::Segmented(segments) => segments.pop(),
+                };
+                let key = match key {
+                    Some(key) => key,
+                    None => break,
+                };
+                if key == candidate || priority_of(&key) != Some(class) {
+                    continue;
+                }
+                // Keys of a lower priority are evicted without comparing how often they are read.
+                if let (Some(sketch), Order::Fifo(order), true) =
+                    (&self.sketch, &mut *order, class == priority)
+                {
+                    let sketch = sketch.lock().unwrap();
+                    if sketch.estimate(candidate) <= sketch.estimate(&key) {
+                        order.push_front(key);
+                        return None;
+                    }
+                }
+                return Some(key);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::CacheMetrics;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn sketch_estimates_frequency() {
+        let mut sut = FrequencySketch::new(64);
+
+        for _ in 0..3 {
+            sut.increment("a");
+        }
+        sut.increment("b");
+
+        assert_eq!(sut.estimate("a"), 3);
+        assert_eq!(sut.estimate("b"), 1);
+    }
+
+    #[test]
+    fn sketch_forgets_old_accesses() {
+        let mut sut = FrequencySketch::new(4);
+
+        for _ in 0..10 {
+            sut.increment("a");
+        }
+        for i in 0..40 {
+            sut.increment(&i.to_string());
+        }
+
+        assert!(sut.estimate("a") < 10);
+    }
+
+    fn normal(_: &str) -> Option<Priority> {
+        Some(Priority::Normal)
+    }
+
+    fn evictor(policy: EvictionPolicy) -> Evictor {
+        let metrics = CacheMetrics::default();
+        Evictor::new(policy, metrics.segment_items, metrics.segment_size).unwrap()
+    }
+
+    #[test]
+    fn tinylfu_only_admits_more_frequent_keys() {
+        let sut = evictor(EvictionPolicy::TinyLfu);
+        sut.record_write("hot", 1, 1, Priority::Normal, normal);
+        sut.record_read("hot");
+
+        assert_eq!(sut.victim("new", Priority::Normal, normal), None);
+        for _ in 0..3 {
+            sut.record_read("new");
+        }
+        assert_eq!(
+            sut.victim("new", Priority::Normal, normal),
+            Some("hot".into())
+        );
+    }
+
+    #[test]
+    fn removed_keys_are_skipped() {
+        let sut = evictor(EvictionPolicy::TinyLfu);
+        sut.record_write("removed", 1, 2, Priority::Normal, normal);
+        sut.record_write("kept", 1, 2, Priority::Normal, normal);
+        sut.record_read("new");
+        sut.record_read("new");
+
+        assert_eq!(
+            sut.victim("new", Priority::Normal, |key| {
+                normal(key).filter(|_| key == "kept")
+            }),
+            Some("kept".into())
+        );
+    }
+
+    #[test]
+    fn slru_evicts_from_probation_first() {
+        let sut = evictor(EvictionPolicy::Slru);
+        for key in &["a", "b", "c"] {
+            sut.record_write(key, 1, 3, Priority::Normal, normal);
+        }
+        sut.record_hit("a");
+
+        assert_eq!(
+            sut.victim("new", Priority::Normal, normal),
+            Some("b".into())
+        );
+        assert_eq!(
+            sut.victim("new", Priority::Normal, normal),
+            Some("c".into())
+        );
+        assert_eq!(
+            sut.victim("new", Priority::Normal, normal),
+            Some("a".into())
+        );
+        assert_eq!(sut.victim("new", Priority::Normal, normal), None);
+    }
+
+    #[test]
+    fn slru_demotes_when_protected_is_full() {
+        let sut = evictor(EvictionPolicy::Slru);
+        for key in &["a", "b", "c", "d", "e"] {
+            sut.record_write(key, 1, 5, Priority::Normal, normal);
+            sut.record_hit(key);
+        }
+
+        let protected = sut.segment_items.with_label_values(&["protected"]).get();
+        let probation = sut.segment_items.with_label_values(&["probation"]).get();
+
+        assert_eq!((protected, probation), (4, 1));
+        assert_eq!(
+            sut.victim("new", Priority::Normal, normal),
+            Some("a".into())
+        );
+    }
+
+    #[test]
+    fn lower_priorities_are_evicted_first() {
+        let sut = evictor(EvictionPolicy::Slru);
+        let priorities: HashMap<&str, Priority> = vec![
+            ("config", Priority::High),
+            ("page", Priority::Normal),
+            ("bulk", Priority::Low),
+        ]
+        .into_iter()
+        .collect();
+        let priority_of = |key: &str| priorities.get(key).copied();
+        for (key, priority) in &priorities {
+            sut.record_write(key, 1, 3, *priority, priority_of);
+        }
+        sut.record_hit("bulk");
+
+        assert_eq!(
+            sut.victim("new", Priority::Normal, priority_of),
+            Some("bulk".into())
+        );
+        assert_eq!(
+            sut.victim("new", Priority::Normal, priority_of),
+            Some("page".into())
+        );
+        assert_eq!(sut.victim("new", Priority::Normal, priority_of), None);
+        assert_eq!(
+            sut.victim("new", Priority::High, priority_of),
+            Some("config".into())
+        );
+    }
+
+    #[test]
+    fn priorities_are_read_from_the_header() {
+        let priority = |value| {
+            let req = TestRequest::default().header(PRIORITY_HEADER, value);
+            Priority::requested(&req.to_http_request())
+        };
+
+        assert_eq!(priority("high"), Ok(Priority::High));
+        assert_eq!(priority("Low"), Ok(Priority::Low));
+        assert!(priority("urgent").is_err());
+        assert_eq!(
+            Priority::requested(&TestRequest::default().to_http_request()),
+            Ok(Priority::Normal)
+        );
+    }
+
+    #[cfg(feature = "admin")]
+    #[test]
+    fn previews_do_not_evict() {
+        let sut = evictor(EvictionPolicy::TinyLfu);
+        for key in &["a", "b", "a"] {
+            sut.record_write(key, 1, 3, Priority::Normal, normal);
+        }
+        for _ in 0..3 {
+            sut.record_read("new");
+        }
+        let mut previewed = Vec::new();
+
+        sut.preview(Priority::Normal, normal, |key| {
+            previewed.push(key.to_string());
+            true
+        });
+
+        assert_eq!(previewed, vec!["a", "b"]);
+        assert_eq!(
+            sut.victim("new", Priority::Normal, normal),
+            Some("a".into())
+        );
+    }
+}
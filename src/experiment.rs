@@ -0,0 +1,193 @@
+//! Runs a second eviction policy alongside `cache.eviction_policy` on the same reads and writes,
+//! simulated on the keys and sizes of values only, so policies can be compared on production
+//! traffic without changing what is cached.
+//!
+//! The simulated cache holds `cache.eviction_experiment.capacity` bytes, the high-water mark of
+//! memory pressure by default, and evicts with `cache.eviction_experiment.policy`. The reads of
+//! both caches are counted by `eviction_experiment_queries_total`, labelled by policy, whether the
+//! policy is the `real` or `simulated` one and by hit or miss, so their hit ratios can be compared.
+//! Keys that expire are removed from both, deleted keys are only removed from the real cache.
+use crate::cache::MetricOpts;
+use crate::eviction::{Evictor, Priority};
+use crate::plugin::CachePlugin;
+use crate::settings::EvictionPolicy;
+use crate::value::Value;
+use prometheus::{IntCounterVec, IntGaugeVec, Opts, Registry};
+use std::{collections::HashMap, sync::Mutex};
+
+/// Returns the name of `policy` in the configuration.
+fn label(policy: EvictionPolicy) -> &'static str {
+    match policy {
+        EvictionPolicy::None => "none",
+        EvictionPolicy::TinyLfu => "tinylfu",
+        EvictionPolicy::Slru => "slru",
+    }
+}
+
+/// The keys in the simulated cache and the size of their values.
+#[derive(Default)]
+struct Simulated {
+    sizes: HashMap<String, usize>,
+    size: usize,
+}
+
+impl Simulated {
+    fn remove(&mut self, key: &str) -> bool {
+        match self.sizes.remove(key) {
+            Some(size) => {
+                self.size -= size;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the priority of `key` if it is in the simulated cache, which ignores priorities.
+    fn priority_of(&self, key: &str) -> Option<Priority> {
+        self.sizes.get(key).map(|_| Priority::Normal)
+    }
+}
+
+/// A cache plugin comparing the hit ratio of the real eviction policy with a simulated one.
+pub struct EvictionExperiment {
+    real: &'static str,
+    policy: &'static str,
+    capacity: usize,
+    /// Chooses the keys evicted from the simulated cache, which is never full without one.
+    evictor: Option<Evictor>,
+    simulated: Mutex<Simulated>,
+    /// A count of reads by policy, real or simulated, and hit or miss.
+    queries: IntCounterVec,
+}
+
+impl EvictionExperiment {
+    /// Returns a new `EvictionExperiment`.
+    /// # Arguments
+    /// * `real` - The eviction policy of the cache.
+    /// * `policy` - The eviction policy simulated.
+    /// * `capacity` - The size in bytes of the simulated cache.
+    /// * `opts` - The naming of the metrics.
+    pub fn new(
+        real: EvictionPolicy,
+        policy: EvictionPolicy,
+        capacity: u64,
+        opts: &MetricOpts,
+    ) -> Self {
+        // The segments of the simulated cache are not reported.
+        let segments = || IntGaugeVec::new(Opts::new("segment", "segment"), &["segment"]).unwrap();
+        Self {
+            real: label(real),
+            policy: label(policy),
+            capacity: capacity as usize,
+            evictor: Evictor::new(policy, segments(), segments()),
+            simulated: Mutex::new(Simulated::default()),
+            queries: IntCounterVec::new(
+                opts.opts(
+                    "eviction_experiment_queries_total",
+                    "A count of reads of the real and simulated caches, by eviction policy",
+                ),
+                &["policy", "role", "hit_or_miss"],
+            )
+            .unwrap(),
+        }
+    }
+
+    /// Registers the metrics with a registry.
+    pub fn register(&self, registry: &Registry) {
+        registry.register(Box::new(self.queries.clone())).unwrap();
+    }
+
+    fn count(&self, policy: &str, role: &str, hit: bool) {
+        let outcome = if hit { "hit" } else { "miss" };
+        self.queries
+            .with_label_values(&[policy, role, outcome])
+            .inc();
+    }
+}
+
+impl CachePlugin for EvictionExperiment {
+    fn before_put(&self, key: &str, value: &Value) {
+        let mut simulated = self.simulated.lock().unwrap();
+        simulated.remove(key);
+        let size = value.len();
+        while simulated.size + size > self.capacity {
+            let victim = self.evictor.as_ref().and_then(|evictor| {
+                evictor.victim(key, Priority::Normal, |key| simulated.priority_of(key))
+            });
+            match victim {
+                Some(victim) => {
+                    simulated.remove(&victim);
+                }
+                None => {
+                    // Not admitted, as the real cache would reject the write.
+                    if let Some(evictor) = &self.evictor {
+                        evictor.forget(key);
+                    }
+                    return;
+                }
+            }
+        }
+        simulated.size += size;
+        simulated.sizes.insert(key.to_string(), size);
+        if let Some(evictor) = &self.evictor {
+            let len = simulated.sizes.len();
+            evictor.record_write(key, size, len, Priority::Normal, |key| {
+                simulated.priority_of(key)
+            });
+        }
+    }
+
+    fn after_get(&self, key: &str, hit: bool) {
+        self.count(self.real, "real", hit);
+        let simulated_hit = self.simulated.lock().unwrap().sizes.contains_key(key);
+        if let Some(evictor) = &self.evictor {
+            evictor.record_read(key);
+            if simulated_hit {
+                evictor.record_hit(key);
+            }
+        }
+        self.count(self.policy, "simulated", simulated_hit);
+    }
+
+    fn on_expire(&self, key: &str, _size: usize) {
+        if self.simulated.lock().unwrap().remove(key) {
+            if let Some(evictor) = &self.evictor {
+                evictor.forget(key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn the_simulated_policy_evicts_within_its_capacity() {
+        let sut = EvictionExperiment::new(
+            EvictionPolicy::None,
+            EvictionPolicy::Slru,
+            10,
+            &MetricOpts::default(),
+        );
+        let value = Value::from("12345".to_string());
+
+        sut.before_put("a", &value);
+        sut.before_put("b", &value);
+        sut.after_get("a", true);
+        sut.before_put("c", &value);
+        sut.after_get("a", true);
+        sut.after_get("b", false);
+
+        let queries = |role: &str, hit_or_miss: &str| {
+            let policy = if role == "real" { "none" } else { "slru" };
+            sut.queries
+                .with_label_values(&[policy, role, hit_or_miss])
+                .get()
+        };
+        assert_eq!(queries("real", "hit"), 2);
+        assert_eq!(queries("real", "miss"), 1);
+        assert_eq!(queries("simulated", "hit"), 2);
+        assert_eq!(queries("simulated", "miss"), 1);
+    }
+}
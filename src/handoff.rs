@@ -0,0 +1,309 @@
+//! Hands the cache contents and listening sockets from a running process to its replacement over
+//! a Unix socket, so the cache can be restarted without losing its contents or connections.
+//!
+//! The new process is started with `--handoff` and connects to the old process, which only hands
+//! off to processes of its own user or root. The old process sends a header with the listening
+//! sockets attached, pauses its servers, waits for the writes in progress, streams every entry as a
+//! JSON line and then shuts down. Connections queue on the shared sockets until the new process
+//! starts serving.
+use crate::cache::{ExportedEntry, SimpleCache};
+use crate::write_gate::WriteGate;
+use actix_web::{dev::Server, web};
+use futures::{channel::oneshot, executor::block_on, future::join_all};
+use nix::{
+    sys::{
+        socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags},
+        uio::IoVec,
+    },
+    unistd::geteuid,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{Shutdown, TcpListener},
+    os::unix::{
+        fs::PermissionsExt,
+        io::{AsRawFd, FromRawFd, RawFd},
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    thread,
+};
+
+/// The largest header accepted from the old process.
+const MAX_HEADER_SIZE: usize = 4096;
+/// The most listening sockets that can be handed over.
+const MAX_LISTENERS: usize = 64;
+
+/// The number of listening sockets, in order, attached to the header.
+#[derive(Deserialize, Serialize)]
+struct Header {
+    cache_server: usize,
+    metrics_server: usize,
+}
+
+/// The state received from the old process.
+pub struct Handoff {
+    pub cache_listeners: Vec<TcpListener>,
+    pub metrics_listeners: Vec<TcpListener>,
+    pub entries: Vec<ExportedEntry>,
+}
+
+/// The listening sockets of the running process.
+pub struct Listeners {
+    pub cache_server: Vec<TcpListener>,
+    pub metrics_server: Vec<TcpListener>,
+}
+
+fn nix_error(err: nix::Error) -> io::Error {
+    io::Error::other(err)
+}
+
+/// Returns the user of the process on the other end of `stream`.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+    getsockopt(stream.as_raw_fd(), PeerCredentials)
+        .map(|credentials| credentials.uid())
+        .map_err(nix_error)
+}
+
+/// Returns the user of the process on the other end of `stream`.
+#[cfg(any(
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "ios",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd"
+))]
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    nix::unistd::getpeereid(stream.as_raw_fd())
+        .map(|(uid, _)| uid.as_raw())
+        .map_err(nix_error)
+}
+
+/// Returns the user of the process on the other end of `stream`.
+#[cfg(not(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "ios",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd"
+)))]
+fn peer_uid(_: &UnixStream) -> io::Result<u32> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "The user of a handoff peer can not be checked on this platform",
+    ))
+}
+
+/// Returns an error unless the process on the other end of `stream` runs as this process's user or
+/// as root.
+fn check_peer(stream: &UnixStream) -> io::Result<()> {
+    let uid = peer_uid(stream)?;
+    if uid == 0 || uid == geteuid().as_raw() {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("Refused to hand off to a process of user {}", uid),
+        ))
+    }
+}
+
+/// Receives the listening sockets and cache contents from the process listening on `path`.
+pub fn receive<P: AsRef<Path>>(path: P) -> io::Result<Handoff> {
+    let stream = UnixStream::connect(path)?;
+    let mut buffer = vec![0; MAX_HEADER_SIZE];
+    let mut cmsg_buffer = nix::cmsg_space!([RawFd; MAX_LISTENERS]);
+    let (bytes, fds) = {
+        let message = recvmsg(
+            stream.as_raw_fd(),
+            &[IoVec::from_mut_slice(&mut buffer)],
+            Some(&mut cmsg_buffer),
+            MsgFlags::empty(),
+        )
+        .map_err(nix_error)?;
+        let mut fds = Vec::new();
+        for cmsg in message.cmsgs() {
+            if let ControlMessageOwned::ScmRights(received) = cmsg {
+                fds.extend(received);
+            }
+        }
+        (message.bytes, fds)
+    };
+    // Take ownership of the sockets first so they are closed if anything below fails.
+    let mut listeners: Vec<TcpListener> = fds
+        .into_iter()
+        .map(|fd| unsafe { TcpListener::from_raw_fd(fd) })
+        .collect();
+
+    let received = &buffer[..bytes];
+    let header_end = received
+        .iter()
+        .position(|&byte| byte == b'\n')
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Incomplete handoff header"))?;
+    let header: Header = serde_json::from_slice(&received[..header_end])?;
+    if header.cache_server + header.metrics_server != listeners.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Handoff header does not match the sockets received",
+        ));
+    }
+    let metrics_listeners = listeners.split_off(header.cache_server);
+
+    let reader = BufReader::new((&received[header_end + 1..]).chain(stream));
+    let mut entries = Vec::new();
+    for line in reader.lines() {
+        entries.push(serde_json::from_str(&line?)?);
+    }
+    log::info!(
+        "Received {} listeners and {} entries from the previous process",
+        listeners.len() + metrics_listeners.len(),
+        entries.len()
+    );
+    Ok(Handoff {
+        cache_listeners: listeners,
+        metrics_listeners,
+        entries,
+    })
+}
+
+fn send(
+    mut stream: UnixStream,
+    listeners: &Listeners,
+    cache: &SimpleCache<'static>,
+    gate: &WriteGate,
+    servers: &[Server],
+) -> io::Result<()> {
+    let header = Header {
+        cache_server: listeners.cache_server.len(),
+        metrics_server: listeners.metrics_server.len(),
+    };
+    let mut header = serde_json::to_vec(&header)?;
+    header.push(b'\n');
+    let fds: Vec<RawFd> = listeners
+        .cache_server
+        .iter()
+        .chain(&listeners.metrics_server)
+        .map(AsRawFd::as_raw_fd)
+        .collect();
+    sendmsg(
+        stream.as_raw_fd(),
+        &[IoVec::from_slice(&header)],
+        &[ControlMessage::ScmRights(&fds)],
+        MsgFlags::empty(),
+        None,
+    )
+    .map_err(nix_error)?;
+
+    // Stop accepting connections, writes on open ones and writes in the background so no writes
+    // are missed while the entries are sent.
+    block_on(join_all(servers.iter().map(Server::pause)));
+    gate.close();
+    let result = (|| {
+        let mut writer = io::BufWriter::new(&mut stream);
+        let entries = cache.entries(|_| true);
+        for entry in &entries {
+            serde_json::to_writer(&mut writer, entry)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        log::info!("Handed off {} entries", entries.len());
+        Ok(())
+    })();
+    if result.is_err() {
+        gate.open();
+        block_on(join_all(servers.iter().map(Server::resume)));
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+    result
+}
+
+/// Listens on `path` for a replacement process and hands the sockets and cache contents to the
+/// first one that connects. Returns a receiver that completes once the handoff has succeeded.
+/// # Arguments
+/// * `path` - The path of the Unix socket, any existing file is replaced. Only the owner can
+///   connect to it.
+/// * `listeners` - Copies of the listening sockets of the servers.
+/// * `cache` - The cache to hand over.
+/// * `gate` - The gate closed to writes while the cache is sent.
+/// * `servers` - The servers to pause while the cache is sent.
+pub fn listen<P: AsRef<Path>>(
+    path: P,
+    listeners: Listeners,
+    cache: web::Data<SimpleCache<'static>>,
+    gate: web::Data<WriteGate>,
+    servers: Vec<Server>,
+) -> io::Result<oneshot::Receiver<()>> {
+    let path = path.as_ref();
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    log::info!("Listening for handoff on {}", path.display());
+    let (done, handed_off) = oneshot::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let handed_off = stream.and_then(|stream| {
+                check_peer(&stream)?;
+                send(stream, &listeners, &cache, &gate, &servers)
+            });
+            match handed_off {
+                Ok(()) => {
+                    let _ = done.send(());
+                    return;
+                }
+                Err(err) => {
+                    log::error!("Handoff failed. {}", err);
+                    cache.internal_error("handoff");
+                }
+            }
+        }
+    });
+    Ok(handed_off)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::CacheMetrics;
+    use std::{env, process, time::Duration};
+
+    #[test]
+    fn listeners_and_entries_are_handed_off() {
+        let path = env::temp_dir().join(format!("simple-mem-cache-{}.sock", process::id()));
+        let cache = web::Data::new(SimpleCache::new(
+            Duration::from_secs(60),
+            CacheMetrics::default(),
+        ));
+        cache.put("key", "value".to_string());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let listeners = Listeners {
+            cache_server: vec![listener],
+            metrics_server: vec![],
+        };
+
+        let gate = web::Data::new(WriteGate::default());
+
+        let handed_off = listen(&path, listeners, cache, gate.clone(), vec![]).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        let result = receive(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(result.cache_listeners[0].local_addr().unwrap(), addr);
+        assert!(result.metrics_listeners.is_empty());
+        assert_eq!(result.entries[0].key, "key");
+        assert_eq!(result.entries[0].value, "value");
+        assert_eq!(block_on(handed_off), Ok(()));
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(WriteGate::enter(&gate).is_none());
+    }
+}
@@ -0,0 +1,201 @@
+//! Counts and times the HTTP requests of a server by endpoint, method and status, as
+//! `<namespace>_http_requests_total` and `<namespace>_http_requests_duration_seconds`.
+//!
+//! Endpoints are labelled by their route template without the patterns of its segments, e.g.
+//! `/v1/keys/{key}` for every key, so the number of series does not grow with the keys. Requests
+//! that matched no route, routes beyond `MAX_ENDPOINTS` and non-standard methods are labelled
+//! `other`. Requests are timed until their response is ready, not until its body has been sent.
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse},
+    http::Method,
+    Error,
+};
+use futures::future::{Future, FutureExt};
+use prometheus::{HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, RwLock},
+    time::Instant,
+};
+
+/// The label of requests that are not counted on their own.
+const OTHER: &str = "other";
+
+/// The most endpoints labelled on their own, later ones are labelled `other`.
+const MAX_ENDPOINTS: usize = 100;
+
+/// Returns the route template of `pattern`, e.g. `/{key}` for `/{key:[^_].*}`.
+fn template(pattern: &str) -> String {
+    let mut template = String::with_capacity(pattern.len());
+    let mut depth = 0;
+    let mut in_pattern = false;
+    for c in pattern.chars() {
+        match c {
+            '{' => {
+                depth += 1;
+                if depth == 1 {
+                    template.push(c);
+                }
+            }
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    in_pattern = false;
+                    template.push(c);
+                }
+            }
+            ':' if depth == 1 => in_pattern = true,
+            _ if !in_pattern => template.push(c),
+            _ => {}
+        }
+    }
+    template
+}
+
+/// Returns the label of `method`.
+fn method_label(method: &Method) -> &str {
+    match *method {
+        Method::GET
+        | Method::HEAD
+        | Method::POST
+        | Method::PUT
+        | Method::DELETE
+        | Method::PATCH
+        | Method::OPTIONS => method.as_str(),
+        _ => OTHER,
+    }
+}
+
+/// The HTTP metrics of a server.
+pub struct HttpMetrics {
+    /// A count of requests by endpoint, method and status.
+    requests: IntCounterVec,
+    /// The durations of requests by endpoint, method and status.
+    durations: HistogramVec,
+    /// The endpoints labelled on their own.
+    endpoints: RwLock<HashSet<String>>,
+}
+
+impl HttpMetrics {
+    /// Returns new `HttpMetrics`.
+    /// # Arguments
+    /// * `namespace` - The prefix of the names of the metrics.
+    /// * `const_labels` - Labels added to every sample.
+    pub fn new(namespace: &str, const_labels: HashMap<String, String>) -> Self {
+        Self {
+            requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "Total number of HTTP requests")
+                    .namespace(namespace)
+                    .const_labels(const_labels.clone()),
+                &["endpoint", "method", "status"],
+            )
+            .unwrap(),
+            durations: HistogramVec::new(
+                HistogramOpts::new(
+                    "http_requests_duration_seconds",
+                    "HTTP request duration in seconds for all requests",
+                )
+                .namespace(namespace)
+                .const_labels(const_labels),
+                &["endpoint", "method", "status"],
+            )
+            .unwrap(),
+            endpoints: RwLock::new(HashSet::new()),
+        }
+    }
+
+    /// Registers the metrics with a registry.
+    pub fn register(&self, registry: &Registry) {
+        registry.register(Box::new(self.requests.clone())).unwrap();
+        registry.register(Box::new(self.durations.clone())).unwrap();
+    }
+
+    /// Returns the label of the route `pattern` a request matched.
+    fn endpoint(&self, pattern: Option<&str>) -> String {
+        let endpoint = match pattern {
+            Some(pattern) => template(pattern),
+            None => return OTHER.to_string(),
+        };
+        if self.endpoints.read().unwrap().contains(&endpoint) {
+            return endpoint;
+        }
+        let mut endpoints = self.endpoints.write().unwrap();
+        if endpoints.len() >= MAX_ENDPOINTS {
+            return OTHER.to_string();
+        }
+        endpoints.insert(endpoint.clone());
+        endpoint
+    }
+}
+
+/// Counts and times every request.
+/// # Arguments
+/// * `metrics` - The HTTP metrics of the server.
+/// * `req` - The incoming request.
+/// * `srv` - The service handling the request.
+pub fn track<S, B>(
+    metrics: &Arc<HttpMetrics>,
+    req: ServiceRequest,
+    srv: &mut S,
+) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    let metrics = metrics.clone();
+    let started = Instant::now();
+    srv.call(req).map(move |response| {
+        let elapsed = started.elapsed().as_secs_f64();
+        let (endpoint, method, status) = match &response {
+            Ok(response) => {
+                let request = response.request();
+                (
+                    metrics.endpoint(request.match_pattern().as_deref()),
+                    method_label(request.method()).to_string(),
+                    response.status(),
+                )
+            }
+            Err(err) => (
+                OTHER.to_string(),
+                OTHER.to_string(),
+                err.as_response_error().status_code(),
+            ),
+        };
+        let labels = [endpoint.as_str(), method.as_str(), status.as_str()];
+        metrics.requests.with_label_values(&labels).inc();
+        metrics
+            .durations
+            .with_label_values(&labels)
+            .observe(elapsed);
+        response
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn endpoints_are_labelled_by_route_template() {
+        assert_eq!(template("/v1/keys/{key:[^_].*}"), "/v1/keys/{key}");
+        assert_eq!(template("/{key:[a-z]{2}}/list/push"), "/{key}/list/push");
+        assert_eq!(
+            template("/_admin/purge/{token}/confirm"),
+            "/_admin/purge/{token}/confirm"
+        );
+    }
+
+    #[test]
+    fn unexpected_endpoints_are_labelled_other() {
+        let sut = HttpMetrics::new("test", HashMap::new());
+
+        assert_eq!(sut.endpoint(None), OTHER);
+        for n in 0..MAX_ENDPOINTS {
+            sut.endpoint(Some(&format!("/{}", n)));
+        }
+
+        assert_eq!(sut.endpoint(Some("/0")), "/0");
+        assert_eq!(sut.endpoint(Some("/new")), OTHER);
+        assert_eq!(method_label(&Method::GET), "GET");
+        assert_eq!(method_label(&Method::from_bytes(b"SCAN").unwrap()), OTHER);
+    }
+}
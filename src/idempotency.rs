@@ -0,0 +1,270 @@
+//! Deduplicates retried POST requests that send an `Idempotency-Key` header. The outcome of the
+//! first successful request is recorded in the cache under `idempotency.namespace`, the client and
+//! the idempotency key, and later requests from the client with the same key get the recorded
+//! response instead of being applied again, until the record expires after `key_live_duration`.
+//! Clients are told apart by a hash of the `idempotency.header` they send, e.g. their API key, or
+//! by their address, so one client can not replay the responses sent to another.
+//!
+//! A pending record is created before the request is applied, only if there is no record, so of
+//! concurrent requests with the same key one is applied and the others are answered with 409.
+//! Only successful responses are recorded, a failed request changed nothing and its pending
+//! record is removed so it can be retried. Reusing a key for a different path is answered with 422.
+use crate::cache::SimpleCache;
+use crate::digest;
+use crate::eviction::Priority;
+use crate::settings;
+use crate::ttl::Expiry;
+use crate::value::Value;
+use actix_web::{
+    dev::{Body, ResponseBody, Service, ServiceRequest, ServiceResponse},
+    http::{header, HeaderName, Method, StatusCode},
+    web, Error, HttpResponse,
+};
+use futures::future::{ok, FutureExt, LocalBoxFuture};
+use serde::{Deserialize, Serialize};
+
+/// The header clients send to make a POST request idempotent.
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+/// The header marking a response replayed from a record.
+const REPLAYED: &str = "idempotent-replayed";
+
+/// The recorded outcome of a request.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+struct Record {
+    path: String,
+    status: u16,
+    content_type: Option<String>,
+    body: String,
+    /// The request is being applied and there is no response yet.
+    #[serde(default)]
+    pending: bool,
+}
+
+impl Record {
+    /// Returns the record of `response` to a request for `path`, if it has a body that can be
+    /// recorded.
+    fn new(path: &str, response: &HttpResponse) -> Option<Self> {
+        let body = match response.body() {
+            ResponseBody::Body(Body::Bytes(bytes)) => String::from_utf8_lossy(bytes).into_owned(),
+            ResponseBody::Body(Body::Empty) | ResponseBody::Body(Body::None) => String::new(),
+            _ => return None,
+        };
+        Some(Self {
+            path: path.to_string(),
+            status: response.status().as_u16(),
+            content_type: response
+                .headers()
+                .get(header::CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string),
+            body,
+            pending: false,
+        })
+    }
+
+    /// Returns the record of a request for `path` that is being applied.
+    fn pending(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            status: 0,
+            content_type: None,
+            body: String::new(),
+            pending: true,
+        }
+    }
+
+    /// Returns the record as a JSON value.
+    fn to_value(&self) -> Value {
+        // Serializing a struct of strings and numbers can not fail.
+        Value::from(serde_json::to_string(self).unwrap()).into_json(true)
+    }
+
+    /// Returns the recorded response.
+    fn respond(self) -> HttpResponse {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK);
+        let mut response = HttpResponse::build(status);
+        response.header(HeaderName::from_static(REPLAYED), "true");
+        if let Some(content_type) = self.content_type {
+            response.content_type(content_type);
+        }
+        response.body(self.body)
+    }
+}
+
+/// Replays the recorded response to a POST request with an `Idempotency-Key` header, or calls the
+/// service and records its response.
+/// # Arguments
+/// * `settings` - Whether requests are deduplicated and where records are kept.
+/// * `cache` - The cache records are stored in.
+/// * `req` - The incoming request.
+/// * `srv` - The service to call when there is no record.
+pub fn deduplicate<S>(
+    settings: &settings::Idempotency,
+    cache: &web::Data<SimpleCache<'static>>,
+    req: ServiceRequest,
+    srv: &mut S,
+) -> LocalBoxFuture<'static, Result<ServiceResponse<Body>, Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<Body>, Error = Error>,
+    S::Future: 'static,
+{
+    let idempotency_key = req
+        .headers()
+        .get(IDEMPOTENCY_KEY)
+        .and_then(|value| value.to_str().ok());
+    let record_key = match idempotency_key {
+        Some(idempotency_key) if settings.enabled && req.method() == Method::POST => format!(
+            "{}{}/{}",
+            settings.namespace,
+            client(settings, &req),
+            idempotency_key
+        ),
+        _ => return srv.call(req).boxed_local(),
+    };
+    let path = req.path().to_string();
+    let pending = Record::pending(&path).to_value();
+    while cache
+        .put_if_absent(
+            record_key.clone(),
+            pending.clone(),
+            Expiry::Default,
+            Priority::Normal,
+        )
+        .is_none()
+    {
+        let record = cache
+            .peek(&record_key, &|value| {
+                serde_json::from_reader::<_, Record>(value.reader()).ok()
+            })
+            .flatten();
+        // The record expired since it was found, so try creating it again.
+        let record = match record {
+            Some(record) => record,
+            None => continue,
+        };
+        let response = if record.path != path {
+            HttpResponse::UnprocessableEntity()
+                .body("The idempotency key was used for a different request")
+        } else if record.pending {
+            HttpResponse::Conflict().body("A request with the idempotency key is in progress")
+        } else {
+            log::debug!("Replaying the response for idempotency key: {}", record_key);
+            record.respond()
+        };
+        return ok(req.into_response(response)).boxed_local();
+    }
+    let cache = cache.clone();
+    let response = srv.call(req);
+    async move {
+        let response = response.await;
+        let record = response
+            .as_ref()
+            .ok()
+            .filter(|response| response.status().is_success())
+            .and_then(|response| Record::new(&path, response.response()));
+        match record {
+            Some(record) => {
+                cache.put(record_key, record.to_value());
+            }
+            // Nothing was recorded, so the request can be retried.
+            None => {
+                cache.remove(&record_key);
+            }
+        }
+        response
+    }
+    .boxed_local()
+}
+
+/// Returns the client records of `req` are scoped to, a hash of its `idempotency.header` or its
+/// address.
+fn client(settings: &settings::Idempotency, req: &ServiceRequest) -> String {
+    match req.headers().get(settings.header.as_str()) {
+        Some(value) => digest::to_hex(digest::hash(value.as_bytes())),
+        None => req
+            .peer_addr()
+            .map_or_else(|| "unknown".to_string(), |addr| addr.ip().to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::CacheMetrics;
+    use actix_web::{test, App};
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    #[actix_rt::test]
+    async fn records_are_kept_per_client_and_pending_requests_conflict() {
+        let settings = settings::Idempotency {
+            enabled: true,
+            ..Default::default()
+        };
+        let cache = web::Data::new(SimpleCache::new(
+            Duration::from_secs(60),
+            CacheMetrics::default(),
+        ));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let (app_cache, app_calls) = (cache.clone(), calls.clone());
+        let mut app = test::init_service(
+            App::new()
+                .wrap_fn(move |req, srv| deduplicate(&settings, &app_cache, req, srv))
+                .route(
+                    "/a",
+                    web::post().to(move || {
+                        app_calls.fetch_add(1, Ordering::Relaxed);
+                        HttpResponse::Created().body("1")
+                    }),
+                ),
+        )
+        .await;
+        let request = |api_key: &str| {
+            test::TestRequest::post()
+                .uri("/a")
+                .header("Idempotency-Key", "k")
+                .header("X-Api-Key", api_key)
+                .to_request()
+        };
+        let pending_key = format!("_idempotency/{}/k", digest::to_hex(digest::hash(b"c")));
+        cache.put(pending_key, Record::pending("/a").to_value());
+
+        let first = test::call_service(&mut app, request("a")).await;
+        let replayed = test::call_service(&mut app, request("a")).await;
+        let other = test::call_service(&mut app, request("b")).await;
+        let pending = test::call_service(&mut app, request("c")).await;
+
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+        assert!(first.headers().get(REPLAYED).is_none());
+        assert_eq!(replayed.status(), StatusCode::CREATED);
+        assert_eq!(replayed.headers().get(REPLAYED).unwrap(), "true");
+        assert!(other.headers().get(REPLAYED).is_none());
+        assert_eq!(pending.status(), StatusCode::CONFLICT);
+    }
+
+    #[test]
+    fn responses_are_recorded_and_replayed() {
+        let response = HttpResponse::Created()
+            .content_type("application/json")
+            .body(r#"{"len":1}"#);
+
+        let record = Record::new("/a/list/push", &response).unwrap();
+        let replayed = record.respond();
+
+        assert_eq!(replayed.status(), StatusCode::CREATED);
+        assert_eq!(
+            replayed.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/json"
+        );
+        assert_eq!(replayed.headers().get(REPLAYED).unwrap(), "true");
+        assert_eq!(
+            Record::new("/a/list/push", &replayed).unwrap().body,
+            r#"{"len":1}"#
+        );
+    }
+}
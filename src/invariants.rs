@@ -0,0 +1,181 @@
+//! Checks the accounting of the cache in the background with `invariants.enabled`, catching bugs
+//! that would otherwise only show as slowly wrong dashboards: every `invariants.interval` the
+//! `cache_items` and `cache_size` gauges are compared to the values recomputed from the entries,
+//! and entries still stored `invariants.expired_grace` after they expired are counted.
+//!
+//! Each check is one pass over the entries, so the interval sets its cost. Writes racing a pass
+//! make the gauges briefly differ, so drift is only reported when it is seen by two checks in a
+//! row. Drift is logged and counted in `cache_invariant_violations_total`, and the last drift
+//! found is the `cache_invariant_drift` gauge.
+use crate::cache::{Accounting, MetricOpts, SimpleCache};
+use crate::settings;
+use actix_rt::time::delay_for;
+use actix_web::web;
+use prometheus::{IntCounterVec, IntGaugeVec, Registry};
+use std::time::Duration;
+
+/// Container for the invariant checker metrics.
+#[derive(Clone)]
+pub struct InvariantMetrics {
+    /// The difference between a gauge and its recomputed value, or the number of stale entries.
+    pub drift: IntGaugeVec,
+    /// A count of the checks that found drift, labelled by the invariant.
+    pub violations: IntCounterVec,
+}
+
+impl InvariantMetrics {
+    /// Creates a new InvariantMetrics named using `opts`.
+    pub fn with_opts(opts: &MetricOpts) -> Self {
+        Self {
+            drift: IntGaugeVec::new(
+                opts.opts(
+                    "cache_invariant_drift",
+                    "The drift found by the last invariant check",
+                ),
+                &["invariant"],
+            )
+            .unwrap(),
+            violations: IntCounterVec::new(
+                opts.opts(
+                    "cache_invariant_violations_total",
+                    "A count of invariant checks that found drift",
+                ),
+                &["invariant"],
+            )
+            .unwrap(),
+        }
+    }
+
+    /// Registers the invariant checker metrics with a registry.
+    pub fn register(&self, registry: &Registry) {
+        registry.register(Box::new(self.drift.clone())).unwrap();
+        registry
+            .register(Box::new(self.violations.clone()))
+            .unwrap();
+    }
+}
+
+/// Returns the drift of each invariant in `accounting`.
+fn drift(accounting: &Accounting) -> [(&'static str, i64); 3] {
+    [
+        ("items", accounting.items.0 - accounting.items.1),
+        ("size", accounting.size.0 - accounting.size.1),
+        ("stale_entries", accounting.stale as i64),
+    ]
+}
+
+/// Checks the accounting of a cache on an interval.
+pub struct InvariantChecker {
+    interval: Duration,
+    expired_grace: Duration,
+    metrics: InvariantMetrics,
+}
+
+impl InvariantChecker {
+    /// Returns a checker configured by `settings`, or `None` if checks are disabled.
+    /// # Arguments
+    /// * `settings` - The invariant checker settings.
+    /// * `key_live_duration` - The default ttl, the grace period is twice as long by default.
+    /// * `metrics` - A container for the metrics used by the checker.
+    pub fn new(
+        settings: &settings::Invariants,
+        key_live_duration: Duration,
+        metrics: InvariantMetrics,
+    ) -> Option<Self> {
+        if !settings.enabled {
+            return None;
+        }
+        Some(Self {
+            interval: Duration::from_secs(settings.interval),
+            expired_grace: settings
+                .expired_grace
+                .map_or(key_live_duration * 2, Duration::from_secs),
+            metrics,
+        })
+    }
+
+    /// Records the drift found by a check and returns the invariants that drifted in this check
+    /// and the previous one.
+    /// # Arguments
+    /// * `accounting` - The accounting of the cache.
+    /// * `previous` - The drift found by the previous check.
+    fn record(
+        &self,
+        accounting: &Accounting,
+        previous: &[(&'static str, i64); 3],
+    ) -> Vec<(&'static str, i64)> {
+        let mut drifted = Vec::new();
+        for (&(invariant, drift), &(_, previous)) in drift(accounting).iter().zip(previous) {
+            self.metrics
+                .drift
+                .with_label_values(&[invariant])
+                .set(drift);
+            if drift != 0 && previous != 0 {
+                drifted.push((invariant, drift));
+            }
+        }
+        drifted
+    }
+
+    /// Checks the accounting of `cache` every interval, until the future is dropped.
+    pub async fn run(self, cache: web::Data<SimpleCache<'static>>) {
+        log::info!("Starting invariant checker");
+        let mut previous = [("items", 0), ("size", 0), ("stale_entries", 0)];
+        loop {
+            delay_for(self.interval).await;
+            let accounting = cache.accounting(self.expired_grace);
+            for (invariant, drift) in self.record(&accounting, &previous) {
+                self.metrics
+                    .violations
+                    .with_label_values(&[invariant])
+                    .inc();
+                log::warn!(
+                    "The {} invariant of the cache drifted by {}: {:?}",
+                    invariant,
+                    drift,
+                    accounting
+                );
+            }
+            previous = drift(&accounting);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn accounting(items: (i64, i64), stale: usize) -> Accounting {
+        Accounting {
+            items,
+            size: (10, 10),
+            stale,
+        }
+    }
+
+    #[test]
+    fn drift_is_reported_when_seen_twice_in_a_row() {
+        let settings = settings::Invariants {
+            enabled: true,
+            ..Default::default()
+        };
+        let metrics = InvariantMetrics::with_opts(&MetricOpts::default());
+        let sut = InvariantChecker::new(&settings, Duration::from_secs(60), metrics).unwrap();
+        let none = [("items", 0), ("size", 0), ("stale_entries", 0)];
+
+        let first = accounting((3, 2), 0);
+        assert!(sut.record(&first, &none).is_empty());
+        assert_eq!(
+            sut.record(&accounting((3, 2), 1), &drift(&first)),
+            vec![("items", 1)]
+        );
+        assert_eq!(
+            sut.metrics
+                .drift
+                .with_label_values(&["stale_entries"])
+                .get(),
+            1
+        );
+        assert_eq!(sut.expired_grace, Duration::from_secs(120));
+    }
+}
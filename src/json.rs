@@ -0,0 +1,114 @@
+//! Values stored in JSON mode, which are validated when they are added, can have their top level
+//! fields projected when they are read and can be updated with a JSON merge patch.
+use crate::value::Value;
+use serde::de::IgnoredAny;
+use serde_json::Map;
+
+/// Returns an error if `value` is not a JSON document.
+pub fn validate(value: &Value) -> serde_json::Result<()> {
+    serde_json::from_reader::<_, IgnoredAny>(value.reader()).map(|_| ())
+}
+
+/// Returns a copy of the JSON object in `value` with only the named top level fields, fields that
+/// are not present are skipped. Returns `None` if the value is not a JSON object.
+/// # Arguments
+/// * `value` - A value stored in JSON mode.
+/// * `fields` - The names of the fields to keep.
+pub fn project(value: &Value, fields: &[&str]) -> Option<serde_json::Value> {
+    let mut object = match serde_json::from_reader(value.reader()) {
+        Ok(serde_json::Value::Object(object)) => object,
+        _ => return None,
+    };
+    let projected: Map<String, serde_json::Value> = fields
+        .iter()
+        .filter_map(|&field| object.remove_entry(field))
+        .collect();
+    Some(serde_json::Value::Object(projected))
+}
+
+/// Applies a JSON merge patch (RFC 7396) to `target`.
+/// # Arguments
+/// * `target` - The document to patch.
+/// * `patch` - The patch, where `null` removes a field and objects are merged recursively.
+pub fn merge_patch(target: &mut serde_json::Value, patch: &serde_json::Value) {
+    let patch = match patch {
+        serde_json::Value::Object(patch) => patch,
+        patch => {
+            *target = patch.clone();
+            return;
+        }
+    };
+    if !target.is_object() {
+        *target = serde_json::Value::Object(Map::new());
+    }
+    if let serde_json::Value::Object(object) = target {
+        for (name, value) in patch {
+            if value.is_null() {
+                object.remove(name);
+            } else {
+                merge_patch(
+                    object
+                        .entry(name.clone())
+                        .or_insert(serde_json::Value::Null),
+                    value,
+                );
+            }
+        }
+    }
+}
+
+/// Returns `value` with a JSON merge patch applied, or an error if `value` is not a JSON document.
+/// # Arguments
+/// * `value` - A value stored in JSON mode.
+/// * `patch` - The merge patch to apply.
+pub fn patch(value: &Value, patch: &serde_json::Value) -> serde_json::Result<Value> {
+    let mut document = serde_json::from_reader(value.reader())?;
+    merge_patch(&mut document, patch);
+    Ok(Value::from(serde_json::to_string(&document)?).into_json(true))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn documents_are_validated() {
+        assert!(validate(&Value::from(r#"{"a": 1}"#)).is_ok());
+        assert!(validate(&Value::from(r#"{"a": "#)).is_err());
+    }
+
+    #[test]
+    fn fields_are_projected() {
+        let value = Value::from(r#"{"a": 1, "b": {"c": 2}, "d": 3}"#);
+
+        let projected = project(&value, &["b", "a", "missing"]).unwrap();
+
+        assert_eq!(projected, serde_json::json!({"a": 1, "b": {"c": 2}}));
+        assert_eq!(project(&Value::from("[1]"), &["a"]), None);
+    }
+
+    #[test]
+    fn merge_patches_follow_rfc_7396() {
+        let mut document = serde_json::json!({"a": "b", "c": {"d": "e", "f": "g"}, "h": [1]});
+
+        merge_patch(
+            &mut document,
+            &serde_json::json!({"a": "z", "c": {"f": null}, "h": {"i": 2}}),
+        );
+
+        assert_eq!(
+            document,
+            serde_json::json!({"a": "z", "c": {"d": "e"}, "h": {"i": 2}})
+        );
+    }
+
+    #[test]
+    fn patched_values_stay_in_json_mode() {
+        let value = Value::from(r#"{"a": 1}"#).into_json(true);
+
+        let patched = patch(&value, &serde_json::json!({"b": 2})).unwrap();
+
+        assert!(patched.is_json());
+        assert_eq!(patched, Value::from(r#"{"a":1,"b":2}"#).into_json(true));
+    }
+}
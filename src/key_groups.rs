@@ -0,0 +1,196 @@
+//! Groups keys with the regex `metrics.key_group`, e.g. `^([^/]+)/` for the first path segment, so
+//! reads, writes and removals can be counted per group without the cache knowing the key scheme.
+//! The group of a key is the first capture group of the regex, or its whole match.
+//!
+//! Groups are labels of the `cache_group_*` metrics and are reported by `GET /_admin/groups`. To
+//! keep the labels low-cardinality, keys matching no group or a group beyond the first
+//! `metrics.max_key_groups` seen are counted as `other`.
+use crate::cache::MetricOpts;
+use crate::plugin::CachePlugin;
+use crate::value::Value;
+use prometheus::{IntCounterVec, Registry};
+use regex::Regex;
+#[cfg(feature = "admin")]
+use serde::Serialize;
+#[cfg(feature = "admin")]
+use std::collections::BTreeMap;
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+/// The number of groups counted separately when `metrics.max_key_groups` is not set.
+pub const DEFAULT_MAX_GROUPS: usize = 100;
+
+/// The group of keys that match no group or a group beyond the maximum.
+const OTHER: &str = "other";
+
+/// The counts of one group, reported by `/_admin/groups`.
+#[cfg(feature = "admin")]
+#[derive(Debug, PartialEq, Serialize)]
+pub struct GroupStats {
+    hits: i64,
+    misses: i64,
+    writes: i64,
+    written_bytes: i64,
+    evictions: i64,
+    expirations: i64,
+}
+
+/// A cache plugin counting reads, writes and removals by key group.
+#[derive(Clone)]
+pub struct KeyGroups {
+    pattern: Regex,
+    max_groups: usize,
+    /// The groups counted separately.
+    groups: Arc<Mutex<HashSet<String>>>,
+    /// A count of hits and misses by group.
+    queries: IntCounterVec,
+    /// A count of writes by group.
+    writes: IntCounterVec,
+    /// A count of the bytes of values written by group.
+    written_bytes: IntCounterVec,
+    /// A count of keys evicted or expired by group.
+    removals: IntCounterVec,
+}
+
+impl KeyGroups {
+    /// Returns a new `KeyGroups`.
+    /// # Arguments
+    /// * `pattern` - The regex matching the group of a key.
+    /// * `max_groups` - The number of groups counted separately.
+    /// * `opts` - The naming of the metrics.
+    pub fn new(pattern: &str, max_groups: usize, opts: &MetricOpts) -> Result<Self, regex::Error> {
+        let counter = |name, help, labels: &[&str]| {
+            IntCounterVec::new(opts.opts(name, help), labels).unwrap()
+        };
+        Ok(Self {
+            pattern: Regex::new(pattern)?,
+            max_groups,
+            groups: Arc::default(),
+            queries: counter(
+                "cache_group_queries_total",
+                "A count of cache hits and misses by key group",
+                &["group", "hit_or_miss"],
+            ),
+            writes: counter(
+                "cache_group_writes_total",
+                "A count of writes by key group",
+                &["group"],
+            ),
+            written_bytes: counter(
+                "cache_group_written_bytes_total",
+                "A count of the bytes of values written by key group",
+                &["group"],
+            ),
+            removals: counter(
+                "cache_group_removals_total",
+                "A count of keys evicted or expired by key group",
+                &["group", "reason"],
+            ),
+        })
+    }
+
+    /// Registers the metrics with a registry.
+    pub fn register(&self, registry: &Registry) {
+        registry.register(Box::new(self.queries.clone())).unwrap();
+        registry.register(Box::new(self.writes.clone())).unwrap();
+        registry
+            .register(Box::new(self.written_bytes.clone()))
+            .unwrap();
+        registry.register(Box::new(self.removals.clone())).unwrap();
+    }
+
+    /// Returns the group `key` is counted in.
+    fn group(&self, key: &str) -> String {
+        let group = match self.pattern.captures(key) {
+            Some(captures) => captures.get(1).or_else(|| captures.get(0)),
+            None => None,
+        };
+        let group = match group {
+            Some(group) => group.as_str(),
+            None => return OTHER.to_string(),
+        };
+        let mut groups = self.groups.lock().unwrap();
+        if groups.contains(group) || (groups.len() < self.max_groups && groups.insert(group.into()))
+        {
+            group.to_string()
+        } else {
+            OTHER.to_string()
+        }
+    }
+
+    /// Returns the counts of every group, including `other`.
+    #[cfg(feature = "admin")]
+    pub fn report(&self) -> BTreeMap<String, GroupStats> {
+        let mut groups: Vec<String> = self.groups.lock().unwrap().iter().cloned().collect();
+        groups.push(OTHER.to_string());
+        groups
+            .into_iter()
+            .map(|group| {
+                let stats = GroupStats {
+                    hits: self.queries.with_label_values(&[&group, "hit"]).get(),
+                    misses: self.queries.with_label_values(&[&group, "miss"]).get(),
+                    writes: self.writes.with_label_values(&[&group]).get(),
+                    written_bytes: self.written_bytes.with_label_values(&[&group]).get(),
+                    evictions: self.removals.with_label_values(&[&group, "evicted"]).get(),
+                    expirations: self.removals.with_label_values(&[&group, "expired"]).get(),
+                };
+                (group, stats)
+            })
+            .collect()
+    }
+}
+
+impl CachePlugin for KeyGroups {
+    fn before_put(&self, key: &str, value: &Value) {
+        let group = self.group(key);
+        self.writes.with_label_values(&[&group]).inc();
+        self.written_bytes
+            .with_label_values(&[&group])
+            .inc_by(value.len() as i64);
+    }
+
+    fn after_get(&self, key: &str, hit: bool) {
+        let hit_or_miss = if hit { "hit" } else { "miss" };
+        self.queries
+            .with_label_values(&[&self.group(key), hit_or_miss])
+            .inc();
+    }
+
+    fn on_evict(&self, key: &str, _size: usize) {
+        self.removals
+            .with_label_values(&[&self.group(key), "evicted"])
+            .inc();
+    }
+
+    fn on_expire(&self, key: &str, _size: usize) {
+        self.removals
+            .with_label_values(&[&self.group(key), "expired"])
+            .inc();
+    }
+}
+
+#[cfg(all(test, feature = "admin"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn keys_beyond_the_maximum_groups_are_counted_as_other() {
+        let sut = KeyGroups::new("^([^/]+)/", 2, &MetricOpts::default()).unwrap();
+
+        for key in &["users/1", "pages/1", "users/2", "orders/1", "plain"] {
+            sut.after_get(key, true);
+        }
+        sut.before_put("users/3", &Value::from("123"));
+
+        let report = sut.report();
+        assert_eq!(
+            report.keys().collect::<Vec<_>>(),
+            vec!["other", "pages", "users"]
+        );
+        assert_eq!(report["users"].hits, 2);
+        assert_eq!(report["users"].written_bytes, 3);
+        assert_eq!(report["other"].hits, 2);
+    }
+}
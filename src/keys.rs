@@ -0,0 +1,178 @@
+//! Normalizes and validates keys, so each key has a single spelling, e.g. `/a%2Fb` and `/a%2fB`
+//! can be made the same key `a/b` with percent-decoding and lowercasing.
+//!
+//! Keys are percent-decoded first, then lowercased and finally checked against the maximum length
+//! and allowed characters. Nested keys must not have empty, `.` or `..` segments. Handlers take a
+//! `CacheKey`, which applies the rules of the cache and responds with 400 to invalid keys, or 404
+//! to keys of several path segments unless nested keys are enabled.
+//!
+//! Paths starting with `/_` are reserved for endpoints such as `/_status` and never touch the
+//! cache: key routes do not match them, and keys starting with `_` are rejected once decoded, so
+//! internal keys like those of the idempotency namespace can not be read or overwritten by clients.
+use crate::cache::SimpleCache;
+use crate::settings::{self, KeyCharset};
+use actix_web::{
+    dev::Payload,
+    error::{ErrorBadRequest, ErrorNotFound},
+    web, Error, FromRequest, HttpRequest,
+};
+use futures::future::{ready, Ready};
+use std::{fmt, ops::Deref, str};
+
+/// The first character of paths reserved for endpoints rather than keys.
+const RESERVED_PREFIX: char = '_';
+
+/// Returned when a key does not follow the key rules.
+#[derive(Debug, PartialEq)]
+pub struct InvalidKey(String);
+
+impl fmt::Display for InvalidKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid key: {}", self.0)
+    }
+}
+
+/// Decodes `%XX` escapes, returning None for incomplete escapes or bytes that are not UTF-8.
+fn percent_decode(key: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(key.len());
+    let mut rest = key.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+impl KeyCharset {
+    fn allows(self, c: char) -> bool {
+        match self {
+            KeyCharset::Any => true,
+            KeyCharset::Printable => !c.is_control() && !c.is_whitespace(),
+            KeyCharset::UrlSafe => c.is_ascii_alphanumeric() || "-._~:/".contains(c),
+        }
+    }
+}
+
+/// Returns `key` normalized, or why it is not a valid key.
+/// # Arguments
+/// * `rules` - How keys are normalized and what they may contain.
+/// * `key` - The key as it was received.
+pub fn normalize(rules: &settings::Keys, key: &str) -> Result<String, InvalidKey> {
+    let mut key = if rules.percent_decode {
+        percent_decode(key).ok_or_else(|| InvalidKey("bad percent-encoding".into()))?
+    } else {
+        key.to_string()
+    };
+    if rules.lowercase {
+        key = key.to_lowercase();
+    }
+    if key.is_empty() {
+        return Err(InvalidKey("keys can not be empty".into()));
+    }
+    if key.starts_with(RESERVED_PREFIX) {
+        return Err(InvalidKey(format!(
+            "keys starting with {} are reserved",
+            RESERVED_PREFIX
+        )));
+    }
+    if let Some(max_length) = rules
+        .max_length
+        .filter(|max_length| key.len() > *max_length)
+    {
+        return Err(InvalidKey(format!("longer than {} bytes", max_length)));
+    }
+    if let Some(c) = key.chars().find(|c| !rules.charset.allows(*c)) {
+        return Err(InvalidKey(format!("{:?} is not allowed", c)));
+    }
+    let invalid = |segment: &str| segment.is_empty() || segment == "." || segment == "..";
+    if rules.nested && key.split('/').any(invalid) {
+        return Err(InvalidKey(
+            "empty, . and .. segments are not allowed".into(),
+        ));
+    }
+    Ok(key)
+}
+
+/// The `{key}` of a request path normalized by the rules of the cache.
+pub struct CacheKey(String);
+
+impl CacheKey {
+    /// Returns the normalized key.
+    pub fn into_inner(self) -> String {
+        self.0
+    }
+}
+
+impl Deref for CacheKey {
+    type Target = String;
+
+    fn deref(&self) -> &String {
+        &self.0
+    }
+}
+
+impl FromRequest for CacheKey {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let key = req.match_info().get("key").unwrap_or_default();
+        let key = match req.app_data::<web::Data<SimpleCache<'static>>>() {
+            // Routes match several segments, which are only keys when nested keys are enabled.
+            Some(cache) if !cache.key_rules().nested && key.contains('/') => {
+                Err(ErrorNotFound("Not found"))
+            }
+            Some(cache) => cache.key(key).map_err(ErrorBadRequest),
+            None => Ok(key.to_string()),
+        };
+        ready(key.map(CacheKey))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rules() -> settings::Keys {
+        settings::Keys {
+            max_length: Some(8),
+            charset: KeyCharset::UrlSafe,
+            lowercase: true,
+            percent_decode: true,
+            nested: true,
+        }
+    }
+
+    #[test]
+    fn keys_are_decoded_and_lowercased() {
+        assert_eq!(normalize(&rules(), "A%2fB"), Ok("a/b".into()));
+        assert_eq!(normalize(&rules(), "a/b"), Ok("a/b".into()));
+        assert_eq!(normalize(&Default::default(), "A%2fB"), Ok("A%2fB".into()));
+    }
+
+    #[test]
+    fn invalid_keys_are_rejected() {
+        assert!(normalize(&rules(), "").is_err());
+        assert!(normalize(&rules(), "toolongkey").is_err());
+        assert!(normalize(&rules(), "a b").is_err());
+        assert!(normalize(&rules(), "a%2").is_err());
+        assert!(normalize(&rules(), "%ff").is_err());
+        assert!(normalize(&rules(), "_status").is_err());
+        assert!(normalize(&rules(), "%5Fstatus").is_err());
+    }
+
+    #[test]
+    fn nested_keys_must_have_named_segments() {
+        assert_eq!(normalize(&rules(), "a/b/c"), Ok("a/b/c".into()));
+        assert!(normalize(&rules(), "a//c").is_err());
+        assert!(normalize(&rules(), "a/../c").is_err());
+        assert!(normalize(&rules(), "a/").is_err());
+    }
+}
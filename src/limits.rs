@@ -0,0 +1,149 @@
+//! Limits how quickly new keys are created, so a client flooding the cache with unique keys is
+//! throttled before it can fill the cache or degrade the backing store.
+//!
+//! New keys are counted per client IP address and in total over fixed one minute windows. Writes
+//! to keys that already exist are never limited.
+use crate::cache::MetricOpts;
+use crate::settings;
+use actix_web::{HttpRequest, HttpResponse};
+use prometheus::{IntCounterVec, Registry};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// The length of the windows new keys are counted over.
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// The new keys counted in the current window.
+struct Window {
+    start: Instant,
+    total: u32,
+    clients: HashMap<IpAddr, u32>,
+}
+
+/// Counts new keys per client and in total, and throttles writes over the configured limits.
+pub struct KeyLimiter {
+    settings: settings::KeyLimits,
+    window: Mutex<Window>,
+    /// A count of new keys rejected, labelled by the limit that was reached.
+    throttled: IntCounterVec,
+}
+
+impl KeyLimiter {
+    /// Returns a new `KeyLimiter`, which never throttles without limits.
+    /// # Arguments
+    /// * `settings` - The new keys allowed per client and in total each minute.
+    /// * `opts` - The naming of the throttling metric.
+    pub fn new(settings: settings::KeyLimits, opts: &MetricOpts) -> Self {
+        Self {
+            settings,
+            window: Mutex::new(Window {
+                start: Instant::now(),
+                total: 0,
+                clients: HashMap::new(),
+            }),
+            throttled: IntCounterVec::new(
+                opts.opts(
+                    "new_keys_throttled_total",
+                    "A count of new keys rejected by the new key limits",
+                ),
+                &["limit"],
+            )
+            .unwrap(),
+        }
+    }
+
+    /// Registers the throttling metric with a registry.
+    pub fn register(&self, registry: &Registry) {
+        registry.register(Box::new(self.throttled.clone())).unwrap();
+    }
+
+    /// Counts a new key from `client` and returns true, or returns false if the client or the
+    /// cache has created too many keys in the current minute.
+    pub fn allow_new_key(&self, client: Option<IpAddr>) -> bool {
+        let settings = &self.settings;
+        if settings.per_client.is_none() && settings.global.is_none() {
+            return true;
+        }
+        let mut window = self.window.lock().unwrap();
+        let now = Instant::now();
+        if now.duration_since(window.start) >= WINDOW {
+            window.start = now;
+            window.total = 0;
+            window.clients.clear();
+        }
+        if settings
+            .global
+            .is_some_and(|global| window.total >= global)
+        {
+            self.throttled.with_label_values(&["global"]).inc();
+            return false;
+        }
+        if let (Some(per_client), Some(client)) = (settings.per_client, client) {
+            let count = window.clients.entry(client).or_insert(0);
+            if *count >= per_client {
+                log::debug!("Throttling new keys from client: {}", client);
+                self.throttled.with_label_values(&["client"]).inc();
+                return false;
+            }
+            *count += 1;
+        }
+        window.total += 1;
+        true
+    }
+
+    /// Returns a 429 response if `new_key` is true and the client of `req` may not create another
+    /// key.
+    pub fn check(&self, req: &HttpRequest, new_key: bool) -> Option<HttpResponse> {
+        if !new_key || self.allow_new_key(req.peer_addr().map(|addr| addr.ip())) {
+            return None;
+        }
+        Some(HttpResponse::TooManyRequests().body("Too many new keys, try again later"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn limiter(per_client: Option<u32>, global: Option<u32>) -> KeyLimiter {
+        let settings = settings::KeyLimits { per_client, global };
+        KeyLimiter::new(settings, &MetricOpts::default())
+    }
+
+    #[test]
+    fn clients_are_limited_separately() {
+        let sut = limiter(Some(1), None);
+        let a = Some(IpAddr::from([10, 0, 0, 1]));
+        let b = Some(IpAddr::from([10, 0, 0, 2]));
+
+        assert!(sut.allow_new_key(a));
+        assert!(!sut.allow_new_key(a));
+        assert!(sut.allow_new_key(b));
+        assert_eq!(sut.throttled.with_label_values(&["client"]).get(), 1);
+    }
+
+    #[test]
+    fn global_limit_applies_to_all_clients() {
+        let sut = limiter(Some(10), Some(2));
+
+        assert!(sut.allow_new_key(Some(IpAddr::from([10, 0, 0, 1]))));
+        assert!(sut.allow_new_key(Some(IpAddr::from([10, 0, 0, 2]))));
+        assert!(!sut.allow_new_key(Some(IpAddr::from([10, 0, 0, 3]))));
+        assert_eq!(sut.throttled.with_label_values(&["global"]).get(), 1);
+    }
+
+    #[test]
+    fn counts_reset_after_a_window() {
+        let sut = limiter(Some(1), None);
+        let client = Some(IpAddr::from([10, 0, 0, 1]));
+        assert!(sut.allow_new_key(client));
+
+        sut.window.lock().unwrap().start -= WINDOW;
+
+        assert!(sut.allow_new_key(client));
+    }
+}
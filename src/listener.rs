@@ -0,0 +1,184 @@
+use crate::settings;
+use actix_rt::time::delay_for;
+use serde::Serialize;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    io,
+    net::{SocketAddr, TcpListener},
+    time::Duration,
+};
+
+/// The backlog used by actix_web when none is configured.
+const DEFAULT_BACKLOG: i32 = 2048;
+
+/// The addresses the servers are listening on, with any port 0 replaced by the chosen port.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct BoundAddresses {
+    pub cache_server: Vec<SocketAddr>,
+    pub metrics_server: Vec<SocketAddr>,
+}
+
+/// Creates a listening socket in the same way as `HttpServer::bind` with the configured socket
+/// options applied.
+fn create_tcp_listener(
+    addr: SocketAddr,
+    settings: &settings::HttpServer,
+) -> io::Result<TcpListener> {
+    let domain = match addr {
+        SocketAddr::V4(_) => Domain::ipv4(),
+        SocketAddr::V6(_) => Domain::ipv6(),
+    };
+    let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+    socket.set_reuse_address(true)?;
+    if let Some(reuse_port) = settings.reuse_port {
+        set_reuse_port(&socket, reuse_port)?;
+    }
+    if let Some(tcp_nodelay) = settings.tcp_nodelay {
+        socket.set_nodelay(tcp_nodelay)?;
+    }
+    if let Some(tcp_keepalive) = settings.tcp_keepalive {
+        socket.set_keepalive(Some(Duration::from_secs(tcp_keepalive)))?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(settings.backlog.unwrap_or(DEFAULT_BACKLOG))?;
+    Ok(socket.into_tcp_listener())
+}
+
+#[cfg(unix)]
+fn set_reuse_port(socket: &Socket, reuse_port: bool) -> io::Result<()> {
+    socket.set_reuse_port(reuse_port)
+}
+
+#[cfg(not(unix))]
+fn set_reuse_port(_: &Socket, reuse_port: bool) -> io::Result<()> {
+    if reuse_port {
+        log::warn!("reuse_port is not supported on this platform");
+    }
+    Ok(())
+}
+
+/// Creates a listening socket, retrying while the address is in use.
+async fn create_tcp_listener_with_retry(
+    addr: SocketAddr,
+    settings: &settings::HttpServer,
+) -> io::Result<TcpListener> {
+    let retry = &settings.bind_retry;
+    let mut attempt = 0;
+    loop {
+        match create_tcp_listener(addr, settings) {
+            Err(err) if err.kind() == io::ErrorKind::AddrInUse && attempt < retry.attempts => {
+                attempt += 1;
+                log::warn!(
+                    "{} is in use, retrying in {}ms ({}/{})",
+                    addr,
+                    retry.delay,
+                    attempt,
+                    retry.attempts
+                );
+                delay_for(Duration::from_millis(retry.delay)).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Binds every listen address of a server.
+/// # Arguments
+/// * `name` - The name of the server used in errors and logging.
+/// * `settings` - The server settings containing the addresses to bind.
+pub async fn bind(name: &str, settings: &settings::HttpServer) -> io::Result<Vec<TcpListener>> {
+    let mut listeners = Vec::with_capacity(settings.listen_addresses.len());
+    for &addr in &settings.listen_addresses {
+        let listener = create_tcp_listener_with_retry(addr, settings)
+            .await
+            .map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!("Could not bind {} to {}: {}", name, addr, err),
+                )
+            })?;
+        log::info!("Bound {} to {}", name, listener.local_addr()?);
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+/// Returns the local addresses of `listeners`.
+pub fn local_addrs(listeners: &[TcpListener]) -> io::Result<Vec<SocketAddr>> {
+    listeners.iter().map(TcpListener::local_addr).collect()
+}
+
+/// Returns copies of `listeners` that share the same underlying sockets.
+pub fn try_clone_all(listeners: &[TcpListener]) -> io::Result<Vec<TcpListener>> {
+    listeners.iter().map(TcpListener::try_clone).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::thread;
+
+    fn settings(listen_addresses: Vec<SocketAddr>) -> settings::HttpServer {
+        settings::HttpServer {
+            listen_addresses,
+            ..Default::default()
+        }
+    }
+
+    #[actix_rt::test]
+    async fn port_zero_is_replaced_by_the_chosen_port() {
+        let listeners = bind("test", &settings(vec!["127.0.0.1:0".parse().unwrap()]))
+            .await
+            .unwrap();
+
+        let addrs = local_addrs(&listeners).unwrap();
+
+        assert_ne!(addrs[0].port(), 0);
+    }
+
+    #[actix_rt::test]
+    async fn bind_failures_name_the_address() {
+        let listeners = bind("test", &settings(vec!["127.0.0.1:0".parse().unwrap()]))
+            .await
+            .unwrap();
+        let taken = local_addrs(&listeners).unwrap()[0];
+
+        let err = bind("test", &settings(vec![taken])).await.unwrap_err();
+
+        assert!(err.to_string().contains(&taken.to_string()));
+    }
+
+    #[actix_rt::test]
+    async fn addresses_can_be_shared_with_reuse_port() {
+        let mut settings = settings(vec!["127.0.0.1:0".parse().unwrap()]);
+        settings.reuse_port = Some(true);
+        let listeners = bind("test", &settings).await.unwrap();
+        settings.listen_addresses = local_addrs(&listeners).unwrap();
+
+        let result = bind("test", &settings).await;
+
+        assert!(result.is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn bind_retries_until_the_address_is_free() {
+        let listeners = bind("test", &settings(vec!["127.0.0.1:0".parse().unwrap()]))
+            .await
+            .unwrap();
+        let taken = local_addrs(&listeners).unwrap()[0];
+        let mut settings = settings(vec![taken]);
+        settings.bind_retry = settings::BindRetry {
+            attempts: 50,
+            delay: 10,
+        };
+
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            drop(listeners);
+        });
+        let result = bind("test", &settings).await;
+
+        assert!(result.is_ok());
+    }
+}
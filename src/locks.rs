@@ -0,0 +1,80 @@
+//! Locks on groups of keys, so scripts and transactions using several keys are applied atomically
+//! with respect to each other. Single key endpoints do not take these locks.
+use std::{
+    collections::HashSet,
+    sync::{Condvar, Mutex},
+};
+
+/// The keys used by running scripts and transactions.
+#[derive(Default)]
+pub struct KeyLocks {
+    locked: Mutex<HashSet<String>>,
+    released: Condvar,
+}
+
+/// Releases its keys when it is dropped.
+pub struct KeyGuard<'a> {
+    locks: &'a KeyLocks,
+    keys: Vec<String>,
+}
+
+impl KeyLocks {
+    /// Waits until none of `keys` are locked by another guard and locks them. All keys are taken
+    /// at once, so guards waiting for overlapping keys can not deadlock.
+    pub fn lock(&self, keys: &[String]) -> KeyGuard<'_> {
+        let mut locked = self.locked.lock().unwrap();
+        while keys.iter().any(|key| locked.contains(key)) {
+            locked = self.released.wait(locked).unwrap();
+        }
+        locked.extend(keys.iter().cloned());
+        KeyGuard {
+            locks: self,
+            keys: keys.to_vec(),
+        }
+    }
+}
+
+impl Drop for KeyGuard<'_> {
+    fn drop(&mut self) {
+        let mut locked = self.locks.locked.lock().unwrap();
+        for key in &self.keys {
+            locked.remove(key);
+        }
+        self.locks.released.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn overlapping_keys_wait_for_each_other() {
+        let sut = Arc::new(KeyLocks::default());
+        let locked = Arc::new(AtomicBool::new(false));
+        let guard = sut.lock(&["a".into(), "b".into()]);
+        let waiting = {
+            let (sut, locked) = (sut.clone(), locked.clone());
+            thread::spawn(move || {
+                let _guard = sut.lock(&["b".into(), "c".into()]);
+                locked.store(true, Ordering::SeqCst);
+            })
+        };
+        thread::sleep(Duration::from_millis(20));
+        assert!(!locked.load(Ordering::SeqCst));
+        drop(sut.lock(&["d".into()]));
+
+        drop(guard);
+
+        waiting.join().unwrap();
+        assert!(locked.load(Ordering::SeqCst));
+    }
+}
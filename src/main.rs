@@ -1,149 +1,1338 @@
+mod admin;
+mod audit;
+mod batch;
+mod bloom;
+mod build_info;
 mod cache;
+mod cache_control;
+#[cfg(feature = "chaos")]
+mod chaos;
+mod checksum;
+mod chunks;
+mod clock;
+mod collections;
+mod consistency;
+mod cors;
+mod counter;
+#[cfg(feature = "admin")]
+mod dashboard;
+mod deny;
+mod digest;
+mod discovery;
+#[cfg(feature = "persistence")]
+mod disk;
+mod encoding;
+mod eviction;
+mod experiment;
+#[cfg(all(unix, feature = "persistence"))]
+mod handoff;
+mod http_metrics;
+mod idempotency;
+mod invariants;
+mod json;
+mod key_groups;
+mod keys;
+mod limits;
+mod listener;
+mod locks;
+#[cfg(feature = "admin")]
+mod openapi;
+#[cfg(feature = "metrics")]
+mod openmetrics;
+mod pins;
+mod pipeline;
+mod plugin;
+mod pressure;
+#[cfg(feature = "admin")]
+mod purge;
+mod redis;
+#[cfg(feature = "persistence")]
+mod refresh;
+mod replay;
+#[cfg(unix)]
+mod sandbox;
+mod schema;
+#[cfg(feature = "metrics")]
+mod scrape;
+#[cfg(feature = "scripting")]
+mod script;
+mod selftest;
+mod service;
 mod settings;
-use crate::cache::{CacheMetrics, SimpleCache};
-use crate::settings::Settings;
-use actix_web::{get, middleware, post, rt::System, web, App, HttpResponse, HttpServer};
-use actix_web_prom::PrometheusMetrics;
+mod shadow;
+mod slab;
+mod slo;
+mod stampede;
+#[cfg(feature = "metrics")]
+mod statsd;
+mod status;
+mod streaming;
+mod supervisor;
+mod throughput;
+mod trace;
+mod ttl;
+mod txn;
+mod usage;
+mod value;
+mod vary;
+#[cfg(feature = "persistence")]
+mod warmup;
+mod write_checks;
+mod write_gate;
+use crate::audit::{AuditMetrics, Auditor};
+use crate::build_info::BuildInfo;
+use crate::cache::{CacheMetrics, ExportedEntry, MetricOpts, SimpleCache, DEFAULT_SIZE_BUCKETS};
+use crate::cors::Cors;
+use crate::deny::DenyList;
+use crate::discovery::Registration;
+#[cfg(feature = "persistence")]
+use crate::disk::{DiskMetrics, DiskTier};
+use crate::eviction::Priority;
+use crate::experiment::EvictionExperiment;
+use crate::http_metrics::HttpMetrics;
+use crate::invariants::{InvariantChecker, InvariantMetrics};
+use crate::key_groups::{KeyGroups, DEFAULT_MAX_GROUPS};
+use crate::keys::CacheKey;
+use crate::limits::KeyLimiter;
+use crate::listener::BoundAddresses;
+#[cfg(feature = "persistence")]
+use crate::pins::Pins;
+use crate::pressure::MemoryPressure;
+#[cfg(feature = "admin")]
+use crate::purge::Purges;
+use crate::redis::{RedisMetrics, RedisTier};
+#[cfg(feature = "persistence")]
+use crate::refresh::Scheduler;
+use crate::schema::{SchemaErrors, Schemas};
+#[cfg(feature = "metrics")]
+use crate::scrape::Scrapes;
+#[cfg(feature = "scripting")]
+use crate::script::Scripts;
+use crate::settings::{ConsistencyLevel, Settings};
+use crate::shadow::Shadow;
+use crate::slab::{SlabAllocator, SlabMetrics};
+use crate::slo::SloTracker;
+use crate::stampede::{QueueMetrics, RequestQueue};
+#[cfg(feature = "metrics")]
+use crate::statsd::StatsdExporter;
+use crate::supervisor::{supervise, Backoff};
+use crate::throughput::Throughput;
+use crate::trace::TraceRecorder;
+use crate::ttl::{Expiry, TtlPolicy};
+use crate::usage::{UsageMetrics, UsageTracker};
+use crate::value::{Value, DEFAULT_CHUNK_SIZE, DEFAULT_MAX_VALUE_SIZE};
+use crate::write_checks::{Rejected, WriteChecks};
+use crate::write_gate::WriteGate;
+use actix_web::{
+    delete, dev::Server, get, http::header, middleware, patch, post, put, rt::signal::ctrl_c,
+    rt::System, web, web::Bytes, App, Error, HttpMessage, HttpRequest, HttpResponse, HttpServer,
+};
+use futures::{
+    channel::oneshot,
+    future::{join, join5, pending, select, Either, FutureExt},
+};
+use log4rs::file::{Deserializers, RawConfig};
 use prometheus::Registry;
-use std::{io, thread, thread::JoinHandle, time::Duration};
+use serde::Deserialize;
+use std::{env, io, net::TcpListener, path::Path, sync::Arc, time::Duration};
 
-#[get("/{key}")]
-async fn index_get<'a>(key: web::Path<String>, cache: web::Data<SimpleCache<'a>>) -> HttpResponse {
-    match cache.get(key.into_inner(), &|value| HttpResponse::Ok().body(value)) {
-        Some(value) => value,
+/// Listening sockets and entries received from a previous process.
+type Received = (Vec<TcpListener>, Vec<TcpListener>, Vec<ExportedEntry>);
+
+/// Queues concurrent read-through fetches of the same key.
+type ReadThroughQueue = RequestQueue<Option<Value>>;
+
+/// The logging configuration used when `logger_config_file` does not exist.
+const DEFAULT_LOGGING: &str = include_str!("../config/log4rs.yaml");
+
+#[derive(Deserialize)]
+struct GetQuery {
+    /// Comma separated top level fields to return from a JSON object.
+    fields: Option<String>,
+}
+
+/// Responds with `value`, or only the requested fields of a JSON object.
+fn respond(req: &HttpRequest, value: &Value, fields: Option<&str>) -> HttpResponse {
+    let fields: Vec<&str> = match fields {
+        Some(fields) => fields
+            .split(',')
+            .filter(|field| !field.is_empty())
+            .collect(),
+        None => return streaming::respond(req, value.clone()),
+    };
+    match json::project(value, &fields) {
+        Some(projected) if value.is_json() => HttpResponse::Ok().json(projected),
+        _ => HttpResponse::BadRequest().body("fields can only be selected from JSON objects"),
+    }
+}
+
+#[get("/{key:[^_].*}")]
+async fn index_get<'a>(
+    req: HttpRequest,
+    key: CacheKey,
+    query: web::Query<GetQuery>,
+    cache: web::Data<SimpleCache<'a>>,
+    settings: web::Data<settings::Cache>,
+    redis: Option<web::Data<RedisTier>>,
+    queue: Option<web::Data<ReadThroughQueue>>,
+) -> HttpResponse {
+    let path_key = key.into_inner();
+    let key = vary::storage_key(&settings.vary, &path_key, req.headers());
+    let strong = match consistency::level(&settings.consistency, &path_key, req.headers()) {
+        Ok(level) => level == ConsistencyLevel::Strong,
+        Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+    };
+    let fields = query.fields.as_deref();
+    let headers = |response: &mut HttpResponse| {
+        let meta = cache.meta(&key);
+        cache_control::apply(&settings.cache_control, &path_key, meta.as_ref(), response);
+        vary::apply(&settings.vary, &path_key, response);
+    };
+    // Strong reads skip the local copy when every write goes through to the Redis tier, without
+    // write-through the local copy may be the only one.
+    let bypass_local = strong && redis.as_ref().is_some_and(|redis| redis.write_through());
+    if !bypass_local {
+        if let Some(mut response) = cache.get(key.clone(), &|value| respond(&req, value, fields)) {
+            headers(&mut response);
+            return response;
+        }
+    }
+    let redis = match redis {
+        Some(redis) => redis,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    let fetch = || async {
+        let redis_key = key.clone();
+        let read_since = cache.now();
+        match web::block(move || redis.get(&redis_key)).await {
+            Ok(Some(value)) => match String::from_utf8(value) {
+                Ok(value) => {
+                    let value = Value::from(value);
+                    cache.refresh(&key, value.clone(), read_since);
+                    Some(value)
+                }
+                Err(err) => {
+                    log::warn!("Not caching key: {} from Redis as it is not UTF-8", key);
+                    Some(Value::from(Bytes::from(err.into_bytes())))
+                }
+            },
+            Ok(None) => None,
+            Err(err) => {
+                log::error!("Could not read key: {} from Redis. {}", key, err);
+                cache.internal_error("redis_read");
+                None
+            }
+        }
+    };
+    let value = match &queue {
+        Some(queue) => queue.fetch(&key, fetch).await,
+        None => fetch().await,
+    };
+    match value {
+        Some(value) => {
+            let mut response = respond(&req, &value, fields);
+            headers(&mut response);
+            response
+        }
         None => HttpResponse::NotFound().finish(),
     }
 }
 
-#[post("/{key}")]
+/// Writes a value, creating the key or, unless `api.post_creates_only` is set, replacing the
+/// value of an existing key.
+#[post("/{key:[^_].*}")]
+#[allow(clippy::too_many_arguments)]
 async fn index_post<'a>(
-    key: web::Path<String>,
-    value: String,
+    req: HttpRequest,
+    key: CacheKey,
+    payload: web::Payload,
+    cache: web::Data<SimpleCache<'a>>,
+    pressure: web::Data<MemoryPressure>,
+    limiter: web::Data<KeyLimiter>,
+    settings: web::Data<settings::Cache>,
+    api: web::Data<settings::Api>,
+    redis: Option<web::Data<RedisTier>>,
+) -> Result<HttpResponse, Error> {
+    write(
+        req,
+        key,
+        payload,
+        cache,
+        pressure,
+        limiter,
+        settings,
+        redis,
+        api.post_creates_only,
+    )
+    .await
+}
+
+/// Writes a value, creating the key or replacing the value of an existing key.
+#[put("/{key:[^_].*}")]
+#[allow(clippy::too_many_arguments)]
+async fn index_put<'a>(
+    req: HttpRequest,
+    key: CacheKey,
+    payload: web::Payload,
+    cache: web::Data<SimpleCache<'a>>,
+    pressure: web::Data<MemoryPressure>,
+    limiter: web::Data<KeyLimiter>,
+    settings: web::Data<settings::Cache>,
+    redis: Option<web::Data<RedisTier>>,
+) -> Result<HttpResponse, Error> {
+    write(
+        req, key, payload, cache, pressure, limiter, settings, redis, false,
+    )
+    .await
+}
+
+/// Stores the value sent in the body under `key`, responding 409 if `create_only` and the key
+/// exists.
+#[allow(clippy::too_many_arguments)]
+async fn write<'a>(
+    req: HttpRequest,
+    key: CacheKey,
+    payload: web::Payload,
+    cache: web::Data<SimpleCache<'a>>,
+    pressure: web::Data<MemoryPressure>,
+    limiter: web::Data<KeyLimiter>,
+    settings: web::Data<settings::Cache>,
+    redis: Option<web::Data<RedisTier>>,
+    create_only: bool,
+) -> Result<HttpResponse, Error> {
+    let checks = WriteChecks::new(&req, pressure, limiter, settings);
+    if let Err(rejected) = checks.admit(&cache) {
+        return Ok(rejected.response());
+    }
+    if let Err(rejected) = checks.check_key(&cache, &key) {
+        return Ok(rejected.response());
+    }
+    let settings = &checks.settings;
+    let key = vary::storage_key(&settings.vary, &key.into_inner(), req.headers());
+    let expiry = match TtlPolicy::new(settings).requested(&req, &key) {
+        Ok(expiry) => expiry,
+        Err(err) => return Ok(HttpResponse::BadRequest().body(err)),
+    };
+    let priority = match Priority::requested(&req) {
+        Ok(priority) => priority,
+        Err(err) => return Ok(HttpResponse::BadRequest().body(err)),
+    };
+    if create_only && cache.contains_key(&key) {
+        return Ok(HttpResponse::Conflict().body("The key already exists"));
+    }
+    if let Err(rejected) = checks.check_new_key(&cache, &key) {
+        return Ok(rejected.response());
+    }
+    let value = Value::read(
+        payload,
+        settings.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE),
+        settings.max_value_size.unwrap_or(DEFAULT_MAX_VALUE_SIZE),
+    )
+    .await?;
+    // Values sent as JSON are stored in JSON mode.
+    let json = req.content_type() == "application/json";
+    if json {
+        if let Err(err) = json::validate(&value) {
+            return Ok(HttpResponse::BadRequest().body(format!("Invalid JSON: {}", err)));
+        }
+    }
+    let value = value.into_json(json);
+    if let Err(rejected) = checks.check_value(&cache, &key, &value) {
+        return Ok(rejected.response());
+    }
+    let bytes = value.to_bytes();
+    let created = match checks.store(&cache, key.clone(), value, expiry, priority, create_only) {
+        Ok(created) => created,
+        Err(rejected) => return Ok(rejected.response()),
+    };
+    if let Some(redis) = redis.filter(|redis| redis.write_through()) {
+        let ttl = match expiry {
+            Expiry::After(ttl) => ttl,
+            _ => Duration::from_secs(settings.key_live_duration),
+        };
+        let redis_key = key.clone();
+        if let Err(err) = web::block(move || redis.set(&redis_key, &bytes, ttl)).await {
+            log::error!("Could not write key: {} through to Redis. {}", key, err);
+            cache.internal_error("redis_write");
+        }
+    }
+    if created {
+        Ok(HttpResponse::Created()
+            .header(header::LOCATION, req.path())
+            .finish())
+    } else {
+        Ok(HttpResponse::Ok().finish())
+    }
+}
+
+/// Returns a 422 listing why a value does not conform to the schema of its namespace.
+fn schema_errors(errors: SchemaErrors) -> HttpResponse {
+    HttpResponse::UnprocessableEntity().json(serde_json::json!({ "errors": errors.0 }))
+}
+
+/// Applies a JSON merge patch (RFC 7396) to a value stored in JSON mode.
+#[patch("/{key:[^_].*}")]
+#[allow(clippy::too_many_arguments)]
+async fn index_patch<'a>(
+    req: HttpRequest,
+    key: CacheKey,
+    payload: web::Payload,
+    cache: web::Data<SimpleCache<'a>>,
+    pressure: web::Data<MemoryPressure>,
+    limiter: web::Data<KeyLimiter>,
+    settings: web::Data<settings::Cache>,
+    redis: Option<web::Data<RedisTier>>,
+) -> Result<HttpResponse, Error> {
+    let checks = WriteChecks::new(&req, pressure, limiter, settings);
+    if let Err(rejected) = checks.admit(&cache) {
+        return Ok(rejected.response());
+    }
+    if let Err(rejected) = checks.check_key(&cache, &key) {
+        return Ok(rejected.response());
+    }
+    let settings = &checks.settings;
+    let key = vary::storage_key(&settings.vary, &key.into_inner(), req.headers());
+    let body = Value::read(
+        payload,
+        settings.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE),
+        settings.max_value_size.unwrap_or(DEFAULT_MAX_VALUE_SIZE),
+    )
+    .await?;
+    let patch: serde_json::Value = match serde_json::from_reader(body.reader()) {
+        Ok(patch) => patch,
+        Err(err) => return Ok(HttpResponse::BadRequest().body(format!("Invalid JSON: {}", err))),
+    };
+    // A patch grows the value by at most about the size of the patch.
+    if checks.pressure.check(cache.size()) && !cache.make_room(&key, body.len(), Priority::Normal) {
+        return Ok(Rejected::Pressure.response());
+    }
+    // The bytes the API key already stores under the key, read before the entry is locked.
+    let owned = checks
+        .usage
+        .as_ref()
+        .map(|usage| (usage, cache.owned_size(&key, usage)));
+    let patched = cache.update(&key, |value| {
+        if !value.is_json() {
+            return Err(HttpResponse::Conflict().body("Only JSON values can be patched"));
+        }
+        let patched = json::patch(value, &patch).map_err(|err| {
+            log::error!("Could not patch key: {}. {}", key, err);
+            HttpResponse::InternalServerError().finish()
+        })?;
+        cache.validate(&key, &patched).map_err(schema_errors)?;
+        if let Some((usage, owned)) = owned {
+            if !usage.within_quota(patched.len().saturating_sub(owned)) {
+                return Err(Rejected::OverQuota.response());
+            }
+        }
+        Ok(patched)
+    });
+    let bytes = match patched {
+        Some(Ok(value)) => value.to_bytes(),
+        Some(Err(response)) => return Ok(response),
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    if let Some(usage) = &checks.usage {
+        cache.set_owner(&key, usage);
+    }
+    if let Some(redis) = redis.filter(|redis| redis.write_through()) {
+        // The patched value keeps its expiry, so Redis gets the ttl it has left.
+        let ttl = cache
+            .meta(&key)
+            .filter(|meta| !meta.immortal)
+            .map_or(Duration::from_secs(settings.key_live_duration), |meta| {
+                Duration::from_millis(meta.ttl_ms)
+            });
+        let redis_key = key.clone();
+        if let Err(err) = web::block(move || redis.set(&redis_key, &bytes, ttl)).await {
+            log::error!("Could not write key: {} through to Redis. {}", key, err);
+            cache.internal_error("redis_write");
+        }
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Removes the value of `key` from memory, the disk tier and, with write-through, the Redis tier.
+#[delete("/{key:[^_].*}")]
+async fn index_delete<'a>(
+    req: HttpRequest,
+    key: CacheKey,
     cache: web::Data<SimpleCache<'a>>,
+    settings: web::Data<settings::Cache>,
+    redis: Option<web::Data<RedisTier>>,
 ) -> HttpResponse {
-    cache.put(key.into_inner(), value);
-    HttpResponse::Ok().finish()
+    let key = vary::storage_key(&settings.vary, &key.into_inner(), req.headers());
+    let removed = cache.remove(&key);
+    if let Some(redis) = redis.filter(|redis| redis.write_through()) {
+        let redis_key = key.clone();
+        if let Err(err) = web::block(move || redis.delete(&redis_key)).await {
+            log::error!("Could not delete key: {} from Redis. {}", key, err);
+            cache.internal_error("redis_delete");
+        }
+    }
+    if removed {
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::NotFound().finish()
+    }
 }
 
-fn start_cache_cleaner(cache: web::Data<SimpleCache<'static>>) -> JoinHandle<()> {
-    thread::spawn(move || {
-        let mut sys = System::new("cleaner");
-        let cleaner = SimpleCache::cleaner(cache.clone());
-        sys.block_on(cleaner);
-    })
+/// Completes when the process is asked to stop with SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use actix_web::rt::signal::unix::{signal, SignalKind};
+        if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+            select(Box::pin(ctrl_c()), Box::pin(terminate.recv())).await;
+            return;
+        }
+    }
+    let _ = ctrl_c().await;
+}
+
+/// Registers the script endpoint, if scripting is compiled in.
+#[cfg(feature = "scripting")]
+fn configure_scripts(cfg: &mut web::ServiceConfig) {
+    cfg.service(script::script);
+}
+
+#[cfg(not(feature = "scripting"))]
+fn configure_scripts(_: &mut web::ServiceConfig) {}
+
+/// Registers the routes of keys, relative to the path the data API is served under.
+fn configure_keys(cfg: &mut web::ServiceConfig) {
+    cfg.service(collections::list_push)
+        .service(collections::list_range)
+        .service(collections::set_add)
+        .service(collections::set_contains)
+        .service(collections::count)
+        .service(collections::bloom_create)
+        .service(collections::bloom_add)
+        .service(collections::bloom_contains)
+        .service(index_get)
+        .service(index_post)
+        .service(index_put)
+        .service(index_patch)
+        .service(index_delete);
 }
 
+#[allow(clippy::too_many_arguments)]
 fn start_cache_server(
     settings: settings::HttpServer,
+    listeners: Vec<TcpListener>,
     cache: web::Data<SimpleCache<'static>>,
-    http_metrics: PrometheusMetrics,
-) -> JoinHandle<()> {
-    thread::spawn(|| {
-        let mut sys = System::new("cache_server");
-        let mut cache_server = HttpServer::new(move || {
-            App::new()
-                .app_data(cache.clone()) // add shared state
-                .wrap(http_metrics.clone())
-                .wrap(middleware::Logger::default())
-                .service(index_get)
-                .service(index_post)
-        });
-        config_items! {
-            cache_server = settings;
-            workers,
-            backlog,
-            max_connections,
-            max_connection_rate,
-            client_timeout,
-            client_shutdown,
-            shutdown_timeout
-        };
-        for socket_addr in settings.listen_addresses {
-            cache_server = cache_server.bind(socket_addr).unwrap();
+    pressure: web::Data<MemoryPressure>,
+    limiter: web::Data<KeyLimiter>,
+    cache_settings: web::Data<settings::Cache>,
+    idempotency: settings::Idempotency,
+    usage: Option<web::Data<UsageTracker>>,
+    auditor: Option<web::Data<Auditor>>,
+    slo: Option<web::Data<SloTracker>>,
+    shadow: Option<web::Data<Shadow>>,
+    #[cfg(feature = "scripting")] scripts: Option<web::Data<Scripts>>,
+    redis: Option<web::Data<RedisTier>>,
+    queue: Option<web::Data<ReadThroughQueue>>,
+    cors: Option<web::Data<Cors>>,
+    http_metrics: Arc<HttpMetrics>,
+    api: web::Data<settings::Api>,
+    write_gate: web::Data<WriteGate>,
+) -> io::Result<Server> {
+    let mut cache_server = HttpServer::new(move || {
+        let idempotency = idempotency.clone();
+        let idempotency_cache = cache.clone();
+        let http_metrics = http_metrics.clone();
+        let mut app = App::new()
+            .app_data(cache.clone()) // add shared state
+            .app_data(pressure.clone())
+            .app_data(limiter.clone())
+            .app_data(cache_settings.clone())
+            .app_data(api.clone())
+            .app_data(write_gate.clone());
+        if let Some(usage) = &usage {
+            app = app.app_data(usage.clone());
+        }
+        if let Some(auditor) = &auditor {
+            app = app.app_data(auditor.clone());
+        }
+        if let Some(slo) = &slo {
+            app = app.app_data(slo.clone());
+        }
+        if let Some(shadow) = &shadow {
+            app = app.app_data(shadow.clone());
+        }
+        #[cfg(feature = "scripting")]
+        if let Some(scripts) = &scripts {
+            app = app.app_data(scripts.clone());
+        }
+        if let Some(redis) = &redis {
+            app = app.app_data(redis.clone());
+        }
+        if let Some(queue) = &queue {
+            app = app.app_data(queue.clone());
+        }
+        if let Some(cors) = &cors {
+            app = app.app_data(cors.clone());
+        }
+        let app = app
+            .wrap_fn(write_gate::guard)
+            .wrap_fn(move |req, srv| {
+                idempotency::deduplicate(&idempotency, &idempotency_cache, req, srv)
+            })
+            // Each middleware future is boxed, nested futures grow the type of the app with every
+            // layer until it no longer compiles.
+            .wrap_fn(|req, srv| usage::track(req, srv).boxed_local())
+            .wrap_fn(|req, srv| audit::audit(req, srv).boxed_local())
+            .wrap_fn(|req, srv| slo::track(req, srv).boxed_local())
+            .wrap_fn(|req, srv| shadow::mirror(req, srv).boxed_local())
+            .wrap_fn(move |req, srv| http_metrics::track(&http_metrics, req, srv).boxed_local())
+            .wrap(middleware::Logger::default())
+            .wrap_fn(|req, srv| status::answer(req, srv).boxed_local())
+            .wrap_fn(|req, srv| cors::handle(req, srv).boxed_local())
+            .service(pipeline::pipeline)
+            .configure(configure_scripts)
+            .service(txn::txn)
+            .service(batch::batch_get)
+            .service(batch::batch_put)
+            // Registered before the legacy routes, which would match /v1/keys/... as a key.
+            .service(web::scope("/v1/keys").configure(configure_keys));
+        if api.legacy_routes {
+            app.configure(configure_keys)
+        } else {
+            app
         }
-        let srv = cache_server.run();
-        sys.block_on(srv).unwrap();
     })
+    .disable_signals();
+    config_items! {
+        cache_server = settings;
+        workers,
+        max_connections,
+        max_connection_rate,
+        client_timeout,
+        client_shutdown,
+        shutdown_timeout
+    };
+    for listener in listeners {
+        cache_server = cache_server.listen(listener)?;
+    }
+    Ok(cache_server.run())
 }
 
-fn configure_metrics(registry: Registry) -> (PrometheusMetrics, PrometheusMetrics) {
-    let http_metrics_with_api = PrometheusMetrics::new_with_registry(
-        registry.clone(),
-        "private_api",
-        Some("/metrics"),
-        None,
-    )
-    // It is safe to unwrap when __no other app has the same namespace__
-    .unwrap();
-    let http_metrics = PrometheusMetrics::new_with_registry(
-        registry.clone(),
-        "public_api",
-        // Metrics should not be available from the outside
-        None,
-        None,
-    )
-    .unwrap();
-    (http_metrics, http_metrics_with_api)
+/// Joins the configured namespace and subsystem with the api name, skipping empty parts.
+fn http_namespace(settings: &settings::Metrics, api: &str) -> String {
+    [settings.namespace.as_str(), settings.subsystem.as_str(), api]
+        .iter()
+        .filter(|part| !part.is_empty())
+        .cloned()
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+/// Returns the trace to replay when started with `--replay <path>`.
+fn replay_path() -> Option<String> {
+    env::args().skip_while(|arg| arg != "--replay").nth(1)
+}
+
+/// Returns true when started with the `selftest` subcommand.
+fn is_selftest() -> bool {
+    env::args().nth(1).as_deref() == Some("selftest")
+}
+
+/// Receives the state of the previous process when started with `--handoff`.
+#[cfg(all(unix, feature = "persistence"))]
+fn receive_handoff(settings: &settings::Handoff) -> Option<Received> {
+    if !env::args().any(|arg| arg == "--handoff") {
+        return None;
+    }
+    let path = match &settings.socket_path {
+        Some(path) => path,
+        None => {
+            log::warn!("Ignoring --handoff as handoff.socket_path is not set");
+            return None;
+        }
+    };
+    match handoff::receive(path) {
+        Ok(handoff) => Some((
+            handoff.cache_listeners,
+            handoff.metrics_listeners,
+            handoff.entries,
+        )),
+        Err(err) => {
+            log::warn!(
+                "Could not receive handoff from {}, starting empty. {}",
+                path,
+                err
+            );
+            None
+        }
+    }
+}
+
+#[cfg(all(unix, not(feature = "persistence")))]
+fn receive_handoff(_: &settings::Handoff) -> Option<Received> {
+    if env::args().any(|arg| arg == "--handoff") {
+        not_compiled_in("persistence", "--handoff");
+    }
+    None
 }
 
+#[cfg(not(unix))]
+fn receive_handoff(_: &settings::Handoff) -> Option<Received> {
+    if env::args().any(|arg| arg == "--handoff") {
+        log::warn!("Ignoring --handoff as it is not supported on this platform");
+    }
+    None
+}
+
+/// Waits for a replacement process when `handoff.socket_path` is set, the returned receiver
+/// completes once the cache has been handed off.
+#[cfg(all(unix, feature = "persistence"))]
+fn listen_for_handoff(
+    settings: &settings::Handoff,
+    (cache_server, metrics_server): (Vec<TcpListener>, Vec<TcpListener>),
+    cache: web::Data<SimpleCache<'static>>,
+    write_gate: web::Data<WriteGate>,
+    servers: Vec<Server>,
+) -> io::Result<Option<oneshot::Receiver<()>>> {
+    match &settings.socket_path {
+        Some(path) => {
+            let listeners = handoff::Listeners {
+                cache_server,
+                metrics_server,
+            };
+            handoff::listen(path, listeners, cache, write_gate, servers).map(Some)
+        }
+        None => Ok(None),
+    }
+}
+
+#[cfg(all(unix, not(feature = "persistence")))]
+fn listen_for_handoff(
+    settings: &settings::Handoff,
+    _: (Vec<TcpListener>, Vec<TcpListener>),
+    _: web::Data<SimpleCache<'static>>,
+    _: web::Data<WriteGate>,
+    _: Vec<Server>,
+) -> io::Result<Option<oneshot::Receiver<()>>> {
+    if settings.socket_path.is_some() {
+        not_compiled_in("persistence", "handoff.socket_path");
+    }
+    Ok(None)
+}
+
+#[cfg(not(unix))]
+fn listen_for_handoff(
+    _: &settings::Handoff,
+    _: (Vec<TcpListener>, Vec<TcpListener>),
+    _: web::Data<SimpleCache<'static>>,
+    _: web::Data<WriteGate>,
+    _: Vec<Server>,
+) -> io::Result<Option<oneshot::Receiver<()>>> {
+    Ok(None)
+}
+
+/// Logs that `setting` is ignored as `feature` is not compiled in.
+/// # Arguments
+/// * `feature` - The name of the cargo feature.
+/// * `setting` - The setting or argument that needs the feature.
+#[cfg(not(all(feature = "metrics", feature = "persistence", feature = "scripting")))]
+fn not_compiled_in(feature: &str, setting: &str) {
+    log::warn!(
+        "Ignoring {} as this build does not include the {} feature",
+        setting,
+        feature
+    );
+}
+
+/// Initializes logging from `path`, or from the configuration compiled in when there is no such
+/// file, e.g. in a container with no files mounted.
+fn init_logging(path: &str) {
+    if Path::new(path).exists() {
+        return log4rs::init_file(path, Default::default()).unwrap();
+    }
+    let config: RawConfig = serde_yaml::from_str(DEFAULT_LOGGING).unwrap();
+    let (appenders, errors) = config.appenders_lossy(&Deserializers::default());
+    assert!(errors.is_empty(), "Invalid default logging: {:?}", errors);
+    let (config, errors) = log4rs::config::Config::builder()
+        .appenders(appenders)
+        .loggers(config.loggers())
+        .build_lossy(config.root());
+    assert!(errors.is_empty(), "Invalid default logging: {:?}", errors);
+    log4rs::init_config(config).unwrap();
+}
+
+fn configure_metrics(
+    registry: &Registry,
+    settings: &settings::Metrics,
+) -> (Arc<HttpMetrics>, Arc<HttpMetrics>) {
+    // Metrics are served by the admin endpoints only
+    let http_metrics_with_api = HttpMetrics::new(
+        &http_namespace(settings, "private_api"),
+        settings.const_labels.clone(),
+    );
+    let http_metrics = HttpMetrics::new(
+        &http_namespace(settings, "public_api"),
+        settings.const_labels.clone(),
+    );
+    http_metrics_with_api.register(registry);
+    http_metrics.register(registry);
+    (Arc::new(http_metrics), Arc::new(http_metrics_with_api))
+}
+
+#[allow(clippy::too_many_arguments)]
 fn start_metrics_server(
     settings: settings::HttpServer,
-    http_metrics_with_api: PrometheusMetrics,
-) -> JoinHandle<()> {
-    thread::spawn(move || {
-        let mut sys = System::new("metrics_server");
-        let mut metrics_server = HttpServer::new(move || {
-            App::new()
-                .wrap(http_metrics_with_api.clone())
-                .wrap(middleware::Logger::default())
-        });
-
-        config_items! {
-            metrics_server = settings;
-            workers,
-            backlog,
-            max_connections,
-            max_connection_rate,
-            client_timeout,
-            client_shutdown,
-            shutdown_timeout
-        };
-        for socket_addr in settings.listen_addresses {
-            metrics_server = metrics_server.bind(socket_addr).unwrap();
+    listeners: Vec<TcpListener>,
+    http_metrics_with_api: Arc<HttpMetrics>,
+    cache: web::Data<SimpleCache<'static>>,
+    pressure: web::Data<MemoryPressure>,
+    config: web::Data<Settings>,
+    registry: web::Data<Registry>,
+    bound_addresses: web::Data<BoundAddresses>,
+    usage: Option<web::Data<UsageTracker>>,
+    auditor: Option<web::Data<Auditor>>,
+    key_groups: Option<web::Data<KeyGroups>>,
+    write_gate: web::Data<WriteGate>,
+) -> io::Result<Server> {
+    let auth_token = config.admin.auth_token.clone();
+    #[cfg(feature = "admin")]
+    let purges = web::Data::new(Purges::default());
+    #[cfg(feature = "metrics")]
+    let scrapes = web::Data::new(Scrapes::new(Duration::from_millis(
+        config.metrics.scrape_cache_ms,
+    )));
+    let mut metrics_server = HttpServer::new(move || {
+        let auth_token = auth_token.clone();
+        let http_metrics_with_api = http_metrics_with_api.clone();
+        let mut app = App::new()
+            .app_data(cache.clone())
+            .app_data(pressure.clone())
+            .app_data(config.clone())
+            .app_data(registry.clone())
+            .app_data(bound_addresses.clone())
+            .app_data(write_gate.clone());
+        #[cfg(feature = "admin")]
+        {
+            app = app.app_data(purges.clone());
         }
-
-        let srv = metrics_server.run();
-        sys.block_on(srv).unwrap();
+        #[cfg(feature = "metrics")]
+        {
+            app = app.app_data(scrapes.clone());
+        }
+        if let Some(usage) = &usage {
+            app = app.app_data(usage.clone());
+        }
+        if let Some(auditor) = &auditor {
+            app = app.app_data(auditor.clone());
+        }
+        if let Some(key_groups) = &key_groups {
+            app = app.app_data(key_groups.clone());
+        }
+        app.wrap_fn(write_gate::guard)
+            .wrap_fn(move |req, srv| admin::authorize(&auth_token, req, srv))
+            .wrap_fn(|req, srv| audit::audit(req, srv).boxed_local())
+            .wrap_fn(move |req, srv| {
+                http_metrics::track(&http_metrics_with_api, req, srv).boxed_local()
+            })
+            .wrap(middleware::Logger::default())
+            .configure(admin::configure)
     })
+    .disable_signals();
+
+    config_items! {
+        metrics_server = settings;
+        workers,
+        max_connections,
+        max_connection_rate,
+        client_timeout,
+        client_shutdown,
+        shutdown_timeout
+    };
+    for listener in listeners {
+        metrics_server = metrics_server.listen(listener)?;
+    }
+
+    Ok(metrics_server.run())
 }
 
 fn main() -> std::io::Result<()> {
+    if let Some(command) = service::Command::from_args() {
+        return command.run();
+    }
+    #[cfg(windows)]
+    {
+        if service::is_service() {
+            return service::dispatch();
+        }
+    }
+    System::new("main").block_on(run(None)).map_err(|err| {
+        log::error!("{}", err);
+        err
+    })
+}
+
+/// Runs the servers until the process is asked to stop.
+/// # Arguments
+/// * `service_stop` - When running as a Windows service, completes when the service is stopped.
+async fn run(service_stop: Option<oneshot::Receiver<()>>) -> io::Result<()> {
+    let settings = match Settings::new() {
+        Ok(settings) => settings,
+        Err(err) => return Err(io::Error::other(err)),
+    };
+    let config = web::Data::new(settings.clone());
     let Settings {
         cache: cache_settings,
         cache_server: cache_server_settings,
         metrics_server: metrics_server_settings,
         logger_config_file,
-    } = match Settings::new() {
-        Ok(settings) => settings,
-        Err(err) => return Err(io::Error::new(io::ErrorKind::Other, err)),
-    };
+        metrics: metrics_settings,
+        statsd: statsd_settings,
+        slo: slo_settings,
+        handoff: handoff_settings,
+        memory_pressure: memory_pressure_settings,
+        disk_tier: disk_tier_settings,
+        redis: redis_settings,
+        key_limits: key_limits_settings,
+        idempotency: idempotency_settings,
+        usage: usage_settings,
+        audit: audit_settings,
+        scripting: scripting_settings,
+        shadow: shadow_settings,
+        trace: trace_settings,
+        api: api_settings,
+        discovery: discovery_settings,
+        refresh: refresh_settings,
+        cors: cors_settings,
+        sandbox: sandbox_settings,
+        invariants: invariants_settings,
+        ..
+    } = settings;
 
-    log4rs::init_file(logger_config_file, Default::default()).unwrap();
+    // A Windows service logs to the event log.
+    if service_stop.is_none() {
+        init_logging(&logger_config_file);
+    }
+
+    if let Some(path) = replay_path() {
+        return replay::run(&path, &config);
+    }
+    if is_selftest() {
+        return selftest::run(&config);
+    }
+    log::info!("Starting {}", BuildInfo::new(&config));
+    // Confined before any other file is opened, so every path is resolved inside the chroot.
+    #[cfg(unix)]
+    let privileges = sandbox::confine(&sandbox_settings)?;
+    #[cfg(not(unix))]
+    if sandbox_settings.user.is_some()
+        || sandbox_settings.group.is_some()
+        || sandbox_settings.chroot.is_some()
+    {
+        log::warn!("Ignoring sandbox as it is not supported on this platform");
+    }
 
     let registry = prometheus::default_registry();
-    let (http_metrics, http_metrics_with_api) = configure_metrics(registry.clone());
+    let (http_metrics, http_metrics_with_api) = configure_metrics(registry, &metrics_settings);
 
     let key_live_duration = Duration::from_secs(cache_settings.key_live_duration);
-    let cache_metrics = CacheMetrics::new();
+    let metric_opts = MetricOpts {
+        namespace: metrics_settings.namespace,
+        subsystem: metrics_settings.subsystem,
+        const_labels: metrics_settings.const_labels,
+    };
+    let cache_metrics = CacheMetrics::with_opts(&metric_opts).with_size_buckets(
+        cache_settings
+            .size_buckets
+            .as_deref()
+            .unwrap_or(DEFAULT_SIZE_BUCKETS),
+    );
     cache_metrics.register(registry);
-    let cache = web::Data::new(SimpleCache::new(key_live_duration, cache_metrics));
+    let experiment_capacity = cache_settings
+        .eviction_experiment
+        .capacity
+        .or(memory_pressure_settings.high_water_mark);
+    let pressure = MemoryPressure::new(memory_pressure_settings, &metric_opts);
+    pressure.register(registry);
+    let pressure = web::Data::new(pressure);
+    let limiter = KeyLimiter::new(key_limits_settings, &metric_opts);
+    limiter.register(registry);
+    let usage = if usage_settings.enabled {
+        let usage_metrics = UsageMetrics::with_opts(&metric_opts);
+        usage_metrics.register(registry);
+        Some(web::Data::new(UsageTracker::new(
+            usage_settings,
+            usage_metrics,
+        )))
+    } else {
+        None
+    };
+    let auditor = if audit_settings.enabled {
+        let audit_metrics = AuditMetrics::with_opts(&metric_opts);
+        audit_metrics.register(registry);
+        let auditor = Auditor::new(audit_settings, audit_metrics, cache_metrics.clone())?;
+        Some(web::Data::new(auditor))
+    } else {
+        None
+    };
+    let slo = if slo_settings.enabled {
+        let slo = SloTracker::new(slo_settings, &metric_opts);
+        slo.register(registry);
+        Some(web::Data::new(slo))
+    } else {
+        None
+    };
+    let shadow = if shadow_settings.url.is_some() {
+        let shadow = Shadow::new(shadow_settings, &metric_opts);
+        shadow.register(registry);
+        Some(web::Data::new(shadow))
+    } else {
+        None
+    };
+    #[cfg(feature = "scripting")]
+    let scripts = match &scripting_settings.dir {
+        Some(dir) => Some(web::Data::new(Scripts::load(
+            dir,
+            Duration::from_millis(scripting_settings.timeout),
+        )?)),
+        None => None,
+    };
+    #[cfg(not(feature = "scripting"))]
+    if scripting_settings.dir.is_some() {
+        not_compiled_in("scripting", "scripting.dir");
+    }
+    let cors = Cors::new(cors_settings)?.map(web::Data::new);
+    let cleaner_restarts = cache_metrics.cleaner_restarts.clone();
+    let throughput = Throughput::new(metrics_settings.throughput_prefixes, &metric_opts);
+    throughput.register(registry);
+    let key_groups = match &metrics_settings.key_group {
+        Some(pattern) => {
+            let max_groups = metrics_settings
+                .max_key_groups
+                .unwrap_or(DEFAULT_MAX_GROUPS);
+            let key_groups = KeyGroups::new(pattern, max_groups, &metric_opts).map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid metrics.key_group {:?}. {}", pattern, err),
+                )
+            })?;
+            key_groups.register(registry);
+            Some(key_groups)
+        }
+        None => None,
+    };
+    let write_gate = web::Data::new(WriteGate::default());
+    let trace_metrics = cache_metrics.clone();
+    let mut cache = SimpleCache::new(key_live_duration, cache_metrics)
+        .with_write_gate(write_gate.clone())
+        .with_checksums(cache_settings.checksum, cache_settings.verify_checksums)
+        .with_eviction_policy(cache_settings.eviction_policy)
+        .with_chunk_size(cache_settings.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE))
+        .with_key_rules(cache_settings.keys.clone())
+        .with_plugin(throughput);
+    if let Some(key_groups) = &key_groups {
+        cache = cache.with_plugin(key_groups.clone());
+    }
+    if let Some(path) = trace_settings.path.clone() {
+        cache = cache.with_plugin(TraceRecorder::new(trace_settings, &path, trace_metrics)?);
+    }
+    if let Some(policy) = cache_settings.eviction_experiment.policy {
+        match experiment_capacity {
+            Some(capacity) => {
+                let experiment = EvictionExperiment::new(
+                    cache_settings.eviction_policy,
+                    policy,
+                    capacity,
+                    &metric_opts,
+                );
+                experiment.register(registry);
+                cache = cache.with_plugin(experiment);
+            }
+            None => log::warn!("The eviction experiment needs a capacity or a high-water mark"),
+        }
+    }
+    let deny_settings = &cache_settings.deny_keys;
+    if !deny_settings.prefixes.is_empty() || !deny_settings.patterns.is_empty() {
+        let deny_list =
+            DenyList::new(deny_settings.clone(), &metric_opts).map_err(io::Error::other)?;
+        deny_list.register(registry);
+        cache = cache.with_deny_list(deny_list);
+    }
+    if !cache_settings.schemas.is_empty() {
+        cache = cache.with_schemas(Schemas::load(&cache_settings.schemas)?);
+    }
+    if cache_settings.adaptive_ttl.enabled {
+        cache = cache.with_adaptive_ttl(cache_settings.adaptive_ttl.clone());
+    }
+    if let Some(time_to_idle) = cache_settings.time_to_idle {
+        cache = cache.with_time_to_idle(Duration::from_secs(time_to_idle));
+    }
+    let slab_settings = &cache_settings.slab;
+    if slab_settings.enabled {
+        let slab_metrics = SlabMetrics::with_opts(&metric_opts);
+        slab_metrics.register(registry);
+        cache = cache.with_slab_allocator(SlabAllocator::new(
+            slab_settings.slab_size,
+            slab_settings.max_value_size,
+            slab_metrics,
+        ));
+    }
+    #[cfg(feature = "persistence")]
+    let mut sweeper_restarts = None;
+    #[cfg(feature = "persistence")]
+    if let Some(path) = &disk_tier_settings.path {
+        let disk_metrics = DiskMetrics::with_opts(&metric_opts);
+        disk_metrics.register(registry);
+        sweeper_restarts = Some(disk_metrics.sweeper_restarts.clone());
+        if !cache.evicts() {
+            log::warn!("Nothing is written to the disk tier as cache.eviction_policy is none");
+        }
+        cache = cache.with_disk_tier(DiskTier::open(path, disk_metrics)?);
+    }
+    #[cfg(feature = "persistence")]
+    if let Some(path) = &cache_settings.pins_path {
+        cache = cache.with_pins(Pins::load(path)?);
+    }
+    #[cfg(not(feature = "persistence"))]
+    {
+        if disk_tier_settings.path.is_some() {
+            not_compiled_in("persistence", "disk_tier.path");
+        }
+        if cache_settings.pins_path.is_some() {
+            not_compiled_in("persistence", "cache.pins_path");
+        }
+        if !refresh_settings.jobs.is_empty() {
+            not_compiled_in("persistence", "refresh.jobs");
+        }
+    }
+    let cache = web::Data::new(cache);
+    let (redis, queue) = match &redis_settings.url {
+        Some(_) => {
+            let redis_metrics = RedisMetrics::with_opts(&metric_opts);
+            redis_metrics.register(registry);
+            let queue_metrics = QueueMetrics::with_opts(&metric_opts);
+            queue_metrics.register(registry);
+            (
+                Some(web::Data::new(RedisTier::new(
+                    &redis_settings,
+                    redis_metrics,
+                )?)),
+                Some(web::Data::new(RequestQueue::new(
+                    Duration::from_millis(redis_settings.queue_timeout),
+                    queue_metrics,
+                ))),
+            )
+        }
+        None => (None, None),
+    };
+
+    let (cache_listeners, metrics_listeners) = match receive_handoff(&handoff_settings) {
+        Some((cache_listeners, metrics_listeners, entries)) => {
+            cache.import(entries);
+            (cache_listeners, metrics_listeners)
+        }
+        None => {
+            #[cfg(feature = "persistence")]
+            if let Some(path) = &cache_settings.warmup_manifest {
+                warmup::load(path, &cache, &cache_settings).await?;
+            }
+            #[cfg(not(feature = "persistence"))]
+            if cache_settings.warmup_manifest.is_some() {
+                not_compiled_in("persistence", "cache.warmup_manifest");
+            }
+            (
+                listener::bind("cache server", &cache_server_settings).await?,
+                listener::bind("metrics server", &metrics_server_settings).await?,
+            )
+        }
+    };
 
-    start_cache_cleaner(cache.clone());
-    let thread_cache_server = start_cache_server(cache_server_settings, cache, http_metrics);
-    let thread_metrics_server =
-        start_metrics_server(metrics_server_settings, http_metrics_with_api);
+    #[cfg(unix)]
+    sandbox::drop_privileges(privileges)?;
 
-    thread_cache_server.join().unwrap();
-    thread_metrics_server.join().unwrap();
+    let (stop_tasks, tasks_stopped) = oneshot::channel::<()>();
+    let tasks_stopped = tasks_stopped.map(|_| ()).shared();
+    let cleaner_cache = cache.clone();
+    let cleaner = supervise(
+        "cache cleaner",
+        cleaner_restarts,
+        Backoff::default(),
+        tasks_stopped.clone(),
+        move || SimpleCache::cleaner(cleaner_cache.clone()),
+    );
+    #[cfg(feature = "metrics")]
+    let pusher = {
+        let statsd = if statsd_settings.enabled {
+            Some(StatsdExporter::new(statsd_settings, registry.clone())?)
+        } else {
+            None
+        };
+        let statsd_stopped = tasks_stopped.clone();
+        async move {
+            if let Some(statsd) = statsd {
+                select(Box::pin(statsd.run()), statsd_stopped).await;
+            }
+        }
+    };
+    #[cfg(not(feature = "metrics"))]
+    let pusher = {
+        if statsd_settings.enabled {
+            not_compiled_in("metrics", "statsd.enabled");
+        }
+        async {}
+    };
+    #[cfg(feature = "persistence")]
+    let refresher = {
+        let scheduler = Scheduler::new(refresh_settings, cache_settings.clone())?;
+        let refresher_cache = cache.clone();
+        let refresher_gate = write_gate.clone();
+        let refresher_stopped = tasks_stopped.clone();
+        async move {
+            if let Some(scheduler) = scheduler {
+                let run = scheduler.run(refresher_cache, refresher_gate);
+                select(Box::pin(run), refresher_stopped).await;
+            }
+        }
+    };
+    #[cfg(not(feature = "persistence"))]
+    let refresher = async {};
+    let sampler_pressure = pressure.clone();
+    let sampler_stopped = tasks_stopped.clone();
+    let sampler = async move {
+        let sampler = join(sampler_pressure.sampler(), sampler_pressure.notifier());
+        select(Box::pin(sampler), sampler_stopped).await;
+    };
+    let invariant_metrics = InvariantMetrics::with_opts(&metric_opts);
+    invariant_metrics.register(registry);
+    let checker = InvariantChecker::new(&invariants_settings, key_live_duration, invariant_metrics);
+    let checker_cache = cache.clone();
+    let checker_stopped = tasks_stopped.clone();
+    let checker = async move {
+        if let Some(checker) = checker {
+            select(Box::pin(checker.run(checker_cache)), checker_stopped).await;
+        }
+    };
+    #[cfg(feature = "persistence")]
+    let sweeper = {
+        let sweeper_cache = cache.clone();
+        let sweep_interval = Duration::from_secs(disk_tier_settings.sweep_interval);
+        async move {
+            if let Some(restarts) = sweeper_restarts {
+                supervise(
+                    "disk tier sweeper",
+                    restarts,
+                    Backoff::default(),
+                    tasks_stopped,
+                    move || {
+                        let cache = sweeper_cache.clone();
+                        async move {
+                            if let Some(disk_tier) = cache.disk_tier() {
+                                disk_tier.sweeper(sweep_interval).await;
+                            }
+                        }
+                    },
+                )
+                .await;
+            }
+        }
+    };
+    #[cfg(not(feature = "persistence"))]
+    let sweeper = async {};
+
+    let bound_addresses = web::Data::new(BoundAddresses {
+        cache_server: listener::local_addrs(&cache_listeners)?,
+        metrics_server: listener::local_addrs(&metrics_listeners)?,
+    });
+    let registration = Registration::new(discovery_settings, &bound_addresses);
+
+    let handoff_listeners = (
+        listener::try_clone_all(&cache_listeners)?,
+        listener::try_clone_all(&metrics_listeners)?,
+    );
+    let cache_server = start_cache_server(
+        cache_server_settings,
+        cache_listeners,
+        cache.clone(),
+        pressure.clone(),
+        web::Data::new(limiter),
+        web::Data::new(cache_settings),
+        idempotency_settings,
+        usage.clone(),
+        auditor.clone(),
+        slo,
+        shadow,
+        #[cfg(feature = "scripting")]
+        scripts,
+        redis,
+        queue,
+        cors,
+        http_metrics,
+        web::Data::new(api_settings),
+        write_gate.clone(),
+    )?;
+    let metrics_server = start_metrics_server(
+        metrics_server_settings,
+        metrics_listeners,
+        http_metrics_with_api,
+        cache.clone(),
+        pressure,
+        config,
+        web::Data::new(registry.clone()),
+        bound_addresses,
+        usage,
+        auditor,
+        key_groups.map(web::Data::new),
+        write_gate.clone(),
+    )?;
+    let handed_off = listen_for_handoff(
+        &handoff_settings,
+        handoff_listeners,
+        cache,
+        write_gate,
+        vec![cache_server.clone(), metrics_server.clone()],
+    )?;
+
+    let handed_off = async {
+        match handed_off {
+            Some(handed_off) => match handed_off.await {
+                Ok(()) => log::info!("Handed off to the new process"),
+                Err(_) => pending().await,
+            },
+            None => pending().await,
+        }
+    };
+
+    // Every subsystem is stopped when a signal is received, either server stops or the cache has
+    // been handed off.
+    let shutdown = async {
+        if let Some(registration) = &registration {
+            registration.register().await;
+        }
+        let servers = select(cache_server.clone(), metrics_server.clone());
+        let service_stopped = async {
+            match service_stop {
+                Some(stop) => {
+                    let _ = stop.await;
+                }
+                None => pending().await,
+            }
+        };
+        let signal = select(Box::pin(shutdown_signal()), Box::pin(service_stopped));
+        let stopped = select(signal, servers);
+        let stopped = select(stopped, Box::pin(handed_off)).await;
+        log::info!("Shutting down");
+        // The new process keeps the registration after a handoff.
+        if let (Some(registration), Either::Left(_)) = (&registration, stopped) {
+            registration.deregister().await;
+        }
+        join(cache_server.stop(true), metrics_server.stop(true)).await;
+        let _ = stop_tasks.send(());
+    };
+    join5(
+        cleaner,
+        join(sweeper, sampler),
+        join(pusher, checker),
+        refresher,
+        shutdown,
+    )
+    .await;
     Ok(())
 }
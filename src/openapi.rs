@@ -0,0 +1,52 @@
+//! The OpenAPI 3 description of the cache and metrics servers, served at `/_admin/openapi.json` so
+//! client teams can generate typed clients, and Swagger UI for it at `/_admin/docs` when
+//! `admin.swagger_ui` is enabled.
+//!
+//! The document is kept by hand in `api/openapi.json`, endpoints are added to it with their routes.
+
+/// The OpenAPI document.
+pub const DOCUMENT: &str = include_str!("../api/openapi.json");
+
+/// The version of Swagger UI loaded from its CDN.
+const SWAGGER_UI_VERSION: &str = "3.52.5";
+
+/// Returns a page running Swagger UI on the document at `url`.
+pub fn swagger_ui(url: &str) -> String {
+    format!(
+        r##"<!DOCTYPE html>
+<html>
+<head>
+<title>simple-mem-cache API</title>
+<link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@{version}/swagger-ui.css">
+</head>
+<body>
+<div id="swagger-ui"></div>
+<script src="https://unpkg.com/swagger-ui-dist@{version}/swagger-ui-bundle.js"></script>
+<script>SwaggerUIBundle({{ url: "{url}", dom_id: "#swagger-ui" }});</script>
+</body>
+</html>
+"##,
+        version = SWAGGER_UI_VERSION,
+        url = url
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn the_document_describes_the_routes() {
+        let document: serde_json::Value = serde_json::from_str(DOCUMENT).unwrap();
+
+        assert_eq!(document["openapi"], "3.0.3");
+        for path in &[
+            "/v1/keys/{key}",
+            "/_txn",
+            "/_status",
+            "/_admin/openapi.json",
+        ] {
+            assert!(document["paths"][path].is_object(), "{}", path);
+        }
+    }
+}
@@ -0,0 +1,226 @@
+//! Writes metrics in the OpenMetrics text format, served by `/metrics` to scrapers that ask for it
+//! with `Accept: application/openmetrics-text`. Other scrapers get the Prometheus text format.
+//!
+//! Counter families are named without their `_total` suffix, which every counter sample has, and
+//! histograms get a `+Inf` bucket. With `metrics.timestamps`, samples carry the time of the scrape.
+use actix_web::{http::header, HttpRequest};
+use prometheus::proto::{LabelPair, MetricFamily, MetricType};
+use std::fmt::Write;
+
+/// The content type of the OpenMetrics text format.
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Returns true if `req` accepts the OpenMetrics text format.
+pub fn accepted(req: &HttpRequest) -> bool {
+    req.headers()
+        .get_all(header::ACCEPT)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|media_type| {
+            let mut params = media_type.split(';').map(str::trim);
+            params.next() == Some("application/openmetrics-text")
+                && params.all(|param| param.replace(' ', "") != "q=0")
+        })
+}
+
+/// Sets the timestamp of every sample in `families` to `timestamp_ms`.
+pub fn set_timestamps(families: &mut [MetricFamily], timestamp_ms: i64) {
+    for family in families {
+        for metric in family.mut_metric().iter_mut() {
+            metric.set_timestamp_ms(timestamp_ms);
+        }
+    }
+}
+
+/// Escapes a label value or help text.
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Formats a sample value, with the special values spelled as OpenMetrics requires.
+fn number(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".into()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.into()
+    } else {
+        format!("{:?}", value)
+    }
+}
+
+/// Writes one sample line, `extra` is a label added after those of the metric.
+fn sample(
+    out: &mut String,
+    name: &str,
+    labels: &[LabelPair],
+    extra: Option<(&str, String)>,
+    value: f64,
+    timestamp_ms: i64,
+) {
+    out.push_str(name);
+    let mut labels: Vec<String> = labels
+        .iter()
+        .map(|label| format!("{}=\"{}\"", label.get_name(), escape(label.get_value())))
+        .collect();
+    if let Some((name, value)) = extra {
+        labels.push(format!("{}=\"{}\"", name, value));
+    }
+    if !labels.is_empty() {
+        let _ = write!(out, "{{{}}}", labels.join(","));
+    }
+    let _ = write!(out, " {}", number(value));
+    if timestamp_ms != 0 {
+        let _ = write!(out, " {}.{:03}", timestamp_ms / 1000, timestamp_ms % 1000);
+    }
+    out.push('\n');
+}
+
+/// Returns `families` in the OpenMetrics text format.
+pub fn encode(families: &[MetricFamily]) -> String {
+    let mut out = String::new();
+    for family in families {
+        let kind = family.get_field_type();
+        let name = match kind {
+            MetricType::COUNTER => {
+                let name = family.get_name();
+                name.strip_suffix("_total").unwrap_or(name)
+            }
+            _ => family.get_name(),
+        };
+        let type_name = match kind {
+            MetricType::COUNTER => "counter",
+            MetricType::GAUGE => "gauge",
+            MetricType::HISTOGRAM => "histogram",
+            MetricType::SUMMARY => "summary",
+            MetricType::UNTYPED => "unknown",
+        };
+        let _ = writeln!(out, "# TYPE {} {}", name, type_name);
+        let _ = writeln!(out, "# HELP {} {}", name, escape(family.get_help()));
+        for metric in family.get_metric() {
+            let labels = metric.get_label();
+            let timestamp_ms = metric.get_timestamp_ms();
+            match kind {
+                MetricType::COUNTER => {
+                    let total = format!("{}_total", name);
+                    let value = metric.get_counter().get_value();
+                    sample(&mut out, &total, labels, None, value, timestamp_ms);
+                }
+                MetricType::GAUGE => {
+                    let value = metric.get_gauge().get_value();
+                    sample(&mut out, name, labels, None, value, timestamp_ms);
+                }
+                MetricType::UNTYPED => {
+                    let value = metric.get_untyped().get_value();
+                    sample(&mut out, name, labels, None, value, timestamp_ms);
+                }
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    let bucket = format!("{}_bucket", name);
+                    let count = histogram.get_sample_count() as f64;
+                    for b in histogram.get_bucket() {
+                        let le = Some(("le", number(b.get_upper_bound())));
+                        let value = b.get_cumulative_count() as f64;
+                        sample(&mut out, &bucket, labels, le, value, timestamp_ms);
+                    }
+                    let le = Some(("le", number(f64::INFINITY)));
+                    sample(&mut out, &bucket, labels, le, count, timestamp_ms);
+                    let count_name = format!("{}_count", name);
+                    sample(&mut out, &count_name, labels, None, count, timestamp_ms);
+                    let sum_name = format!("{}_sum", name);
+                    let sum = histogram.get_sample_sum();
+                    sample(&mut out, &sum_name, labels, None, sum, timestamp_ms);
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    for q in summary.get_quantile() {
+                        let quantile = Some(("quantile", number(q.get_quantile())));
+                        let value = q.get_value();
+                        sample(&mut out, name, labels, quantile, value, timestamp_ms);
+                    }
+                    let count_name = format!("{}_count", name);
+                    let count = summary.get_sample_count() as f64;
+                    sample(&mut out, &count_name, labels, None, count, timestamp_ms);
+                    let sum_name = format!("{}_sum", name);
+                    let sum = summary.get_sample_sum();
+                    sample(&mut out, &sum_name, labels, None, sum, timestamp_ms);
+                }
+            }
+        }
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use prometheus::{Histogram, HistogramOpts, IntCounterVec, Opts, Registry};
+
+    fn registry() -> Registry {
+        let registry = Registry::new();
+        let requests = IntCounterVec::new(
+            Opts::new("requests_total", "A count of \"requests\""),
+            &["path"],
+        )
+        .unwrap();
+        requests.with_label_values(&["/a"]).inc_by(3);
+        registry.register(Box::new(requests)).unwrap();
+        let latency =
+            Histogram::with_opts(HistogramOpts::new("latency", "Latency").buckets(vec![1.0]))
+                .unwrap();
+        latency.observe(0.5);
+        registry.register(Box::new(latency)).unwrap();
+        registry
+    }
+
+    #[test]
+    fn families_are_encoded_as_openmetrics() {
+        let encoded = encode(&registry().gather());
+
+        assert_eq!(
+            encoded,
+            "# TYPE latency histogram\n\
+             # HELP latency Latency\n\
+             latency_bucket{le=\"1.0\"} 1.0\n\
+             latency_bucket{le=\"+Inf\"} 1.0\n\
+             latency_count 1.0\n\
+             latency_sum 0.5\n\
+             # TYPE requests counter\n\
+             # HELP requests A count of \\\"requests\\\"\n\
+             requests_total{path=\"/a\"} 3.0\n\
+             # EOF\n"
+        );
+    }
+
+    #[test]
+    fn samples_can_have_timestamps() {
+        let mut families = registry().gather();
+
+        set_timestamps(&mut families, 1_600_000_000_123);
+
+        assert!(encode(&families).contains("requests_total{path=\"/a\"} 3.0 1600000000.123\n"));
+    }
+
+    #[test]
+    fn openmetrics_is_negotiated_with_accept() {
+        let openmetrics = TestRequest::default()
+            .header(
+                header::ACCEPT,
+                "application/openmetrics-text; version=1.0.0, text/plain; q=0.5",
+            )
+            .to_http_request();
+        let refused = TestRequest::default()
+            .header(header::ACCEPT, "application/openmetrics-text; q=0")
+            .to_http_request();
+        let prometheus = TestRequest::default()
+            .header(header::ACCEPT, "text/plain")
+            .to_http_request();
+
+        assert!(accepted(&openmetrics));
+        assert!(!accepted(&refused));
+        assert!(!accepted(&prometheus));
+    }
+}
@@ -0,0 +1,163 @@
+//! Exempts keys and key prefixes pinned with `POST /_admin/pins` from eviction to make room for
+//! new keys. Pinned values still expire with their ttl, pin values written with `X-Ttl: 0` to keep
+//! them until they are removed.
+//!
+//! With `cache.pins_path` the pinned keys and prefixes are written to a JSON file on every change
+//! and read back when the server starts, so pins survive restarts.
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "persistence")]
+use std::path::Path;
+#[cfg(feature = "admin")]
+use std::path::PathBuf;
+use std::{collections::BTreeSet, sync::RwLock};
+#[cfg(any(feature = "admin", feature = "persistence"))]
+use std::{fs, io};
+
+/// A key or key prefix to pin or unpin.
+#[cfg(feature = "admin")]
+#[derive(Debug, Deserialize)]
+pub struct Pin {
+    pub key: String,
+    /// `key` is a prefix of the keys to pin.
+    #[serde(default)]
+    pub prefix: bool,
+}
+
+#[cfg(feature = "admin")]
+impl Pin {
+    /// Returns true if `key` is pinned by this pin.
+    pub fn matches(&self, key: &str) -> bool {
+        if self.prefix {
+            key.starts_with(self.key.as_str())
+        } else {
+            key == self.key
+        }
+    }
+}
+
+/// The pinned keys and key prefixes, in the form they are listed and saved.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct PinSet {
+    pub keys: BTreeSet<String>,
+    pub prefixes: BTreeSet<String>,
+}
+
+impl PinSet {
+    fn contains(&self, key: &str) -> bool {
+        self.keys.contains(key)
+            || self
+                .prefixes
+                .iter()
+                .any(|prefix| key.starts_with(prefix.as_str()))
+    }
+
+    #[cfg(feature = "admin")]
+    fn of(&mut self, pin: &Pin) -> &mut BTreeSet<String> {
+        if pin.prefix {
+            &mut self.prefixes
+        } else {
+            &mut self.keys
+        }
+    }
+}
+
+/// The keys exempt from eviction.
+#[derive(Default)]
+pub struct Pins {
+    pins: RwLock<PinSet>,
+    /// The file the pins are saved to.
+    #[cfg(feature = "admin")]
+    path: Option<PathBuf>,
+}
+
+impl Pins {
+    /// Returns the pins saved to `path`, which is created on the first change if it does not exist.
+    #[cfg(feature = "persistence")]
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let pins = match fs::read(path) {
+            Ok(json) => serde_json::from_slice(&json)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => PinSet::default(),
+            Err(err) => return Err(err),
+        };
+        log::info!(
+            "Loaded {} pinned keys and {} pinned prefixes from {}",
+            pins.keys.len(),
+            pins.prefixes.len(),
+            path.display()
+        );
+        Ok(Self {
+            pins: RwLock::new(pins),
+            #[cfg(feature = "admin")]
+            path: Some(path.to_path_buf()),
+        })
+    }
+
+    /// Returns true if `key` is pinned.
+    pub fn contains(&self, key: &str) -> bool {
+        self.pins.read().unwrap().contains(key)
+    }
+
+    /// Returns the pinned keys and prefixes.
+    #[cfg(feature = "admin")]
+    pub fn list(&self) -> PinSet {
+        self.pins.read().unwrap().clone()
+    }
+
+    /// Pins `pin`, returning false if it was already pinned.
+    #[cfg(feature = "admin")]
+    pub fn add(&self, pin: &Pin) -> io::Result<bool> {
+        self.change(|pins| pins.of(pin).insert(pin.key.clone()))
+    }
+
+    /// Unpins `pin`, returning false if it was not pinned.
+    #[cfg(feature = "admin")]
+    pub fn remove(&self, pin: &Pin) -> io::Result<bool> {
+        self.change(|pins| pins.of(pin).remove(&pin.key))
+    }
+
+    /// Applies `change` and saves the pins if it changed them. The change is kept in memory even
+    /// if saving fails.
+    #[cfg(feature = "admin")]
+    fn change<F: FnOnce(&mut PinSet) -> bool>(&self, change: F) -> io::Result<bool> {
+        let mut pins = self.pins.write().unwrap();
+        if !change(&mut pins) {
+            return Ok(false);
+        }
+        if let Some(path) = &self.path {
+            // Written to a temporary file first so a crash does not leave a partial file.
+            let temporary = path.with_extension("tmp");
+            fs::write(&temporary, serde_json::to_vec(&*pins)?)?;
+            fs::rename(&temporary, path)?;
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(all(test, feature = "admin", feature = "persistence"))]
+mod test {
+    use super::*;
+    use std::{env, process};
+
+    #[test]
+    fn pins_are_saved_and_loaded() {
+        let path = env::temp_dir().join(format!("simple-mem-cache-pins-{}.json", process::id()));
+        let sut = Pins::load(&path).unwrap();
+        let prefix = Pin {
+            key: "config/".into(),
+            prefix: true,
+        };
+
+        assert!(sut.add(&prefix).unwrap());
+        assert!(!sut.add(&prefix).unwrap());
+        assert!(sut.contains("config/flags"));
+        assert!(!sut.contains("users/1"));
+        let loaded = Pins::load(&path).unwrap();
+        assert!(loaded.contains("config/flags"));
+        assert!(sut.remove(&prefix).unwrap());
+        fs::remove_file(&path).unwrap();
+
+        assert!(!sut.contains("config/flags"));
+    }
+}
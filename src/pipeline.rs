@@ -0,0 +1,383 @@
+//! Applies a stream of newline delimited JSON operations, e.g. `{"op": "put", "key": "a",
+//! "value": "1"}` or `{"op": "get", "key": "a"}`, and streams back one JSON result per line in the
+//! same order. A put may set the eviction priority of its value like the `Cache-Priority` header,
+//! e.g. `"priority": "high"`, and goes through the checks of `POST /{key}`. Operations are applied
+//! as soon as their line has arrived, so bulk loads need neither one request per operation nor the
+//! whole body to be buffered.
+use crate::audit::{Auditor, Change};
+use crate::cache::SimpleCache;
+use crate::eviction::Priority;
+use crate::limits::KeyLimiter;
+use crate::pressure::MemoryPressure;
+use crate::settings;
+use crate::value::Value;
+use crate::write_checks::WriteChecks;
+use actix_web::{
+    post,
+    web::{self, BufMut, Bytes, BytesMut},
+    Error, HttpRequest, HttpResponse,
+};
+use futures::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+
+/// The longest operation accepted, longer lines end the pipeline. The values of puts are limited
+/// to `cache.max_value_size` like those of the single key endpoints.
+const MAX_LINE_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Operation {
+    Get {
+        key: String,
+    },
+    Put {
+        key: String,
+        value: String,
+        #[serde(default)]
+        priority: Priority,
+    },
+}
+
+impl Operation {
+    fn key_mut(&mut self) -> &mut String {
+        match self {
+            Operation::Get { key } | Operation::Put { key, .. } => key,
+        }
+    }
+}
+
+/// The result of one operation, `status` follows the status codes of the single key endpoints.
+#[derive(Debug, PartialEq, Serialize)]
+struct OperationResult {
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl OperationResult {
+    fn new(status: u16, key: String) -> Self {
+        Self {
+            status,
+            key: Some(key),
+            value: None,
+            error: None,
+        }
+    }
+
+    fn error(status: u16, error: String) -> Self {
+        Self {
+            status,
+            key: None,
+            value: None,
+            error: Some(error),
+        }
+    }
+}
+
+/// Applies the operation in `line` to the cache, and returns its result and the change it made.
+fn apply(
+    line: &[u8],
+    cache: &SimpleCache<'static>,
+    checks: &WriteChecks,
+) -> (OperationResult, Option<Change>) {
+    let mut operation: Operation = match serde_json::from_slice(line) {
+        Ok(operation) => operation,
+        Err(err) => return (OperationResult::error(400, err.to_string()), None),
+    };
+    // Keys follow the same rules as in the path of the single key endpoints.
+    let key = operation.key_mut();
+    match cache.key(key) {
+        Ok(normalized) => *key = normalized,
+        Err(err) => {
+            let result = OperationResult {
+                error: Some(err.to_string()),
+                ..OperationResult::new(400, key.clone())
+            };
+            return (result, None);
+        }
+    }
+    match operation {
+        Operation::Get { key } => match cache.get(key.clone(), &|value| {
+            String::from_utf8_lossy(&value.to_bytes()).into_owned()
+        }) {
+            Some(value) => {
+                let result = OperationResult {
+                    value: Some(value),
+                    ..OperationResult::new(200, key)
+                };
+                (result, None)
+            }
+            None => (OperationResult::new(404, key), None),
+        },
+        Operation::Put {
+            key,
+            value,
+            priority,
+        } => {
+            let size = value.len() as u64;
+            match checks.put(cache, key.clone(), Value::from(value), priority) {
+                Ok(_) => {
+                    let change = Change {
+                        operation: "put",
+                        key: key.clone(),
+                        size: Some(size),
+                    };
+                    (OperationResult::new(200, key), Some(change))
+                }
+                Err(rejected) => {
+                    let result = OperationResult {
+                        error: Some(rejected.to_string()),
+                        ..OperationResult::new(rejected.status().as_u16(), key)
+                    };
+                    (result, None)
+                }
+            }
+        }
+    }
+}
+
+fn write_result(out: &mut BytesMut, result: &OperationResult) {
+    // Serializing a struct of strings and numbers can not fail.
+    let mut line = serde_json::to_vec(result).unwrap();
+    line.push(b'\n');
+    out.put_slice(&line);
+}
+
+/// The state of a pipeline between chunks of the request body.
+struct Pipeline {
+    req: HttpRequest,
+    payload: web::Payload,
+    buffer: BytesMut,
+    cache: web::Data<SimpleCache<'static>>,
+    checks: WriteChecks,
+    auditor: Option<web::Data<Auditor>>,
+    done: bool,
+}
+
+impl Pipeline {
+    /// Applies every complete line in the buffer and returns their results.
+    fn apply_lines(&mut self) -> BytesMut {
+        let mut out = BytesMut::new();
+        while let Some(end) = self.buffer.iter().position(|&byte| byte == b'\n') {
+            let line = self.buffer.split_to(end + 1);
+            self.apply_line(&line[..end], &mut out);
+        }
+        if self.buffer.len() > MAX_LINE_SIZE {
+            let error = format!("Operation is longer than {} bytes", MAX_LINE_SIZE);
+            write_result(&mut out, &OperationResult::error(413, error));
+            self.done = true;
+        }
+        out
+    }
+
+    fn apply_line(&self, line: &[u8], out: &mut BytesMut) {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if !line.iter().all(u8::is_ascii_whitespace) {
+            let (result, change) = apply(line, &self.cache, &self.checks);
+            if let (Some(auditor), Some(change)) = (&self.auditor, change) {
+                auditor.write_changes(&self.req, &[change]);
+            }
+            write_result(out, &result);
+        }
+    }
+
+    /// Reads the request body until at least one result is available or the body ends.
+    async fn next_results(mut self) -> Option<(Result<Bytes, Error>, Self)> {
+        while !self.done {
+            match self.payload.next().await {
+                Some(Ok(chunk)) => {
+                    self.buffer.extend_from_slice(&chunk);
+                    let out = self.apply_lines();
+                    if !out.is_empty() {
+                        return Some((Ok(out.freeze()), self));
+                    }
+                }
+                Some(Err(err)) => {
+                    self.done = true;
+                    return Some((Err(err.into()), self));
+                }
+                None => {
+                    // The last line does not need to end with a newline.
+                    self.done = true;
+                    let mut out = BytesMut::new();
+                    let line = self.buffer.split();
+                    self.apply_line(&line, &mut out);
+                    if !out.is_empty() {
+                        return Some((Ok(out.freeze()), self));
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+#[post("/_pipeline")]
+async fn pipeline(
+    req: HttpRequest,
+    payload: web::Payload,
+    cache: web::Data<SimpleCache<'static>>,
+    pressure: web::Data<MemoryPressure>,
+    limiter: web::Data<KeyLimiter>,
+    settings: web::Data<settings::Cache>,
+    auditor: Option<web::Data<Auditor>>,
+) -> HttpResponse {
+    let pipeline = Pipeline {
+        checks: WriteChecks::new(&req, pressure, limiter, settings),
+        req,
+        payload,
+        buffer: BytesMut::new(),
+        cache,
+        auditor,
+        done: false,
+    };
+    HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(Box::pin(stream::unfold(pipeline, Pipeline::next_results)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::{CacheMetrics, MetricOpts};
+    use crate::deny::DenyList;
+    use crate::settings::{DenyKeys, KeyLimits, Keys, Settings};
+    use std::{net::IpAddr, time::Duration};
+
+    fn cache() -> SimpleCache<'static> {
+        SimpleCache::new(Duration::from_secs(60), CacheMetrics::default())
+    }
+
+    fn checks(per_client: Option<u32>) -> WriteChecks {
+        let limits = KeyLimits {
+            per_client,
+            global: None,
+        };
+        WriteChecks {
+            pressure: web::Data::new(MemoryPressure::new(
+                Default::default(),
+                &MetricOpts::default(),
+            )),
+            limiter: web::Data::new(KeyLimiter::new(limits, &MetricOpts::default())),
+            settings: web::Data::new(Settings::new().unwrap().cache),
+            client: Some(IpAddr::from([127, 0, 0, 1])),
+            usage: None,
+        }
+    }
+
+    #[test]
+    fn operations_are_applied_to_the_cache() {
+        let cache = cache();
+        let checks = checks(None);
+
+        let (put, change) = apply(br#"{"op":"put","key":"a","value":"1"}"#, &cache, &checks);
+        let (get, _) = apply(br#"{"op":"get","key":"a"}"#, &cache, &checks);
+        let (miss, _) = apply(br#"{"op":"get","key":"b"}"#, &cache, &checks);
+
+        assert_eq!(put, OperationResult::new(200, "a".into()));
+        assert_eq!(
+            change,
+            Some(Change {
+                operation: "put",
+                key: "a".into(),
+                size: Some(1),
+            })
+        );
+        assert_eq!(get.value, Some("1".into()));
+        assert_eq!(miss, OperationResult::new(404, "b".into()));
+    }
+
+    #[test]
+    fn invalid_operations_are_reported() {
+        let (result, _) = apply(br#"{"op":"delete","key":"a"}"#, &cache(), &checks(None));
+
+        assert_eq!(result.status, 400);
+        assert!(result.error.is_some());
+    }
+
+    #[test]
+    fn new_keys_over_the_limit_are_throttled() {
+        let cache = cache();
+        let checks = checks(Some(1));
+        let put = |line: &[u8]| apply(line, &cache, &checks).0.status;
+
+        assert_eq!(put(br#"{"op":"put","key":"a","value":"1"}"#), 200);
+        assert_eq!(put(br#"{"op":"put","key":"b","value":"1"}"#), 429);
+        assert_eq!(put(br#"{"op":"put","key":"a","value":"2"}"#), 200);
+    }
+
+    #[test]
+    fn denied_keys_are_not_written() {
+        let settings = DenyKeys {
+            prefixes: vec!["secrets/".into()],
+            patterns: vec![],
+        };
+        let deny_list = DenyList::new(settings, &MetricOpts::default()).unwrap();
+        let cache = cache().with_deny_list(deny_list);
+
+        let (put, _) = apply(
+            br#"{"op":"put","key":"secrets/a","value":"1"}"#,
+            &cache,
+            &checks(None),
+        );
+
+        assert_eq!(put.status, 422);
+        assert!(!cache.contains_key("secrets/a"));
+    }
+
+    #[test]
+    fn values_over_the_max_value_size_are_rejected() {
+        let cache = cache();
+        let mut checks = checks(None);
+        let mut settings = Settings::new().unwrap().cache;
+        settings.max_value_size = Some(2);
+        checks.settings = web::Data::new(settings);
+
+        let (put, _) = apply(br#"{"op":"put","key":"a","value":"123"}"#, &cache, &checks);
+
+        assert_eq!(put.status, 413);
+        assert!(!cache.contains_key("a"));
+    }
+
+    #[test]
+    fn keys_are_normalized() {
+        let rules = Keys {
+            lowercase: true,
+            max_length: Some(4),
+            ..Default::default()
+        };
+        let cache = cache().with_key_rules(rules);
+        let checks = checks(None);
+
+        apply(br#"{"op":"put","key":"A","value":"1"}"#, &cache, &checks);
+        let (get, _) = apply(br#"{"op":"get","key":"a"}"#, &cache, &checks);
+        let (invalid, _) = apply(br#"{"op":"get","key":"abcde"}"#, &cache, &checks);
+
+        assert_eq!(get.value, Some("1".into()));
+        assert_eq!(invalid.status, 400);
+    }
+
+    #[test]
+    fn puts_are_written_with_their_priority() {
+        let cache = cache();
+        let checks = checks(None);
+
+        apply(
+            br#"{"op":"put","key":"a","value":"1","priority":"high"}"#,
+            &cache,
+            &checks,
+        );
+        let (invalid, _) = apply(
+            br#"{"op":"put","key":"b","value":"1","priority":"urgent"}"#,
+            &cache,
+            &checks,
+        );
+
+        assert_eq!(cache.entries(|_| true)[0].priority, Priority::High);
+        assert_eq!(invalid.status, 400);
+    }
+}
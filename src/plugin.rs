@@ -0,0 +1,75 @@
+//! Hooks into the life of cache entries, so behaviour like metrics or logging can be added to the
+//! cache without changing it. Plugins are registered with `SimpleCache::with_plugin` and are called
+//! in the order they were registered, after the built-in `MetricsPlugin` and `LogPlugin`.
+//!
+//! Hooks are called outside of the locks of the backing store, so they may read from the cache,
+//! and should return quickly since they run on the thread serving the request.
+use crate::cache::CacheMetrics;
+use crate::value::Value;
+use prometheus::{IntCounter, IntCounterVec};
+
+/// Hooks called by the cache, every hook does nothing by default.
+pub trait CachePlugin: Send + Sync {
+    /// Called before `value` is stored under `key`.
+    fn before_put(&self, _key: &str, _value: &Value) {}
+
+    /// Called after `key` was read, `hit` is false if there was no value.
+    fn after_get(&self, _key: &str, _hit: bool) {}
+
+    /// Called after a value of `size` bytes was read from `key`.
+    fn after_read(&self, _key: &str, _size: usize) {}
+
+    /// Called after `key` was evicted to make room for another key.
+    fn on_evict(&self, _key: &str, _size: usize) {}
+
+    /// Called after `key` was removed because it expired.
+    fn on_expire(&self, _key: &str, _size: usize) {}
+}
+
+/// Counts hits, misses and evictions.
+pub struct MetricsPlugin {
+    queries: IntCounterVec,
+    evictions: IntCounter,
+}
+
+impl MetricsPlugin {
+    /// Returns a plugin counting with the metrics of `metrics`.
+    pub fn new(metrics: &CacheMetrics) -> Self {
+        Self {
+            queries: metrics.queries.clone(),
+            evictions: metrics.evictions.clone(),
+        }
+    }
+}
+
+impl CachePlugin for MetricsPlugin {
+    fn after_get(&self, _key: &str, hit: bool) {
+        let label = if hit { "hit" } else { "miss" };
+        self.queries.with_label_values(&[label]).inc();
+    }
+
+    fn on_evict(&self, _key: &str, _size: usize) {
+        self.evictions.inc();
+    }
+}
+
+/// Logs reads, evictions and expiries at debug level.
+pub struct LogPlugin;
+
+impl CachePlugin for LogPlugin {
+    fn after_get(&self, key: &str, hit: bool) {
+        if hit {
+            log::debug!("Cache hit for key: {}", key);
+        } else {
+            log::debug!("Cache miss for key: {}", key);
+        }
+    }
+
+    fn on_evict(&self, key: &str, size: usize) {
+        log::debug!("Evicted key: {} of {} bytes", key, size);
+    }
+
+    fn on_expire(&self, key: &str, _size: usize) {
+        log::debug!("Removed expired key from cache: {}", key);
+    }
+}
@@ -0,0 +1,443 @@
+//! Sheds writes while the process is under memory pressure.
+//!
+//! Pressure starts when the measured memory reaches the high-water mark and only ends once it has
+//! fallen below the low-water mark, so the state does not flap around a single threshold.
+//!
+//! Operators get notice before writes are shed with `memory_pressure.warning_thresholds`, fractions
+//! of the high-water mark, e.g. `[0.8, 0.95]`. Crossing one is logged and sets its
+//! `memory_pressure_warning` gauge, which is reset once memory use falls
+//! `memory_pressure.warning_hysteresis` of the high-water mark below the threshold, so a warning
+//! does not flap either.
+//!
+//! With `memory_pressure.webhook_url`, every warning raised or cleared and pressure starting or
+//! ending is also POSTed there as a JSON event by `notifier`, in the order they happened.
+//!
+//! The resident set size is read every `memory_pressure.sample_interval` milliseconds by
+//! `sampler`, not on every write.
+use crate::cache::MetricOpts;
+use crate::settings::{self, PressureSource};
+use actix_rt::time::delay_for;
+use actix_web::client::Client;
+use futures::{
+    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    StreamExt,
+};
+use prometheus::{IntGauge, IntGaugeVec, Registry};
+use serde::Serialize;
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// How long the webhook has to answer an event.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What changed.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum EventKind {
+    WarningRaised,
+    WarningCleared,
+    PressureStarted,
+    PressureEnded,
+}
+
+/// A change of the pressure state, as sent to `memory_pressure.webhook_url`.
+#[derive(Debug, Serialize)]
+struct Event {
+    event: EventKind,
+    /// The warning threshold, for warnings.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    threshold: Option<f64>,
+    /// The measured memory use in bytes.
+    used: u64,
+    high_water_mark: u64,
+    /// The milliseconds since the Unix epoch.
+    at_ms: u64,
+}
+
+/// Tracks whether memory use is above the configured water marks.
+pub struct MemoryPressure {
+    settings: settings::MemoryPressure,
+    under_pressure: AtomicBool,
+    /// The last resident set size read in bytes, 0 if it is not available.
+    rss: AtomicU64,
+    /// 1 while under memory pressure, otherwise 0.
+    gauge: IntGauge,
+    /// The warning thresholds and whether memory use is above each.
+    warnings: Vec<(f64, AtomicBool)>,
+    /// 1 while memory use is above a warning threshold, by `threshold`.
+    warning_gauge: IntGaugeVec,
+    /// Queues the events for `notifier`, if there is a webhook.
+    events: Option<UnboundedSender<Event>>,
+    /// The events queued, until `notifier` takes them.
+    queued: Mutex<Option<UnboundedReceiver<Event>>>,
+}
+
+impl MemoryPressure {
+    /// Returns a new `MemoryPressure`, which never reports pressure without a high-water mark.
+    /// # Arguments
+    /// * `settings` - The water marks and what they are measured against.
+    /// * `opts` - The naming of the pressure metric.
+    pub fn new(settings: settings::MemoryPressure, opts: &MetricOpts) -> Self {
+        let rss = match settings.source {
+            PressureSource::Rss => resident_set_size(),
+            PressureSource::CacheSize => None,
+        };
+        if settings.source == PressureSource::Rss && rss.is_none() {
+            log::warn!("Resident set size is not available, memory pressure will not be reported");
+        }
+        let warning_gauge = IntGaugeVec::new(
+            opts.opts(
+                "memory_pressure_warning",
+                "1 while memory use is above a fraction of the high-water mark, otherwise 0",
+            ),
+            &["threshold"],
+        )
+        .unwrap();
+        let mut thresholds = settings.warning_thresholds.clone();
+        thresholds.retain(|threshold| {
+            let valid = *threshold > 0.0 && *threshold < 1.0;
+            if !valid {
+                log::warn!(
+                    "Ignoring memory_pressure.warning_thresholds {}, not between 0 and 1",
+                    threshold
+                );
+            }
+            valid
+        });
+        let warnings = thresholds
+            .into_iter()
+            .map(|threshold| {
+                warning_gauge
+                    .with_label_values(&[&threshold.to_string()])
+                    .set(0);
+                (threshold, AtomicBool::new(false))
+            })
+            .collect();
+        let (events, queued) = match settings.webhook_url {
+            Some(_) => {
+                let (events, queued) = mpsc::unbounded();
+                (Some(events), Some(queued))
+            }
+            None => (None, None),
+        };
+        Self {
+            settings,
+            under_pressure: AtomicBool::new(false),
+            rss: AtomicU64::new(rss.unwrap_or(0)),
+            gauge: IntGauge::with_opts(opts.opts(
+                "memory_pressure",
+                "1 while writes are rejected because of memory pressure, otherwise 0",
+            ))
+            .unwrap(),
+            warnings,
+            warning_gauge,
+            events,
+            queued: Mutex::new(queued),
+        }
+    }
+
+    /// Registers the pressure metric with a registry.
+    pub fn register(&self, registry: &Registry) {
+        registry.register(Box::new(self.gauge.clone())).unwrap();
+        registry
+            .register(Box::new(self.warning_gauge.clone()))
+            .unwrap();
+    }
+
+    /// Reads the resident set size every `sample_interval` milliseconds while memory pressure is
+    /// measured against it, otherwise returns at once.
+    pub async fn sampler(&self) {
+        if self.settings.source != PressureSource::Rss || self.settings.high_water_mark.is_none() {
+            return;
+        }
+        let interval = Duration::from_millis(self.settings.sample_interval.max(1));
+        loop {
+            delay_for(interval).await;
+            self.sample(resident_set_size());
+        }
+    }
+
+    /// POSTs the events to `memory_pressure.webhook_url` one at a time as they happen, otherwise
+    /// returns at once.
+    pub async fn notifier(&self) {
+        let queued = self.queued.lock().unwrap().take();
+        let (url, mut queued) = match (&self.settings.webhook_url, queued) {
+            (Some(url), Some(queued)) => (url, queued),
+            _ => return,
+        };
+        let client = Client::builder().timeout(WEBHOOK_TIMEOUT).finish();
+        while let Some(event) = queued.next().await {
+            match client.post(url).send_json(&event).await {
+                Ok(response) if response.status().is_success() => {}
+                Ok(response) => log::warn!(
+                    "Memory pressure webhook answered {} to {:?}",
+                    response.status(),
+                    event.event
+                ),
+                Err(err) => log::warn!("Could not send {:?} to the webhook. {}", event.event, err),
+            }
+        }
+    }
+
+    /// Queues an event for the webhook, if there is one.
+    fn emit(&self, event: EventKind, threshold: Option<f64>, used: u64, high: u64) {
+        if let Some(events) = &self.events {
+            let at_ms = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64;
+            let _ = events.unbounded_send(Event {
+                event,
+                threshold,
+                used,
+                high_water_mark: high,
+                at_ms,
+            });
+        }
+    }
+
+    /// Records the resident set size read in bytes, `None` if it is not available.
+    fn sample(&self, rss: Option<u64>) {
+        self.rss.store(rss.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    /// Measures memory use, updates the pressure state and returns whether writes should be
+    /// rejected.
+    /// # Arguments
+    /// * `cache_size` - The size in bytes of all values in the cache.
+    pub fn check(&self, cache_size: i64) -> bool {
+        let high = match self.settings.high_water_mark {
+            Some(high) => high,
+            None => return false,
+        };
+        let low = self.settings.low_water_mark.unwrap_or(high);
+        let used = match self.settings.source {
+            PressureSource::CacheSize => Some(cache_size.max(0) as u64),
+            PressureSource::Rss => Some(self.rss.load(Ordering::Relaxed)).filter(|rss| *rss > 0),
+        };
+        let used = match used {
+            Some(used) => used,
+            None => return self.is_under_pressure(),
+        };
+        self.warn(used, high);
+        let was_under_pressure = self.is_under_pressure();
+        let under_pressure = if was_under_pressure {
+            used >= low
+        } else {
+            used >= high
+        };
+        if under_pressure != was_under_pressure {
+            self.under_pressure.store(under_pressure, Ordering::Relaxed);
+            self.gauge.set(under_pressure as i64);
+            if under_pressure {
+                log::warn!(
+                    "Memory use of {} bytes is above {}, rejecting writes",
+                    used,
+                    high
+                );
+                self.emit(EventKind::PressureStarted, None, used, high);
+            } else {
+                log::info!(
+                    "Memory use of {} bytes is below {}, accepting writes",
+                    used,
+                    low
+                );
+                self.emit(EventKind::PressureEnded, None, used, high);
+            }
+        }
+        under_pressure
+    }
+
+    /// Logs, flips the gauge of and emits an event for each warning threshold memory use crossed.
+    /// A warning is raised at its threshold and cleared `warning_hysteresis` below it.
+    /// # Arguments
+    /// * `used` - The measured memory use in bytes.
+    /// * `high` - The high-water mark.
+    fn warn(&self, used: u64, high: u64) {
+        let hysteresis = self.settings.warning_hysteresis.max(0.0);
+        for (threshold, crossed) in &self.warnings {
+            let raised_at = threshold * high as f64;
+            let cleared_at = (threshold - hysteresis) * high as f64;
+            let is_crossed = if crossed.load(Ordering::Relaxed) {
+                used as f64 >= cleared_at
+            } else {
+                used as f64 >= raised_at
+            };
+            if crossed.swap(is_crossed, Ordering::Relaxed) == is_crossed {
+                continue;
+            }
+            self.warning_gauge
+                .with_label_values(&[&threshold.to_string()])
+                .set(is_crossed as i64);
+            if is_crossed {
+                log::warn!(
+                    "Memory use of {} bytes is above {}% of the high-water mark {}",
+                    used,
+                    threshold * 100.0,
+                    high
+                );
+                self.emit(EventKind::WarningRaised, Some(*threshold), used, high);
+            } else {
+                log::info!(
+                    "Memory use of {} bytes is below {} bytes, clearing the {}% warning",
+                    used,
+                    cleared_at as u64,
+                    threshold * 100.0
+                );
+                self.emit(EventKind::WarningCleared, Some(*threshold), used, high);
+            }
+        }
+    }
+
+    /// Returns the pressure state from the last check.
+    pub fn is_under_pressure(&self) -> bool {
+        self.under_pressure.load(Ordering::Relaxed)
+    }
+}
+
+/// Returns the resident set size of the process in bytes.
+#[cfg(target_os = "linux")]
+fn resident_set_size() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let kilobytes = status
+        .lines()
+        .find(|line| line.starts_with("VmRSS:"))?
+        .split_whitespace()
+        .nth(1)?
+        .parse::<u64>()
+        .ok()?;
+    Some(kilobytes * 1024)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn resident_set_size() -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pressure(high: u64, low: u64) -> MemoryPressure {
+        MemoryPressure::new(
+            settings::MemoryPressure {
+                source: PressureSource::CacheSize,
+                high_water_mark: Some(high),
+                low_water_mark: Some(low),
+                warning_thresholds: vec![0.5, 0.9],
+                ..Default::default()
+            },
+            &MetricOpts::default(),
+        )
+    }
+
+    #[test]
+    fn pressure_ends_below_the_low_water_mark() {
+        let pressure = pressure(100, 50);
+
+        assert!(!pressure.check(99));
+        assert!(pressure.check(100));
+        assert!(pressure.check(50));
+        assert!(!pressure.check(49));
+        assert!(!pressure.check(99));
+    }
+
+    #[test]
+    fn pressure_is_exposed_as_a_metric() {
+        let pressure = pressure(100, 50);
+
+        pressure.check(100);
+
+        assert_eq!(pressure.gauge.get(), 1);
+    }
+
+    #[test]
+    fn crossing_a_warning_threshold_flips_its_gauge() {
+        let pressure = pressure(100, 50);
+        let warning = |threshold| pressure.warning_gauge.with_label_values(&[threshold]).get();
+
+        pressure.check(60);
+        assert_eq!((warning("0.5"), warning("0.9")), (1, 0));
+        pressure.check(90);
+        assert_eq!((warning("0.5"), warning("0.9")), (1, 1));
+        // Warnings clear 5% of the high-water mark below their threshold.
+        pressure.check(85);
+        assert_eq!((warning("0.5"), warning("0.9")), (1, 1));
+        pressure.check(84);
+        assert_eq!((warning("0.5"), warning("0.9")), (1, 0));
+        pressure.check(45);
+        assert_eq!((warning("0.5"), warning("0.9")), (1, 0));
+        pressure.check(44);
+        assert_eq!((warning("0.5"), warning("0.9")), (0, 0));
+    }
+
+    #[test]
+    fn warnings_and_pressure_are_queued_for_the_webhook() {
+        let pressure = MemoryPressure::new(
+            settings::MemoryPressure {
+                source: PressureSource::CacheSize,
+                high_water_mark: Some(100),
+                low_water_mark: Some(50),
+                warning_thresholds: vec![0.9],
+                webhook_url: Some("http://127.0.0.1:9/events".into()),
+                ..Default::default()
+            },
+            &MetricOpts::default(),
+        );
+
+        pressure.check(90);
+        pressure.check(100);
+        pressure.check(49);
+        let mut queued = pressure.queued.lock().unwrap().take().unwrap();
+        let events: Vec<_> = std::iter::from_fn(|| queued.try_next().ok().flatten())
+            .map(|event| (event.event, event.threshold, event.used))
+            .collect();
+
+        assert_eq!(
+            events,
+            vec![
+                (EventKind::WarningRaised, Some(0.9), 90),
+                (EventKind::PressureStarted, None, 100),
+                (EventKind::WarningCleared, Some(0.9), 49),
+                (EventKind::PressureEnded, None, 49),
+            ]
+        );
+    }
+
+    #[test]
+    fn no_pressure_without_a_high_water_mark() {
+        let pressure = MemoryPressure::new(Default::default(), &MetricOpts::default());
+
+        assert!(!pressure.check(i64::MAX));
+    }
+
+    #[test]
+    fn rss_pressure_uses_the_last_sample() {
+        let pressure = MemoryPressure::new(
+            settings::MemoryPressure {
+                source: PressureSource::Rss,
+                high_water_mark: Some(100),
+                ..Default::default()
+            },
+            &MetricOpts::default(),
+        );
+
+        pressure.sample(Some(99));
+        assert!(!pressure.check(i64::MAX));
+        pressure.sample(Some(100));
+        assert!(pressure.check(0));
+        pressure.sample(None);
+        assert!(pressure.check(0));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn resident_set_size_is_read() {
+        assert!(resident_set_size().unwrap() > 0);
+    }
+}
@@ -0,0 +1,158 @@
+//! Deletes every key matching a pattern in two steps, so deletion requests can be checked before
+//! they are carried out: a dry run lists the matching keys and returns a token, and confirming the
+//! token deletes exactly the keys that were listed.
+//!
+//! Patterns are globs where `*` matches any characters and `?` matches one character. Tokens expire
+//! after `TOKEN_LIFETIME` and can only be confirmed once.
+use serde::Serialize;
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::{BuildHasher, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// How long a dry run can be confirmed for.
+pub const TOKEN_LIFETIME: Duration = Duration::from_secs(300);
+
+/// Returns true if `key` matches the glob `pattern`.
+pub fn matches(pattern: &str, key: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let key: Vec<char> = key.chars().collect();
+    let (mut p, mut k) = (0, 0);
+    // The position of the last `*` and the key position it was tried at.
+    let mut backtrack = None;
+    while k < key.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, k));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == key[k] => {
+                p += 1;
+                k += 1;
+            }
+            _ => match backtrack {
+                // Let the last `*` match one more character.
+                Some((star, star_k)) => {
+                    backtrack = Some((star, star_k + 1));
+                    p = star + 1;
+                    k = star_k + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// The keys listed by a dry run.
+#[derive(Debug, Serialize)]
+pub struct DryRun {
+    pub token: String,
+    pub pattern: String,
+    pub keys: Vec<String>,
+    /// Seconds until the token expires.
+    pub expires_in: u64,
+}
+
+struct Pending {
+    pattern: String,
+    keys: Vec<String>,
+    created: Instant,
+}
+
+/// The dry runs that have not been confirmed yet.
+pub struct Purges {
+    pending: Mutex<HashMap<String, Pending>>,
+    tokens: RandomState,
+    count: AtomicU64,
+}
+
+impl Default for Purges {
+    fn default() -> Self {
+        Self {
+            pending: Mutex::new(HashMap::new()),
+            tokens: RandomState::new(),
+            count: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Purges {
+    /// Returns a new token that can not be guessed from earlier ones.
+    fn token(&self) -> String {
+        let count = self.count.fetch_add(1, Ordering::Relaxed);
+        let mut token = String::new();
+        for part in 0..2 {
+            let mut hasher = self.tokens.build_hasher();
+            hasher.write_u64(count);
+            hasher.write_u8(part);
+            token.push_str(&format!("{:016x}", hasher.finish()));
+        }
+        token
+    }
+
+    /// Records the keys matching `pattern` and returns them with the token that confirms them.
+    pub fn dry_run(&self, pattern: String, keys: Vec<String>) -> DryRun {
+        let token = self.token();
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, purge| purge.created.elapsed() < TOKEN_LIFETIME);
+        pending.insert(
+            token.clone(),
+            Pending {
+                pattern: pattern.clone(),
+                keys: keys.clone(),
+                created: Instant::now(),
+            },
+        );
+        DryRun {
+            token,
+            pattern,
+            keys,
+            expires_in: TOKEN_LIFETIME.as_secs(),
+        }
+    }
+
+    /// Returns the pattern and keys of the dry run with `token`, or None if there is no such dry
+    /// run or it has expired. A token can only be confirmed once.
+    pub fn confirm(&self, token: &str) -> Option<(String, Vec<String>)> {
+        self.pending
+            .lock()
+            .unwrap()
+            .remove(token)
+            .filter(|purge| purge.created.elapsed() < TOKEN_LIFETIME)
+            .map(|purge| (purge.pattern, purge.keys))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn globs_match_keys() {
+        assert!(matches("users/42/*", "users/42/profile"));
+        assert!(matches("*42*", "users/42/profile"));
+        assert!(matches("user?/*", "users/1"));
+        assert!(matches("exact", "exact"));
+        assert!(!matches("users/42/*", "users/421"));
+        assert!(!matches("a*b", "acbc"));
+    }
+
+    #[test]
+    fn tokens_are_confirmed_once() {
+        let sut = Purges::default();
+        let dry_run = sut.dry_run("a*".into(), vec!["ab".into()]);
+
+        assert_eq!(
+            sut.confirm(&dry_run.token),
+            Some(("a*".into(), vec!["ab".into()]))
+        );
+        assert_eq!(sut.confirm(&dry_run.token), None);
+        assert_eq!(sut.confirm("unknown"), None);
+    }
+}
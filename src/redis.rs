@@ -0,0 +1,322 @@
+//! A Redis tier below the cache, so the server can be used as a near-cache in front of Redis.
+//!
+//! Misses are read from Redis and added to the cache, and puts can be written through to Redis.
+//! Only GET, SET and DEL are needed, so this speaks RESP directly over pooled blocking connections,
+//! which are used from the actix blocking thread pool.
+use crate::cache::MetricOpts;
+use prometheus::{IntCounterVec, Registry};
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::TcpStream,
+    sync::Mutex,
+    time::Duration,
+};
+
+/// A reply from Redis.
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Status(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Reply>>),
+}
+
+/// The parts of a `redis://[:password@]host[:port][/db]` url.
+#[derive(Debug, PartialEq)]
+struct Address {
+    host_port: String,
+    password: Option<String>,
+    db: Option<u32>,
+}
+
+fn invalid_url(url: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Invalid Redis url: {}", url),
+    )
+}
+
+fn parse_url(url: &str) -> io::Result<Address> {
+    let rest = url
+        .strip_prefix("redis://")
+        .ok_or_else(|| invalid_url(url))?;
+    let (authority, db) = match rest.find('/') {
+        Some(slash) => (&rest[..slash], &rest[slash + 1..]),
+        None => (rest, ""),
+    };
+    let (password, host_port) = match authority.rfind('@') {
+        Some(at) => {
+            let user_info = &authority[..at];
+            let password = user_info
+                .split_once(':')
+                .map_or(user_info, |(_, password)| password);
+            (Some(password.to_string()), &authority[at + 1..])
+        }
+        None => (None, authority),
+    };
+    if host_port.is_empty() {
+        return Err(invalid_url(url));
+    }
+    let host_port = if host_port.contains(':') {
+        host_port.to_string()
+    } else {
+        format!("{}:6379", host_port)
+    };
+    let db = match db {
+        "" => None,
+        db => Some(db.parse().map_err(|_| invalid_url(url))?),
+    };
+    Ok(Address {
+        host_port,
+        password,
+        db,
+    })
+}
+
+fn encode(args: &[&[u8]]) -> Vec<u8> {
+    let mut command = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        command.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        command.extend_from_slice(arg);
+        command.extend_from_slice(b"\r\n");
+    }
+    command
+}
+
+fn protocol_error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<String> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    match line.strip_suffix("\r\n") {
+        Some(line) => Ok(line.to_string()),
+        None => Err(io::ErrorKind::UnexpectedEof.into()),
+    }
+}
+
+fn read_reply<R: BufRead>(reader: &mut R) -> io::Result<Reply> {
+    let line = read_line(reader)?;
+    let (kind, rest) = line.split_at(line.len().min(1));
+    let length = || {
+        rest.parse::<i64>()
+            .map_err(|_| protocol_error(format!("Invalid length: {}", rest)))
+    };
+    match kind {
+        "+" => Ok(Reply::Status(rest.to_string())),
+        "-" => Err(io::Error::other(format!("Redis error: {}", rest))),
+        ":" => Ok(Reply::Integer(length()?)),
+        "$" => match length()? {
+            length if length < 0 => Ok(Reply::Bulk(None)),
+            length => {
+                let mut value = vec![0; length as usize + 2];
+                reader.read_exact(&mut value)?;
+                value.truncate(length as usize);
+                Ok(Reply::Bulk(Some(value)))
+            }
+        },
+        "*" => match length()? {
+            length if length < 0 => Ok(Reply::Array(None)),
+            length => (0..length)
+                .map(|_| read_reply(reader))
+                .collect::<io::Result<_>>()
+                .map(|replies| Reply::Array(Some(replies))),
+        },
+        _ => Err(protocol_error(format!("Unexpected reply: {}", line))),
+    }
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+}
+
+impl Connection {
+    fn command(&mut self, args: &[&[u8]]) -> io::Result<Reply> {
+        self.reader.get_mut().write_all(&encode(args))?;
+        read_reply(&mut self.reader)
+    }
+}
+
+/// Container for the Redis tier metrics.
+#[derive(Clone)]
+pub struct RedisMetrics {
+    /// A count of Redis commands by command and result.
+    pub commands: IntCounterVec,
+}
+
+impl RedisMetrics {
+    /// Creates a new RedisMetrics named using `opts`.
+    pub fn with_opts(opts: &MetricOpts) -> Self {
+        Self {
+            commands: IntCounterVec::new(
+                opts.opts(
+                    "redis_commands_total",
+                    "A count of commands sent to the Redis tier by result",
+                ),
+                &["command", "result"],
+            )
+            .unwrap(),
+        }
+    }
+
+    /// Registers the Redis tier metrics with a registry.
+    pub fn register(&self, registry: &Registry) {
+        registry.register(Box::new(self.commands.clone())).unwrap();
+    }
+}
+
+/// A pool of connections to one Redis server.
+pub struct RedisTier {
+    address: Address,
+    timeout: Duration,
+    pool_size: usize,
+    pool: Mutex<Vec<Connection>>,
+    write_through: bool,
+    metrics: RedisMetrics,
+}
+
+impl RedisTier {
+    /// Returns a new `RedisTier`, connections are opened when they are first needed.
+    /// # Arguments
+    /// * `settings` - The Redis url, timeouts and pool size.
+    /// * `metrics` - A container for the metrics used by the Redis tier.
+    pub fn new(settings: &crate::settings::Redis, metrics: RedisMetrics) -> io::Result<Self> {
+        let url = settings.url.as_deref().unwrap_or_default();
+        Ok(Self {
+            address: parse_url(url)?,
+            timeout: Duration::from_millis(settings.timeout),
+            pool_size: settings.pool_size,
+            pool: Mutex::new(Vec::new()),
+            write_through: settings.write_through,
+            metrics,
+        })
+    }
+
+    /// Returns true when puts should also be written to Redis.
+    pub fn write_through(&self) -> bool {
+        self.write_through
+    }
+
+    fn connect(&self) -> io::Result<Connection> {
+        let addr = std::net::ToSocketAddrs::to_socket_addrs(&self.address.host_port)?
+            .next()
+            .ok_or_else(|| invalid_url(&self.address.host_port))?;
+        let stream = TcpStream::connect_timeout(&addr, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        stream.set_nodelay(true)?;
+        let mut connection = Connection {
+            reader: BufReader::new(stream),
+        };
+        if let Some(password) = &self.address.password {
+            connection.command(&[b"AUTH", password.as_bytes()])?;
+        }
+        if let Some(db) = self.address.db {
+            connection.command(&[b"SELECT", db.to_string().as_bytes()])?;
+        }
+        Ok(connection)
+    }
+
+    /// Runs a command on a pooled connection, which is only returned to the pool on success.
+    fn command(&self, name: &str, args: &[&[u8]]) -> io::Result<Reply> {
+        let pooled = self.pool.lock().unwrap().pop();
+        let result = pooled
+            .map_or_else(|| self.connect(), Ok)
+            .and_then(|mut connection| {
+                let reply = connection.command(args)?;
+                let mut pool = self.pool.lock().unwrap();
+                if pool.len() < self.pool_size {
+                    pool.push(connection);
+                }
+                Ok(reply)
+            });
+        let label = if result.is_ok() { "ok" } else { "error" };
+        self.metrics
+            .commands
+            .with_label_values(&[name, label])
+            .inc();
+        result
+    }
+
+    /// Returns the value of `key`.
+    pub fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        match self.command("get", &[b"GET", key.as_bytes()])? {
+            Reply::Bulk(value) => Ok(value),
+            reply => Err(protocol_error(format!("Unexpected reply: {:?}", reply))),
+        }
+    }
+
+    /// Sets the value of `key` with a ttl.
+    pub fn set(&self, key: &str, value: &[u8], ttl: Duration) -> io::Result<()> {
+        let ttl = ttl.as_millis().max(1).to_string();
+        self.command(
+            "set",
+            &[b"SET", key.as_bytes(), value, b"PX", ttl.as_bytes()],
+        )
+        .map(|_| ())
+    }
+
+    /// Removes `key`.
+    pub fn delete(&self, key: &str) -> io::Result<()> {
+        self.command("del", &[b"DEL", key.as_bytes()]).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{io::Read, net::TcpListener, thread};
+
+    #[test]
+    fn urls_are_parsed() {
+        assert_eq!(
+            parse_url("redis://:secret@localhost:6380/2").unwrap(),
+            Address {
+                host_port: "localhost:6380".into(),
+                password: Some("secret".into()),
+                db: Some(2),
+            }
+        );
+        assert_eq!(
+            parse_url("redis://localhost").unwrap().host_port,
+            "localhost:6379"
+        );
+        assert!(parse_url("http://localhost").is_err());
+    }
+
+    #[test]
+    fn replies_are_read() {
+        let mut input: &[u8] = b"*3\r\n+OK\r\n:5\r\n$3\r\nabc\r\n$-1\r\n";
+
+        assert_eq!(
+            read_reply(&mut input).unwrap(),
+            Reply::Array(Some(vec![
+                Reply::Status("OK".into()),
+                Reply::Integer(5),
+                Reply::Bulk(Some(b"abc".to_vec())),
+            ]))
+        );
+        assert_eq!(read_reply(&mut input).unwrap(), Reply::Bulk(None));
+    }
+
+    #[test]
+    fn values_are_read_from_the_server() {
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("redis://{}", server.local_addr().unwrap());
+        thread::spawn(move || {
+            let (mut stream, _) = server.accept().unwrap();
+            let mut command = vec![0; encode(&[b"GET", b"a"]).len()];
+            stream.read_exact(&mut command).unwrap();
+            stream.write_all(b"$5\r\nvalue\r\n").unwrap();
+        });
+        let settings = crate::settings::Redis {
+            url: Some(url),
+            ..Default::default()
+        };
+        let sut =
+            RedisTier::new(&settings, RedisMetrics::with_opts(&MetricOpts::default())).unwrap();
+
+        assert_eq!(sut.get("a").unwrap(), Some(b"value".to_vec()));
+    }
+}
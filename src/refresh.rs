@@ -0,0 +1,275 @@
+//! Refreshes sets of keys on a schedule, for data that should always be cached. Each job in
+//! `refresh.jobs` has a cron `schedule` and a `manifest` in the format of `cache.warmup_manifest`,
+//! whose entries are fetched and stored again each minute the schedule matches.
+//!
+//! Schedules have the five cron fields, minute, hour, day of month, month and day of week, in UTC.
+//! Each field is `*` or a list of values and ranges, optionally with a `/step`, and Sunday is 0 or
+//! 7. As in cron, a day matches either day field when both are restricted. A job is skipped while
+//! its previous run is still going, or while writes are paused for a handoff, and a handoff waits
+//! for the jobs running to finish.
+use crate::cache::SimpleCache;
+use crate::settings;
+use crate::warmup;
+use crate::write_gate::WriteGate;
+use actix_rt::time::delay_for;
+use actix_web::{rt, web};
+use std::{
+    cell::Cell,
+    io,
+    rc::Rc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// The allowed values of each field of a schedule.
+const FIELDS: [(&str, u32, u32); 5] = [
+    ("minute", 0, 59),
+    ("hour", 0, 23),
+    ("day of month", 1, 31),
+    ("month", 1, 12),
+    ("day of week", 0, 7),
+];
+
+/// A UTC minute broken down into the fields of a schedule.
+#[derive(Debug, PartialEq)]
+struct Minute {
+    minute: u32,
+    hour: u32,
+    day: u32,
+    month: u32,
+    /// Sunday is 0.
+    weekday: u32,
+}
+
+impl Minute {
+    /// Returns the minute `secs` seconds after the Unix epoch falls in.
+    fn at(secs: u64) -> Self {
+        let days = (secs / 86_400) as i64;
+        let seconds_of_day = (secs % 86_400) as u32;
+        // Converts days since 1970-01-01 to a civil date, see
+        // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+        let z = days + 719_468;
+        let day_of_era = z.rem_euclid(146_097);
+        let year_of_era =
+            (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+        let month = if month_index < 10 {
+            month_index + 3
+        } else {
+            month_index - 9
+        };
+        Self {
+            minute: seconds_of_day / 60 % 60,
+            hour: seconds_of_day / 3_600,
+            day: day as u32,
+            month: month as u32,
+            // 1970-01-01 was a Thursday.
+            weekday: ((days + 4).rem_euclid(7)) as u32,
+        }
+    }
+}
+
+/// A cron schedule, each field a set of values as bits.
+#[derive(Debug, PartialEq)]
+struct Schedule {
+    fields: [u64; 5],
+    /// Whether the day of month and day of week fields are `*`.
+    any_day: (bool, bool),
+}
+
+/// Returns the values of one field of a schedule as bits.
+fn parse_field(field: &str, min: u32, max: u32) -> Option<u64> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse().ok().filter(|step| *step > 0)?),
+            None => (part, 1),
+        };
+        let (first, last) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((first, last)) => (first.parse().ok()?, last.parse().ok()?),
+                None => {
+                    let value = range.parse().ok()?;
+                    (value, if step > 1 { max } else { value })
+                }
+            },
+        };
+        if first < min || last > max || first > last {
+            return None;
+        }
+        for value in (first..=last).step_by(step) {
+            bits |= 1 << value;
+        }
+    }
+    Some(bits)
+}
+
+impl Schedule {
+    /// Parses a schedule of five cron fields.
+    fn parse(schedule: &str) -> Result<Self, String> {
+        let parts: Vec<&str> = schedule.split_whitespace().collect();
+        if parts.len() != FIELDS.len() {
+            return Err(format!("expected 5 fields but got {}", parts.len()));
+        }
+        let mut fields = [0; 5];
+        for (index, (part, (name, min, max))) in parts.iter().zip(&FIELDS).enumerate() {
+            fields[index] = parse_field(part, *min, *max)
+                .ok_or_else(|| format!("invalid {} field: {}", name, part))?;
+        }
+        // Sunday may be given as 7.
+        if fields[4] & 1 << 7 != 0 {
+            fields[4] = (fields[4] & !(1 << 7)) | 1;
+        }
+        Ok(Self {
+            fields,
+            any_day: (parts[2] == "*", parts[4] == "*"),
+        })
+    }
+
+    /// Returns true if the schedule matches `minute`.
+    fn matches(&self, minute: &Minute) -> bool {
+        let has = |field: usize, value: u32| self.fields[field] & 1 << value != 0;
+        let day_of_month = has(2, minute.day);
+        let day_of_week = has(4, minute.weekday);
+        let day = match self.any_day {
+            (false, false) => day_of_month || day_of_week,
+            _ => day_of_month && day_of_week,
+        };
+        has(0, minute.minute) && has(1, minute.hour) && has(3, minute.month) && day
+    }
+}
+
+/// A refresh job and whether it is running.
+struct Job {
+    schedule: Schedule,
+    manifest: String,
+    running: Rc<Cell<bool>>,
+}
+
+/// Runs the refresh jobs.
+pub struct Scheduler {
+    jobs: Vec<Job>,
+    /// The default ttl, the largest value fetched and the number of concurrent fetches.
+    cache_settings: Rc<settings::Cache>,
+}
+
+impl Scheduler {
+    /// Returns a `Scheduler` for the jobs in `settings`, or `None` if there are none.
+    /// # Arguments
+    /// * `settings` - The refresh jobs.
+    /// * `cache_settings` - How entries are fetched and stored.
+    pub fn new(
+        settings: settings::Refresh,
+        cache_settings: settings::Cache,
+    ) -> io::Result<Option<Self>> {
+        if settings.jobs.is_empty() {
+            return Ok(None);
+        }
+        let jobs = settings
+            .jobs
+            .into_iter()
+            .map(|job| {
+                let schedule = Schedule::parse(&job.schedule).map_err(|err| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Invalid refresh schedule {:?}: {}", job.schedule, err),
+                    )
+                })?;
+                Ok(Job {
+                    schedule,
+                    manifest: job.manifest,
+                    running: Rc::new(Cell::new(false)),
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Some(Self {
+            jobs,
+            cache_settings: Rc::new(cache_settings),
+        }))
+    }
+
+    /// Starts the jobs whose schedule matches at the start of every minute.
+    /// # Arguments
+    /// * `cache` - The cache the entries are stored in.
+    /// * `gate` - The gate each run passes through, closed while the cache is handed off.
+    pub async fn run(self, cache: web::Data<SimpleCache<'static>>, gate: web::Data<WriteGate>) {
+        loop {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            let next_minute = (now.as_secs() / 60 + 1) * 60;
+            delay_for(Duration::from_secs(next_minute) - now).await;
+            let minute = Minute::at(next_minute);
+            for job in self.jobs.iter().filter(|job| job.schedule.matches(&minute)) {
+                if job.running.replace(true) {
+                    log::warn!("Skipped refreshing {}, still running", job.manifest);
+                    continue;
+                }
+                let write = match WriteGate::enter(&gate) {
+                    Some(write) => write,
+                    None => {
+                        log::warn!("Skipped refreshing {}, writes are paused", job.manifest);
+                        job.running.set(false);
+                        continue;
+                    }
+                };
+                let cache = cache.clone();
+                let manifest = job.manifest.clone();
+                let running = job.running.clone();
+                let cache_settings = self.cache_settings.clone();
+                rt::spawn(async move {
+                    if let Err(err) = warmup::load(&manifest, &cache, &cache_settings).await {
+                        log::error!("Could not refresh {}. {}", manifest, err);
+                    }
+                    running.set(false);
+                    drop(write);
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn minutes_are_broken_down_in_utc() {
+        // 2021-02-28T23:59:00Z, a Sunday.
+        assert_eq!(
+            Minute::at(1_614_556_740),
+            Minute {
+                minute: 59,
+                hour: 23,
+                day: 28,
+                month: 2,
+                weekday: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn schedules_match_like_cron() {
+        let minute = Minute {
+            minute: 30,
+            hour: 6,
+            day: 15,
+            month: 3,
+            weekday: 1,
+        };
+        let matches = |schedule: &str| Schedule::parse(schedule).unwrap().matches(&minute);
+
+        assert!(matches("* * * * *"));
+        assert!(matches("*/15 6 * * *"));
+        assert!(matches("0,30 1-6 * 3 1-5"));
+        assert!(!matches("*/7 * * * *"));
+        assert!(!matches("30 6 * * 0,7"));
+        // Either day field matches when both are restricted.
+        assert!(matches("30 6 1 * 1"));
+        assert!(!matches("30 6 1 * *"));
+        assert!(Schedule::parse("60 * * * *").is_err());
+        assert!(Schedule::parse("* * * *").is_err());
+    }
+}
@@ -0,0 +1,273 @@
+//! Replays a trace of requests against a cache on virtual time, started with
+//! `--replay trace.json`, so expiry and eviction bugs can be reproduced deterministically.
+//!
+//! A trace is a JSON array of events, each with the milliseconds since the start of the trace in
+//! `at_ms`, an `op` of `get`, `put` or `delete`, a `key` and, for puts, a `value` and optional
+//! `ttl_ms`. Time only passes between events, when keys that have expired are removed as the
+//! cleaner would. Writes under memory pressure, measured by the cache size, make room with
+//! `cache.eviction_policy` as the cache server does. The outcome of every event is printed as a
+//! JSON line, and the replay fails if an event has an `expect`ed outcome it did not get.
+//!
+//! A binary trace recorded with `trace.path` can be replayed too. Its keys are named by their hash
+//! and the values written are filled to their recorded size.
+use crate::cache::{CacheMetrics, MetricOpts, SimpleCache};
+use crate::clock::VirtualClock;
+use crate::eviction::Priority;
+use crate::pressure::MemoryPressure;
+use crate::settings::{self, PressureSource, Settings};
+use crate::trace::{self, TraceOp, TraceRecord};
+use crate::value::Value;
+use serde::{Deserialize, Serialize};
+use std::{fs, io, sync::Arc, time::Duration};
+
+/// A request in a trace.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Request {
+    Get {
+        key: String,
+    },
+    Put {
+        key: String,
+        value: String,
+        ttl_ms: Option<u64>,
+    },
+    Delete {
+        key: String,
+    },
+}
+
+/// What happened to a request.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Hit,
+    Miss,
+    Stored,
+    /// The write was rejected under memory pressure.
+    Rejected,
+    Removed,
+    Absent,
+}
+
+/// A request and when it was made.
+#[derive(Debug, Deserialize)]
+pub struct Event {
+    #[serde(default)]
+    pub at_ms: u64,
+    #[serde(flatten)]
+    pub request: Request,
+    pub expect: Option<Outcome>,
+}
+
+/// The outcome of an event.
+#[derive(Debug, Serialize)]
+pub struct Replayed {
+    pub at_ms: u64,
+    pub op: &'static str,
+    pub key: String,
+    pub outcome: Outcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected: Option<Outcome>,
+}
+
+impl Replayed {
+    /// Returns true if the event had an expected outcome that it did not get.
+    pub fn unexpected(&self) -> bool {
+        self.expected
+            .is_some_and(|expected| expected != self.outcome)
+    }
+}
+
+/// Applies `event` to `cache` once `clock` has been moved to its time.
+/// # Arguments
+/// * `cache` - The cache, reading the time from `clock`.
+/// * `clock` - The virtual clock of the cache.
+/// * `pressure` - Whether writes need to make room.
+/// * `event` - The event to replay.
+pub fn replay(
+    cache: &SimpleCache<'static>,
+    clock: &VirtualClock,
+    pressure: &MemoryPressure,
+    event: Event,
+) -> Replayed {
+    clock.set(Duration::from_millis(event.at_ms));
+    cache.remove_expired();
+    let (op, key, outcome) = match event.request {
+        Request::Get { key } => {
+            let hit = cache.get(key.clone(), &|_| ()).is_some();
+            let outcome = if hit { Outcome::Hit } else { Outcome::Miss };
+            ("get", key, outcome)
+        }
+        Request::Put { key, value, ttl_ms } => {
+            let value = Value::from(value);
+            let under_pressure = pressure.check(cache.size());
+            let outcome = if !under_pressure || cache.make_room(&key, value.len(), Priority::Normal)
+            {
+                match ttl_ms {
+                    Some(ttl_ms) => {
+                        cache.put_with_ttl(key.clone(), value, Duration::from_millis(ttl_ms))
+                    }
+                    None => cache.put(key.clone(), value),
+                };
+                Outcome::Stored
+            } else {
+                Outcome::Rejected
+            };
+            ("put", key, outcome)
+        }
+        Request::Delete { key } => {
+            let outcome = if cache.remove(&key) {
+                Outcome::Removed
+            } else {
+                Outcome::Absent
+            };
+            ("delete", key, outcome)
+        }
+    };
+    Replayed {
+        at_ms: event.at_ms,
+        op,
+        key,
+        outcome,
+        expected: event.expect,
+    }
+}
+
+/// Returns the events of a binary trace, timed from its first record.
+fn events(records: Vec<TraceRecord>) -> Vec<Event> {
+    let start_ms = records.first().map_or(0, |record| record.timestamp_ms);
+    records
+        .into_iter()
+        .map(|record| {
+            let key = format!("{:016x}", record.key_hash);
+            Event {
+                at_ms: record.timestamp_ms.saturating_sub(start_ms),
+                request: match record.op {
+                    TraceOp::Get => Request::Get { key },
+                    TraceOp::Put => Request::Put {
+                        key,
+                        value: "0".repeat(record.size as usize),
+                        ttl_ms: None,
+                    },
+                },
+                expect: None,
+            }
+        })
+        .collect()
+}
+
+/// Replays the trace at `path` against an empty cache configured by `settings`, printing the
+/// outcome of each event.
+pub fn run(path: &str, settings: &Settings) -> io::Result<()> {
+    let bytes = fs::read(path)?;
+    let events = if bytes.starts_with(trace::MAGIC) {
+        events(trace::read(&bytes[..])?)
+    } else {
+        serde_json::from_slice(&bytes)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?
+    };
+    let clock = Arc::new(VirtualClock::default());
+    let mut cache = SimpleCache::new(
+        Duration::from_secs(settings.cache.key_live_duration),
+        CacheMetrics::default(),
+    )
+    .with_eviction_policy(settings.cache.eviction_policy)
+    .with_clock(clock.clone());
+    if settings.cache.adaptive_ttl.enabled {
+        cache = cache.with_adaptive_ttl(settings.cache.adaptive_ttl.clone());
+    }
+    let pressure = MemoryPressure::new(
+        settings::MemoryPressure {
+            source: PressureSource::CacheSize,
+            ..settings.memory_pressure.clone()
+        },
+        &MetricOpts::default(),
+    );
+    let mut unexpected = 0;
+    for event in events {
+        let replayed = replay(&cache, &clock, &pressure, event);
+        if replayed.unexpected() {
+            unexpected += 1;
+        }
+        // Serializing a struct of strings and numbers can not fail.
+        println!("{}", serde_json::to_string(&replayed).unwrap());
+    }
+    if unexpected > 0 {
+        return Err(io::Error::other(format!(
+            "{} events did not have the expected outcome",
+            unexpected
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(trace: &str) -> Vec<Event> {
+        serde_json::from_str(trace).unwrap()
+    }
+
+    #[test]
+    fn keys_expire_on_virtual_time() {
+        let clock = Arc::new(VirtualClock::default());
+        let cache = SimpleCache::new(Duration::from_secs(1), CacheMetrics::default())
+            .with_clock(clock.clone());
+        let pressure = MemoryPressure::new(Default::default(), &MetricOpts::default());
+        let trace = parse(
+            r#"[
+                {"at_ms": 0, "op": "put", "key": "a", "value": "1"},
+                {"at_ms": 0, "op": "put", "key": "b", "value": "2", "ttl_ms": 5000},
+                {"at_ms": 999, "op": "get", "key": "a", "expect": "hit"},
+                {"at_ms": 1000, "op": "get", "key": "a", "expect": "miss"},
+                {"at_ms": 1001, "op": "get", "key": "b", "expect": "hit"},
+                {"at_ms": 5000, "op": "delete", "key": "b", "expect": "absent"}
+            ]"#,
+        );
+
+        let outcomes: Vec<Outcome> = trace
+            .into_iter()
+            .map(|event| replay(&cache, &clock, &pressure, event))
+            .map(|replayed| replayed.outcome)
+            .collect();
+
+        assert_eq!(
+            outcomes,
+            vec![
+                Outcome::Stored,
+                Outcome::Stored,
+                Outcome::Hit,
+                Outcome::Miss,
+                Outcome::Hit,
+                Outcome::Absent
+            ]
+        );
+    }
+
+    #[test]
+    fn binary_traces_are_timed_from_their_first_record() {
+        let record = |timestamp_ms, op, size| TraceRecord {
+            timestamp_ms,
+            op,
+            key_hash: 0xab,
+            size,
+            hit: false,
+        };
+
+        let events = events(vec![
+            record(1_000, TraceOp::Put, 3),
+            record(1_500, TraceOp::Get, 0),
+        ]);
+
+        assert_eq!(events[1].at_ms, 500);
+        match &events[0].request {
+            Request::Put { key, value, .. } => {
+                assert_eq!(key, "00000000000000ab");
+                assert_eq!(value, "000");
+            }
+            request => panic!("Unexpected request: {:?}", request),
+        }
+    }
+}
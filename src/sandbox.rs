@@ -0,0 +1,128 @@
+//! Limits what the process can do, since the servers parse untrusted input: with `sandbox.chroot`
+//! the process is confined to a directory, and with `sandbox.user` and `sandbox.group` it switches
+//! to an unprivileged user once its sockets are bound, e.g. after binding ports below 1024 as root.
+//!
+//! The process is confined before any file named in the settings is opened, so the disk tier, the
+//! pins, the audit log, the handoff socket and every other path are resolved inside the chroot
+//! both when they are first opened and when they are written later. Only the settings and the
+//! logging configuration are read before.
+use crate::settings;
+use nix::unistd::{chdir, chroot, setgid, setuid, Gid, Group, User};
+use std::io;
+
+fn nix_error(err: nix::Error) -> io::Error {
+    io::Error::other(err)
+}
+
+/// Returns the user called `name`.
+fn user(name: &str) -> io::Result<User> {
+    User::from_name(name)
+        .map_err(nix_error)?
+        .ok_or_else(|| io::Error::other(format!("Unknown sandbox.user {}", name)))
+}
+
+/// Returns the id of the group called `name`.
+fn group(name: &str) -> io::Result<Gid> {
+    Group::from_name(name)
+        .map_err(nix_error)?
+        .map(|group| group.gid)
+        .ok_or_else(|| io::Error::other(format!("Unknown sandbox.group {}", name)))
+}
+
+/// The user and group the process switches to once its sockets are bound.
+pub struct Privileges {
+    user: Option<User>,
+    gid: Option<Gid>,
+}
+
+/// Looks up the user and group to switch to and confines the process to `sandbox.chroot`.
+pub fn confine(settings: &settings::Sandbox) -> io::Result<Privileges> {
+    // The user and group are looked up before the chroot hides the user database.
+    let user = settings.user.as_deref().map(user).transpose()?;
+    let gid = match (&settings.group, &user) {
+        (Some(name), _) => Some(group(name)?),
+        (None, Some(user)) => Some(user.gid),
+        (None, None) => None,
+    };
+    if let Some(path) = &settings.chroot {
+        chroot(path.as_str()).map_err(nix_error)?;
+        chdir("/").map_err(nix_error)?;
+        log::info!("Confined to {}", path);
+    }
+    Ok(Privileges { user, gid })
+}
+
+/// Switches to the user and group looked up by `confine`.
+pub fn drop_privileges(privileges: Privileges) -> io::Result<()> {
+    // The group is switched first, as an unprivileged user cannot switch groups.
+    if let Some(gid) = privileges.gid {
+        #[cfg(not(any(target_os = "ios", target_os = "macos")))]
+        nix::unistd::setgroups(&[gid]).map_err(nix_error)?;
+        setgid(gid).map_err(nix_error)?;
+        log::info!("Switched to group {}", gid);
+    }
+    if let Some(user) = privileges.user {
+        setuid(user.uid).map_err(nix_error)?;
+        log::info!("Switched to user {}", user.name);
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "persistence"))]
+mod test {
+    use super::*;
+    use crate::cache::MetricOpts;
+    use crate::disk::{DiskMetrics, DiskTier};
+    use crate::value::Value;
+    use nix::unistd::geteuid;
+    use std::{
+        env, fs,
+        process::{self, Command},
+        time::{Duration, Instant},
+    };
+
+    /// Set to the chroot in the copy of the test binary that confines itself.
+    const CHROOT_VAR: &str = "SIMPLE_MEM_CACHE_TEST_CHROOT";
+
+    #[test]
+    fn the_disk_tier_is_written_inside_the_chroot() {
+        // The chroot applies to the whole process, so it is made by a copy of the test binary
+        // running only this test, rather than in a fork of the threads of the test harness.
+        if let Ok(root) = env::var(CHROOT_VAR) {
+            let settings = settings::Sandbox {
+                chroot: Some(root),
+                ..Default::default()
+            };
+            drop_privileges(confine(&settings).unwrap()).unwrap();
+            let metrics = DiskMetrics::with_opts(&MetricOpts::default());
+            let disk_tier = DiskTier::open("/disk", metrics).unwrap();
+            let expiry = Instant::now() + Duration::from_secs(60);
+            disk_tier.put("a", &Value::from("value"), expiry).unwrap();
+            return;
+        }
+        // Only root may chroot.
+        if !geteuid().is_root() {
+            return;
+        }
+        let root = env::temp_dir().join(format!("simple-mem-cache-chroot-{}", process::id()));
+        fs::create_dir_all(&root).unwrap();
+
+        let output = Command::new(env::current_exe().unwrap())
+            .args([
+                "sandbox::test::the_disk_tier_is_written_inside_the_chroot",
+                "--exact",
+                "--test-threads=1",
+            ])
+            .env(CHROOT_VAR, &root)
+            .output()
+            .unwrap();
+
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stdout)
+        );
+        assert_eq!(fs::read_dir(root.join("disk")).unwrap().count(), 1);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,276 @@
+//! Validates values against a JSON Schema chosen by the longest key prefix in `cache.schemas`, so
+//! consumers of a namespace can rely on the shape of its values.
+//!
+//! The schemas are read from files when the cache server starts. The keywords `type`, `enum`,
+//! `const`, `properties`, `required`, `additionalProperties`, `items`, `minItems`, `maxItems`,
+//! `minLength`, `maxLength`, `pattern`, `minimum` and `maximum` are supported, others are ignored.
+use crate::value::Value;
+use regex::Regex;
+use serde_json::{Map, Value as Json};
+use std::{collections::HashMap, fmt, fs, io};
+
+/// The ways a value does not conform to its schema, each prefixed by the JSON pointer of the part
+/// of the value it is about.
+#[derive(Debug, PartialEq)]
+pub struct SchemaErrors(pub Vec<String>);
+
+impl fmt::Display for SchemaErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.join("; "))
+    }
+}
+
+/// The schemas of each namespace.
+pub struct Schemas {
+    namespaces: HashMap<String, Json>,
+}
+
+impl Schemas {
+    /// Returns the schemas read from the files in `settings`, or the error of a file that could
+    /// not be read or is not JSON.
+    /// # Arguments
+    /// * `settings` - The path of the schema file for each key prefix.
+    pub fn load(settings: &HashMap<String, String>) -> io::Result<Self> {
+        let mut namespaces = HashMap::new();
+        for (prefix, path) in settings {
+            let schema = serde_json::from_slice(&fs::read(path)?).map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid schema {}: {}", path, err),
+                )
+            })?;
+            namespaces.insert(prefix.clone(), schema);
+        }
+        Ok(Self { namespaces })
+    }
+
+    /// Returns the schema of `key`, from the namespace with the longest prefix of `key`.
+    fn schema(&self, key: &str) -> Option<&Json> {
+        self.namespaces
+            .iter()
+            .filter(|(prefix, _)| key.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, schema)| schema)
+    }
+
+    /// Returns Ok if `value` conforms to the schema of `key` or there is none.
+    pub fn validate(&self, key: &str, value: &Value) -> Result<(), SchemaErrors> {
+        let schema = match self.schema(key) {
+            Some(schema) => schema,
+            None => return Ok(()),
+        };
+        let instance: Json = serde_json::from_reader(value.reader())
+            .map_err(|err| SchemaErrors(vec![format!("/: not JSON, {}", err)]))?;
+        let mut errors = Vec::new();
+        check(schema, &instance, "", &mut errors);
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(SchemaErrors(errors))
+        }
+    }
+}
+
+/// Returns the JSON Schema type name of `instance`.
+fn type_name(instance: &Json) -> &'static str {
+    match instance {
+        Json::Null => "null",
+        Json::Bool(_) => "boolean",
+        Json::Number(number) if number.is_i64() || number.is_u64() => "integer",
+        Json::Number(_) => "number",
+        Json::String(_) => "string",
+        Json::Array(_) => "array",
+        Json::Object(_) => "object",
+    }
+}
+
+/// Returns true if `instance` has the JSON Schema type `name`.
+fn has_type(instance: &Json, name: &str) -> bool {
+    let actual = type_name(instance);
+    actual == name || (name == "number" && actual == "integer")
+}
+
+/// Returns the JSON pointer `path`, or `/` for the whole value.
+fn pointer(path: &str) -> &str {
+    if path.is_empty() {
+        "/"
+    } else {
+        path
+    }
+}
+
+/// Adds the ways `instance` at `path` does not conform to `schema` to `errors`.
+fn check(schema: &Json, instance: &Json, path: &str, errors: &mut Vec<String>) {
+    let schema = match schema {
+        Json::Object(schema) => schema,
+        // `true` and `{}` accept anything, `false` nothing.
+        Json::Bool(false) => return errors.push(format!("{}: not allowed", pointer(path))),
+        _ => return,
+    };
+    let mut error = |message: String| errors.push(format!("{}: {}", pointer(path), message));
+    let types: Vec<&str> = match schema.get("type") {
+        Some(Json::String(name)) => vec![name.as_str()],
+        Some(Json::Array(names)) => names.iter().filter_map(Json::as_str).collect(),
+        _ => vec![],
+    };
+    if !types.is_empty() && !types.iter().any(|name| has_type(instance, name)) {
+        return error(format!(
+            "expected {}, found {}",
+            types.join(" or "),
+            type_name(instance)
+        ));
+    }
+    if let Some(Json::Array(values)) = schema.get("enum") {
+        if !values.contains(instance) {
+            error(format!("must be one of {}", Json::Array(values.clone())));
+        }
+    }
+    if let Some(value) = schema.get("const") {
+        if value != instance {
+            error(format!("must be {}", value));
+        }
+    }
+    let limit = |name: &str| schema.get(name).and_then(Json::as_f64);
+    match instance {
+        Json::String(string) => {
+            let len = string.chars().count() as f64;
+            if limit("minLength").is_some_and(|min| len < min) {
+                error(format!("shorter than {}", schema["minLength"]));
+            }
+            if limit("maxLength").is_some_and(|max| len > max) {
+                error(format!("longer than {}", schema["maxLength"]));
+            }
+            if let Some(pattern) = schema.get("pattern").and_then(Json::as_str) {
+                match Regex::new(pattern) {
+                    Ok(regex) if !regex.is_match(string) => {
+                        error(format!("does not match {}", pattern))
+                    }
+                    Ok(_) => {}
+                    Err(err) => error(format!("invalid pattern {}: {}", pattern, err)),
+                }
+            }
+        }
+        Json::Number(number) => {
+            let number = number.as_f64().unwrap_or_default();
+            if limit("minimum").is_some_and(|min| number < min) {
+                error(format!("less than {}", schema["minimum"]));
+            }
+            if limit("maximum").is_some_and(|max| number > max) {
+                error(format!("greater than {}", schema["maximum"]));
+            }
+        }
+        Json::Array(items) => {
+            let len = items.len() as f64;
+            if limit("minItems").is_some_and(|min| len < min) {
+                error(format!("fewer than {} items", schema["minItems"]));
+            }
+            if limit("maxItems").is_some_and(|max| len > max) {
+                error(format!("more than {} items", schema["maxItems"]));
+            }
+            if let Some(item_schema) = schema.get("items") {
+                for (index, item) in items.iter().enumerate() {
+                    check(item_schema, item, &format!("{}/{}", path, index), errors);
+                }
+            }
+        }
+        Json::Object(object) => check_object(schema, object, path, errors),
+        _ => {}
+    }
+}
+
+/// Adds the ways the fields of `object` at `path` do not conform to `schema` to `errors`.
+fn check_object(
+    schema: &Map<String, Json>,
+    object: &Map<String, Json>,
+    path: &str,
+    errors: &mut Vec<String>,
+) {
+    if let Some(Json::Array(required)) = schema.get("required") {
+        for name in required.iter().filter_map(Json::as_str) {
+            if !object.contains_key(name) {
+                errors.push(format!(
+                    "{}: missing required field {}",
+                    pointer(path),
+                    name
+                ));
+            }
+        }
+    }
+    let properties = schema.get("properties").and_then(Json::as_object);
+    for (name, value) in object {
+        let field_path = format!("{}/{}", path, name.replace('~', "~0").replace('/', "~1"));
+        match properties.and_then(|properties| properties.get(name)) {
+            Some(field_schema) => check(field_schema, value, &field_path, errors),
+            None => {
+                if let Some(additional) = schema.get("additionalProperties") {
+                    check(additional, value, &field_path, errors);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn schemas() -> Schemas {
+        let mut namespaces = HashMap::new();
+        namespaces.insert(
+            "users/".to_string(),
+            json!({
+                "type": "object",
+                "required": ["name"],
+                "properties": {
+                    "name": {"type": "string", "minLength": 1},
+                    "age": {"type": "integer", "minimum": 0},
+                    "tags": {"type": "array", "items": {"enum": ["a", "b"]}}
+                },
+                "additionalProperties": false
+            }),
+        );
+        Schemas { namespaces }
+    }
+
+    fn validate(key: &str, value: Json) -> Result<(), SchemaErrors> {
+        schemas().validate(key, &Value::from(value.to_string()))
+    }
+
+    #[test]
+    fn conforming_values_are_accepted() {
+        assert_eq!(
+            validate("users/1", json!({"name": "a", "age": 3, "tags": ["b"]})),
+            Ok(())
+        );
+        assert_eq!(validate("other", json!(1)), Ok(()));
+    }
+
+    #[test]
+    fn every_error_is_reported() {
+        let errors = validate(
+            "users/1",
+            json!({"age": -1.5, "tags": ["c"], "extra": true}),
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            errors.0,
+            vec![
+                "/: missing required field name",
+                "/age: expected integer, found number",
+                "/extra: not allowed",
+                "/tags/0: must be one of [\"a\",\"b\"]",
+            ]
+        );
+    }
+
+    #[test]
+    fn values_must_be_json() {
+        let errors = schemas()
+            .validate("users/1", &Value::from("not json"))
+            .unwrap_err();
+
+        assert!(errors.0[0].starts_with("/: not JSON"));
+    }
+}
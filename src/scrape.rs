@@ -0,0 +1,67 @@
+//! Keeps the rendered output of `/metrics` for `metrics.scrape_cache_ms`, so frequent and
+//! concurrent scrapes, e.g. by several Prometheus replicas, share one gathering of the registry.
+//! Scrapes arriving while the output is rendered wait for it rather than rendering it again.
+use actix_web::web::Bytes;
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// The output of `/metrics` last rendered in each format and when.
+pub struct Scrapes {
+    ttl: Duration,
+    /// Indexed by whether the output is in the OpenMetrics format.
+    rendered: Mutex<[Option<(Instant, Bytes)>; 2]>,
+}
+
+impl Scrapes {
+    /// Returns new `Scrapes` keeping the output for `ttl`, or never when it is zero.
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            rendered: Mutex::new([None, None]),
+        }
+    }
+
+    /// Returns the output in the OpenMetrics or Prometheus format, rendered with `render` unless
+    /// it was rendered within the ttl.
+    pub fn get_or_render<E, F>(&self, openmetrics: bool, render: F) -> Result<Bytes, E>
+    where
+        F: FnOnce() -> Result<Bytes, E>,
+    {
+        if self.ttl == Duration::default() {
+            return render();
+        }
+        let mut rendered = self.rendered.lock().unwrap();
+        let slot = &mut rendered[openmetrics as usize];
+        if let Some((at, output)) = slot {
+            if at.elapsed() < self.ttl {
+                return Ok(output.clone());
+            }
+        }
+        let output = render()?;
+        *slot = Some((Instant::now(), output.clone()));
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn output_is_rendered_once_per_format_within_the_ttl() {
+        let sut = Scrapes::new(Duration::from_secs(60));
+        let mut renders = 0;
+        let mut render = |openmetrics| {
+            sut.get_or_render(openmetrics, || {
+                renders += 1;
+                Ok::<_, ()>(Bytes::from(renders.to_string()))
+            })
+        };
+
+        assert_eq!(render(false), Ok(Bytes::from("1")));
+        assert_eq!(render(false), Ok(Bytes::from("1")));
+        assert_eq!(render(true), Ok(Bytes::from("2")));
+    }
+}
@@ -0,0 +1,411 @@
+//! Runs Lua scripts from `scripting.dir` on the cache server, so operators can define small
+//! server-side operations such as conditional updates or updates of several related keys, invoked
+//! with `POST /_script/{name}` and a body of `{"keys": [...], "args": [...]}`.
+//!
+//! Like Redis scripts, a script gets its keys in `KEYS` and its arguments in `ARGV` and can only
+//! use the keys it declared, through `cache.get(key)`, `cache.put(key, value)` and
+//! `cache.delete(key)`. Scripts are applied atomically with respect to other scripts and
+//! transactions using any of the same keys. The value returned by a script is sent back as JSON.
+//!
+//! Scripts only get the base, `string`, `table` and `math` libraries, without the base functions
+//! that read files or load bytecode, and are stopped once they have run for `scripting.timeout`
+//! milliseconds. Their puts go through the checks of `POST /{key}` and every put and delete is
+//! recorded in the audit trail.
+use crate::audit::{Auditor, Change};
+use crate::cache::SimpleCache;
+use crate::eviction::Priority;
+use crate::limits::KeyLimiter;
+use crate::pressure::MemoryPressure;
+use crate::settings;
+use crate::value::Value;
+use crate::write_checks::WriteChecks;
+use actix_web::{error::BlockingError, post, web, HttpRequest, HttpResponse};
+use mlua::{HookTriggers, Lua, LuaSerdeExt, StdLib};
+use serde::Deserialize;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fs, io,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+/// The extension of script files.
+const EXTENSION: &str = "lua";
+
+/// The base library functions scripts do not get, as they read files or load bytecode.
+const REMOVED_GLOBALS: [&str; 3] = ["dofile", "loadfile", "load"];
+
+/// The number of instructions a script runs between checks of its deadline.
+const INSTRUCTIONS_PER_CHECK: u32 = 1000;
+
+/// The keys and arguments a script is called with.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Call {
+    keys: Vec<String>,
+    args: Vec<serde_json::Value>,
+}
+
+/// The result of a script, or its error, and the changes it made before it returned or failed.
+pub struct Outcome {
+    pub result: Result<serde_json::Value, String>,
+    pub changes: Vec<Change>,
+}
+
+/// The scripts that can be called, by name.
+pub struct Scripts {
+    sources: HashMap<String, String>,
+    timeout: Duration,
+}
+
+impl Scripts {
+    /// Reads every `.lua` file in `dir`, named by the file name without the extension.
+    /// # Arguments
+    /// * `dir` - The directory of the scripts.
+    /// * `timeout` - How long a script may run before it is stopped.
+    pub fn load<P: AsRef<Path>>(dir: P, timeout: Duration) -> io::Result<Self> {
+        let mut sources = HashMap::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == EXTENSION)
+            {
+                if let Some(name) = path.file_stem().and_then(|name| name.to_str()) {
+                    sources.insert(name.to_string(), fs::read_to_string(&path)?);
+                }
+            }
+        }
+        log::info!("Loaded {} scripts", sources.len());
+        Ok(Self { sources, timeout })
+    }
+
+    /// Runs the script `name` with `call` and returns its result, or None if there is no such
+    /// script.
+    /// # Arguments
+    /// * `name` - The name of the script.
+    /// * `call` - The keys and arguments of the script.
+    /// * `cache` - The cache the script reads and writes.
+    /// * `checks` - What the puts of the script are checked against.
+    pub fn run(
+        &self,
+        name: &str,
+        mut call: Call,
+        cache: &SimpleCache<'static>,
+        checks: &WriteChecks,
+    ) -> Option<Outcome> {
+        let source = self.sources.get(name)?;
+        // Keys follow the same rules as in the path of the single key endpoints.
+        let keys: Result<Vec<_>, _> = call.keys.iter().map(|key| cache.key(key)).collect();
+        call.keys = match keys {
+            Ok(keys) => keys,
+            Err(err) => {
+                return Some(Outcome {
+                    result: Err(err.to_string()),
+                    changes: vec![],
+                })
+            }
+        };
+        let _guard = cache.lock_keys(&call.keys);
+        let changes = RefCell::new(vec![]);
+        let deadline = Instant::now() + self.timeout;
+        let result = run(name, source, &call, cache, checks, &changes, deadline);
+        Some(Outcome {
+            result: result.map_err(|err| message(&err)),
+            changes: changes.into_inner(),
+        })
+    }
+}
+
+/// Returns the message of `err`, without the traceback of errors raised by a callback.
+fn message(err: &mlua::Error) -> String {
+    match err {
+        mlua::Error::CallbackError { cause, .. } => message(cause),
+        err => err.to_string(),
+    }
+}
+
+/// Returns the normalized `key`, or an error unless the script declared it.
+fn declared(call: &Call, cache: &SimpleCache<'static>, key: &str) -> mlua::Result<String> {
+    let key = cache
+        .key(key)
+        .map_err(|err| mlua::Error::RuntimeError(err.to_string()))?;
+    if call.keys.contains(&key) {
+        Ok(key)
+    } else {
+        Err(mlua::Error::RuntimeError(format!(
+            "key {} is not in KEYS",
+            key
+        )))
+    }
+}
+
+/// Returns a Lua state with only the libraries scripts may use, which stops with an error once
+/// `deadline` has passed.
+fn sandbox(deadline: Instant) -> mlua::Result<Lua> {
+    let lua = Lua::new_with(StdLib::STRING | StdLib::TABLE | StdLib::MATH)?;
+    for name in &REMOVED_GLOBALS {
+        lua.globals().set(*name, mlua::Value::Nil)?;
+    }
+    let triggers = HookTriggers {
+        every_nth_instruction: Some(INSTRUCTIONS_PER_CHECK),
+        ..Default::default()
+    };
+    lua.set_hook(triggers, move |_, _| {
+        if Instant::now() < deadline {
+            Ok(())
+        } else {
+            Err(mlua::Error::RuntimeError(
+                "The script ran for longer than scripting.timeout".to_string(),
+            ))
+        }
+    })?;
+    Ok(lua)
+}
+
+fn run(
+    name: &str,
+    source: &str,
+    call: &Call,
+    cache: &SimpleCache<'static>,
+    checks: &WriteChecks,
+    changes: &RefCell<Vec<Change>>,
+    deadline: Instant,
+) -> mlua::Result<serde_json::Value> {
+    let lua = sandbox(deadline)?;
+    let globals = lua.globals();
+    globals.set("KEYS", call.keys.clone())?;
+    globals.set("ARGV", lua.to_value(&call.args)?)?;
+    lua.scope(|scope| {
+        let api = lua.create_table()?;
+        api.set(
+            "get",
+            scope.create_function(|_, key: String| {
+                let key = declared(call, cache, &key)?;
+                Ok(cache.get(key, &|value| {
+                    String::from_utf8_lossy(&value.to_bytes()).into_owned()
+                }))
+            })?,
+        )?;
+        api.set(
+            "put",
+            scope.create_function(|_, (key, value): (String, String)| {
+                let key = declared(call, cache, &key)?;
+                let size = value.len() as u64;
+                checks
+                    .put(cache, key.clone(), Value::from(value), Priority::Normal)
+                    .map_err(|rejected| mlua::Error::RuntimeError(rejected.to_string()))?;
+                changes.borrow_mut().push(Change {
+                    operation: "put",
+                    key,
+                    size: Some(size),
+                });
+                Ok(())
+            })?,
+        )?;
+        api.set(
+            "delete",
+            scope.create_function(|_, key: String| {
+                let key = declared(call, cache, &key)?;
+                let removed = cache.remove(&key);
+                if removed {
+                    changes.borrow_mut().push(Change {
+                        operation: "delete",
+                        key,
+                        size: None,
+                    });
+                }
+                Ok(removed)
+            })?,
+        )?;
+        globals.set("cache", api)?;
+        let result: mlua::Value = lua.load(source).set_name(name)?.eval()?;
+        lua.from_value(result)
+    })
+}
+
+/// Runs a script and responds with its result as JSON.
+#[post("/_script/{name}")]
+#[allow(clippy::too_many_arguments)]
+async fn script(
+    req: HttpRequest,
+    name: web::Path<String>,
+    call: web::Json<Call>,
+    cache: web::Data<SimpleCache<'static>>,
+    pressure: web::Data<MemoryPressure>,
+    limiter: web::Data<KeyLimiter>,
+    settings: web::Data<settings::Cache>,
+    scripts: Option<web::Data<Scripts>>,
+    auditor: Option<web::Data<Auditor>>,
+) -> HttpResponse {
+    let scripts = match scripts {
+        Some(scripts) => scripts,
+        None => return HttpResponse::NotFound().body("Scripting is not enabled"),
+    };
+    let name = name.into_inner();
+    let checks = WriteChecks::new(&req, pressure, limiter, settings);
+    // Scripts may wait for the keys of other scripts and transactions, so they run on the blocking
+    // thread pool.
+    let outcome = web::block(move || {
+        scripts
+            .run(&name, call.into_inner(), &cache, &checks)
+            .ok_or(())
+    })
+    .await;
+    let outcome = match outcome {
+        Ok(outcome) => outcome,
+        Err(BlockingError::Error(())) => return HttpResponse::NotFound().body("No such script"),
+        Err(BlockingError::Canceled) => return HttpResponse::InternalServerError().finish(),
+    };
+    if let Some(auditor) = auditor {
+        auditor.write_changes(&req, &outcome.changes);
+    }
+    match outcome.result {
+        Ok(result) => HttpResponse::Ok().json(result),
+        Err(err) => HttpResponse::UnprocessableEntity().body(format!("Script error: {}", err)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::{CacheMetrics, MetricOpts};
+    use crate::settings::Settings;
+
+    fn scripts(source: &str) -> Scripts {
+        let mut sources = HashMap::new();
+        sources.insert("test".to_string(), source.to_string());
+        Scripts {
+            sources,
+            timeout: Duration::from_secs(1),
+        }
+    }
+
+    fn call(keys: &[&str], args: serde_json::Value) -> Call {
+        Call {
+            keys: keys.iter().map(|key| key.to_string()).collect(),
+            args: serde_json::from_value(args).unwrap(),
+        }
+    }
+
+    fn checks(pressure: settings::MemoryPressure) -> WriteChecks {
+        WriteChecks {
+            pressure: web::Data::new(MemoryPressure::new(pressure, &MetricOpts::default())),
+            limiter: web::Data::new(KeyLimiter::new(Default::default(), &MetricOpts::default())),
+            settings: web::Data::new(Settings::new().unwrap().cache),
+            client: None,
+            usage: None,
+        }
+    }
+
+    #[test]
+    fn scripts_update_their_keys() {
+        let cache = SimpleCache::new(Duration::from_secs(60), CacheMetrics::default());
+        cache.put("a", "1");
+        let sut = scripts(
+            r#"
+            if cache.get(KEYS[1]) == ARGV[1] then
+                cache.put(KEYS[1], ARGV[2])
+                return {updated = true}
+            end
+            return {updated = false}
+            "#,
+        );
+        let checks = checks(Default::default());
+
+        let updated = sut
+            .run(
+                "test",
+                call(&["a"], serde_json::json!(["1", "2"])),
+                &cache,
+                &checks,
+            )
+            .unwrap();
+        let unchanged = sut
+            .run(
+                "test",
+                call(&["a"], serde_json::json!(["1", "3"])),
+                &cache,
+                &checks,
+            )
+            .unwrap();
+
+        assert_eq!(updated.result, Ok(serde_json::json!({"updated": true})));
+        assert_eq!(
+            updated.changes,
+            vec![Change {
+                operation: "put",
+                key: "a".to_string(),
+                size: Some(1),
+            }]
+        );
+        assert_eq!(unchanged.result, Ok(serde_json::json!({"updated": false})));
+        assert!(unchanged.changes.is_empty());
+        assert_eq!(cache.get("a", &|value| value.clone()), Some("2".into()));
+    }
+
+    #[test]
+    fn scripts_can_only_use_their_keys() {
+        let cache = SimpleCache::new(Duration::from_secs(60), CacheMetrics::default());
+        let sut = scripts(r#"return cache.get("b")"#);
+        let checks = checks(Default::default());
+
+        let outcome = sut.run("test", call(&["a"], serde_json::json!([])), &cache, &checks);
+
+        assert!(outcome.unwrap().result.is_err());
+        assert!(sut.run("other", Call::default(), &cache, &checks).is_none());
+    }
+
+    #[test]
+    fn scripts_only_get_safe_libraries() {
+        let cache = SimpleCache::new(Duration::from_secs(60), CacheMetrics::default());
+        let sut = scripts(
+            r#"
+            return {io = io == nil, os = os == nil, load = load == nil, math = math.max(1, 2)}
+            "#,
+        );
+
+        let outcome = sut.run("test", Call::default(), &cache, &checks(Default::default()));
+
+        assert_eq!(
+            outcome.unwrap().result,
+            Ok(serde_json::json!({"io": true, "os": true, "load": true, "math": 2}))
+        );
+    }
+
+    #[test]
+    fn scripts_are_stopped_after_the_timeout() {
+        let cache = SimpleCache::new(Duration::from_secs(60), CacheMetrics::default());
+        let mut sut = scripts("while true do end");
+        sut.timeout = Duration::from_millis(10);
+
+        let outcome = sut.run("test", Call::default(), &cache, &checks(Default::default()));
+
+        assert!(outcome.unwrap().result.unwrap_err().contains("timeout"));
+    }
+
+    #[test]
+    fn script_puts_are_checked_like_writes() {
+        let cache = SimpleCache::new(Duration::from_secs(60), CacheMetrics::default());
+        let sut = scripts("cache.put(KEYS[1], ARGV[1])");
+        let checks = checks(settings::MemoryPressure {
+            high_water_mark: Some(1),
+            ..Default::default()
+        });
+        cache.put("b", "1");
+
+        let outcome = sut
+            .run(
+                "test",
+                call(&["a"], serde_json::json!(["1"])),
+                &cache,
+                &checks,
+            )
+            .unwrap();
+
+        assert!(outcome.result.unwrap_err().contains("memory pressure"));
+        assert!(outcome.changes.is_empty());
+        assert!(!cache.contains_key("a"));
+    }
+}
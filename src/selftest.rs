@@ -0,0 +1,247 @@
+//! Checks that the cache works in a new environment, started with `selftest`: values are written,
+//! read, expired, exported and imported, and spilled to a disk tier against caches configured by
+//! the settings, on a virtual clock so no check waits for a ttl. The directories the configured
+//! persistence uses are checked for access.
+//!
+//! The outcome of every check is printed as a JSON line, and the self-test fails if any check
+//! failed. The servers are not started and the configured disk tier is not opened, so a self-test
+//! can run next to a running server.
+use crate::cache::{CacheMetrics, MetricOpts, SimpleCache};
+use crate::clock::VirtualClock;
+#[cfg(feature = "persistence")]
+use crate::disk::{DiskMetrics, DiskTier};
+use crate::settings::Settings;
+use crate::slab::{SlabAllocator, SlabMetrics};
+use actix_web::web::Bytes;
+use serde::Serialize;
+use std::{io, sync::Arc, time::Duration};
+#[cfg(feature = "persistence")]
+use std::{env, fs, path::Path, process};
+
+/// The value written by the checks.
+const VALUE: &str = "simple-mem-cache self-test";
+
+/// A check run against caches configured by the settings.
+type CheckFn = fn(&Settings) -> Result<(), String>;
+
+/// The outcome of a check.
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub check: &'static str,
+    pub passed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Returns `error` unless `condition` holds.
+fn ensure(condition: bool, error: &str) -> Result<(), String> {
+    if condition {
+        Ok(())
+    } else {
+        Err(error.to_string())
+    }
+}
+
+/// Returns the value of `key` in `cache`.
+fn read(cache: &SimpleCache<'static>, key: &'static str) -> Option<Bytes> {
+    cache.get(key, &|value| value.to_bytes())
+}
+
+/// Returns an empty cache configured like the cache server by `settings`, reading the time from
+/// `clock`.
+fn cache(settings: &Settings, clock: &Arc<VirtualClock>) -> SimpleCache<'static> {
+    let cache_settings = &settings.cache;
+    let mut cache = SimpleCache::new(
+        Duration::from_secs(cache_settings.key_live_duration),
+        CacheMetrics::default(),
+    )
+    .with_checksums(cache_settings.checksum, cache_settings.verify_checksums)
+    .with_eviction_policy(cache_settings.eviction_policy)
+    .with_clock(clock.clone());
+    if let Some(time_to_idle) = cache_settings.time_to_idle {
+        cache = cache.with_time_to_idle(Duration::from_secs(time_to_idle));
+    }
+    let slab_settings = &cache_settings.slab;
+    if slab_settings.enabled {
+        cache = cache.with_slab_allocator(SlabAllocator::new(
+            slab_settings.slab_size,
+            slab_settings.max_value_size,
+            SlabMetrics::with_opts(&MetricOpts::default()),
+        ));
+    }
+    cache
+}
+
+/// Writes, replaces, reads and removes a value.
+fn round_trip(settings: &Settings) -> Result<(), String> {
+    let cache = cache(settings, &Arc::default());
+    let key = "selftest/round-trip";
+    cache.put(key, VALUE);
+    ensure(
+        read(&cache, key) == Some(Bytes::from(VALUE)),
+        "The value written was not read",
+    )?;
+    cache.put(key, "replaced");
+    ensure(
+        read(&cache, key) == Some(Bytes::from("replaced")),
+        "The value replaced was not read",
+    )?;
+    ensure(cache.remove(key), "The value was not removed")?;
+    ensure(read(&cache, key).is_none(), "The value removed was read")
+}
+
+/// Expires values with a ttl and the default ttl, but not values that never expire.
+fn expiry(settings: &Settings) -> Result<(), String> {
+    let clock = Arc::new(VirtualClock::default());
+    let cache = cache(settings, &clock);
+    cache.put_with_ttl("selftest/ttl", VALUE, Duration::from_secs(1));
+    cache.put("selftest/default", VALUE);
+    cache.put_forever("selftest/forever", VALUE);
+    ensure(
+        read(&cache, "selftest/ttl").is_some(),
+        "A value with a ttl expired early",
+    )?;
+    clock.advance(Duration::from_secs(settings.cache.key_live_duration + 1));
+    cache.remove_expired();
+    ensure(
+        read(&cache, "selftest/ttl").is_none(),
+        "A value with a ttl did not expire",
+    )?;
+    ensure(
+        read(&cache, "selftest/default").is_none(),
+        "A value did not expire after cache.key_live_duration",
+    )?;
+    ensure(
+        read(&cache, "selftest/forever").is_some(),
+        "A value that never expires expired",
+    )
+}
+
+/// Exports values and imports them into another cache, as a handoff does.
+fn export_import(settings: &Settings) -> Result<(), String> {
+    let source = cache(settings, &Arc::default());
+    source.put("selftest/exported", VALUE);
+    source.put_forever("selftest/forever", VALUE);
+    let entries = source.entries(|_| true);
+    let entries = serde_json::to_vec(&entries).map_err(|err| err.to_string())?;
+    let entries = serde_json::from_slice(&entries).map_err(|err| err.to_string())?;
+    let target = cache(settings, &Arc::default());
+    ensure(target.import(entries) == 2, "Not every value was imported")?;
+    ensure(
+        read(&target, "selftest/exported") == Some(Bytes::from(VALUE)),
+        "An imported value was not read",
+    )?;
+    ensure(
+        read(&target, "selftest/forever").is_some(),
+        "An imported value that never expires was not read",
+    )
+}
+
+/// Spills a value to a disk tier in a temporary directory and reads it back into memory.
+#[cfg(feature = "persistence")]
+fn disk_tier(settings: &Settings) -> Result<(), String> {
+    let dir = env::temp_dir().join(format!("simple-mem-cache-selftest-{}", process::id()));
+    let disk_tier = DiskTier::open(&dir, DiskMetrics::with_opts(&MetricOpts::default()))
+        .map_err(|err| format!("Could not open a disk tier in {}. {}", dir.display(), err))?;
+    let cache = cache(settings, &Arc::default()).with_disk_tier(disk_tier);
+    let result = ensure(
+        cache.put_on_disk("selftest/disk", VALUE),
+        "The value was not written to disk",
+    )
+    .and_then(|_| {
+        ensure(
+            read(&cache, "selftest/disk") == Some(Bytes::from(VALUE)),
+            "The value written to disk was not read",
+        )
+    });
+    let _ = fs::remove_dir_all(&dir);
+    result
+}
+
+/// Creates and removes a file in `dir`, creating it if needed.
+#[cfg(feature = "persistence")]
+fn writable(setting: &str, dir: &Path) -> Result<(), String> {
+    let probe = dir.join(".simple-mem-cache-selftest");
+    fs::create_dir_all(dir)
+        .and_then(|_| fs::write(&probe, VALUE))
+        .and_then(|_| fs::remove_file(&probe))
+        .map_err(|err| format!("{} {} is not writable. {}", setting, dir.display(), err))
+}
+
+/// Checks the directories and files of the configured persistence.
+#[cfg(feature = "persistence")]
+fn persistence(settings: &Settings) -> Result<(), String> {
+    if let Some(path) = &settings.disk_tier.path {
+        writable("disk_tier.path", Path::new(path))?;
+    }
+    if let Some(path) = &settings.handoff.socket_path {
+        if let Some(dir) = Path::new(path)
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+        {
+            writable("The directory of handoff.socket_path", dir)?;
+        }
+    }
+    if let Some(dir) = settings
+        .cache
+        .pins_path
+        .as_deref()
+        .and_then(|path| Path::new(path).parent())
+        .filter(|dir| !dir.as_os_str().is_empty())
+    {
+        writable("The directory of cache.pins_path", dir)?;
+    }
+    if let Some(path) = &settings.cache.warmup_manifest {
+        fs::metadata(path)
+            .map_err(|err| format!("cache.warmup_manifest {} can not be read. {}", path, err))?;
+    }
+    Ok(())
+}
+
+/// Runs every check against caches configured by `settings`, printing the outcome of each.
+pub fn run(settings: &Settings) -> io::Result<()> {
+    let checks: Vec<(&'static str, CheckFn)> = vec![
+        ("round_trip", round_trip),
+        ("expiry", expiry),
+        ("export_import", export_import),
+        #[cfg(feature = "persistence")]
+        ("disk_tier", disk_tier),
+        #[cfg(feature = "persistence")]
+        ("persistence", persistence),
+    ];
+    let total = checks.len();
+    let mut failed = 0;
+    for (check, test) in checks {
+        let error = test(settings).err();
+        if error.is_some() {
+            failed += 1;
+        }
+        let check = Check {
+            check,
+            passed: error.is_none(),
+            error,
+        };
+        // Serializing a struct of strings and booleans can not fail.
+        println!("{}", serde_json::to_string(&check).unwrap());
+    }
+    if failed > 0 {
+        return Err(io::Error::other(format!(
+            "{} of {} self-test checks failed",
+            failed, total
+        )));
+    }
+    log::info!("Passed {} self-test checks", total);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn checks_pass_with_the_default_settings() {
+        let settings = Settings::new().unwrap();
+
+        assert!(run(&settings).is_ok());
+    }
+}
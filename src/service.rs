@@ -0,0 +1,284 @@
+//! Runs the server under the service manager of the host: the `install` and `uninstall`
+//! subcommands register the binary as a Windows service or a launchd daemon on macOS, starting it
+//! with the configuration files next to the binary.
+//!
+//! Windows starts the service with `--service`, logging then goes to the Windows event log under
+//! the `simple-mem-cache` source and a stop request from the service control manager shuts the
+//! server down like SIGTERM does. launchd stops the daemon with SIGTERM.
+use std::{env, io};
+
+/// The name of the service and the event log source.
+#[cfg(any(windows, target_os = "macos"))]
+const SERVICE_NAME: &str = "simple-mem-cache";
+
+/// The subcommand, if the process was started with one.
+pub enum Command {
+    Install,
+    Uninstall,
+}
+
+impl Command {
+    /// Returns the subcommand of the process arguments.
+    pub fn from_args() -> Option<Self> {
+        match env::args().nth(1).as_deref() {
+            Some("install") => Some(Self::Install),
+            Some("uninstall") => Some(Self::Uninstall),
+            _ => None,
+        }
+    }
+
+    /// Runs the subcommand.
+    pub fn run(self) -> io::Result<()> {
+        match self {
+            Self::Install => install(),
+            Self::Uninstall => uninstall(),
+        }
+    }
+}
+
+/// Returns the path of the binary and the directory it is in, which the service runs in.
+#[cfg(any(windows, target_os = "macos"))]
+fn executable() -> io::Result<(std::path::PathBuf, std::path::PathBuf)> {
+    let path = env::current_exe()?;
+    let dir = path
+        .parent()
+        .map(std::path::PathBuf::from)
+        .ok_or_else(|| io::Error::other("The binary is not in a directory"))?;
+    Ok((path, dir))
+}
+
+#[cfg(windows)]
+pub use self::windows::{dispatch, install, is_service, uninstall};
+
+#[cfg(target_os = "macos")]
+pub use self::launchd::{install, uninstall};
+
+#[cfg(not(any(windows, target_os = "macos")))]
+fn install() -> io::Result<()> {
+    Err(io::Error::other(
+        "install is only supported on Windows and macOS, use the init system of the host",
+    ))
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+fn uninstall() -> io::Result<()> {
+    install()
+}
+
+#[cfg(windows)]
+mod windows {
+    use super::{executable, SERVICE_NAME};
+    use actix_web::rt::System;
+    use futures::channel::oneshot;
+    use std::{env, ffi::OsString, io, time::Duration};
+    use windows_service::{
+        define_windows_service,
+        service::{
+            ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl,
+            ServiceExitCode, ServiceInfo, ServiceStartType, ServiceState, ServiceStatus,
+            ServiceType,
+        },
+        service_control_handler::{self, ServiceControlHandlerResult},
+        service_dispatcher,
+        service_manager::{ServiceManager, ServiceManagerAccess},
+    };
+
+    define_windows_service!(ffi_service_main, service_main);
+
+    /// Returns true if the process was started by the service control manager.
+    pub fn is_service() -> bool {
+        env::args().any(|arg| arg == "--service")
+    }
+
+    /// Registers the service, started automatically, and the event log source.
+    pub fn install() -> io::Result<()> {
+        let (path, _) = executable()?;
+        let manager = ServiceManager::local_computer(
+            None::<&str>,
+            ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+        )
+        .map_err(io::Error::other)?;
+        let info = ServiceInfo {
+            name: SERVICE_NAME.into(),
+            display_name: "Simple Mem Cache".into(),
+            service_type: ServiceType::OWN_PROCESS,
+            start_type: ServiceStartType::AutoStart,
+            error_control: ServiceErrorControl::Normal,
+            executable_path: path,
+            launch_arguments: vec!["--service".into()],
+            dependencies: vec![],
+            account_name: None,
+            account_password: None,
+        };
+        let service = manager
+            .create_service(&info, ServiceAccess::CHANGE_CONFIG)
+            .map_err(io::Error::other)?;
+        service
+            .set_description("An in-memory key value cache with an HTTP API")
+            .map_err(io::Error::other)?;
+        eventlog::register(SERVICE_NAME).map_err(|err| io::Error::other(err.to_string()))?;
+        println!("Installed the {} service", SERVICE_NAME);
+        Ok(())
+    }
+
+    /// Stops and removes the service and the event log source.
+    pub fn uninstall() -> io::Result<()> {
+        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+            .map_err(io::Error::other)?;
+        let access = ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE;
+        let service = manager
+            .open_service(SERVICE_NAME, access)
+            .map_err(io::Error::other)?;
+        let status = service.query_status().map_err(io::Error::other)?;
+        if status.current_state != ServiceState::Stopped {
+            service.stop().map_err(io::Error::other)?;
+        }
+        service.delete().map_err(io::Error::other)?;
+        eventlog::deregister(SERVICE_NAME).map_err(|err| io::Error::other(err.to_string()))?;
+        println!("Uninstalled the {} service", SERVICE_NAME);
+        Ok(())
+    }
+
+    /// Hands the process to the service control manager, returning once the service has stopped.
+    pub fn dispatch() -> io::Result<()> {
+        service_dispatcher::start(SERVICE_NAME, ffi_service_main).map_err(io::Error::other)
+    }
+
+    fn service_main(_arguments: Vec<OsString>) {
+        if let Err(err) = run_service() {
+            log::error!("{}", err);
+        }
+    }
+
+    /// Returns the status reported to the service control manager in `state`.
+    fn status(state: ServiceState, exit_code: u32) -> ServiceStatus {
+        ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: state,
+            controls_accepted: match state {
+                ServiceState::Running => {
+                    ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN
+                }
+                _ => ServiceControlAccept::empty(),
+            },
+            exit_code: ServiceExitCode::Win32(exit_code),
+            checkpoint: 0,
+            wait_hint: Duration::default(),
+            process_id: None,
+        }
+    }
+
+    fn run_service() -> io::Result<()> {
+        eventlog::init(SERVICE_NAME, log::Level::Info)
+            .map_err(|err| io::Error::other(err.to_string()))?;
+        // Services start in the system directory, the configuration files are next to the binary.
+        let (_, dir) = executable()?;
+        env::set_current_dir(dir)?;
+
+        let (stop, stopped) = oneshot::channel();
+        let mut stop = Some(stop);
+        let handler = move |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                if let Some(stop) = stop.take() {
+                    let _ = stop.send(());
+                }
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        };
+        let handle =
+            service_control_handler::register(SERVICE_NAME, handler).map_err(io::Error::other)?;
+        handle
+            .set_service_status(status(ServiceState::Running, 0))
+            .map_err(io::Error::other)?;
+
+        let result = System::new(SERVICE_NAME).block_on(crate::run(Some(stopped)));
+        if let Err(err) = &result {
+            log::error!("{}", err);
+        }
+        handle
+            .set_service_status(status(ServiceState::Stopped, result.is_err() as u32))
+            .map_err(io::Error::other)
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod launchd {
+    use super::{executable, SERVICE_NAME};
+    use std::{fs, io, path::Path, process};
+
+    /// The label of the daemon.
+    const LABEL: &str = "com.github.jlowry.simple-mem-cache";
+
+    /// Returns the path of the property list of the daemon.
+    fn plist_path() -> String {
+        format!("/Library/LaunchDaemons/{}.plist", LABEL)
+    }
+
+    /// Runs `launchctl` with `args`.
+    fn launchctl(args: &[&str]) -> io::Result<()> {
+        let status = process::Command::new("launchctl").args(args).status()?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(io::Error::other(format!(
+                "launchctl {} failed with {}",
+                args.join(" "),
+                status
+            )))
+        }
+    }
+
+    /// Writes the property list of a daemon started at boot and restarted when it exits, and
+    /// loads it.
+    pub fn install() -> io::Result<()> {
+        let (path, dir) = executable()?;
+        let log = format!("/usr/local/var/log/{}.log", SERVICE_NAME);
+        let plist = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{}</string>
+    <key>ProgramArguments</key>
+    <array>
+        <string>{}</string>
+    </array>
+    <key>WorkingDirectory</key>
+    <string>{}</string>
+    <key>RunAtLoad</key>
+    <true/>
+    <key>KeepAlive</key>
+    <true/>
+    <key>StandardOutPath</key>
+    <string>{}</string>
+    <key>StandardErrorPath</key>
+    <string>{}</string>
+</dict>
+</plist>
+"#,
+            LABEL,
+            path.display(),
+            dir.display(),
+            log,
+            log
+        );
+        if let Some(log_dir) = Path::new(&log).parent() {
+            fs::create_dir_all(log_dir)?;
+        }
+        fs::write(plist_path(), plist)?;
+        launchctl(&["load", "-w", &plist_path()])?;
+        println!("Installed the {} daemon", LABEL);
+        Ok(())
+    }
+
+    /// Unloads the daemon and removes its property list.
+    pub fn uninstall() -> io::Result<()> {
+        launchctl(&["unload", "-w", &plist_path()])?;
+        fs::remove_file(plist_path())?;
+        println!("Uninstalled the {} daemon", LABEL);
+        Ok(())
+    }
+}
@@ -1,17 +1,60 @@
-use config::{Config, ConfigError, Environment, File};
-use serde::Deserialize;
+use config::{Config, ConfigError, Environment, File, FileFormat};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::env;
 use std::net::SocketAddr;
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Settings {
     pub cache_server: HttpServer,
     pub metrics_server: HttpServer,
     pub logger_config_file: String,
     pub cache: Cache,
+    #[serde(default)]
+    pub metrics: Metrics,
+    #[serde(default)]
+    pub statsd: Statsd,
+    #[serde(default)]
+    pub slo: Slo,
+    #[serde(default)]
+    pub admin: Admin,
+    #[serde(default)]
+    pub handoff: Handoff,
+    #[serde(default)]
+    pub memory_pressure: MemoryPressure,
+    #[serde(default)]
+    pub disk_tier: DiskTier,
+    #[serde(default)]
+    pub redis: Redis,
+    #[serde(default)]
+    pub key_limits: KeyLimits,
+    #[serde(default)]
+    pub idempotency: Idempotency,
+    #[serde(default)]
+    pub usage: Usage,
+    #[serde(default)]
+    pub audit: Audit,
+    #[serde(default)]
+    pub scripting: Scripting,
+    #[serde(default)]
+    pub shadow: Shadow,
+    #[serde(default)]
+    pub trace: Trace,
+    #[serde(default)]
+    pub api: Api,
+    #[serde(default)]
+    pub discovery: Discovery,
+    #[serde(default)]
+    pub refresh: Refresh,
+    #[serde(default)]
+    pub cors: Cors,
+    #[serde(default)]
+    pub sandbox: Sandbox,
+    #[serde(default)]
+    pub invariants: Invariants,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct HttpServer {
     pub workers: Option<usize>,
     pub backlog: Option<i32>,
@@ -21,19 +64,759 @@ pub struct HttpServer {
     pub client_shutdown: Option<u64>,
     pub shutdown_timeout: Option<u64>,
     pub listen_addresses: Vec<SocketAddr>,
+    #[serde(default)]
+    pub bind_retry: BindRetry,
+    /// Sets SO_REUSEPORT so several processes can listen on the same port (unix only).
+    pub reuse_port: Option<bool>,
+    /// Sets TCP_NODELAY, which accepted connections inherit.
+    pub tcp_nodelay: Option<bool>,
+    /// Enables TCP keepalive probes after the connection has been idle for this many seconds.
+    pub tcp_keepalive: Option<u64>,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct BindRetry {
+    /// The number of times to retry binding an address that is in use.
+    pub attempts: u32,
+    /// The delay in milliseconds between attempts.
+    pub delay: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Cache {
     pub key_live_duration: u64,
+    /// The size in bytes of the chunks large values are stored in.
+    pub chunk_size: Option<usize>,
+    /// The largest value in bytes accepted by the cache server.
+    pub max_value_size: Option<usize>,
+    /// The checksum stored with each value.
+    #[serde(default)]
+    pub checksum: ChecksumAlgorithm,
+    /// Verifies the checksum of a value each time it is read.
+    #[serde(default)]
+    pub verify_checksums: bool,
+    #[serde(default)]
+    pub adaptive_ttl: AdaptiveTtl,
+    /// Seconds values may go unread and unchanged before they expire, even before
+    /// `key_live_duration` has elapsed.
+    #[serde(default)]
+    pub time_to_idle: Option<u64>,
+    /// Key prefixes whose values never expire unless a ttl is requested.
+    #[serde(default)]
+    pub immortal_prefixes: Vec<String>,
+    /// The shortest ttl in seconds clients may request, shorter requests are raised to it.
+    #[serde(default)]
+    pub min_ttl: Option<u64>,
+    /// The longest ttl in seconds clients may request, including for values that never expire.
+    #[serde(default)]
+    pub max_ttl: Option<u64>,
+    /// How room is made for new keys under memory pressure.
+    #[serde(default)]
+    pub eviction_policy: EvictionPolicy,
+    /// Another eviction policy simulated on the same requests, to compare hit ratios.
+    #[serde(default)]
+    pub eviction_experiment: EvictionExperiment,
+    #[serde(default)]
+    pub slab: Slab,
+    /// How keys are normalized and validated.
+    #[serde(default)]
+    pub keys: Keys,
+    /// Keys that may not be written.
+    #[serde(default)]
+    pub deny_keys: DenyKeys,
+    /// The path of a JSON Schema file values must conform to, by key prefix.
+    #[serde(default)]
+    pub schemas: HashMap<String, String>,
+    /// The caching headers sent with values.
+    #[serde(default)]
+    pub cache_control: CacheControl,
+    /// The request headers values are stored separately for, by key prefix.
+    #[serde(default)]
+    pub vary: HashMap<String, Vec<String>>,
+    /// Whether reads may be served by the local copy of a value.
+    #[serde(default)]
+    pub consistency: Consistency,
+    /// A file of entries loaded before the servers start listening.
+    #[serde(default)]
+    pub warmup_manifest: Option<String>,
+    /// The number of warmup URLs fetched at once.
+    #[serde(default)]
+    pub warmup_concurrency: Option<usize>,
+    /// The upper bounds in bytes of the size buckets items are counted in.
+    #[serde(default)]
+    pub size_buckets: Option<Vec<usize>>,
+    /// The file the keys pinned against eviction are saved to.
+    #[serde(default)]
+    pub pins_path: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EvictionPolicy {
+    /// Nothing is evicted, writes are rejected or go to the disk tier.
+    #[default]
+    None,
+    /// The oldest keys are evicted if the new key was accessed more often recently.
+    #[serde(rename = "tinylfu")]
+    TinyLfu,
+    /// Segmented LRU, keys read more than once are protected from eviction by scans.
+    Slru,
+}
+
+/// Simulates an eviction policy on the keys and sizes of the values written, alongside the real one.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct EvictionExperiment {
+    /// The simulated policy, no policy is simulated when `None`.
+    pub policy: Option<EvictionPolicy>,
+    /// The size in bytes of the simulated cache, the high-water mark of memory pressure when
+    /// `None`.
+    pub capacity: Option<u64>,
+}
+
+/// Renews frequently read keys when they expire, so `key_live_duration` can be lowered to expire
+/// rarely read keys sooner while the hot set stays resident.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AdaptiveTtl {
+    pub enabled: bool,
+    /// The reads within one `key_live_duration` needed to renew a key for another.
+    pub min_hits: u32,
+    /// The longest time in seconds a key is kept after it was written.
+    pub max_ttl: u64,
+}
+
+impl Default for AdaptiveTtl {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_hits: 2,
+            max_ttl: 7200,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheControlPolicy {
+    /// No caching headers are sent.
+    #[default]
+    None,
+    /// Any HTTP cache may keep values until they expire.
+    Public,
+    /// Only the client's own HTTP cache may keep values until they expire.
+    Private,
+    /// HTTP caches must not keep values.
+    NoStore,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CacheControl {
+    /// The policy for keys outside of every namespace.
+    pub default: CacheControlPolicy,
+    /// The policy for keys starting with each prefix, the longest matching prefix is used.
+    pub namespaces: HashMap<String, CacheControlPolicy>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConsistencyLevel {
+    /// Values in memory or on disk may be returned, even if Redis has a newer value.
+    #[default]
+    Eventual,
+    /// Values are read from Redis when there is a Redis tier.
+    Strong,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Consistency {
+    /// The level for keys outside of every namespace.
+    pub default: ConsistencyLevel,
+    /// The level for keys starting with each prefix, the longest matching prefix is used.
+    pub namespaces: HashMap<String, ConsistencyLevel>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyCharset {
+    /// Any character.
+    #[default]
+    Any,
+    /// Any character except control characters and whitespace.
+    Printable,
+    /// ASCII letters, digits and `-._~:/`.
+    UrlSafe,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Keys {
+    /// The longest key in bytes after normalization, unlimited when `None`.
+    pub max_length: Option<usize>,
+    /// The characters keys may contain.
+    pub charset: KeyCharset,
+    /// Lowercases keys.
+    pub lowercase: bool,
+    /// Decodes `%XX` escapes in keys, before lowercasing.
+    pub percent_decode: bool,
+    /// Accepts keys of several path segments, e.g. `GET /users/42/profile`.
+    pub nested: bool,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DenyKeys {
+    /// Writes to keys starting with any of these are rejected.
+    pub prefixes: Vec<String>,
+    /// Writes to keys matching any of these regular expressions are rejected.
+    pub patterns: Vec<String>,
+}
+
+/// Copies small values into shared slabs to reduce allocator churn.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Slab {
+    pub enabled: bool,
+    /// The size in bytes of each slab.
+    pub slab_size: usize,
+    /// The largest value in bytes copied into a slab.
+    pub max_value_size: usize,
+}
+
+impl Default for Slab {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            slab_size: 65536,
+            max_value_size: 1024,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub enum ChecksumAlgorithm {
+    #[default]
+    #[serde(rename = "xxhash")]
+    XxHash64,
+    #[serde(rename = "sha256")]
+    Sha256,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Metrics {
+    pub namespace: String,
+    pub subsystem: String,
+    pub const_labels: HashMap<String, String>,
+    /// Adds the time of the scrape to every sample served by `/metrics`.
+    pub timestamps: bool,
+    /// Milliseconds the output of `/metrics` is reused for, it is rendered for every scrape when 0.
+    pub scrape_cache_ms: u64,
+    /// Key prefixes whose bytes read and written are counted separately.
+    pub throughput_prefixes: Vec<String>,
+    /// A regex whose first capture group, or whole match, is the group a key is counted in.
+    pub key_group: Option<String>,
+    /// The number of key groups counted separately, later groups are counted as `other`.
+    pub max_key_groups: Option<usize>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StatsdFormat {
+    /// Labels and tags are sent as DogStatsD tags.
+    #[default]
+    Dogstatsd,
+    /// Label values are appended to the metric name and tags are not sent.
+    Statsd,
+}
+
+/// Pushes the metrics to a StatsD or DogStatsD agent.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Statsd {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+    /// Prepended to the name of every metric, followed by a dot.
+    pub prefix: String,
+    /// Sent with every metric in the DogStatsD format.
+    pub tags: HashMap<String, String>,
+    pub format: StatsdFormat,
+    /// The number of seconds between pushes.
+    pub interval: u64,
+    /// Stops serving `/metrics`, so metrics are only pushed to the agent.
+    pub replace_prometheus: bool,
+}
+
+impl Default for Statsd {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            host: "127.0.0.1".into(),
+            port: 8125,
+            prefix: String::new(),
+            tags: HashMap::new(),
+            format: StatsdFormat::default(),
+            interval: 10,
+            replace_prometheus: false,
+        }
+    }
+}
+
+/// Counts the requests of each endpoint answered within a latency threshold.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Slo {
+    pub enabled: bool,
+    /// The latency threshold in milliseconds of endpoints without their own.
+    pub threshold_ms: u64,
+    /// Thresholds in milliseconds by endpoint, e.g. `get` or `list_push`, overriding `threshold_ms`.
+    pub thresholds: HashMap<String, u64>,
+}
+
+impl Default for Slo {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            threshold_ms: 100,
+            thresholds: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Admin {
+    /// The bearer token required by the admin endpoints, no authentication when `None`.
+    pub auth_token: Option<String>,
+    /// Serves Swagger UI for the OpenAPI document at `/_admin/docs`.
+    pub swagger_ui: bool,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Handoff {
+    /// The Unix socket used to hand the cache to a process started with `--handoff`.
+    pub socket_path: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PressureSource {
+    /// The size of all values in the cache.
+    #[default]
+    CacheSize,
+    /// The resident set size of the process (Linux only).
+    Rss,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MemoryPressure {
+    /// What the water marks are measured against.
+    pub source: PressureSource,
+    /// Writes are rejected once memory use reaches this many bytes, never when `None`.
+    pub high_water_mark: Option<u64>,
+    /// Writes are accepted again once memory use falls below this many bytes, defaults to the
+    /// high-water mark.
+    pub low_water_mark: Option<u64>,
+    /// The fractions of the high-water mark that are warned about before writes are rejected.
+    pub warning_thresholds: Vec<f64>,
+    /// The fraction of the high-water mark memory use falls below a warning threshold by before
+    /// its warning clears.
+    pub warning_hysteresis: f64,
+    /// Warnings and pressure starting or ending are POSTed to this URL as JSON events, if set.
+    pub webhook_url: Option<String>,
+    /// The number of milliseconds between reads of the resident set size.
+    pub sample_interval: u64,
+}
+
+impl Default for MemoryPressure {
+    fn default() -> Self {
+        Self {
+            source: PressureSource::default(),
+            high_water_mark: None,
+            low_water_mark: None,
+            warning_thresholds: vec![],
+            warning_hysteresis: 0.05,
+            webhook_url: None,
+            sample_interval: 1000,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DiskTier {
+    /// The directory values are written to under memory pressure, no disk tier when `None`.
+    pub path: Option<String>,
+    /// The number of seconds between removing expired values from disk.
+    pub sweep_interval: u64,
+}
+
+impl Default for DiskTier {
+    fn default() -> Self {
+        Self {
+            path: None,
+            sweep_interval: 60,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Redis {
+    /// A `redis://[:password@]host[:port][/db]` url read from on a miss, no Redis tier when `None`.
+    pub url: Option<String>,
+    /// Also writes puts to Redis.
+    pub write_through: bool,
+    /// The connect, read and write timeout in milliseconds.
+    pub timeout: u64,
+    /// The most idle connections kept open.
+    pub pool_size: usize,
+    /// How long in milliseconds concurrent misses of a key wait for the first one's read from
+    /// Redis before reading it themselves, 0 disables queuing.
+    pub queue_timeout: u64,
+}
+
+impl Default for Redis {
+    fn default() -> Self {
+        Self {
+            url: None,
+            write_through: false,
+            timeout: 100,
+            pool_size: 16,
+            queue_timeout: 1000,
+        }
+    }
 }
 
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct KeyLimits {
+    /// The new keys each client IP address may create per minute, unlimited when `None`.
+    pub per_client: Option<u32>,
+    /// The new keys all clients together may create per minute, unlimited when `None`.
+    pub global: Option<u32>,
+}
+
+/// Deduplicates POST requests with the same `Idempotency-Key` header.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Idempotency {
+    pub enabled: bool,
+    /// The prefix of the keys the outcomes of requests are recorded under.
+    pub namespace: String,
+    /// The request header identifying the client records are scoped to, its address is used when
+    /// the header is missing.
+    pub header: String,
+}
+
+impl Default for Idempotency {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            namespace: "_idempotency/".to_string(),
+            header: "x-api-key".to_string(),
+        }
+    }
+}
+
+/// Tracks requests and stored bytes per API key.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Usage {
+    pub enabled: bool,
+    /// The request header carrying the API key.
+    pub header: String,
+    /// The bytes each API key may store, unlimited when `None`.
+    pub quota: Option<u64>,
+    /// Quotas of individual API keys, overriding `quota`.
+    pub quotas: HashMap<String, u64>,
+    /// The names API keys are reported by instead of a hash of the key.
+    pub aliases: HashMap<String, String>,
+}
+
+impl Default for Usage {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            header: "x-api-key".to_string(),
+            quota: None,
+            quotas: HashMap::new(),
+            aliases: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditSink {
+    /// Appends records to `path` as newline delimited JSON.
+    File,
+    /// POSTs each record as JSON to `url`.
+    Http,
+}
+
+/// Records the requests that change the cache.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Audit {
+    pub enabled: bool,
+    pub sink: AuditSink,
+    pub path: String,
+    /// The size in bytes at which the audit file is rotated.
+    pub max_size: u64,
+    /// The number of rotated files kept.
+    pub max_files: usize,
+    pub url: Option<String>,
+    /// The request header identifying the client, recorded along with its address.
+    pub identity_header: String,
+}
+
+impl Default for Audit {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            sink: AuditSink::File,
+            path: "audit.log".to_string(),
+            max_size: 100 * 1024 * 1024,
+            max_files: 10,
+            url: None,
+            identity_header: "x-api-key".to_string(),
+        }
+    }
+}
+
+/// Lua scripts that can be run with `POST /_script/{name}`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Scripting {
+    /// The directory of `.lua` files, scripting is disabled when `None`.
+    pub dir: Option<String>,
+    /// The number of milliseconds a script may run before it is stopped with an error.
+    pub timeout: u64,
+}
+
+impl Default for Scripting {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            timeout: 1000,
+        }
+    }
+}
+
+/// Mirrors a percentage of requests to another instance, discarding its responses.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Shadow {
+    /// The base URL of the shadow instance, requests are not mirrored when `None`.
+    pub url: Option<String>,
+    /// The percentage of requests mirrored.
+    pub percentage: f64,
+    /// The largest request and response bodies in bytes that are mirrored and compared.
+    pub max_body_size: usize,
+    /// How long in milliseconds the shadow instance may take to answer.
+    pub timeout_ms: u64,
+}
+
+impl Default for Shadow {
+    fn default() -> Self {
+        Self {
+            url: None,
+            percentage: 100.0,
+            max_body_size: 1024 * 1024,
+            timeout_ms: 1000,
+        }
+    }
+}
+
+/// Records a sample of reads and writes in a binary trace file.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Trace {
+    /// The trace file, replaced on start, nothing is recorded when `None`.
+    pub path: Option<String>,
+    /// The percentage of keys whose reads and writes are recorded.
+    pub percentage: f64,
+    /// The size in bytes at which recording stops.
+    pub max_size: u64,
+}
+
+impl Default for Trace {
+    fn default() -> Self {
+        Self {
+            path: None,
+            percentage: 1.0,
+            max_size: 1024 * 1024 * 1024,
+        }
+    }
+}
+
+/// The routes of the data API.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Api {
+    /// Also serves keys at `/{key}`, as before the API was versioned under `/v1/keys/{key}`.
+    pub legacy_routes: bool,
+    /// Makes `POST /{key}` create keys only, responding 409 if the key exists, so values are
+    /// replaced with `PUT /{key}`.
+    pub post_creates_only: bool,
+}
+
+impl Default for Api {
+    fn default() -> Self {
+        Self {
+            legacy_routes: true,
+            post_creates_only: false,
+        }
+    }
+}
+
+/// Refreshes sets of keys on a schedule.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Refresh {
+    pub jobs: Vec<RefreshJob>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RefreshJob {
+    /// When the job runs, as the five cron fields in UTC, e.g. `*/5 * * * *`.
+    pub schedule: String,
+    /// The entries refreshed, in the format of `cache.warmup_manifest`.
+    pub manifest: String,
+}
+
+/// Registers the cache server with a Consul agent so clients can discover it.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Discovery {
+    /// The URL of the Consul agent, nothing is registered when `None`.
+    pub consul_url: Option<String>,
+    /// The ACL token sent to the agent.
+    pub token: Option<String>,
+    /// The name of the service registered.
+    pub service_name: String,
+    /// The id of this instance, `<service_name>-<address>-<port>` when `None`.
+    pub service_id: Option<String>,
+    /// The address advertised, the first cache server listen address when `None`.
+    pub address: Option<String>,
+    pub tags: Vec<String>,
+    /// How often in seconds the agent checks `/healthz` on the metrics server.
+    pub check_interval: u64,
+    /// How long in seconds a failing instance stays registered, e.g. after a crash.
+    pub deregister_after: u64,
+}
+
+impl Default for Discovery {
+    fn default() -> Self {
+        Self {
+            consul_url: None,
+            token: None,
+            service_name: "simple-mem-cache".into(),
+            service_id: None,
+            address: None,
+            tags: vec![],
+            check_interval: 10,
+            deregister_after: 60,
+        }
+    }
+}
+
+/// Checks the accounting of the cache in the background.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Invariants {
+    pub enabled: bool,
+    /// The seconds between checks.
+    pub interval: u64,
+    /// The seconds entries may stay stored after they expired, twice `cache.key_live_duration`
+    /// when not set.
+    pub expired_grace: Option<u64>,
+}
+
+impl Default for Invariants {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval: 300,
+            expired_grace: None,
+        }
+    }
+}
+
+/// Confines the process, and drops its privileges once its sockets are bound (Unix only).
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Sandbox {
+    /// The user to switch to.
+    pub user: Option<String>,
+    /// The group to switch to, the primary group of `user` when not set.
+    pub group: Option<String>,
+    /// The directory the process is confined to.
+    pub chroot: Option<String>,
+}
+
+/// Lets browsers on other origins call the cache server.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Cors {
+    /// The origins allowed, e.g. `https://app.example.com`, or `*` for any. CORS is disabled when
+    /// empty.
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    /// The request headers browsers may send.
+    pub allowed_headers: Vec<String>,
+    /// The response headers browsers may read besides the safelisted ones.
+    pub exposed_headers: Vec<String>,
+    /// The seconds browsers may cache the response to a preflight request.
+    pub max_age: u64,
+    /// Lets browsers send cookies and credentials.
+    pub allow_credentials: bool,
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Self {
+            allowed_origins: vec![],
+            allowed_methods: vec![
+                "GET".into(),
+                "POST".into(),
+                "PUT".into(),
+                "PATCH".into(),
+                "DELETE".into(),
+            ],
+            allowed_headers: vec![
+                "Content-Type".into(),
+                "If-None-Match".into(),
+                "Idempotency-Key".into(),
+                "X-Api-Key".into(),
+                "X-Ttl".into(),
+                "Expires-At".into(),
+                "Cache-Priority".into(),
+            ],
+            exposed_headers: vec!["ETag".into(), "Location".into()],
+            max_age: 3600,
+            allow_credentials: false,
+        }
+    }
+}
+
+/// The default configuration, compiled in so the binary runs with no files mounted.
+const DEFAULT_CONFIG: &str = include_str!("../config/default.yaml");
+
 impl Settings {
     pub fn new() -> Result<Self, ConfigError> {
         let mut s = Config::new();
 
-        // Start off by merging in the "default" configuration file
-        s.merge(File::with_name("config/default"))?;
+        // Start off with the defaults compiled into the binary, so it runs without any files
+        s.merge(File::from_str(DEFAULT_CONFIG, FileFormat::Yaml))?;
+
+        // Merge in the "default" configuration file, if it is deployed alongside the binary
+        s.merge(File::with_name("config/default").required(false))?;
 
         // Add in the current environment file
         // Default to 'development' env
@@ -52,6 +835,43 @@ impl Settings {
         // You can deserialize (and thus freeze) the entire configuration as
         s.try_into()
     }
+
+    /// Returns a copy of the settings that is safe to show to operators.
+    #[cfg(feature = "admin")]
+    pub fn redacted(&self) -> Self {
+        let mut settings = self.clone();
+        if settings.admin.auth_token.is_some() {
+            settings.admin.auth_token = Some("<redacted>".into());
+        }
+        if let Some(url) = &mut settings.redis.url {
+            redact_credentials(url);
+        }
+        if let Some(url) = &mut settings.audit.url {
+            redact_credentials(url);
+        }
+        if let Some(url) = &mut settings.shadow.url {
+            redact_credentials(url);
+        }
+        if let Some(url) = &mut settings.memory_pressure.webhook_url {
+            redact_credentials(url);
+        }
+        if let Some(url) = &mut settings.discovery.consul_url {
+            redact_credentials(url);
+        }
+        if settings.discovery.token.is_some() {
+            settings.discovery.token = Some("<redacted>".into());
+        }
+        settings
+    }
+}
+
+/// Replaces the user and password of `url`, if it has any.
+#[cfg(feature = "admin")]
+fn redact_credentials(url: &mut String) {
+    // Only the credentials part of the url is secret.
+    if let (Some(scheme_end), Some(at)) = (url.find("://"), url.rfind('@')) {
+        *url = format!("{}<redacted>{}", &url[..scheme_end + 3], &url[at..]);
+    }
 }
 
 #[macro_export]
@@ -0,0 +1,285 @@
+//! Mirrors a percentage of requests to a shadow instance at `shadow.url`, e.g. one running a new
+//! version or store, without waiting for it. Shadow responses are discarded once they have been
+//! compared with the responses sent to clients, and differences are counted by
+//! `shadow_divergences_total`, labelled by endpoint and by whether the status or body differed.
+//!
+//! Mirrored requests are spread evenly, writes included. Only requests with a `Content-Length` of
+//! at most `shadow.max_body_size` are mirrored, and bodies are only compared when the response
+//! was not streamed.
+use crate::audit;
+use crate::cache::MetricOpts;
+use crate::settings;
+use actix_web::{
+    client::Client,
+    dev::{Body, Payload, ResponseBody, Service, ServiceRequest, ServiceResponse},
+    http::{header, HeaderMap, Method},
+    rt, web, Error, HttpMessage,
+};
+use futures::{
+    future::{Future, FutureExt},
+    StreamExt,
+};
+use prometheus::{IntCounter, IntCounterVec, Registry};
+use std::{
+    cell::RefCell,
+    rc::Rc,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// The status and, unless it was streamed, the body of a response.
+#[derive(Debug, PartialEq)]
+struct Answer {
+    status: u16,
+    body: Option<web::Bytes>,
+}
+
+impl Answer {
+    /// Returns the answer of the response sent to the client.
+    fn of(response: &ServiceResponse<Body>) -> Self {
+        let body = match response.response().body() {
+            ResponseBody::Body(Body::Bytes(bytes)) => Some(bytes.clone()),
+            ResponseBody::Body(Body::Empty) | ResponseBody::Body(Body::None) => {
+                Some(web::Bytes::new())
+            }
+            _ => None,
+        };
+        Self {
+            status: response.status().as_u16(),
+            body,
+        }
+    }
+
+    /// Returns how `shadow` differs from this answer, if it does.
+    fn divergence(&self, shadow: &Answer) -> Option<&'static str> {
+        if self.status != shadow.status {
+            return Some("status");
+        }
+        match (&self.body, &shadow.body) {
+            (Some(body), Some(shadow_body)) if body != shadow_body => Some("body"),
+            _ => None,
+        }
+    }
+}
+
+/// A copy of a request to send to the shadow instance.
+struct Mirrored {
+    endpoint: String,
+    method: Method,
+    path: String,
+    headers: HeaderMap,
+    body: web::Bytes,
+}
+
+/// Mirrors requests to the shadow instance and counts how its responses differ.
+pub struct Shadow {
+    settings: settings::Shadow,
+    /// The number of requests seen, used to spread mirrored requests evenly.
+    seen: AtomicU64,
+    /// A count of the requests mirrored.
+    requests: IntCounter,
+    /// A count of the mirrored requests the shadow instance did not answer.
+    errors: IntCounter,
+    /// A count of the shadow responses that differed, labelled by endpoint and reason.
+    divergences: IntCounterVec,
+}
+
+impl Shadow {
+    /// Returns a new `Shadow`.
+    /// # Arguments
+    /// * `settings` - The shadow instance and the share of requests mirrored to it.
+    /// * `opts` - The naming of the metrics.
+    pub fn new(settings: settings::Shadow, opts: &MetricOpts) -> Self {
+        Self {
+            settings,
+            seen: AtomicU64::new(0),
+            requests: IntCounter::with_opts(opts.opts(
+                "shadow_requests_total",
+                "A count of the requests mirrored to the shadow instance",
+            ))
+            .unwrap(),
+            errors: IntCounter::with_opts(opts.opts(
+                "shadow_errors_total",
+                "A count of the mirrored requests the shadow instance did not answer",
+            ))
+            .unwrap(),
+            divergences: IntCounterVec::new(
+                opts.opts(
+                    "shadow_divergences_total",
+                    "A count of the shadow responses that differed from those sent to clients",
+                ),
+                &["endpoint", "reason"],
+            )
+            .unwrap(),
+        }
+    }
+
+    /// Registers the metrics with a registry.
+    pub fn register(&self, registry: &Registry) {
+        registry.register(Box::new(self.requests.clone())).unwrap();
+        registry.register(Box::new(self.errors.clone())).unwrap();
+        registry
+            .register(Box::new(self.divergences.clone()))
+            .unwrap();
+    }
+
+    /// Returns true if the next request should be mirrored, spreading them evenly.
+    fn sample(&self) -> bool {
+        let share = self.settings.percentage.clamp(0.0, 100.0) / 100.0;
+        let seen = self.seen.fetch_add(1, Ordering::Relaxed) as f64;
+        ((seen + 1.0) * share).floor() > (seen * share).floor()
+    }
+
+    /// Returns the length of the body of `req` if it is small enough to mirror.
+    fn body_length(&self, req: &ServiceRequest) -> Option<usize> {
+        let length = match req.headers().get(header::CONTENT_LENGTH) {
+            Some(length) => length.to_str().ok()?.parse().ok()?,
+            None if req.headers().contains_key(header::TRANSFER_ENCODING) => return None,
+            None => 0,
+        };
+        Some(length).filter(|length| *length <= self.settings.max_body_size)
+    }
+
+    /// Sends `request` to the shadow instance and compares its response with `primary`.
+    async fn send(&self, request: Mirrored, primary: Answer) {
+        let url = format!(
+            "{}{}",
+            self.settings
+                .url
+                .as_deref()
+                .unwrap_or_default()
+                .trim_end_matches('/'),
+            request.path
+        );
+        let mut shadow_request = Client::builder()
+            .timeout(Duration::from_millis(self.settings.timeout_ms))
+            .finish()
+            .request(request.method, url);
+        for (name, value) in request.headers.iter() {
+            if name != header::HOST && name != header::CONTENT_LENGTH {
+                shadow_request
+                    .headers_mut()
+                    .append(name.clone(), value.clone());
+            }
+        }
+        self.requests.inc();
+        let mut response = match shadow_request.send_body(request.body).await {
+            Ok(response) => response,
+            Err(err) => {
+                log::debug!("The shadow instance did not answer. {}", err);
+                self.errors.inc();
+                return;
+            }
+        };
+        let status = response.status().as_u16();
+        let body = if primary.body.is_some() {
+            match response.body().limit(self.settings.max_body_size).await {
+                Ok(body) => Some(body),
+                Err(err) => {
+                    log::debug!("Could not read the shadow response. {}", err);
+                    self.errors.inc();
+                    return;
+                }
+            }
+        } else {
+            None
+        };
+        if let Some(reason) = primary.divergence(&Answer { status, body }) {
+            self.divergences
+                .with_label_values(&[&request.endpoint, reason])
+                .inc();
+        }
+    }
+}
+
+/// Mirrors a share of requests to the shadow instance once they have been answered, if shadowing
+/// is enabled. The request body is copied as the handler reads it.
+/// # Arguments
+/// * `req` - The incoming request.
+/// * `srv` - The service handling the request.
+pub fn mirror<S>(
+    mut req: ServiceRequest,
+    srv: &mut S,
+) -> impl Future<Output = Result<ServiceResponse<Body>, Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<Body>, Error = Error>,
+{
+    let shadow = req
+        .app_data::<web::Data<Shadow>>()
+        .cloned()
+        .filter(|shadow| shadow.sample());
+    let length = shadow.as_ref().and_then(|shadow| shadow.body_length(&req));
+    let copy = Rc::new(RefCell::new(web::BytesMut::new()));
+    if length.is_some() {
+        let sink = copy.clone();
+        let payload = req.take_payload().inspect(move |chunk| {
+            if let Ok(chunk) = chunk {
+                sink.borrow_mut().extend_from_slice(chunk);
+            }
+        });
+        req.set_payload(Payload::Stream(Box::pin(payload)));
+    }
+    srv.call(req).map(move |response| {
+        if let (Some(shadow), Some(length), Ok(response)) = (shadow, length, &response) {
+            let body = copy.borrow().clone().freeze();
+            // The body is incomplete if the handler answered without reading all of it.
+            if body.len() == length {
+                let request = response.request();
+                let mirrored = Mirrored {
+                    endpoint: audit::operation(
+                        request.method(),
+                        request.match_pattern().as_deref(),
+                    ),
+                    method: request.method().clone(),
+                    path: request
+                        .uri()
+                        .path_and_query()
+                        .map_or("/", |path| path.as_str())
+                        .into(),
+                    headers: request.headers().clone(),
+                    body,
+                };
+                let primary = Answer::of(response);
+                rt::spawn(async move { shadow.send(mirrored, primary).await });
+            }
+        }
+        response
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn requests_are_mirrored_evenly() {
+        let settings = settings::Shadow {
+            url: Some("http://localhost:8081".into()),
+            percentage: 25.0,
+            ..Default::default()
+        };
+        let sut = Shadow::new(settings, &MetricOpts::default());
+
+        let mirrored: Vec<bool> = (0..8).map(|_| sut.sample()).collect();
+
+        assert_eq!(
+            mirrored,
+            vec![false, false, false, true, false, false, false, true]
+        );
+    }
+
+    #[test]
+    fn answers_diverge_by_status_then_body() {
+        let answer = |status, body: Option<&'static str>| Answer {
+            status,
+            body: body.map(|body| web::Bytes::from_static(body.as_bytes())),
+        };
+
+        let primary = answer(200, Some("a"));
+
+        assert_eq!(primary.divergence(&answer(200, Some("a"))), None);
+        assert_eq!(primary.divergence(&answer(200, Some("b"))), Some("body"));
+        assert_eq!(primary.divergence(&answer(404, Some("a"))), Some("status"));
+        assert_eq!(answer(200, None).divergence(&answer(200, Some("b"))), None);
+    }
+}
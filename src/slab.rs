@@ -0,0 +1,192 @@
+//! Small values are copied into shared slabs, so storing one takes a slice of a larger allocation
+//! instead of an allocation of its own and many small writes do not fragment the heap.
+//!
+//! A slab is filled in order and never reused, its memory is freed once every value copied into it
+//! has been dropped. Values are held by the cache with a `SlabAllocation`, which reports the bytes
+//! in use until the value is removed.
+use crate::cache::MetricOpts;
+use crate::value::Value;
+use actix_web::web::BytesMut;
+use prometheus::{IntGauge, Registry};
+use std::sync::{Arc, Mutex};
+
+/// Container for the slab allocator metrics.
+#[derive(Clone)]
+pub struct SlabMetrics {
+    /// The number of slabs holding values or being filled.
+    pub slabs: IntGauge,
+    /// The size in bytes of the values held in slabs.
+    pub used: IntGauge,
+}
+
+impl SlabMetrics {
+    /// Creates a new SlabMetrics named using `opts`.
+    pub fn with_opts(opts: &MetricOpts) -> Self {
+        Self {
+            slabs: IntGauge::with_opts(opts.opts(
+                "cache_slabs",
+                "The number of slabs small values are allocated from",
+            ))
+            .unwrap(),
+            used: IntGauge::with_opts(opts.opts(
+                "cache_slab_used_bytes",
+                "The total size in bytes of values held in slabs",
+            ))
+            .unwrap(),
+        }
+    }
+
+    /// Registers the slab allocator metrics with a registry.
+    pub fn register(&self, registry: &Registry) {
+        registry.register(Box::new(self.slabs.clone())).unwrap();
+        registry.register(Box::new(self.used.clone())).unwrap();
+    }
+}
+
+/// Counts a slab while the allocator or any value allocated from it holds a reference.
+struct SlabUsage {
+    metrics: SlabMetrics,
+}
+
+impl SlabUsage {
+    fn new(metrics: SlabMetrics) -> Arc<Self> {
+        metrics.slabs.inc();
+        Arc::new(Self { metrics })
+    }
+}
+
+impl Drop for SlabUsage {
+    fn drop(&mut self) {
+        self.metrics.slabs.dec();
+    }
+}
+
+/// The space of a value in a slab, which is released when dropped.
+pub struct SlabAllocation {
+    usage: Arc<SlabUsage>,
+    len: usize,
+}
+
+impl Drop for SlabAllocation {
+    fn drop(&mut self) {
+        self.usage.metrics.used.sub(self.len as i64);
+    }
+}
+
+/// The slab being filled.
+struct Slab {
+    buffer: BytesMut,
+    usage: Arc<SlabUsage>,
+}
+
+/// Copies values up to a size threshold into slabs.
+pub struct SlabAllocator {
+    slab_size: usize,
+    max_value_size: usize,
+    current: Mutex<Option<Slab>>,
+    metrics: SlabMetrics,
+}
+
+impl SlabAllocator {
+    /// Returns a new `SlabAllocator`.
+    /// # Arguments
+    /// * `slab_size` - The size in bytes of each slab.
+    /// * `max_value_size` - The largest value in bytes copied into a slab, larger values keep their
+    ///   own allocation.
+    /// * `metrics` - A container for the metrics used by the allocator.
+    pub fn new(slab_size: usize, max_value_size: usize, metrics: SlabMetrics) -> Self {
+        Self {
+            slab_size,
+            max_value_size: max_value_size.min(slab_size),
+            current: Mutex::new(None),
+            metrics,
+        }
+    }
+
+    /// Returns `value` copied into a slab with its allocation, or `value` unchanged if it is empty
+    /// or larger than `max_value_size`.
+    pub fn allocate(&self, value: Value) -> (Value, Option<SlabAllocation>) {
+        let len = value.len();
+        if len == 0 || len > self.max_value_size {
+            return (value, None);
+        }
+        let mut current = self.current.lock().unwrap();
+        if current
+            .as_ref()
+            .is_none_or(|slab| slab.buffer.capacity() < len)
+        {
+            *current = Some(Slab {
+                buffer: BytesMut::with_capacity(self.slab_size),
+                usage: SlabUsage::new(self.metrics.clone()),
+            });
+        }
+        let slab = current.as_mut().unwrap();
+        for chunk in value.chunks() {
+            slab.buffer.extend_from_slice(chunk);
+        }
+        let bytes = slab.buffer.split_to(len).freeze();
+        self.metrics.used.add(len as i64);
+        let allocation = SlabAllocation {
+            usage: slab.usage.clone(),
+            len,
+        };
+        (
+            Value::from(bytes).into_json(value.is_json()),
+            Some(allocation),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn allocator(slab_size: usize, max_value_size: usize) -> SlabAllocator {
+        SlabAllocator::new(
+            slab_size,
+            max_value_size,
+            SlabMetrics::with_opts(&MetricOpts::default()),
+        )
+    }
+
+    #[test]
+    fn small_values_share_a_slab() {
+        let sut = allocator(16, 8);
+
+        let (a, a_allocation) = sut.allocate(Value::from("abc"));
+        let (b, b_allocation) = sut.allocate(Value::from("defgh").into_json(true));
+
+        assert_eq!(a, Value::from("abc"));
+        assert_eq!(b, Value::from("defgh").into_json(true));
+        assert!(a_allocation.is_some() && b_allocation.is_some());
+        assert_eq!(sut.metrics.slabs.get(), 1);
+        assert_eq!(sut.metrics.used.get(), 8);
+    }
+
+    #[test]
+    fn large_values_are_not_copied() {
+        let sut = allocator(16, 8);
+
+        let (value, allocation) = sut.allocate(Value::from("too large for a slab"));
+
+        assert_eq!(value, Value::from("too large for a slab"));
+        assert!(allocation.is_none());
+        assert_eq!(sut.metrics.slabs.get(), 0);
+    }
+
+    #[test]
+    fn slabs_are_released_when_their_values_are_dropped() {
+        let sut = allocator(8, 8);
+        let (_, first) = sut.allocate(Value::from("abcdef"));
+        let (_, second) = sut.allocate(Value::from("ghijkl"));
+        assert_eq!(sut.metrics.slabs.get(), 2);
+
+        drop(first);
+        assert_eq!(sut.metrics.slabs.get(), 1);
+        assert_eq!(sut.metrics.used.get(), 6);
+
+        drop(second);
+        assert_eq!(sut.metrics.slabs.get(), 1);
+        assert_eq!(sut.metrics.used.get(), 0);
+    }
+}
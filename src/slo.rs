@@ -0,0 +1,129 @@
+//! Counts the requests of each endpoint and those that met its latency objective, so burn-rate
+//! alerts can divide two counters instead of computing quantiles from histograms.
+//!
+//! Endpoints are named like audit operations, e.g. `get`, `put` or `list_push`. A request meets
+//! its objective if it was answered within the threshold of its endpoint in `slo.thresholds`, or
+//! `slo.threshold_ms`, without a server error.
+use crate::audit;
+use crate::cache::MetricOpts;
+use crate::settings;
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse},
+    web, Error,
+};
+use futures::future::{Future, FutureExt};
+use prometheus::{IntCounterVec, Registry};
+use std::time::{Duration, Instant};
+
+/// Counts requests against the latency objective of their endpoint.
+pub struct SloTracker {
+    settings: settings::Slo,
+    /// A count of requests, labelled by endpoint.
+    requests: IntCounterVec,
+    /// A count of requests that met the objective, labelled by endpoint.
+    good: IntCounterVec,
+}
+
+impl SloTracker {
+    /// Returns a new `SloTracker`.
+    /// # Arguments
+    /// * `settings` - The latency threshold of each endpoint.
+    /// * `opts` - The naming of the metrics.
+    pub fn new(settings: settings::Slo, opts: &MetricOpts) -> Self {
+        Self {
+            settings,
+            requests: IntCounterVec::new(
+                opts.opts(
+                    "slo_requests_total",
+                    "A count of requests with a latency objective, by endpoint",
+                ),
+                &["endpoint"],
+            )
+            .unwrap(),
+            good: IntCounterVec::new(
+                opts.opts(
+                    "slo_good_requests_total",
+                    "A count of requests answered within their latency objective, by endpoint",
+                ),
+                &["endpoint"],
+            )
+            .unwrap(),
+        }
+    }
+
+    /// Registers the metrics with a registry.
+    pub fn register(&self, registry: &Registry) {
+        registry.register(Box::new(self.requests.clone())).unwrap();
+        registry.register(Box::new(self.good.clone())).unwrap();
+    }
+
+    /// Returns the latency objective of `endpoint`.
+    fn threshold(&self, endpoint: &str) -> Duration {
+        let threshold_ms = self
+            .settings
+            .thresholds
+            .get(endpoint)
+            .copied()
+            .unwrap_or(self.settings.threshold_ms);
+        Duration::from_millis(threshold_ms)
+    }
+
+    /// Counts a request to `endpoint` that took `elapsed` and was answered with `status`.
+    pub fn record(&self, endpoint: &str, elapsed: Duration, status: u16) {
+        self.requests.with_label_values(&[endpoint]).inc();
+        if status < 500 && elapsed <= self.threshold(endpoint) {
+            self.good.with_label_values(&[endpoint]).inc();
+        }
+    }
+}
+
+/// Times requests and counts them against their latency objective, if objectives are enabled.
+/// # Arguments
+/// * `req` - The incoming request.
+/// * `srv` - The service handling the request.
+pub fn track<S, B>(
+    req: ServiceRequest,
+    srv: &mut S,
+) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    let tracker = req.app_data::<web::Data<SloTracker>>().cloned();
+    let started = Instant::now();
+    srv.call(req).map(move |response| {
+        if let (Some(tracker), Ok(response)) = (tracker, &response) {
+            let request = response.request();
+            let endpoint = audit::operation(request.method(), request.match_pattern().as_deref());
+            tracker.record(&endpoint, started.elapsed(), response.status().as_u16());
+        }
+        response
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn requests_within_the_threshold_of_their_endpoint_are_good() {
+        let mut thresholds = HashMap::new();
+        thresholds.insert("put".to_string(), 50);
+        let settings = settings::Slo {
+            enabled: true,
+            threshold_ms: 10,
+            thresholds,
+        };
+        let sut = SloTracker::new(settings, &MetricOpts::default());
+
+        sut.record("get", Duration::from_millis(5), 200);
+        sut.record("get", Duration::from_millis(20), 200);
+        sut.record("put", Duration::from_millis(20), 200);
+        sut.record("put", Duration::from_millis(1), 503);
+
+        assert_eq!(sut.requests.with_label_values(&["get"]).get(), 2);
+        assert_eq!(sut.good.with_label_values(&["get"]).get(), 1);
+        assert_eq!(sut.requests.with_label_values(&["put"]).get(), 2);
+        assert_eq!(sut.good.with_label_values(&["put"]).get(), 1);
+    }
+}
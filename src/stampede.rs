@@ -0,0 +1,183 @@
+//! Stampede protection for read-through misses.
+//!
+//! The first request to miss a key fetches it from the origin, and concurrent requests that miss
+//! the same key wait for that fetch instead of issuing their own. A follower that waits longer
+//! than the wait timeout, or whose leader was cancelled, fetches the key itself.
+use crate::cache::MetricOpts;
+use actix_rt::time::timeout;
+use futures::{
+    channel::oneshot,
+    future::{FutureExt, Shared},
+};
+use prometheus::{Histogram, HistogramOpts, IntGauge, Registry};
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Container for the request queue metrics.
+#[derive(Clone)]
+pub struct QueueMetrics {
+    /// The number of requests waiting for another request's fetch.
+    pub length: IntGauge,
+    /// The time requests waited for another request's fetch.
+    pub wait_time: Histogram,
+}
+
+impl QueueMetrics {
+    /// Creates a new QueueMetrics named using `opts`.
+    pub fn with_opts(opts: &MetricOpts) -> Self {
+        let wait_time = opts.opts(
+            "read_through_queue_wait_seconds",
+            "The time requests waited for a read-through fetch of the same key",
+        );
+        Self {
+            length: IntGauge::with_opts(opts.opts(
+                "read_through_queue_length",
+                "The number of requests waiting for a read-through fetch of the same key",
+            ))
+            .unwrap(),
+            wait_time: Histogram::with_opts(HistogramOpts::from(wait_time)).unwrap(),
+        }
+    }
+
+    /// Registers the request queue metrics with a registry.
+    pub fn register(&self, registry: &Registry) {
+        registry.register(Box::new(self.length.clone())).unwrap();
+        registry.register(Box::new(self.wait_time.clone())).unwrap();
+    }
+}
+
+type Waiter<T> = Shared<oneshot::Receiver<T>>;
+
+/// Queues concurrent fetches of the same key behind the first one.
+pub struct RequestQueue<T> {
+    in_flight: Mutex<HashMap<String, Waiter<T>>>,
+    wait_timeout: Duration,
+    metrics: QueueMetrics,
+}
+
+/// Removes a leader's key when its fetch completes or is cancelled.
+struct InFlight<'q, T> {
+    queue: &'q RequestQueue<T>,
+    key: &'q str,
+}
+
+impl<T> Drop for InFlight<'_, T> {
+    fn drop(&mut self) {
+        self.queue.in_flight.lock().unwrap().remove(self.key);
+    }
+}
+
+impl<T: Clone> RequestQueue<T> {
+    /// Returns a new `RequestQueue`.
+    /// # Arguments
+    /// * `wait_timeout` - How long followers wait for the leader, 0 disables queuing.
+    /// * `metrics` - A container for the metrics used by the queue.
+    pub fn new(wait_timeout: Duration, metrics: QueueMetrics) -> Self {
+        Self {
+            in_flight: Mutex::new(HashMap::new()),
+            wait_timeout,
+            metrics,
+        }
+    }
+
+    /// Returns the result of `fetch`, or of the fetch of `key` already in progress.
+    /// # Arguments
+    /// * `key` - The key being fetched.
+    /// * `fetch` - Fetches the key from the origin.
+    pub async fn fetch<F, Fut>(&self, key: &str, fetch: F) -> T
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = T>,
+    {
+        if self.wait_timeout == Duration::from_secs(0) {
+            return fetch().await;
+        }
+        let leader = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get(key) {
+                Some(waiter) => Err(waiter.clone()),
+                None => {
+                    let (sender, receiver) = oneshot::channel();
+                    in_flight.insert(key.to_string(), receiver.shared());
+                    Ok(sender)
+                }
+            }
+        };
+        match leader {
+            Ok(sender) => {
+                let in_flight = InFlight { queue: self, key };
+                let value = fetch().await;
+                drop(in_flight);
+                let _ = sender.send(value.clone());
+                value
+            }
+            Err(waiter) => {
+                self.metrics.length.inc();
+                let start = Instant::now();
+                let result = timeout(self.wait_timeout, waiter).await;
+                self.metrics.length.dec();
+                self.metrics
+                    .wait_time
+                    .observe(start.elapsed().as_secs_f64());
+                match result {
+                    Ok(Ok(value)) => value,
+                    _ => {
+                        log::debug!("Stopped waiting for the fetch of key: {}", key);
+                        fetch().await
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_rt::time::delay_for;
+    use futures::join;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn queue(wait_timeout: Duration) -> RequestQueue<usize> {
+        RequestQueue::new(
+            wait_timeout,
+            QueueMetrics::with_opts(&MetricOpts::default()),
+        )
+    }
+
+    #[actix_rt::test]
+    async fn concurrent_fetches_are_queued() {
+        let sut = queue(Duration::from_secs(1));
+        let fetches = AtomicUsize::new(0);
+        let fetch = || async {
+            delay_for(Duration::from_millis(20)).await;
+            fetches.fetch_add(1, Ordering::SeqCst) + 1
+        };
+
+        let results = join!(sut.fetch("a", fetch), sut.fetch("a", fetch));
+
+        assert_eq!(results, (1, 1));
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        assert_eq!(sut.metrics.wait_time.get_sample_count(), 1);
+        assert!(sut.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn followers_fetch_after_the_wait_timeout() {
+        let sut = queue(Duration::from_millis(5));
+        let fetches = AtomicUsize::new(0);
+        let fetch = || async {
+            delay_for(Duration::from_millis(50)).await;
+            fetches.fetch_add(1, Ordering::SeqCst) + 1
+        };
+
+        join!(sut.fetch("a", fetch), sut.fetch("a", fetch));
+
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+        assert_eq!(sut.metrics.length.get(), 0);
+    }
+}
@@ -0,0 +1,296 @@
+//! Pushes the metrics in the Prometheus registry to a StatsD or DogStatsD agent every
+//! `statsd.interval` seconds, for deployments that do not scrape `/metrics`.
+//!
+//! Gauges are sent as gauges, counters as their increase since the last push, and histograms and
+//! summaries as the increase of their `_count` and `_sum`. With DogStatsD, labels and `statsd.tags`
+//! are sent as tags. Plain StatsD has no tags, so label values are appended to the metric name.
+use crate::settings::{self, StatsdFormat};
+use actix_rt::time::delay_for;
+use prometheus::{
+    proto::{LabelPair, MetricFamily, MetricType},
+    Registry,
+};
+use std::{
+    collections::HashMap,
+    io,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    time::Duration,
+};
+
+/// The largest datagram sent, small enough not to be fragmented on common networks.
+const MAX_PACKET_SIZE: usize = 1432;
+
+/// Replaces the characters of `part` that `keep` rejects with `_`.
+fn sanitize(part: &str, keep: impl Fn(char) -> bool) -> String {
+    part.chars()
+        .map(|c| if keep(c) { c } else { '_' })
+        .collect()
+}
+
+/// Characters allowed in a segment of a StatsD metric name.
+fn name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+/// Characters allowed in a DogStatsD tag.
+fn tag_char(c: char) -> bool {
+    !matches!(c, '|' | ',' | '#' | '@' | '\n')
+}
+
+/// Joins `lines` into newline separated packets of at most `MAX_PACKET_SIZE` bytes.
+fn packets(lines: &[String]) -> Vec<String> {
+    let mut packets = Vec::new();
+    let mut packet = String::new();
+    for line in lines {
+        if !packet.is_empty() && packet.len() + 1 + line.len() > MAX_PACKET_SIZE {
+            packets.push(std::mem::take(&mut packet));
+        }
+        if !packet.is_empty() {
+            packet.push('\n');
+        }
+        packet.push_str(line);
+    }
+    if !packet.is_empty() {
+        packets.push(packet);
+    }
+    packets
+}
+
+/// Sends the metrics of a registry to a StatsD agent.
+pub struct StatsdExporter {
+    socket: UdpSocket,
+    target: SocketAddr,
+    settings: settings::Statsd,
+    /// The tags of `settings`, sorted so lines do not change between pushes.
+    tags: Vec<String>,
+    registry: Registry,
+    /// The value of each counter at the last push, by name and labels.
+    previous: HashMap<String, f64>,
+}
+
+impl StatsdExporter {
+    /// Returns a new `StatsdExporter`, or the error of resolving the agent or opening a socket.
+    /// # Arguments
+    /// * `settings` - The address of the agent and how metrics are named.
+    /// * `registry` - The metrics to push.
+    pub fn new(settings: settings::Statsd, registry: Registry) -> io::Result<Self> {
+        let target = (settings.host.as_str(), settings.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("Could not resolve {}", settings.host),
+                )
+            })?;
+        let socket = if target.is_ipv4() {
+            UdpSocket::bind("0.0.0.0:0")?
+        } else {
+            UdpSocket::bind("[::]:0")?
+        };
+        let mut tags: Vec<String> = settings
+            .tags
+            .iter()
+            .map(|(name, value)| {
+                format!("{}:{}", sanitize(name, tag_char), sanitize(value, tag_char))
+            })
+            .collect();
+        tags.sort();
+        Ok(Self {
+            socket,
+            target,
+            settings,
+            tags,
+            registry,
+            previous: HashMap::new(),
+        })
+    }
+
+    /// Returns the line sending `value` of the metric `name` with `labels` as a `kind`.
+    fn line(&self, name: &str, labels: &[LabelPair], value: f64, kind: &str) -> String {
+        let mut line = String::new();
+        if !self.settings.prefix.is_empty() {
+            line.push_str(&self.settings.prefix);
+            line.push('.');
+        }
+        line.push_str(name);
+        match self.settings.format {
+            StatsdFormat::Statsd => {
+                for label in labels {
+                    line.push('.');
+                    line.push_str(&sanitize(label.get_value(), name_char));
+                }
+                line.push_str(&format!(":{}|{}", value, kind));
+            }
+            StatsdFormat::Dogstatsd => {
+                line.push_str(&format!(":{}|{}", value, kind));
+                let tags: Vec<String> = labels
+                    .iter()
+                    .map(|label| {
+                        format!(
+                            "{}:{}",
+                            label.get_name(),
+                            sanitize(label.get_value(), tag_char)
+                        )
+                    })
+                    .chain(self.tags.iter().cloned())
+                    .collect();
+                if !tags.is_empty() {
+                    line.push_str("|#");
+                    line.push_str(&tags.join(","));
+                }
+            }
+        }
+        line
+    }
+
+    /// Adds a line setting a gauge, unless `value` is not a number StatsD accepts.
+    fn gauge(&self, lines: &mut Vec<String>, name: &str, labels: &[LabelPair], value: f64) {
+        if value.is_finite() {
+            lines.push(self.line(name, labels, value, "g"));
+        }
+    }
+
+    /// Adds a line counting the increase of a counter since the last push, if it increased.
+    fn count(&mut self, lines: &mut Vec<String>, name: &str, labels: &[LabelPair], value: f64) {
+        if !value.is_finite() {
+            return;
+        }
+        let key = labels.iter().fold(name.to_string(), |key, label| {
+            format!("{},{}={}", key, label.get_name(), label.get_value())
+        });
+        let previous = self.previous.insert(key, value).unwrap_or_default();
+        // A counter that went down was reset, so all of it is new.
+        let increase = if value >= previous {
+            value - previous
+        } else {
+            value
+        };
+        if increase > 0.0 {
+            lines.push(self.line(name, labels, increase, "c"));
+        }
+    }
+
+    /// Returns the lines sending `families`, remembering the value of each counter.
+    fn lines(&mut self, families: &[MetricFamily]) -> Vec<String> {
+        let mut lines = Vec::new();
+        for family in families {
+            let name = family.get_name();
+            for metric in family.get_metric() {
+                let labels = metric.get_label();
+                match family.get_field_type() {
+                    MetricType::COUNTER => {
+                        self.count(&mut lines, name, labels, metric.get_counter().get_value())
+                    }
+                    MetricType::GAUGE => {
+                        self.gauge(&mut lines, name, labels, metric.get_gauge().get_value())
+                    }
+                    MetricType::UNTYPED => {
+                        self.gauge(&mut lines, name, labels, metric.get_untyped().get_value())
+                    }
+                    MetricType::HISTOGRAM => {
+                        let histogram = metric.get_histogram();
+                        let count = histogram.get_sample_count() as f64;
+                        self.count(&mut lines, &format!("{}_count", name), labels, count);
+                        let sum = histogram.get_sample_sum();
+                        self.count(&mut lines, &format!("{}_sum", name), labels, sum);
+                    }
+                    MetricType::SUMMARY => {
+                        let summary = metric.get_summary();
+                        let count = summary.get_sample_count() as f64;
+                        self.count(&mut lines, &format!("{}_count", name), labels, count);
+                        let sum = summary.get_sample_sum();
+                        self.count(&mut lines, &format!("{}_sum", name), labels, sum);
+                    }
+                }
+            }
+        }
+        lines
+    }
+
+    /// Sends the current value of every metric to the agent.
+    pub fn push(&mut self) {
+        let families = self.registry.gather();
+        let lines = self.lines(&families);
+        for packet in packets(&lines) {
+            if let Err(err) = self.socket.send_to(packet.as_bytes(), self.target) {
+                log::warn!("Could not send metrics to {}. {}", self.target, err);
+                return;
+            }
+        }
+    }
+
+    /// Pushes the metrics every `statsd.interval` seconds.
+    pub async fn run(mut self) {
+        log::info!("Pushing metrics to {}", self.target);
+        let interval = Duration::from_secs(self.settings.interval);
+        loop {
+            delay_for(interval).await;
+            self.push();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use prometheus::{IntCounterVec, IntGauge, Opts};
+
+    fn exporter(format: StatsdFormat) -> StatsdExporter {
+        let mut tags = HashMap::new();
+        tags.insert("env".to_string(), "test".to_string());
+        let settings = settings::Statsd {
+            enabled: true,
+            host: "127.0.0.1".into(),
+            prefix: "smc".into(),
+            tags,
+            format,
+            ..settings::Statsd::default()
+        };
+        let registry = Registry::new();
+        let requests =
+            IntCounterVec::new(Opts::new("requests_total", "requests"), &["path"]).unwrap();
+        requests.with_label_values(&["/a"]).inc_by(3);
+        registry.register(Box::new(requests)).unwrap();
+        let items = IntGauge::new("items", "items").unwrap();
+        items.set(2);
+        registry.register(Box::new(items)).unwrap();
+        StatsdExporter::new(settings, registry).unwrap()
+    }
+
+    #[test]
+    fn dogstatsd_lines_have_tags() {
+        let mut sut = exporter(StatsdFormat::Dogstatsd);
+
+        let lines = sut.lines(&sut.registry.gather());
+
+        assert_eq!(
+            lines,
+            vec![
+                "smc.items:2|g|#env:test",
+                "smc.requests_total:3|c|#path:/a,env:test"
+            ]
+        );
+    }
+
+    #[test]
+    fn counters_send_their_increase() {
+        let mut sut = exporter(StatsdFormat::Statsd);
+        let first = sut.lines(&sut.registry.gather());
+
+        let second = sut.lines(&sut.registry.gather());
+
+        assert_eq!(first, vec!["smc.items:2|g", "smc.requests_total._a:3|c"]);
+        assert_eq!(second, vec!["smc.items:2|g"]);
+    }
+
+    #[test]
+    fn lines_are_split_into_packets() {
+        let lines = vec!["a".repeat(1000), "b".repeat(1000), "c".into()];
+
+        let packets = packets(&lines);
+
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[1], format!("{}\nc", "b".repeat(1000)));
+    }
+}
@@ -0,0 +1,34 @@
+//! Answers `GET /_status` on the cache server before any other middleware, so load balancers can
+//! check it at high frequency without it being logged, counted in the HTTP metrics or read from
+//! the cache.
+use actix_web::{
+    dev::{Body, MessageBody, ResponseBody, Service, ServiceRequest, ServiceResponse},
+    http::Method,
+    Error, HttpResponse,
+};
+use futures::future::{ok, Either, Future, FutureExt};
+
+/// The path of the status endpoint.
+const PATH: &str = "/_status";
+
+/// Responds to `GET /_status` with a 200 and passes every other request on.
+/// # Arguments
+/// * `req` - The incoming request.
+/// * `srv` - The service handling every other request.
+pub fn answer<S, B>(
+    req: ServiceRequest,
+    srv: &mut S,
+) -> impl Future<Output = Result<ServiceResponse<Body>, Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    B: MessageBody + Unpin + 'static,
+{
+    if req.method() == Method::GET && req.path() == PATH {
+        return Either::Left(ok(req.into_response(HttpResponse::Ok().body("ok"))));
+    }
+    Either::Right(srv.call(req).map(|response| {
+        response.map(|response| {
+            response.map_body(|_, body| ResponseBody::Other(Body::from_message(body)))
+        })
+    }))
+}
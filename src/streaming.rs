@@ -0,0 +1,158 @@
+//! Serves values as a stream of slices of the stored value, with support for single HTTP byte
+//! ranges so clients can resume partial downloads.
+use crate::value::Value;
+use actix_web::{
+    dev::{Body, SizedStream},
+    http::header,
+    web::Bytes,
+    Error, HttpRequest, HttpResponse,
+};
+use futures::stream;
+use std::ops::Range;
+
+/// The size of the chunks a value is streamed in.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The part of a value requested with a `Range` header.
+#[derive(Debug, PartialEq)]
+enum RangeRequest {
+    /// The whole value, either no range or one that is not supported was requested.
+    Full,
+    /// The bytes in the range.
+    Partial(Range<usize>),
+    /// The range does not overlap the value.
+    Unsatisfiable,
+}
+
+/// Parses a `Range` header, only a single range in bytes is supported.
+/// # Arguments
+/// * `range` - The value of the header.
+/// * `len` - The length of the value.
+fn parse_range(range: &str, len: usize) -> RangeRequest {
+    let spec = match range.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return RangeRequest::Full,
+    };
+    let (start, end) = match spec.find('-') {
+        Some(dash) => (&spec[..dash], &spec[dash + 1..]),
+        None => return RangeRequest::Full,
+    };
+    let range = match (start.parse::<usize>(), end.parse::<usize>()) {
+        // bytes=-500 is the last 500 bytes.
+        (Err(_), Ok(suffix)) if start.is_empty() => {
+            if suffix == 0 {
+                return RangeRequest::Unsatisfiable;
+            }
+            len.saturating_sub(suffix)..len
+        }
+        (Ok(start), Err(_)) if end.is_empty() => start..len,
+        (Ok(start), Ok(end)) if start <= end => start..len.min(end.saturating_add(1)),
+        _ => return RangeRequest::Full,
+    };
+    if range.start >= len {
+        RangeRequest::Unsatisfiable
+    } else {
+        RangeRequest::Partial(range)
+    }
+}
+
+/// Returns a body that streams the chunks of `value` in `CHUNK_SIZE` slices without copying them.
+fn body(value: Value) -> Body {
+    let len = value.len();
+    let slices: Vec<Bytes> = value
+        .chunks()
+        .iter()
+        .flat_map(|chunk| {
+            (0..chunk.len())
+                .step_by(CHUNK_SIZE)
+                .map(move |start| chunk.slice(start..chunk.len().min(start + CHUNK_SIZE)))
+        })
+        .collect();
+    let slices = slices.into_iter().map(Ok::<_, Error>);
+    Body::from_message(SizedStream::new(len as u64, stream::iter(slices)))
+}
+
+/// Responds with `value`, or the part of it requested with a `Range` header. Values stored in JSON
+/// mode are sent as `application/json`.
+pub fn respond(req: &HttpRequest, value: Value) -> HttpResponse {
+    let len = value.len();
+    let range = req
+        .headers()
+        .get(header::RANGE)
+        .and_then(|range| range.to_str().ok())
+        .map_or(RangeRequest::Full, |range| parse_range(range, len));
+    let json = value.is_json();
+    let (mut response, value) = match range {
+        RangeRequest::Full => {
+            let mut response = HttpResponse::Ok();
+            response.header(header::ACCEPT_RANGES, "bytes");
+            (response, value)
+        }
+        RangeRequest::Partial(range) => {
+            let mut response = HttpResponse::PartialContent();
+            response.header(header::ACCEPT_RANGES, "bytes").header(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", range.start, range.end - 1, len),
+            );
+            (response, value.slice(range))
+        }
+        RangeRequest::Unsatisfiable => {
+            return HttpResponse::RangeNotSatisfiable()
+                .header(header::CONTENT_RANGE, format!("bytes */{}", len))
+                .finish()
+        }
+    };
+    if json {
+        response.content_type("application/json");
+    }
+    response.body(body(value))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::value::Value;
+    use actix_web::{test, web::BytesMut};
+    use futures::StreamExt;
+
+    #[test]
+    fn ranges_are_parsed() {
+        assert_eq!(parse_range("bytes=0-9", 100), RangeRequest::Partial(0..10));
+        assert_eq!(
+            parse_range("bytes=90-", 100),
+            RangeRequest::Partial(90..100)
+        );
+        assert_eq!(
+            parse_range("bytes=-10", 100),
+            RangeRequest::Partial(90..100)
+        );
+        assert_eq!(
+            parse_range("bytes=90-200", 100),
+            RangeRequest::Partial(90..100)
+        );
+        assert_eq!(parse_range("bytes=100-", 100), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-1,5-6", 100), RangeRequest::Full);
+        assert_eq!(parse_range("items=0-1", 100), RangeRequest::Full);
+    }
+
+    #[actix_rt::test]
+    async fn values_are_streamed_in_chunks() {
+        let value = Value::from(Bytes::from(vec![1u8; CHUNK_SIZE * 2 + 1]));
+        let req = test::TestRequest::default()
+            .header(header::RANGE, format!("bytes=1-{}", CHUNK_SIZE * 2))
+            .to_http_request();
+
+        let mut resp = respond(&req, value);
+        let mut body = resp.take_body();
+        let mut chunks = 0;
+        let mut received = BytesMut::new();
+        while let Some(chunk) = body.next().await {
+            chunks += 1;
+            received.extend_from_slice(&chunk.unwrap());
+        }
+
+        assert_eq!(resp.status(), 206);
+        assert_eq!(chunks, 2);
+        assert_eq!(received.len(), CHUNK_SIZE * 2);
+    }
+}
@@ -0,0 +1,139 @@
+use actix_rt::time::delay_for;
+use futures::future::{select, Either, Future, FutureExt};
+use prometheus::IntCounter;
+use std::{
+    any::Any,
+    cmp,
+    panic::AssertUnwindSafe,
+    time::{Duration, Instant},
+};
+
+/// The delays used between restarts of a supervised task.
+#[derive(Clone, Copy, Debug)]
+pub struct Backoff {
+    /// The delay before the first restart.
+    pub min: Duration,
+    /// The longest delay between restarts, the delay doubles after each restart until it is
+    /// reached. A task that runs for longer than this resets the delay to `min`.
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            min: Duration::from_millis(100),
+            max: Duration::from_secs(30),
+        }
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+/// Runs the future created by `task` until it completes or `shutdown` completes, restarting it
+/// with backoff when it panics.
+/// # Arguments
+/// * `name` - The name of the task used when logging.
+/// * `restarts` - A counter incremented each time the task is restarted.
+/// * `backoff` - The delays between restarts.
+/// * `shutdown` - A future that stops the task when it completes.
+/// * `task` - Creates the future to supervise.
+pub async fn supervise<F, T, S>(
+    name: &str,
+    restarts: IntCounter,
+    backoff: Backoff,
+    shutdown: S,
+    mut task: F,
+) where
+    F: FnMut() -> T,
+    T: Future<Output = ()>,
+    S: Future<Output = ()>,
+{
+    let mut shutdown = Box::pin(shutdown);
+    let mut delay = backoff.min;
+    loop {
+        let started = Instant::now();
+        let run = Box::pin(AssertUnwindSafe(task()).catch_unwind());
+        shutdown = match select(run, shutdown).await {
+            Either::Left((Ok(()), _)) => {
+                log::info!("{} finished", name);
+                return;
+            }
+            Either::Left((Err(panic), shutdown)) => {
+                if started.elapsed() > backoff.max {
+                    delay = backoff.min;
+                }
+                log::error!(
+                    "{} panicked: {}, restarting in {:?}",
+                    name,
+                    panic_message(panic.as_ref()),
+                    delay
+                );
+                restarts.inc();
+                shutdown
+            }
+            Either::Right(_) => {
+                log::info!("Stopped {}", name);
+                return;
+            }
+        };
+        shutdown = match select(Box::pin(delay_for(delay)), shutdown).await {
+            Either::Left((_, shutdown)) => shutdown,
+            Either::Right(_) => {
+                log::info!("Stopped {}", name);
+                return;
+            }
+        };
+        delay = cmp::min(delay * 2, backoff.max);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::future::{pending, ready};
+    use std::cell::Cell;
+
+    fn backoff() -> Backoff {
+        Backoff {
+            min: Duration::from_millis(1),
+            max: Duration::from_millis(4),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn panicking_tasks_are_restarted() {
+        let restarts = IntCounter::new("restarts", "restarts").unwrap();
+        let runs = Cell::new(0);
+
+        supervise("test", restarts.clone(), backoff(), pending(), || {
+            runs.set(runs.get() + 1);
+            let panics = runs.get() < 3;
+            async move {
+                if panics {
+                    panic!("test panic");
+                }
+            }
+        })
+        .await;
+
+        assert_eq!(runs.get(), 3);
+        assert_eq!(restarts.get(), 2);
+    }
+
+    #[actix_rt::test]
+    async fn tasks_are_stopped_on_shutdown() {
+        let restarts = IntCounter::new("restarts", "restarts").unwrap();
+
+        supervise("test", restarts.clone(), backoff(), ready(()), pending).await;
+
+        assert_eq!(restarts.get(), 0);
+    }
+}
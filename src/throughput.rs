@@ -0,0 +1,104 @@
+//! Counts the bytes of values read on hits and written, by namespace, as
+//! `cache_read_bytes_total` and `cache_written_bytes_total`, so the bandwidth served by the cache
+//! and the origin egress it saves can be estimated.
+//!
+//! The namespace of a key is the longest of `metrics.throughput_prefixes` it starts with, keys
+//! matching none are counted as `other`. Collections are counted as whole values when read with
+//! `GET` and not when changed in place.
+use crate::cache::MetricOpts;
+use crate::plugin::CachePlugin;
+use crate::value::Value;
+use prometheus::{IntCounterVec, Registry};
+
+/// The namespace of keys that match no prefix.
+const OTHER: &str = "other";
+
+/// A cache plugin counting the bytes read and written by namespace.
+pub struct Throughput {
+    prefixes: Vec<String>,
+    /// A count of bytes of values read, by namespace.
+    read: IntCounterVec,
+    /// A count of bytes of values written, by namespace.
+    written: IntCounterVec,
+}
+
+impl Throughput {
+    /// Returns a new `Throughput`.
+    /// # Arguments
+    /// * `prefixes` - The key prefixes counted separately.
+    /// * `opts` - The naming of the metrics.
+    pub fn new(prefixes: Vec<String>, opts: &MetricOpts) -> Self {
+        Self {
+            prefixes,
+            read: IntCounterVec::new(
+                opts.opts(
+                    "cache_read_bytes_total",
+                    "A count of the bytes of values read from the cache, by namespace",
+                ),
+                &["namespace"],
+            )
+            .unwrap(),
+            written: IntCounterVec::new(
+                opts.opts(
+                    "cache_written_bytes_total",
+                    "A count of the bytes of values written to the cache, by namespace",
+                ),
+                &["namespace"],
+            )
+            .unwrap(),
+        }
+    }
+
+    /// Registers the metrics with a registry.
+    pub fn register(&self, registry: &Registry) {
+        registry.register(Box::new(self.read.clone())).unwrap();
+        registry.register(Box::new(self.written.clone())).unwrap();
+    }
+
+    /// Returns the namespace of `key`, the longest prefix it starts with.
+    fn namespace(&self, key: &str) -> &str {
+        self.prefixes
+            .iter()
+            .filter(|prefix| key.starts_with(prefix.as_str()))
+            .max_by_key(|prefix| prefix.len())
+            .map_or(OTHER, |prefix| prefix.as_str())
+    }
+}
+
+impl CachePlugin for Throughput {
+    fn before_put(&self, key: &str, value: &Value) {
+        self.written
+            .with_label_values(&[self.namespace(key)])
+            .inc_by(value.len() as i64);
+    }
+
+    fn after_read(&self, key: &str, size: usize) {
+        self.read
+            .with_label_values(&[self.namespace(key)])
+            .inc_by(size as i64);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bytes_are_counted_by_the_longest_prefix() {
+        let sut = Throughput::new(
+            vec!["users/".into(), "users/images/".into()],
+            &MetricOpts::default(),
+        );
+
+        sut.before_put("users/images/1", &Value::from("12345"));
+        sut.before_put("pages/1", &Value::from("12"));
+        sut.after_read("users/1", 3);
+        sut.after_read("users/2", 4);
+
+        let count =
+            |counter: &IntCounterVec, namespace| counter.with_label_values(&[namespace]).get();
+        assert_eq!(count(&sut.written, "users/images/"), 5);
+        assert_eq!(count(&sut.written, OTHER), 2);
+        assert_eq!(count(&sut.read, "users/"), 7);
+    }
+}
@@ -0,0 +1,231 @@
+//! Records a sample of reads and writes to `trace.path` in a compact binary format, to analyze
+//! key popularity offline or to replay with `--replay`.
+//!
+//! Keys are sampled by the hash of the key, so every read and write of a sampled key is recorded,
+//! and the keys themselves are not. The file starts with `MAGIC`, followed by records of
+//! `RECORD_SIZE` bytes in little endian: milliseconds since the unix epoch (u64), the operation
+//! (u8, 0 for a read and 1 for a write), the xxHash64 of the key (u64), the size of the value
+//! written (u32, 0 for reads) and whether a read was a hit (u8). Recording stops once the file
+//! reaches `trace.max_size` bytes.
+use crate::cache::CacheMetrics;
+use crate::digest;
+use crate::plugin::CachePlugin;
+use crate::settings;
+use crate::value::Value;
+use std::{
+    convert::TryInto,
+    fs::File,
+    io::{self, Read, Write},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The first bytes of a trace file, with the version of the format.
+pub const MAGIC: &[u8; 8] = b"SMCTRC01";
+
+/// The size in bytes of a record.
+const RECORD_SIZE: usize = 22;
+
+/// The operation of a record.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraceOp {
+    Get,
+    Put,
+}
+
+/// A read or write of a sampled key.
+#[derive(Debug, PartialEq)]
+pub struct TraceRecord {
+    pub timestamp_ms: u64,
+    pub op: TraceOp,
+    pub key_hash: u64,
+    pub size: u32,
+    pub hit: bool,
+}
+
+impl TraceRecord {
+    fn encode(&self) -> [u8; RECORD_SIZE] {
+        let mut bytes = [0; RECORD_SIZE];
+        bytes[0..8].copy_from_slice(&self.timestamp_ms.to_le_bytes());
+        bytes[8] = match self.op {
+            TraceOp::Get => 0,
+            TraceOp::Put => 1,
+        };
+        bytes[9..17].copy_from_slice(&self.key_hash.to_le_bytes());
+        bytes[17..21].copy_from_slice(&self.size.to_le_bytes());
+        bytes[21] = self.hit as u8;
+        bytes
+    }
+
+    fn decode(bytes: &[u8; RECORD_SIZE]) -> io::Result<Self> {
+        let op = match bytes[8] {
+            0 => TraceOp::Get,
+            1 => TraceOp::Put,
+            op => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unknown trace operation: {}", op),
+                ))
+            }
+        };
+        // The slices have the length of the integers.
+        Ok(Self {
+            timestamp_ms: u64::from_le_bytes(bytes[0..8].try_into().unwrap()),
+            op,
+            key_hash: u64::from_le_bytes(bytes[9..17].try_into().unwrap()),
+            size: u32::from_le_bytes(bytes[17..21].try_into().unwrap()),
+            hit: bytes[21] != 0,
+        })
+    }
+}
+
+/// Returns the records of a trace, which must start with `MAGIC`.
+pub fn read<R: Read>(mut reader: R) -> io::Result<Vec<TraceRecord>> {
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Not a trace file",
+        ));
+    }
+    let mut records = Vec::new();
+    let mut bytes = [0; RECORD_SIZE];
+    loop {
+        match reader.read_exact(&mut bytes) {
+            Ok(()) => records.push(TraceRecord::decode(&bytes)?),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(records),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// The trace file and its size.
+struct TraceFile {
+    file: File,
+    size: u64,
+}
+
+/// A cache plugin recording the reads and writes of a sample of keys.
+pub struct TraceRecorder {
+    settings: settings::Trace,
+    file: Mutex<TraceFile>,
+    /// Counts the records that could not be written as internal errors.
+    metrics: CacheMetrics,
+}
+
+impl TraceRecorder {
+    /// Returns a new `TraceRecorder`, replacing the trace file at `path`.
+    /// # Arguments
+    /// * `settings` - The share of keys recorded and the largest size of the file.
+    /// * `path` - The path of the trace file.
+    /// * `metrics` - The metrics records that could not be written are counted by.
+    pub fn new(settings: settings::Trace, path: &str, metrics: CacheMetrics) -> io::Result<Self> {
+        let mut file = File::create(path)?;
+        file.write_all(MAGIC)?;
+        Ok(Self {
+            settings,
+            file: Mutex::new(TraceFile {
+                file,
+                size: MAGIC.len() as u64,
+            }),
+            metrics,
+        })
+    }
+
+    /// Returns the hash of `key` if it is in the sample.
+    fn sampled(&self, key: &str) -> Option<u64> {
+        let hash = digest::hash(key.as_bytes());
+        let threshold = (self.settings.percentage.clamp(0.0, 100.0) * 10_000.0) as u64;
+        Some(hash).filter(|hash| hash % 1_000_000 < threshold)
+    }
+
+    /// Appends `record` to the trace file, unless it is full.
+    fn write(&self, record: TraceRecord) {
+        let mut file = self.file.lock().unwrap();
+        if file.size + RECORD_SIZE as u64 > self.settings.max_size {
+            return;
+        }
+        match file.file.write_all(&record.encode()) {
+            Ok(()) => {
+                file.size += RECORD_SIZE as u64;
+                if file.size + RECORD_SIZE as u64 > self.settings.max_size {
+                    log::info!("Stopped recording the trace at trace.max_size");
+                }
+            }
+            Err(err) => {
+                log::error!("Could not write trace record. {}", err);
+                self.metrics.internal_error("trace");
+            }
+        }
+    }
+
+    fn record(&self, op: TraceOp, key: &str, size: usize, hit: bool) {
+        if let Some(key_hash) = self.sampled(key) {
+            self.write(TraceRecord {
+                timestamp_ms: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |since| since.as_millis() as u64),
+                op,
+                key_hash,
+                size: size.min(u32::MAX as usize) as u32,
+                hit,
+            });
+        }
+    }
+}
+
+impl CachePlugin for TraceRecorder {
+    fn before_put(&self, key: &str, value: &Value) {
+        self.record(TraceOp::Put, key, value.len(), false);
+    }
+
+    fn after_get(&self, key: &str, hit: bool) {
+        self.record(TraceOp::Get, key, 0, hit);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn sampled_keys_are_recorded_and_read_back() {
+        let path = env::temp_dir().join("simple-mem-cache-trace");
+        let path = path.to_str().unwrap();
+        let settings = settings::Trace {
+            path: Some(path.to_string()),
+            percentage: 100.0,
+            ..Default::default()
+        };
+        let sut = TraceRecorder::new(settings, path, CacheMetrics::default()).unwrap();
+
+        sut.before_put("a", &Value::from("12345"));
+        sut.after_get("a", true);
+
+        let records = read(File::open(path).unwrap()).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].op, TraceOp::Put);
+        assert_eq!(records[0].size, 5);
+        assert_eq!(records[1].key_hash, digest::hash(b"a"));
+        assert!(records[1].hit);
+    }
+
+    #[test]
+    fn keys_are_sampled_by_hash() {
+        let settings = settings::Trace {
+            percentage: 10.0,
+            ..Default::default()
+        };
+        let path = env::temp_dir().join("simple-mem-cache-trace-sample");
+        let sut =
+            TraceRecorder::new(settings, path.to_str().unwrap(), CacheMetrics::default()).unwrap();
+
+        let sampled = (0..10_000)
+            .filter(|n| sut.sampled(&n.to_string()).is_some())
+            .count();
+
+        assert!(sampled > 800 && sampled < 1200, "{}", sampled);
+    }
+}
@@ -0,0 +1,237 @@
+//! Chooses how long a value written with `POST` or `PUT /{key}` lives: `cache.key_live_duration`
+//! unless the request or the namespace of the key asks otherwise.
+//!
+//! A request asks for a ttl with an `X-Ttl` header in seconds, where `0` or `infinite` means the
+//! value never expires, and keys starting with one of `cache.immortal_prefixes` never expire by
+//! default. Ttls longer than 100 years are rejected, such values should never expire instead.
+//! Values that never expire are not added to the expiry queue, they are only removed when they are
+//! replaced, removed or evicted.
+//!
+//! A request can instead align the expiry with the validity of the upstream data with an
+//! `Expires-At` header, an RFC 3339 date or a unix timestamp in seconds. The timestamp is turned
+//! into a ttl against the wall clock once, when the value is written, so a later step of the wall
+//! clock does not move the expiry and a producer whose clock is ahead only shortens its own
+//! values. Timestamps that have already passed or are more than 100 years away are rejected.
+//!
+//! The ttls requested by clients are clamped to `cache.min_ttl` and `cache.max_ttl`, and with a
+//! `max_ttl` a request for a value that never expires gets `max_ttl` instead. Namespaces in
+//! `cache.immortal_prefixes` are configured by the operator and are not clamped.
+use crate::settings;
+use actix_web::HttpRequest;
+use chrono::DateTime;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The header requesting the ttl of a value.
+pub const TTL_HEADER: &str = "x-ttl";
+
+/// The header requesting the wall clock time a value expires at.
+pub const EXPIRES_AT_HEADER: &str = "expires-at";
+
+/// The longest ttl a request may ask for.
+pub const MAX_TTL: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+
+/// When a written value expires.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Expiry {
+    /// After `cache.key_live_duration`.
+    Default,
+    /// After the given ttl.
+    After(Duration),
+    /// Never.
+    Never,
+}
+
+/// The expiries clients may request.
+#[derive(Debug, Default)]
+pub struct TtlPolicy<'s> {
+    /// The key prefixes whose values never expire.
+    pub immortal_prefixes: &'s [String],
+    /// The shortest ttl a client may request.
+    pub min_ttl: Option<Duration>,
+    /// The longest ttl a client may request.
+    pub max_ttl: Option<Duration>,
+}
+
+impl<'s> TtlPolicy<'s> {
+    /// Returns the policy configured in `cache`.
+    pub fn new(cache: &'s settings::Cache) -> Self {
+        Self {
+            immortal_prefixes: &cache.immortal_prefixes,
+            min_ttl: cache.min_ttl.map(Duration::from_secs),
+            max_ttl: cache.max_ttl.map(Duration::from_secs),
+        }
+    }
+
+    /// Returns `expiry` within the bounds of the ttls clients may request.
+    fn clamp(&self, expiry: Expiry) -> Expiry {
+        match (expiry, self.max_ttl) {
+            (Expiry::Never, Some(max_ttl)) => Expiry::After(max_ttl),
+            (Expiry::After(mut ttl), max_ttl) => {
+                if let Some(min_ttl) = self.min_ttl {
+                    ttl = ttl.max(min_ttl);
+                }
+                if let Some(max_ttl) = max_ttl {
+                    ttl = ttl.min(max_ttl);
+                }
+                Expiry::After(ttl)
+            }
+            (expiry, _) => expiry,
+        }
+    }
+
+    /// Returns when the value of `key` written by `req` expires, or why the requested ttl is
+    /// invalid.
+    /// # Arguments
+    /// * `req` - The write request.
+    /// * `key` - The key written.
+    pub fn requested(&self, req: &HttpRequest, key: &str) -> Result<Expiry, String> {
+        let headers = req.headers();
+        if headers.contains_key(TTL_HEADER) && headers.contains_key(EXPIRES_AT_HEADER) {
+            return Err(format!(
+                "Only one of {} and {} may be sent",
+                TTL_HEADER, EXPIRES_AT_HEADER
+            ));
+        }
+        if let Some(ttl) = headers.get(TTL_HEADER) {
+            return ttl_seconds(ttl.to_str().unwrap_or_default()).map(|ttl| self.clamp(ttl));
+        }
+        if let Some(value) = headers.get(EXPIRES_AT_HEADER) {
+            let value = value.to_str().map_err(|err| err.to_string())?;
+            return expires_at(value, SystemTime::now()).map(|ttl| self.clamp(ttl));
+        }
+        Ok(self.for_key(key))
+    }
+
+    /// Returns when the value of `key` expires when no ttl was requested.
+    pub fn for_key(&self, key: &str) -> Expiry {
+        let immortal = self
+            .immortal_prefixes
+            .iter()
+            .any(|prefix| key.starts_with(prefix.as_str()));
+        if immortal {
+            Expiry::Never
+        } else {
+            Expiry::Default
+        }
+    }
+}
+
+/// Returns the expiry of an `X-Ttl` header of `value`.
+pub fn ttl_seconds(value: &str) -> Result<Expiry, String> {
+    match value.trim() {
+        "infinite" => Ok(Expiry::Never),
+        seconds => match seconds.parse() {
+            Ok(0) => Ok(Expiry::Never),
+            Ok(seconds) if seconds <= MAX_TTL.as_secs() => {
+                Ok(Expiry::After(Duration::from_secs(seconds)))
+            }
+            Ok(_) => Err(format!(
+                "Invalid {}, at most {} seconds, or 0 or infinite for values that never expire",
+                TTL_HEADER,
+                MAX_TTL.as_secs()
+            )),
+            Err(_) => Err(format!(
+                "Invalid {}, expected seconds, or 0 or infinite for values that never expire",
+                TTL_HEADER
+            )),
+        },
+    }
+}
+
+/// Returns the time since the unix epoch of an RFC 3339 date or a unix timestamp in seconds.
+fn parse_timestamp(value: &str) -> Option<Duration> {
+    if let Ok(seconds) = value.parse::<f64>() {
+        return Duration::try_from_secs_f64(seconds).ok();
+    }
+    let millis = DateTime::parse_from_rfc3339(value).ok()?.timestamp_millis();
+    Some(Duration::from_millis(millis.max(0) as u64))
+}
+
+/// Returns the ttl of a value expiring at the `Expires-At` timestamp `value` at `now`.
+fn expires_at(value: &str, now: SystemTime) -> Result<Expiry, String> {
+    let expires_at = parse_timestamp(value.trim()).ok_or_else(|| {
+        format!(
+            "Invalid {}, expected an RFC 3339 date or a unix timestamp",
+            EXPIRES_AT_HEADER
+        )
+    })?;
+    let now = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    match expires_at
+        .checked_sub(now)
+        .filter(|ttl| *ttl > Duration::from_millis(0))
+    {
+        Some(ttl) if ttl > MAX_TTL => Err(format!(
+            "Invalid {}, at most 100 years from now",
+            EXPIRES_AT_HEADER
+        )),
+        Some(ttl) => Ok(Expiry::After(ttl)),
+        None => Err(format!("The {} time has already passed", EXPIRES_AT_HEADER)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn request(policy: &TtlPolicy, ttl: Option<&str>, key: &str) -> Result<Expiry, String> {
+        let mut req = TestRequest::default();
+        if let Some(ttl) = ttl {
+            req = req.header(TTL_HEADER, ttl);
+        }
+        policy.requested(&req.to_http_request(), key)
+    }
+
+    #[test]
+    fn values_never_expire_when_requested_or_in_an_immortal_namespace() {
+        let prefixes = vec!["config/".to_string()];
+        let policy = TtlPolicy {
+            immortal_prefixes: &prefixes,
+            ..Default::default()
+        };
+        let expiry = |ttl, key| request(&policy, ttl, key);
+
+        assert_eq!(expiry(None, "users/1"), Ok(Expiry::Default));
+        assert_eq!(expiry(None, "config/flags"), Ok(Expiry::Never));
+        assert_eq!(expiry(Some("0"), "users/1"), Ok(Expiry::Never));
+        assert_eq!(expiry(Some("infinite"), "users/1"), Ok(Expiry::Never));
+        assert_eq!(
+            expiry(Some("60"), "users/1"),
+            Ok(Expiry::After(Duration::from_secs(60)))
+        );
+        assert!(expiry(Some("-1"), "users/1").is_err());
+        assert!(expiry(Some("18446744073709551615"), "users/1").is_err());
+    }
+
+    #[test]
+    fn requested_ttls_are_clamped() {
+        let prefixes = vec!["config/".to_string()];
+        let policy = TtlPolicy {
+            immortal_prefixes: &prefixes,
+            min_ttl: Some(Duration::from_secs(10)),
+            max_ttl: Some(Duration::from_secs(3600)),
+        };
+        let after = |seconds| Ok(Expiry::After(Duration::from_secs(seconds)));
+
+        assert_eq!(request(&policy, Some("1"), "users/1"), after(10));
+        assert_eq!(request(&policy, Some("60"), "users/1"), after(60));
+        assert_eq!(request(&policy, Some("86400"), "users/1"), after(3600));
+        assert_eq!(request(&policy, Some("0"), "users/1"), after(3600));
+        assert_eq!(request(&policy, None, "config/flags"), Ok(Expiry::Never));
+    }
+
+    #[test]
+    fn absolute_expiries_are_turned_into_ttls() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let after = |seconds| Ok(Expiry::After(Duration::from_secs(seconds)));
+
+        assert_eq!(expires_at("1600000060", now), after(60));
+        assert_eq!(expires_at("2020-09-13T12:27:40Z", now), after(60));
+        assert_eq!(expires_at("2020-09-13T14:27:40+02:00", now), after(60));
+        assert!(expires_at("1599999999", now).is_err());
+        assert!(expires_at("tomorrow", now).is_err());
+        assert!(expires_at("1e300", now).is_err());
+        assert!(expires_at("-1", now).is_err());
+        assert!(expires_at("NaN", now).is_err());
+    }
+}
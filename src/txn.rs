@@ -0,0 +1,362 @@
+//! Applies a group of puts and deletes as a unit with `POST /_txn`, if every condition holds, e.g.
+//! `{"conditions": [{"check": "version", "key": "a", "version": "..."}, {"check": "absent", "key":
+//! "b"}], "operations": [{"op": "put", "key": "a", "value": "1"}, {"op": "delete", "key": "c"}]}`.
+//!
+//! The version of a key is the etag of its value, as returned by `/_admin/meta/{key}` and by
+//! earlier transactions. Conditions are checked and operations applied while holding the keys, so
+//! transactions are atomic with respect to other transactions and scripts using any of the same
+//! keys. Writes and deletes through the single key endpoints do not wait for these keys, so one
+//! made while a transaction is applied can land between its checks and its operations. Every put
+//! goes through the checks of `POST /{key}` before anything is applied, and is stored with the
+//! expiry of its namespace and attributed to the API key of the request. Under memory pressure a
+//! transaction is only applied if entries can be evicted to make room for all of its puts.
+//!
+//! Versions are checked with `SimpleCache::peek`, so checking a condition is not a read: it is not
+//! counted as a hit or miss and does not keep the key alive.
+use crate::audit::{Auditor, Change};
+use crate::cache::SimpleCache;
+use crate::digest;
+use crate::eviction::Priority;
+use crate::keys::InvalidKey;
+use crate::limits::KeyLimiter;
+use crate::pressure::MemoryPressure;
+use crate::settings;
+use crate::ttl::TtlPolicy;
+use crate::value::Value;
+use crate::write_checks::{Rejected, WriteChecks};
+use actix_web::{error::BlockingError, post, web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// A condition that must hold for a transaction to be applied.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "check", rename_all = "snake_case")]
+enum Condition {
+    /// The key has a value with this version.
+    Version { key: String, version: String },
+    /// The key has no value.
+    Absent { key: String },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Operation {
+    Put { key: String, value: String },
+    Delete { key: String },
+}
+
+/// The conditions and operations of a transaction.
+#[derive(Debug, Deserialize)]
+pub struct Transaction {
+    #[serde(default)]
+    conditions: Vec<Condition>,
+    operations: Vec<Operation>,
+}
+
+/// The result of a transaction.
+#[derive(Debug, PartialEq, Serialize)]
+struct Outcome {
+    committed: bool,
+    /// The indexes of the conditions that did not hold.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    failed: Vec<usize>,
+    /// The new version of each key that was put.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    versions: BTreeMap<String, String>,
+    /// The keys put or deleted, as recorded in the audit trail.
+    #[serde(skip)]
+    changes: Vec<Change>,
+}
+
+/// Returns the version of `value`.
+pub fn version(value: &Value) -> String {
+    digest::to_hex(digest::hash_chunks(value.chunks()))
+}
+
+impl Transaction {
+    fn keys_mut(&mut self) -> impl Iterator<Item = &mut String> {
+        let conditions = self.conditions.iter_mut().map(|condition| match condition {
+            Condition::Version { key, .. } | Condition::Absent { key } => key,
+        });
+        let operations = self.operations.iter_mut().map(|operation| match operation {
+            Operation::Put { key, .. } | Operation::Delete { key } => key,
+        });
+        conditions.chain(operations)
+    }
+
+    /// Normalizes every key following the same rules as in the path of the single key endpoints.
+    fn normalize(&mut self, cache: &SimpleCache<'static>) -> Result<(), InvalidKey> {
+        for key in self.keys_mut() {
+            *key = cache.key(key)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the distinct keys of the conditions and operations.
+    fn keys(&mut self) -> Vec<String> {
+        let mut keys: Vec<String> = self.keys_mut().map(|key| key.clone()).collect();
+        keys.sort();
+        keys.dedup();
+        keys
+    }
+
+    fn puts(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.operations
+            .iter()
+            .filter_map(|operation| match operation {
+                Operation::Put { key, value } => Some((key.as_str(), value.as_str())),
+                Operation::Delete { .. } => None,
+            })
+    }
+
+    /// Returns an error response if a put does not pass the checks of `POST /{key}`.
+    fn check(
+        &self,
+        cache: &SimpleCache<'static>,
+        checks: &WriteChecks,
+    ) -> Result<(), HttpResponse> {
+        for (key, value) in self.puts() {
+            let value = Value::from(value.to_string());
+            let checked = checks
+                .check_key(cache, key)
+                .and_then(|_| checks.check_value(cache, key, &value));
+            match checked {
+                Ok(()) => {}
+                Err(Rejected::Invalid(errors)) => {
+                    return Err(HttpResponse::UnprocessableEntity()
+                        .json(serde_json::json!({ "key": key, "errors": errors.0 })))
+                }
+                Err(rejected) => return Err(rejected.response()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies the operations if every condition holds, holding the keys of the transaction, or
+    /// returns an error if the cache is under memory pressure and can not make room for the puts.
+    fn commit(
+        self,
+        keys: &[String],
+        cache: &SimpleCache<'static>,
+        checks: &WriteChecks,
+    ) -> Result<Outcome, Rejected> {
+        let _guard = cache.lock_keys(keys);
+        let failed: Vec<usize> = self
+            .conditions
+            .iter()
+            .enumerate()
+            .filter(|(_, condition)| match condition {
+                Condition::Version {
+                    key,
+                    version: expected,
+                } => cache.peek(key, &version).as_ref() != Some(expected),
+                Condition::Absent { key } => cache.contains_key(key),
+            })
+            .map(|(index, _)| index)
+            .collect();
+        if !failed.is_empty() {
+            return Ok(Outcome {
+                committed: false,
+                failed,
+                versions: BTreeMap::new(),
+                changes: Vec::new(),
+            });
+        }
+        // Room is made for every put before any is applied, so the transaction is not left half
+        // applied.
+        if checks.pressure.check(cache.size()) {
+            for (key, value) in self.puts() {
+                if !cache.make_room(key, value.len(), Priority::Normal) {
+                    return Err(Rejected::Pressure);
+                }
+            }
+        }
+        let ttl_policy = TtlPolicy::new(&checks.settings);
+        let mut versions = BTreeMap::new();
+        let mut changes = Vec::new();
+        for operation in self.operations {
+            match operation {
+                Operation::Put { key, value } => {
+                    let value = Value::from(value);
+                    versions.insert(key.clone(), version(&value));
+                    changes.push(Change {
+                        operation: "put",
+                        key: key.clone(),
+                        size: Some(value.len() as u64),
+                    });
+                    let expiry = ttl_policy.for_key(&key);
+                    checks.insert(cache, key, value, expiry, Priority::Normal, false)?;
+                }
+                Operation::Delete { key } => {
+                    versions.remove(&key);
+                    if cache.remove(&key) {
+                        changes.push(Change {
+                            operation: "delete",
+                            key,
+                            size: None,
+                        });
+                    }
+                }
+            }
+        }
+        Ok(Outcome {
+            committed: true,
+            failed,
+            versions,
+            changes,
+        })
+    }
+}
+
+/// Applies a transaction, responding with 409 and the indexes of the conditions that did not hold
+/// if it was not applied.
+#[post("/_txn")]
+async fn txn(
+    req: HttpRequest,
+    txn: web::Json<Transaction>,
+    cache: web::Data<SimpleCache<'static>>,
+    pressure: web::Data<MemoryPressure>,
+    limiter: web::Data<KeyLimiter>,
+    settings: web::Data<settings::Cache>,
+    auditor: Option<web::Data<Auditor>>,
+) -> HttpResponse {
+    let mut txn = txn.into_inner();
+    if let Err(err) = txn.normalize(&cache) {
+        return HttpResponse::BadRequest().body(err.to_string());
+    }
+    let checks = WriteChecks::new(&req, pressure, limiter, settings);
+    if let Err(response) = txn.check(&cache, &checks) {
+        return response;
+    }
+    if txn.puts().next().is_some() {
+        if let Err(rejected) = checks.admit(&cache) {
+            return rejected.response();
+        }
+    }
+    let creates_keys = txn.puts().any(|(key, _)| !cache.contains_key(key));
+    if let Some(response) = checks.limiter.check(&req, creates_keys) {
+        return response;
+    }
+    let keys = txn.keys();
+    // Transactions may wait for the keys of other transactions and scripts, so they run on the
+    // blocking thread pool.
+    match web::block(move || txn.commit(&keys, &cache, &checks)).await {
+        Ok(outcome) if outcome.committed => {
+            if let Some(auditor) = auditor {
+                auditor.write_changes(&req, &outcome.changes);
+            }
+            HttpResponse::Ok().json(outcome)
+        }
+        Ok(outcome) => HttpResponse::Conflict().json(outcome),
+        Err(BlockingError::Error(rejected)) => rejected.response(),
+        Err(err) => {
+            log::error!("Could not apply transaction. {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::{CacheMetrics, MetricOpts};
+    use crate::settings::Settings;
+    use std::time::Duration;
+
+    fn transaction(json: serde_json::Value) -> Transaction {
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn checks(settings: settings::Cache) -> WriteChecks {
+        WriteChecks {
+            pressure: web::Data::new(MemoryPressure::new(
+                Default::default(),
+                &MetricOpts::default(),
+            )),
+            limiter: web::Data::new(KeyLimiter::new(Default::default(), &MetricOpts::default())),
+            settings: web::Data::new(settings),
+            client: None,
+            usage: None,
+        }
+    }
+
+    fn commit(mut transaction: Transaction, cache: &SimpleCache<'static>) -> Outcome {
+        let keys = transaction.keys();
+        let checks = checks(Settings::new().unwrap().cache);
+        transaction.commit(&keys, cache, &checks).unwrap()
+    }
+
+    #[test]
+    fn transactions_apply_when_every_condition_holds() {
+        let cache = SimpleCache::new(Duration::from_secs(60), CacheMetrics::default());
+        cache.put("a", "1");
+        cache.put("c", "3");
+        let pending = transaction(serde_json::json!({
+            "conditions": [
+                {"check": "version", "key": "a", "version": version(&Value::from("1"))},
+                {"check": "absent", "key": "b"}
+            ],
+            "operations": [
+                {"op": "put", "key": "a", "value": "2"},
+                {"op": "put", "key": "b", "value": "2"},
+                {"op": "delete", "key": "c"}
+            ]
+        }));
+
+        let outcome = commit(pending, &cache);
+
+        assert!(outcome.committed);
+        assert_eq!(outcome.versions["a"], version(&Value::from("2")));
+        assert_eq!(cache.get("b", &|value| value.clone()), Some("2".into()));
+        assert!(!cache.contains_key("c"));
+        let changes: Vec<(&str, &str)> = outcome
+            .changes
+            .iter()
+            .map(|change| (change.operation, change.key.as_str()))
+            .collect();
+        assert_eq!(changes, vec![("put", "a"), ("put", "b"), ("delete", "c")]);
+    }
+
+    #[test]
+    fn transactions_are_not_applied_when_a_condition_fails() {
+        let cache = SimpleCache::new(Duration::from_secs(60), CacheMetrics::default());
+        cache.put("a", "1");
+        let pending = transaction(serde_json::json!({
+            "conditions": [
+                {"check": "version", "key": "a", "version": "0"},
+                {"check": "absent", "key": "b"},
+                {"check": "absent", "key": "a"}
+            ],
+            "operations": [{"op": "put", "key": "b", "value": "2"}]
+        }));
+
+        let outcome = commit(pending, &cache);
+
+        assert_eq!(
+            outcome,
+            Outcome {
+                committed: false,
+                failed: vec![0, 2],
+                versions: BTreeMap::new(),
+                changes: Vec::new(),
+            }
+        );
+        assert!(!cache.contains_key("b"));
+    }
+
+    #[test]
+    fn puts_expire_like_writes() {
+        let cache = SimpleCache::new(Duration::from_secs(60), CacheMetrics::default());
+        let mut settings = Settings::new().unwrap().cache;
+        settings.immortal_prefixes = vec!["config/".into()];
+        let mut pending = transaction(serde_json::json!({
+            "operations": [{"op": "put", "key": "config/a", "value": "1"}]
+        }));
+        let keys = pending.keys();
+
+        let outcome = pending.commit(&keys, &cache, &checks(settings)).unwrap();
+
+        assert!(outcome.committed);
+        assert!(cache.entries(|_| true)[0].immortal);
+    }
+}
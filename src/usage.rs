@@ -0,0 +1,349 @@
+//! Tracks the usage of the cache server per API key, sent in the `usage.header` request header, so
+//! the cache can be shared between teams: the bytes of values each key stores, its requests and
+//! its hit ratio, reported by `GET /_admin/usage` and as metrics labelled by `api_key`.
+//!
+//! Only the API keys in `usage.quotas` or `usage.aliases` are tracked on their own, every other
+//! API key shares the `other` usage and its quota, so sending new API keys neither grows the
+//! tracked keys nor escapes a quota. API keys are reported by their alias, or else by a hash, so
+//! the keys themselves are never published.
+//!
+//! Stored bytes are attributed to the API key that last wrote a value and are released when the
+//! value is removed. Writes that would take an API key over its quota are rejected with 507.
+use crate::cache::MetricOpts;
+use crate::digest;
+use crate::settings;
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse},
+    http::{Method, StatusCode},
+    web, Error, HttpRequest,
+};
+use futures::future::{Future, FutureExt};
+use prometheus::{IntCounterVec, IntGaugeVec, Registry};
+#[cfg(feature = "admin")]
+use serde::Serialize;
+#[cfg(feature = "admin")]
+use std::collections::BTreeMap;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// The name of the usage shared by the API keys that are not tracked on their own.
+pub const OTHER: &str = "other";
+
+/// Container for the usage metrics.
+#[derive(Clone)]
+pub struct UsageMetrics {
+    /// A count of requests by API key.
+    pub requests: IntCounterVec,
+    /// A count of hits and misses of `GET /{key}` by API key.
+    pub queries: IntCounterVec,
+    /// The size in bytes of the values stored by each API key.
+    pub stored: IntGaugeVec,
+}
+
+impl UsageMetrics {
+    /// Creates a new UsageMetrics named using `opts`.
+    pub fn with_opts(opts: &MetricOpts) -> Self {
+        Self {
+            requests: IntCounterVec::new(
+                opts.opts("api_key_requests_total", "A count of requests by API key"),
+                &["api_key"],
+            )
+            .unwrap(),
+            queries: IntCounterVec::new(
+                opts.opts(
+                    "api_key_queries_total",
+                    "A count of cache hits and misses by API key",
+                ),
+                &["api_key", "hit_or_miss"],
+            )
+            .unwrap(),
+            stored: IntGaugeVec::new(
+                opts.opts(
+                    "api_key_stored_bytes",
+                    "The total size in bytes of the values stored by each API key",
+                ),
+                &["api_key"],
+            )
+            .unwrap(),
+        }
+    }
+
+    /// Registers the usage metrics with a registry.
+    pub fn register(&self, registry: &Registry) {
+        registry.register(Box::new(self.requests.clone())).unwrap();
+        registry.register(Box::new(self.queries.clone())).unwrap();
+        registry.register(Box::new(self.stored.clone())).unwrap();
+    }
+}
+
+/// The usage of one API key.
+pub struct ApiKeyUsage {
+    /// The alias or hash the API key is reported by.
+    label: String,
+    quota: Option<u64>,
+    stored: AtomicI64,
+    requests: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    metrics: UsageMetrics,
+}
+
+impl ApiKeyUsage {
+    /// Returns true if storing `size` more bytes keeps the API key within its quota.
+    pub fn within_quota(&self, size: usize) -> bool {
+        self.quota.is_none_or(|quota| {
+            self.stored.load(Ordering::Relaxed).max(0) as u64 + size as u64 <= quota
+        })
+    }
+
+    fn add_stored(&self, size: i64) {
+        self.stored.fetch_add(size, Ordering::Relaxed);
+        self.metrics
+            .stored
+            .with_label_values(&[&self.label])
+            .add(size);
+    }
+}
+
+/// The bytes of a value attributed to an API key, which are released when dropped.
+pub struct StoredBytes {
+    usage: Arc<ApiKeyUsage>,
+    len: usize,
+}
+
+impl StoredBytes {
+    /// Attributes `len` bytes to the API key of `usage`.
+    pub fn new(usage: Arc<ApiKeyUsage>, len: usize) -> Self {
+        usage.add_stored(len as i64);
+        Self { usage, len }
+    }
+
+    /// Returns the usage the bytes are attributed to.
+    pub fn usage(&self) -> &Arc<ApiKeyUsage> {
+        &self.usage
+    }
+}
+
+impl Drop for StoredBytes {
+    fn drop(&mut self) {
+        self.usage.add_stored(-(self.len as i64));
+    }
+}
+
+/// A summary of the usage of an API key.
+#[cfg(feature = "admin")]
+#[derive(Debug, PartialEq, Serialize)]
+pub struct UsageReport {
+    pub stored_bytes: i64,
+    pub quota: Option<u64>,
+    pub requests: u64,
+    pub hits: u64,
+    pub misses: u64,
+    /// Hits divided by hits and misses, or None before the first read.
+    pub hit_ratio: Option<f64>,
+}
+
+/// Tracks the usage of every API key.
+pub struct UsageTracker {
+    settings: settings::Usage,
+    /// The usage of each API key in `usage.quotas` or `usage.aliases`.
+    keys: HashMap<String, Arc<ApiKeyUsage>>,
+    /// The usage of every other API key.
+    other: Arc<ApiKeyUsage>,
+    metrics: UsageMetrics,
+}
+
+impl UsageTracker {
+    /// Returns a new `UsageTracker`.
+    /// # Arguments
+    /// * `settings` - The API key header, quotas and aliases.
+    /// * `metrics` - A container for the usage metrics.
+    pub fn new(settings: settings::Usage, metrics: UsageMetrics) -> Self {
+        let new_usage = |label: String, quota: Option<u64>| {
+            Arc::new(ApiKeyUsage {
+                label,
+                quota,
+                stored: AtomicI64::new(0),
+                requests: AtomicU64::new(0),
+                hits: AtomicU64::new(0),
+                misses: AtomicU64::new(0),
+                metrics: metrics.clone(),
+            })
+        };
+        let keys = settings
+            .quotas
+            .keys()
+            .chain(settings.aliases.keys())
+            .map(|api_key| {
+                let label = settings
+                    .aliases
+                    .get(api_key)
+                    .cloned()
+                    .unwrap_or_else(|| digest::to_hex(digest::hash(api_key.as_bytes())));
+                let quota = settings.quotas.get(api_key).copied().or(settings.quota);
+                (api_key.clone(), new_usage(label, quota))
+            })
+            .collect();
+        let other = new_usage(OTHER.to_string(), settings.quota);
+        Self {
+            settings,
+            keys,
+            other,
+            metrics,
+        }
+    }
+
+    /// Returns the usage of `api_key`, the shared `other` usage unless it is tracked on its own.
+    pub fn usage(&self, api_key: &str) -> Arc<ApiKeyUsage> {
+        self.keys.get(api_key).unwrap_or(&self.other).clone()
+    }
+
+    /// Returns the usage of the API key sent with `req`, if usage is tracked and one was sent.
+    pub fn for_request(req: &HttpRequest) -> Option<Arc<ApiKeyUsage>> {
+        let tracker = req.app_data::<web::Data<UsageTracker>>()?;
+        let api_key = req.headers().get(tracker.settings.header.as_str())?;
+        Some(tracker.usage(api_key.to_str().ok()?))
+    }
+
+    /// Counts a request and, for reads of a single key, whether it was a hit or a miss.
+    fn record_request(&self, api_key: &str, method: &Method, path: &str, status: StatusCode) {
+        let usage = self.usage(api_key);
+        usage.requests.fetch_add(1, Ordering::Relaxed);
+        self.metrics
+            .requests
+            .with_label_values(&[&usage.label])
+            .inc();
+        if method != Method::GET || path.starts_with("/_") {
+            return;
+        }
+        let (counter, hit_or_miss) = match status {
+            StatusCode::OK | StatusCode::PARTIAL_CONTENT => (&usage.hits, "hit"),
+            StatusCode::NOT_FOUND => (&usage.misses, "miss"),
+            _ => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        self.metrics
+            .queries
+            .with_label_values(&[&usage.label, hit_or_miss])
+            .inc();
+    }
+
+    /// Returns the usage of every tracked API key and of the other API keys, by alias or hash.
+    #[cfg(feature = "admin")]
+    pub fn report(&self) -> BTreeMap<String, UsageReport> {
+        self.keys
+            .values()
+            .chain(std::iter::once(&self.other))
+            .map(|usage| {
+                let hits = usage.hits.load(Ordering::Relaxed);
+                let misses = usage.misses.load(Ordering::Relaxed);
+                let report = UsageReport {
+                    stored_bytes: usage.stored.load(Ordering::Relaxed),
+                    quota: usage.quota,
+                    requests: usage.requests.load(Ordering::Relaxed),
+                    hits,
+                    misses,
+                    hit_ratio: Some(hits as f64 / (hits + misses) as f64)
+                        .filter(|_| hits + misses > 0),
+                };
+                (usage.label.clone(), report)
+            })
+            .collect()
+    }
+}
+
+/// Counts the requests of each API key once they have been handled, if usage is tracked.
+/// # Arguments
+/// * `req` - The incoming request.
+/// * `srv` - The service handling the request.
+pub fn track<S, B>(
+    req: ServiceRequest,
+    srv: &mut S,
+) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    let request = req
+        .app_data::<web::Data<UsageTracker>>()
+        .and_then(|tracker| {
+            let api_key = req.headers().get(tracker.settings.header.as_str())?;
+            Some((tracker.clone(), api_key.to_str().ok()?.to_string()))
+        });
+    let method = req.method().clone();
+    let path = req.path().to_string();
+    srv.call(req).map(move |response| {
+        if let (Some((tracker, api_key)), Ok(response)) = (request, &response) {
+            tracker.record_request(&api_key, &method, &path, response.status());
+        }
+        response
+    })
+}
+
+#[cfg(all(test, feature = "admin"))]
+mod test {
+    use super::*;
+
+    fn tracker(quota: Option<u64>) -> UsageTracker {
+        let settings = settings::Usage {
+            quota,
+            aliases: vec![("secret".to_string(), "team".to_string())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        UsageTracker::new(settings, UsageMetrics::with_opts(&MetricOpts::default()))
+    }
+
+    #[test]
+    fn stored_bytes_are_released_when_dropped() {
+        let sut = tracker(Some(10));
+        let usage = sut.usage("secret");
+
+        let stored = StoredBytes::new(usage.clone(), 8);
+        assert!(usage.within_quota(2));
+        assert!(!usage.within_quota(3));
+        assert_eq!(sut.report()["team"].stored_bytes, 8);
+
+        drop(stored);
+        assert_eq!(sut.report()["team"].stored_bytes, 0);
+        assert_eq!(sut.metrics.stored.with_label_values(&["team"]).get(), 0);
+    }
+
+    #[test]
+    fn reads_count_towards_the_hit_ratio() {
+        let sut = tracker(None);
+
+        sut.record_request("secret", &Method::GET, "/a", StatusCode::OK);
+        sut.record_request("secret", &Method::GET, "/b", StatusCode::NOT_FOUND);
+        sut.record_request("secret", &Method::POST, "/b", StatusCode::OK);
+
+        let report = &sut.report()["team"];
+        assert_eq!((report.requests, report.hits, report.misses), (3, 1, 1));
+        assert_eq!(report.hit_ratio, Some(0.5));
+    }
+
+    #[test]
+    fn untracked_api_keys_share_the_other_usage() {
+        let settings = settings::Usage {
+            quota: Some(10),
+            quotas: vec![("listed".to_string(), 20)].into_iter().collect(),
+            ..Default::default()
+        };
+        let sut = UsageTracker::new(settings, UsageMetrics::with_opts(&MetricOpts::default()));
+
+        let _stored = StoredBytes::new(sut.usage("a"), 8);
+
+        assert!(Arc::ptr_eq(&sut.usage("a"), &sut.usage("b")));
+        assert!(!sut.usage("b").within_quota(3));
+        let report = sut.report();
+        let hash = digest::to_hex(digest::hash(b"listed"));
+        assert_eq!(report.keys().collect::<Vec<_>>(), vec![&hash, OTHER]);
+        assert_eq!(report[&hash].quota, Some(20));
+        assert_eq!(report[OTHER].stored_bytes, 8);
+    }
+}
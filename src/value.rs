@@ -0,0 +1,234 @@
+//! Cached values are stored as a list of chunks so a large value never needs one contiguous
+//! allocation, can be read from a request body as it arrives and streamed out chunk by chunk.
+use actix_web::{
+    error::{ErrorBadRequest, ErrorPayloadTooLarge},
+    web::{self, Bytes, BytesMut},
+    Error,
+};
+use futures::StreamExt;
+use std::{
+    io::{self, Read},
+    ops::Range,
+    str,
+};
+
+/// The size of the chunks large values are split into when none is configured, so a value of the
+/// default largest size spans several chunks.
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+/// The largest value accepted when none is configured.
+pub const DEFAULT_MAX_VALUE_SIZE: usize = 256 * 1024;
+
+/// A value made of one or more chunks.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Value {
+    chunks: Vec<Bytes>,
+    len: usize,
+    /// The value was stored as a JSON document.
+    json: bool,
+}
+
+impl Value {
+    /// Marks the value as a JSON document, it is not validated here.
+    pub fn into_json(mut self, json: bool) -> Self {
+        self.json = json;
+        self
+    }
+
+    /// Returns true when the value was stored as a JSON document.
+    pub fn is_json(&self) -> bool {
+        self.json
+    }
+
+    /// Returns a reader over the chunks of the value.
+    pub fn reader(&self) -> impl Read + '_ {
+        self.chunks
+            .iter()
+            .fold(Box::new(io::empty()) as Box<dyn Read>, |reader, chunk| {
+                Box::new(reader.chain(&chunk[..]))
+            })
+    }
+
+    /// Returns the length of the value in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns the chunks of the value in order.
+    pub fn chunks(&self) -> &[Bytes] {
+        &self.chunks
+    }
+
+    /// Returns the value as contiguous bytes, which is only copied when there is more than one
+    /// chunk.
+    pub fn to_bytes(&self) -> Bytes {
+        match self.chunks.as_slice() {
+            [] => Bytes::new(),
+            [chunk] => chunk.clone(),
+            chunks => {
+                let mut bytes = BytesMut::with_capacity(self.len);
+                for chunk in chunks {
+                    bytes.extend_from_slice(chunk);
+                }
+                bytes.freeze()
+            }
+        }
+    }
+
+    /// Returns the bytes in `range` without copying them.
+    pub fn slice(&self, range: Range<usize>) -> Self {
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        for chunk in &self.chunks {
+            let end = start + chunk.len();
+            if end > range.start && start < range.end {
+                let from = range.start.saturating_sub(start);
+                let to = chunk.len().min(range.end - start);
+                chunks.push(chunk.slice(from..to));
+            }
+            start = end;
+        }
+        chunks.into_iter().collect::<Self>().into_json(self.json)
+    }
+
+    /// Reads a request body into chunks of about `chunk_size` bytes. The body must be UTF-8, so
+    /// chunks are split on character boundaries.
+    /// # Arguments
+    /// * `payload` - The request body.
+    /// * `chunk_size` - The size of each chunk.
+    /// * `max_size` - The largest body accepted.
+    pub async fn read(
+        mut payload: web::Payload,
+        chunk_size: usize,
+        max_size: usize,
+    ) -> Result<Self, Error> {
+        let mut chunks = Vec::new();
+        let mut len = 0;
+        let mut buffer = BytesMut::new();
+        while let Some(bytes) = payload.next().await {
+            let bytes = bytes?;
+            len += bytes.len();
+            if len > max_size {
+                return Err(ErrorPayloadTooLarge(format!(
+                    "Values are limited to {} bytes",
+                    max_size
+                )));
+            }
+            buffer.extend_from_slice(&bytes);
+            while buffer.len() >= chunk_size {
+                // Continuation bytes start with 0b10, so a boundary is at most 3 bytes back.
+                let split = (chunk_size.saturating_sub(3)..=chunk_size)
+                    .rev()
+                    .find(|&i| i > 0 && (i == buffer.len() || buffer[i] & 0xc0 != 0x80))
+                    .unwrap_or(chunk_size);
+                chunks.push(validate(buffer.split_to(split).freeze())?);
+            }
+        }
+        if !buffer.is_empty() {
+            chunks.push(validate(buffer.freeze())?);
+        }
+        Ok(chunks.into_iter().collect())
+    }
+}
+
+fn validate(chunk: Bytes) -> Result<Bytes, Error> {
+    match str::from_utf8(&chunk) {
+        Ok(_) => Ok(chunk),
+        Err(err) => Err(ErrorBadRequest(err)),
+    }
+}
+
+impl std::iter::FromIterator<Bytes> for Value {
+    fn from_iter<I: IntoIterator<Item = Bytes>>(chunks: I) -> Self {
+        let chunks: Vec<Bytes> = chunks
+            .into_iter()
+            .filter(|chunk| !chunk.is_empty())
+            .collect();
+        let len = chunks.iter().map(Bytes::len).sum();
+        Self {
+            chunks,
+            len,
+            json: false,
+        }
+    }
+}
+
+impl From<Bytes> for Value {
+    fn from(bytes: Bytes) -> Self {
+        std::iter::once(bytes).collect()
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Bytes::from(value).into()
+    }
+}
+
+impl From<&'static str> for Value {
+    fn from(value: &'static str) -> Self {
+        Bytes::from_static(value.as_bytes()).into()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::test;
+
+    fn value(chunks: &[&'static str]) -> Value {
+        chunks
+            .iter()
+            .map(|chunk| Bytes::from_static(chunk.as_bytes()))
+            .collect()
+    }
+
+    #[test]
+    fn chunks_are_joined() {
+        let value = value(&["ab", "cd", "e"]);
+
+        assert_eq!(value.len(), 5);
+        assert_eq!(value.to_bytes(), Bytes::from("abcde"));
+    }
+
+    #[actix_rt::test]
+    async fn bodies_are_split_on_character_boundaries() {
+        let (_, payload) = test::TestRequest::default()
+            .set_payload("aéb")
+            .to_http_parts();
+
+        let value = Value::read(web::Payload(payload), 2, 100).await.unwrap();
+
+        assert_eq!(value, self::value(&["a", "é", "b"]));
+    }
+
+    #[actix_rt::test]
+    async fn large_bodies_are_rejected() {
+        let (_, payload) = test::TestRequest::default()
+            .set_payload("abc")
+            .to_http_parts();
+
+        let result = Value::read(web::Payload(payload), 2, 2).await;
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn chunks_are_read_in_order() {
+        let mut read = String::new();
+
+        value(&["ab", "cd"])
+            .reader()
+            .read_to_string(&mut read)
+            .unwrap();
+
+        assert_eq!(read, "abcd");
+    }
+
+    #[test]
+    fn slices_span_chunks() {
+        let value = value(&["ab", "cd", "e"]);
+
+        assert_eq!(value.slice(1..4), self::value(&["b", "cd"]));
+        assert_eq!(value.slice(4..5), self::value(&["e"]));
+    }
+}
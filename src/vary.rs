@@ -0,0 +1,107 @@
+//! Stores a separate value for each combination of the values of some request headers, like the
+//! `Vary` header of an HTTP response, so the cache server can hold e.g. a response per
+//! `Accept-Language` under one key.
+//!
+//! The headers are chosen by the longest key prefix in `cache.vary`. The storage key is the key
+//! followed by each header name and value, separated by the unit separator character.
+use actix_web::{
+    http::{header, HeaderMap, HeaderValue},
+    HttpResponse,
+};
+use std::collections::HashMap;
+
+/// Separates the key from the header values in the storage key.
+const SEPARATOR: char = '\u{1f}';
+
+/// Returns the headers values of `key` vary by, from the namespace with the longest prefix of `key`.
+fn headers<'s>(settings: &'s HashMap<String, Vec<String>>, key: &str) -> Option<&'s [String]> {
+    settings
+        .iter()
+        .filter(|(prefix, _)| key.starts_with(prefix.as_str()))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, headers)| headers.as_slice())
+        .filter(|headers| !headers.is_empty())
+}
+
+/// Returns the key the value of `key` is stored under for a request with `request_headers`.
+/// # Arguments
+/// * `settings` - The headers to vary by for each key prefix.
+/// * `key` - The key of the request.
+/// * `request_headers` - The headers of the request.
+pub fn storage_key(
+    settings: &HashMap<String, Vec<String>>,
+    key: &str,
+    request_headers: &HeaderMap,
+) -> String {
+    let mut storage_key = key.to_string();
+    for name in headers(settings, key).unwrap_or_default() {
+        let values: Vec<&str> = request_headers
+            .get_all(name.as_str())
+            .filter_map(|value| value.to_str().ok())
+            .collect();
+        storage_key.push(SEPARATOR);
+        storage_key.push_str(&name.to_ascii_lowercase());
+        storage_key.push('=');
+        storage_key.push_str(&values.join(","));
+    }
+    storage_key
+}
+
+/// Adds a `Vary` header listing the headers a successful response for `key` varies by.
+pub fn apply(settings: &HashMap<String, Vec<String>>, key: &str, response: &mut HttpResponse) {
+    let names = match headers(settings, key) {
+        Some(names) if response.status().is_success() => names.join(", "),
+        _ => return,
+    };
+    if let Ok(value) = HeaderValue::from_str(&names) {
+        response.headers_mut().insert(header::VARY, value);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::http::HeaderName;
+
+    fn settings() -> HashMap<String, Vec<String>> {
+        let mut settings = HashMap::new();
+        settings.insert("pages/".into(), vec!["Accept-Language".into()]);
+        settings
+    }
+
+    fn request_headers(language: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static("accept-language"),
+            HeaderValue::from_static(language),
+        );
+        headers
+    }
+
+    #[test]
+    fn values_are_stored_per_header_value() {
+        let settings = settings();
+
+        let en = storage_key(&settings, "pages/home", &request_headers("en"));
+        let de = storage_key(&settings, "pages/home", &request_headers("de"));
+
+        assert_eq!(en, "pages/home\u{1f}accept-language=en");
+        assert_ne!(en, de);
+        assert_eq!(
+            storage_key(&settings, "other", &request_headers("en")),
+            "other"
+        );
+    }
+
+    #[test]
+    fn responses_list_the_headers() {
+        let mut response = HttpResponse::Ok().finish();
+
+        apply(&settings(), "pages/home", &mut response);
+
+        assert_eq!(
+            response.headers().get(header::VARY).unwrap(),
+            "Accept-Language"
+        );
+    }
+}
@@ -0,0 +1,211 @@
+//! Loads the entries listed in `cache.warmup_manifest` before the servers start listening, so a
+//! fresh deploy starts with its most critical entries already cached.
+//!
+//! The manifest has one JSON object per line with a `key` and either its `value` or a `url` to GET
+//! it from, and optionally its `ttl_ms` or `expires_at_ms`, whether it is `json` and whether it is
+//! `immortal`, so the output of `/_admin/export` is a valid manifest. Entries with an
+//! `expires_at_ms` keep it by the wall clock, however long ago the manifest was written, and are
+//! skipped once it has passed. Up to `cache.warmup_concurrency` URLs are fetched at once, and
+//! entries that cannot be fetched are logged and skipped. Warming is skipped after a hot restart,
+//! which hands the entries over. Refresh jobs load their manifests in the same way.
+use crate::cache::SimpleCache;
+use crate::settings;
+use crate::value::{Value, DEFAULT_MAX_VALUE_SIZE};
+use actix_web::{client::Client, web::Bytes};
+use futures::{future, stream, StreamExt};
+use serde::Deserialize;
+use std::{
+    fs, io,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// The number of URLs fetched at once when `cache.warmup_concurrency` is not set.
+const DEFAULT_CONCURRENCY: usize = 16;
+
+/// How long a URL may take to answer.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where the value of an entry comes from.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+enum Source {
+    Value { value: String },
+    Url { url: String },
+}
+
+/// An entry listed in the manifest.
+#[derive(Debug, Deserialize, PartialEq)]
+struct ManifestEntry {
+    key: String,
+    #[serde(flatten)]
+    source: Source,
+    /// `cache.key_live_duration` when not set.
+    ttl_ms: Option<u64>,
+    #[serde(default)]
+    json: bool,
+    /// The entry never expires, `ttl_ms` is ignored.
+    #[serde(default)]
+    immortal: bool,
+    /// The unix time in milliseconds the entry expires at, which takes precedence over `ttl_ms`.
+    expires_at_ms: Option<u64>,
+}
+
+impl ManifestEntry {
+    /// Returns the ttl left at `now`, `default_ttl` if the entry has neither a ttl nor an expiry,
+    /// or None if it has expired.
+    fn remaining_ttl(&self, default_ttl: Duration, now: SystemTime) -> Option<Duration> {
+        match self.expires_at_ms {
+            Some(expires_at_ms) => {
+                let now = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+                Duration::from_millis(expires_at_ms)
+                    .checked_sub(now)
+                    .filter(|ttl| *ttl > Duration::from_millis(0))
+            }
+            None => Some(self.ttl_ms.map_or(default_ttl, Duration::from_millis)),
+        }
+    }
+}
+
+/// Parses a manifest, skipping blank lines.
+fn parse(manifest: &str) -> io::Result<Vec<ManifestEntry>> {
+    manifest
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            serde_json::from_str(line).map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Invalid warmup manifest entry on line {}. {}",
+                        index + 1,
+                        err
+                    ),
+                )
+            })
+        })
+        .collect()
+}
+
+/// Returns the body of a successful response to a GET of `url`.
+async fn fetch(client: &Client, url: &str, max_size: usize) -> Result<Bytes, String> {
+    let mut response = client
+        .get(url)
+        .send()
+        .await
+        .map_err(|err| err.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("Responded with: {}", response.status()));
+    }
+    response
+        .body()
+        .limit(max_size)
+        .await
+        .map_err(|err| err.to_string())
+}
+
+/// Loads the entries of the manifest at `path` into `cache`.
+/// # Arguments
+/// * `path` - The manifest.
+/// * `cache` - The cache to load the entries into.
+/// * `settings` - The default ttl, the largest value fetched and the number of concurrent fetches.
+pub async fn load(
+    path: &str,
+    cache: &SimpleCache<'static>,
+    settings: &settings::Cache,
+) -> io::Result<()> {
+    let started = Instant::now();
+    let entries = parse(&fs::read_to_string(path)?)?;
+    let listed = entries.len();
+    let client = Client::builder().timeout(FETCH_TIMEOUT).finish();
+    let client = &client;
+    let max_size = settings.max_value_size.unwrap_or(DEFAULT_MAX_VALUE_SIZE);
+    let default_ttl = Duration::from_secs(settings.key_live_duration);
+    let loaded: Vec<(String, Value, Option<Duration>)> = stream::iter(entries)
+        .map(|entry| async move {
+            let ttl = if entry.immortal {
+                None
+            } else {
+                match entry.remaining_ttl(default_ttl, SystemTime::now()) {
+                    Some(ttl) => Some(ttl),
+                    None => {
+                        log::debug!("Skipped key {} which has already expired", entry.key);
+                        return None;
+                    }
+                }
+            };
+            let value = match entry.source {
+                Source::Value { value } => Value::from(value),
+                Source::Url { url } => match fetch(client, &url, max_size).await {
+                    Ok(body) => Value::from(body),
+                    Err(err) => {
+                        log::warn!("Could not fetch key {} from {}. {}", entry.key, url, err);
+                        return None;
+                    }
+                },
+            };
+            Some((entry.key, value.into_json(entry.json), ttl))
+        })
+        .buffer_unordered(
+            settings
+                .warmup_concurrency
+                .unwrap_or(DEFAULT_CONCURRENCY)
+                .max(1),
+        )
+        .filter_map(future::ready)
+        .collect()
+        .await;
+    let count = loaded.len();
+    for (key, value, ttl) in loaded {
+        match ttl {
+            Some(ttl) => cache.put_with_ttl(key, value, ttl),
+            None => cache.put_forever(key, value),
+        };
+    }
+    log::info!(
+        "Loaded {} of {} entries from {} in {:?}",
+        count,
+        listed,
+        path,
+        started.elapsed()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn manifest_entries_have_a_value_or_a_url() {
+        let manifest = r#"{"key": "a", "value": "1", "ttl_ms": 500}
+
+{"key": "b", "url": "http://127.0.0.1:9000/b", "json": true}"#;
+
+        assert_eq!(
+            parse(manifest).unwrap(),
+            vec![
+                ManifestEntry {
+                    key: "a".into(),
+                    source: Source::Value { value: "1".into() },
+                    ttl_ms: Some(500),
+                    json: false,
+                    immortal: false,
+                    expires_at_ms: None,
+                },
+                ManifestEntry {
+                    key: "b".into(),
+                    source: Source::Url {
+                        url: "http://127.0.0.1:9000/b".into()
+                    },
+                    ttl_ms: None,
+                    json: true,
+                    immortal: false,
+                    expires_at_ms: None,
+                },
+            ]
+        );
+        let err = parse(r#"{"key": "c"}"#).unwrap_err();
+        assert!(err.to_string().contains("line 1"), "{}", err);
+    }
+}
@@ -0,0 +1,267 @@
+//! The checks every write goes through, whichever endpoint it comes from: `POST` and `PUT /{key}`,
+//! pipelines, transactions, batch puts and scripts.
+//!
+//! A value is rejected if its key is denied, it does not conform to the schema of its key, it is
+//! larger than `cache.max_value_size`, it would take the API key writing it over its quota, or its
+//! key is new and the client has created too many keys. Under memory pressure a value is only
+//! stored if the cache can evict entries to make room for it, which go to the disk tier if there
+//! is one.
+use crate::cache::SimpleCache;
+use crate::deny::Denied;
+use crate::eviction::Priority;
+use crate::limits::KeyLimiter;
+use crate::pressure::MemoryPressure;
+use crate::schema::SchemaErrors;
+use crate::settings;
+use crate::ttl::{Expiry, TtlPolicy};
+use crate::usage::{ApiKeyUsage, UsageTracker};
+use crate::value::{Value, DEFAULT_MAX_VALUE_SIZE};
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse};
+use std::{fmt, net::IpAddr, sync::Arc};
+
+/// Why a write was rejected.
+#[derive(Debug)]
+pub enum Rejected {
+    /// Writes to the key are denied.
+    Denied(Denied),
+    /// The value does not conform to the schema of its key.
+    Invalid(SchemaErrors),
+    /// The value is larger than `cache.max_value_size`.
+    TooLarge(usize),
+    /// The API key writing the value is over its quota.
+    OverQuota,
+    /// The key is new and the client has created too many keys.
+    TooManyKeys,
+    /// The key exists and the write may only create it.
+    Exists,
+    /// The cache is under memory pressure and can not make room for the value.
+    Pressure,
+}
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejected::Denied(denied) => denied.fmt(f),
+            Rejected::Invalid(errors) => errors.fmt(f),
+            Rejected::TooLarge(max) => write!(f, "The value is larger than {} bytes", max),
+            Rejected::OverQuota => write!(f, "The API key is over its quota"),
+            Rejected::TooManyKeys => write!(f, "Too many new keys, try again later"),
+            Rejected::Exists => write!(f, "The key already exists"),
+            Rejected::Pressure => write!(f, "Rejecting writes under memory pressure"),
+        }
+    }
+}
+
+impl Rejected {
+    /// Returns the status a single key endpoint answers the rejection with.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Rejected::Denied(_) | Rejected::Invalid(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Rejected::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Rejected::OverQuota => StatusCode::INSUFFICIENT_STORAGE,
+            Rejected::TooManyKeys => StatusCode::TOO_MANY_REQUESTS,
+            Rejected::Exists => StatusCode::CONFLICT,
+            Rejected::Pressure => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    /// Returns the response of a single key endpoint, which lists the schema errors as JSON.
+    pub fn response(&self) -> HttpResponse {
+        match self {
+            Rejected::Invalid(errors) => {
+                HttpResponse::build(self.status()).json(serde_json::json!({ "errors": errors.0 }))
+            }
+            rejected => HttpResponse::build(self.status()).body(rejected.to_string()),
+        }
+    }
+}
+
+/// The checks the writes of one request go through.
+pub struct WriteChecks {
+    pub pressure: web::Data<MemoryPressure>,
+    pub limiter: web::Data<KeyLimiter>,
+    pub settings: web::Data<settings::Cache>,
+    /// The address of the client writing.
+    pub client: Option<IpAddr>,
+    /// The usage of the API key writing, if usage is tracked.
+    pub usage: Option<Arc<ApiKeyUsage>>,
+}
+
+impl WriteChecks {
+    /// Returns the checks of the writes of `req`.
+    pub fn new(
+        req: &HttpRequest,
+        pressure: web::Data<MemoryPressure>,
+        limiter: web::Data<KeyLimiter>,
+        settings: web::Data<settings::Cache>,
+    ) -> Self {
+        Self {
+            pressure,
+            limiter,
+            settings,
+            client: req.peer_addr().map(|addr| addr.ip()),
+            usage: UsageTracker::for_request(req),
+        }
+    }
+
+    /// Returns an error if the cache is under memory pressure and can not evict entries, so no
+    /// write can succeed.
+    pub fn admit(&self, cache: &SimpleCache<'_>) -> Result<(), Rejected> {
+        if self.pressure.check(cache.size()) && !cache.evicts() {
+            return Err(Rejected::Pressure);
+        }
+        Ok(())
+    }
+
+    /// Returns an error if writes to `key` are denied.
+    pub fn check_key(&self, cache: &SimpleCache<'_>, key: &str) -> Result<(), Rejected> {
+        cache.check_denied(key).map_err(Rejected::Denied)
+    }
+
+    /// Returns an error if `value` does not conform to the schema of `key`, is too large or would
+    /// take the API key over its quota.
+    pub fn check_value(
+        &self,
+        cache: &SimpleCache<'_>,
+        key: &str,
+        value: &Value,
+    ) -> Result<(), Rejected> {
+        let max_value_size = self
+            .settings
+            .max_value_size
+            .unwrap_or(DEFAULT_MAX_VALUE_SIZE);
+        if value.len() > max_value_size {
+            return Err(Rejected::TooLarge(max_value_size));
+        }
+        cache.validate(key, value).map_err(Rejected::Invalid)?;
+        if let Some(usage) = &self.usage {
+            let size = value.len().saturating_sub(cache.owned_size(key, usage));
+            if !usage.within_quota(size) {
+                return Err(Rejected::OverQuota);
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts a new key and returns an error if `key` is new and the client has created too many
+    /// keys.
+    pub fn check_new_key(&self, cache: &SimpleCache<'_>, key: &str) -> Result<(), Rejected> {
+        if !cache.contains_key(key) && !self.limiter.allow_new_key(self.client) {
+            return Err(Rejected::TooManyKeys);
+        }
+        Ok(())
+    }
+
+    /// Stores a value that has been checked, evicting entries to make room for it under memory
+    /// pressure, and returns true if its key was created.
+    /// # Arguments
+    /// * `cache` - The cache to store the value in.
+    /// * `key` - The key of the value.
+    /// * `value` - The value to store.
+    /// * `expiry` - When the value expires.
+    /// * `priority` - The eviction priority of the value.
+    /// * `create_only` - Reject the write if the key exists.
+    pub fn store(
+        &self,
+        cache: &SimpleCache<'_>,
+        key: String,
+        value: Value,
+        expiry: Expiry,
+        priority: Priority,
+        create_only: bool,
+    ) -> Result<bool, Rejected> {
+        if self.pressure.check(cache.size()) && !cache.make_room(&key, value.len(), priority) {
+            return Err(Rejected::Pressure);
+        }
+        self.insert(cache, key, value, expiry, priority, create_only)
+    }
+
+    /// Stores a value that has been checked once room has been made for it, and returns true if
+    /// its key was created.
+    pub fn insert(
+        &self,
+        cache: &SimpleCache<'_>,
+        key: String,
+        value: Value,
+        expiry: Expiry,
+        priority: Priority,
+        create_only: bool,
+    ) -> Result<bool, Rejected> {
+        let created = if create_only {
+            // The key may have been created since it was checked.
+            cache
+                .put_if_absent(key.clone(), value, expiry, priority)
+                .ok_or(Rejected::Exists)?
+        } else {
+            cache.put_with_priority(key.clone(), value, expiry, priority)
+        };
+        if let Some(usage) = &self.usage {
+            cache.set_owner(&key, usage);
+        }
+        Ok(created)
+    }
+
+    /// Checks and stores `value` under `key` with the expiry of the namespace of the key, and
+    /// returns true if the key was created.
+    /// # Arguments
+    /// * `cache` - The cache to store the value in.
+    /// * `key` - The normalized key of the value.
+    /// * `value` - The value to store.
+    /// * `priority` - The eviction priority of the value.
+    pub fn put(
+        &self,
+        cache: &SimpleCache<'_>,
+        key: String,
+        value: Value,
+        priority: Priority,
+    ) -> Result<bool, Rejected> {
+        self.check_key(cache, &key)?;
+        self.check_value(cache, &key, &value)?;
+        self.check_new_key(cache, &key)?;
+        let expiry = TtlPolicy::new(&self.settings).for_key(&key);
+        self.store(cache, key, value, expiry, priority, false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::{CacheMetrics, MetricOpts};
+    use crate::settings::Settings;
+    use std::time::Duration;
+
+    fn checks(settings: settings::Cache) -> WriteChecks {
+        WriteChecks {
+            pressure: web::Data::new(MemoryPressure::new(
+                Default::default(),
+                &MetricOpts::default(),
+            )),
+            limiter: web::Data::new(KeyLimiter::new(Default::default(), &MetricOpts::default())),
+            settings: web::Data::new(settings),
+            client: None,
+            usage: None,
+        }
+    }
+
+    #[test]
+    fn puts_follow_the_settings_of_writes() {
+        let cache = SimpleCache::new(Duration::from_secs(60), CacheMetrics::default());
+        let mut settings = Settings::new().unwrap().cache;
+        settings.max_value_size = Some(4);
+        settings.immortal_prefixes = vec!["config/".into()];
+        let sut = checks(settings);
+
+        let large = sut.put(&cache, "a".into(), Value::from("12345"), Priority::Normal);
+        let immortal = sut.put(
+            &cache,
+            "config/a".into(),
+            Value::from("1"),
+            Priority::Normal,
+        );
+
+        assert_eq!(large.unwrap_err().status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(!cache.contains_key("a"));
+        assert!(immortal.unwrap());
+        assert!(cache.entries(|_| true)[0].immortal);
+    }
+}
@@ -0,0 +1,125 @@
+//! Closes the servers to writes while the cache contents are handed off. Pausing the servers only
+//! stops new connections, so writes on open keep-alive connections are counted, waited for when
+//! the gate closes and answered with 503 while it is closed, and none land after the entries have
+//! been sent. The writes made in the background, values read through from the Redis tier, promoted
+//! from the disk tier or stored by refresh jobs, pass through the gate as well.
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse},
+    error::ErrorServiceUnavailable,
+    http::Method,
+    web, Error,
+};
+use futures::future::{ok, FutureExt, LocalBoxFuture};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+#[cfg(feature = "persistence")]
+use std::{thread, time::Duration};
+
+/// How often closing checks whether the writes in progress have finished.
+#[cfg(feature = "persistence")]
+const DRAIN_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Counts the writes in progress and rejects new ones once closed.
+#[derive(Debug, Default)]
+pub struct WriteGate {
+    closed: AtomicBool,
+    in_flight: AtomicUsize,
+}
+
+/// A write in progress, counted until dropped.
+pub struct Write(web::Data<WriteGate>);
+
+impl Drop for Write {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl WriteGate {
+    /// Returns the write that has been let through, or `None` if the gate is closed.
+    pub fn enter(gate: &web::Data<WriteGate>) -> Option<Write> {
+        // Counted before checking, so a write is either waited for by close or rejected.
+        gate.in_flight.fetch_add(1, Ordering::SeqCst);
+        let write = Write(gate.clone());
+        if gate.closed.load(Ordering::SeqCst) {
+            return None;
+        }
+        Some(write)
+    }
+
+    /// Rejects new writes and blocks until the writes in progress have finished.
+    #[cfg(feature = "persistence")]
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        while self.in_flight.load(Ordering::SeqCst) > 0 {
+            thread::sleep(DRAIN_INTERVAL);
+        }
+    }
+
+    /// Lets writes through again.
+    #[cfg(feature = "persistence")]
+    pub fn open(&self) {
+        self.closed.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Passes every request other than GET, HEAD and OPTIONS through the gate, if there is one.
+/// # Arguments
+/// * `req` - The incoming request.
+/// * `srv` - The service handling the request.
+pub fn guard<S, B>(
+    req: ServiceRequest,
+    srv: &mut S,
+) -> LocalBoxFuture<'static, Result<ServiceResponse<B>, Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    let gate = match req.app_data::<web::Data<WriteGate>>() {
+        Some(gate) if ![Method::GET, Method::HEAD, Method::OPTIONS].contains(req.method()) => {
+            gate.clone()
+        }
+        _ => return srv.call(req).boxed_local(),
+    };
+    match WriteGate::enter(&gate) {
+        Some(write) => srv
+            .call(req)
+            .map(move |response| {
+                drop(write);
+                response
+            })
+            .boxed_local(),
+        None => ok(req.error_response(ErrorServiceUnavailable(
+            "Writes are paused while the cache is handed off",
+        )))
+        .boxed_local(),
+    }
+}
+
+#[cfg(all(test, feature = "persistence"))]
+mod test {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn closing_waits_for_writes_in_progress() {
+        let gate = web::Data::new(WriteGate::default());
+        let write = WriteGate::enter(&gate).unwrap();
+        let (closed, is_closed) = mpsc::channel();
+        let closing = gate.clone();
+        let closer = thread::spawn(move || {
+            closing.close();
+            closed.send(()).unwrap();
+        });
+
+        thread::sleep(Duration::from_millis(20));
+        assert!(is_closed.try_recv().is_err());
+        assert!(WriteGate::enter(&gate).is_none());
+        drop(write);
+        closer.join().unwrap();
+        assert!(is_closed.try_recv().is_ok());
+
+        gate.open();
+        assert!(WriteGate::enter(&gate).is_some());
+    }
+}
@@ -0,0 +1,242 @@
+//! Boots the cache server on ephemeral ports with a temporary configuration and exercises it over
+//! HTTP, end to end.
+use actix_rt::time::delay_for;
+use actix_web::{client::Client, http::StatusCode};
+use std::{
+    env, fs,
+    net::TcpListener,
+    path::PathBuf,
+    process::{self, Child, Command, Stdio},
+    time::{Duration, Instant},
+};
+
+/// How long the server has to start listening.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A running cache server, stopped and cleaned up when dropped.
+struct Server {
+    process: Child,
+    dir: PathBuf,
+    cache_url: String,
+    metrics_url: String,
+}
+
+/// Returns two ports that are free, holding both until each has been chosen.
+fn free_ports() -> (u16, u16) {
+    let first = TcpListener::bind("127.0.0.1:0").unwrap();
+    let second = TcpListener::bind("127.0.0.1:0").unwrap();
+    (
+        first.local_addr().unwrap().port(),
+        second.local_addr().unwrap().port(),
+    )
+}
+
+impl Server {
+    /// Starts the server in a directory of its own, named after the test, with the default
+    /// configuration listening on free ports, and waits until it answers.
+    async fn start(name: &str) -> Self {
+        let dir = env::temp_dir().join(format!("simple-mem-cache-{}-{}", name, process::id()));
+        let config_dir = dir.join("config");
+        fs::create_dir_all(&config_dir).unwrap();
+        let (cache_port, metrics_port) = free_ports();
+        let manifest_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        let config = fs::read_to_string(manifest_dir.join("config/default.yaml"))
+            .unwrap()
+            .replace("127.0.0.1:8080", &format!("127.0.0.1:{}", cache_port))
+            .replace("127.0.0.1:8081", &format!("127.0.0.1:{}", metrics_port));
+        fs::write(config_dir.join("default.yaml"), config).unwrap();
+        fs::copy(
+            manifest_dir.join("config/log4rs.yaml"),
+            config_dir.join("log4rs.yaml"),
+        )
+        .unwrap();
+        let process = Command::new(env!("CARGO_BIN_EXE_simple-mem-cache"))
+            .current_dir(&dir)
+            .env_remove("RUN_MODE")
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        let server = Self {
+            process,
+            dir,
+            cache_url: format!("http://127.0.0.1:{}", cache_port),
+            metrics_url: format!("http://127.0.0.1:{}", metrics_port),
+        };
+        server.wait_until_ready().await;
+        server
+    }
+
+    async fn wait_until_ready(&self) {
+        let client = Client::default();
+        let started = Instant::now();
+        loop {
+            // The cache server is started before the metrics server, on sockets bound before both.
+            if client.get(self.metrics("/healthz")).send().await.is_ok() {
+                return;
+            }
+            assert!(
+                started.elapsed() < STARTUP_TIMEOUT,
+                "The server did not start listening"
+            );
+            delay_for(Duration::from_millis(50)).await;
+        }
+    }
+
+    /// Returns the URL of `path` on the cache server.
+    fn cache(&self, path: &str) -> String {
+        format!("{}{}", self.cache_url, path)
+    }
+
+    /// Returns the URL of `path` on the metrics server.
+    fn metrics(&self, path: &str) -> String {
+        format!("{}{}", self.metrics_url, path)
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[actix_rt::test]
+async fn values_written_are_read_back() {
+    let server = Server::start("read-back").await;
+    let client = Client::default();
+
+    let written = client
+        .post(server.cache("/v1/keys/a"))
+        .send_body("value")
+        .await
+        .unwrap();
+    let mut read = client.get(server.cache("/v1/keys/a")).send().await.unwrap();
+    let missing = client.get(server.cache("/v1/keys/b")).send().await.unwrap();
+
+    assert_eq!(written.status(), StatusCode::CREATED);
+    assert_eq!(written.headers().get("location").unwrap(), "/v1/keys/a");
+    assert_eq!(read.status(), StatusCode::OK);
+    assert_eq!(read.body().await.unwrap(), "value");
+    assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_rt::test]
+async fn values_put_replace_existing_values() {
+    let server = Server::start("put").await;
+    let client = Client::default();
+
+    let created = client
+        .put(server.cache("/v1/keys/a"))
+        .send_body("old")
+        .await
+        .unwrap();
+    let replaced = client
+        .put(server.cache("/v1/keys/a"))
+        .send_body("new")
+        .await
+        .unwrap();
+    let mut read = client.get(server.cache("/v1/keys/a")).send().await.unwrap();
+
+    assert_eq!(created.status(), StatusCode::CREATED);
+    assert_eq!(replaced.status(), StatusCode::OK);
+    assert_eq!(read.body().await.unwrap(), "new");
+}
+
+#[actix_rt::test]
+async fn deleted_values_are_not_found() {
+    let server = Server::start("delete").await;
+    let client = Client::default();
+    client
+        .put(server.cache("/v1/keys/a"))
+        .header("X-Ttl", "0")
+        .send_body("value")
+        .await
+        .unwrap();
+
+    let deleted = client
+        .delete(server.cache("/v1/keys/a"))
+        .send()
+        .await
+        .unwrap();
+    let read = client.get(server.cache("/v1/keys/a")).send().await.unwrap();
+    let missing = client
+        .delete(server.cache("/v1/keys/a"))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(deleted.status(), StatusCode::NO_CONTENT);
+    assert_eq!(read.status(), StatusCode::NOT_FOUND);
+    assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_rt::test]
+async fn flushed_values_are_not_found() {
+    let server = Server::start("flush").await;
+    let client = Client::default();
+    client
+        .post(server.cache("/v1/keys/a"))
+        .send_body("value")
+        .await
+        .unwrap();
+
+    let mut flushed = client
+        .post(server.metrics("/_admin/flush"))
+        .send()
+        .await
+        .unwrap();
+    let read = client.get(server.cache("/v1/keys/a")).send().await.unwrap();
+
+    assert_eq!(flushed.status(), StatusCode::OK);
+    assert_eq!(flushed.body().await.unwrap(), r#"{"removed":1}"#);
+    assert_eq!(read.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_rt::test]
+async fn hits_and_misses_are_exported_to_prometheus() {
+    let server = Server::start("metrics").await;
+    let client = Client::default();
+    client
+        .post(server.cache("/v1/keys/a"))
+        .send_body("value")
+        .await
+        .unwrap();
+    client.get(server.cache("/v1/keys/a")).send().await.unwrap();
+    client.get(server.cache("/v1/keys/b")).send().await.unwrap();
+
+    let mut response = client.get(server.metrics("/metrics")).send().await.unwrap();
+    let body = response.body().await.unwrap();
+    let metrics = String::from_utf8_lossy(&body);
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(
+        metrics.contains("cache_query{hit_or_miss=\"hit\"} 1"),
+        "{}",
+        metrics
+    );
+    assert!(
+        metrics.contains("cache_query{hit_or_miss=\"miss\"} 1"),
+        "{}",
+        metrics
+    );
+    assert!(metrics.contains("cache_items 1"), "{}", metrics);
+}
+
+#[actix_rt::test]
+async fn the_build_is_reported() {
+    let server = Server::start("version").await;
+    let client = Client::default();
+
+    let mut response = client
+        .get(server.metrics("/_admin/version"))
+        .send()
+        .await
+        .unwrap();
+    let body: serde_json::Value = response.json().await.unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+    assert!(body["commit"].is_string(), "{}", body);
+    assert!(body["limits"]["max_value_size"].is_number(), "{}", body);
+}